@@ -0,0 +1,63 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+#![no_main]
+use libfuzzer_sys::arbitrary::{Arbitrary, Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+
+use regalloc2::fuzzing::checker::Checker;
+use regalloc2::fuzzing::func::{Func, Options};
+use regalloc2::{Algorithm, RegallocOptions};
+
+#[derive(Clone, Debug)]
+struct TestCase {
+    func: Func,
+    spill_everything: bool,
+}
+
+impl Arbitrary for TestCase {
+    fn arbitrary(u: &mut Unstructured) -> Result<TestCase> {
+        Ok(TestCase {
+            func: Func::arbitrary_with_options(
+                u,
+                &Options {
+                    reused_inputs: true,
+                    fixed_regs: true,
+                    clobbers: true,
+                    control_flow: true,
+                    reducible: false,
+                    block_params: true,
+                    always_local_uses: false,
+                    reftypes: true,
+                    multiple_classes: true,
+                    remat: false,
+                    fixed_stack_locs: true,
+                    reg_subsets: true,
+                },
+            )?,
+            spill_everything: bool::arbitrary(u)?,
+        })
+    }
+}
+
+fuzz_target!(|testcase: TestCase| {
+    let func = testcase.func;
+    let _ = env_logger::try_init();
+    log::trace!("func:\n{:?}", func);
+    let env = regalloc2::fuzzing::func::machine_env();
+    let options = RegallocOptions {
+        algorithm: if testcase.spill_everything {
+            Algorithm::SpillEverything
+        } else {
+            Algorithm::Fastalloc
+        },
+        ..RegallocOptions::default()
+    };
+    let out = regalloc2::run(&func, &env, &options).expect("regalloc did not succeed");
+
+    let mut checker = Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().expect("checker failed");
+});
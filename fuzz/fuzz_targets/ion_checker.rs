@@ -9,10 +9,14 @@ use libfuzzer_sys::fuzz_target;
 
 use regalloc2::fuzzing::checker::Checker;
 use regalloc2::fuzzing::func::{Func, Options};
+use regalloc2::fuzzing::ion::Ctx;
+use regalloc2::{Output, PReg, PRegSet, RegClass};
 
 #[derive(Clone, Debug)]
 struct TestCase {
     func: Func,
+    stackmap_regs: bool,
+    budget: Option<usize>,
 }
 
 impl Arbitrary for TestCase {
@@ -29,8 +33,19 @@ impl Arbitrary for TestCase {
                     block_params: true,
                     always_local_uses: false,
                     reftypes: true,
+                    multiple_classes: true,
+                    remat: true,
+                    fixed_stack_locs: true,
+                    reg_subsets: true,
                 },
             )?,
+            stackmap_regs: bool::arbitrary(u)?,
+            // Small budgets, so that the fallback paths are taken.
+            budget: if bool::arbitrary(u)? {
+                Some(u.int_in_range(0..=64)?)
+            } else {
+                None
+            },
         })
     }
 }
@@ -39,8 +54,25 @@ fuzz_target!(|testcase: TestCase| {
     let func = testcase.func;
     let _ = env_logger::try_init();
     log::trace!("func:\n{:?}", func);
-    let env = regalloc2::fuzzing::func::machine_env();
-    let out = regalloc2::fuzzing::ion::run(&func, &env, true).expect("regalloc did not succeed");
+    let mut env = regalloc2::fuzzing::func::machine_env();
+    if testcase.stackmap_regs {
+        // Let reference-typed values stay in the non-preferred
+        // integer registers across safepoints.
+        env.stackmap_regs = (24..32).fold(PRegSet::empty(), |set, hw_enc| {
+            set.with(PReg::new(hw_enc, RegClass::Int))
+        });
+    }
+    let mut out = Output::default();
+    regalloc2::fuzzing::ion::run_with_ctx(
+        &func,
+        &env,
+        true,
+        testcase.budget,
+        None,
+        &mut Ctx::default(),
+        &mut out,
+    )
+    .expect("regalloc did not succeed");
 
     let mut checker = Checker::new(&func, &env);
     checker.prepare(&out);
@@ -0,0 +1,94 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+#![no_main]
+use libfuzzer_sys::arbitrary::{Arbitrary, Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+
+use regalloc2::fuzzing::checker::Checker;
+use regalloc2::fuzzing::func::{Func, Options};
+use regalloc2::fuzzing::optimal;
+use regalloc2::{MachineEnv, PReg, RegClass};
+
+#[derive(Clone, Debug)]
+struct TestCase {
+    func: Func,
+}
+
+impl Arbitrary for TestCase {
+    fn arbitrary(u: &mut Unstructured) -> Result<TestCase> {
+        Ok(TestCase {
+            func: Func::arbitrary_with_options(
+                u,
+                &Options {
+                    reused_inputs: true,
+                    fixed_regs: true,
+                    clobbers: true,
+                    control_flow: true,
+                    reducible: false,
+                    block_params: true,
+                    always_local_uses: false,
+                    reftypes: true,
+                    multiple_classes: true,
+                    remat: false,
+                    fixed_stack_locs: false,
+                    reg_subsets: false,
+                },
+            )?,
+        })
+    }
+}
+
+/// A machine with few registers, so that even tiny functions need
+/// spills and moves.
+fn small_machine_env() -> MachineEnv {
+    let mut env = regalloc2::fuzzing::func::machine_env();
+    for class in RegClass::ALL {
+        // p16 of the float class has no aliases, so that the move
+        // resolver has a register to use as its victim.
+        let second = if class == RegClass::Float { 16 } else { 1 };
+        env.preferred_regs_by_class[class as usize] =
+            vec![PReg::new(0, class), PReg::new(second, class)];
+        env.non_preferred_regs_by_class[class as usize] = vec![PReg::new(24, class)];
+    }
+    env
+}
+
+fuzz_target!(|testcase: TestCase| {
+    let func = testcase.func;
+    let _ = env_logger::try_init();
+    log::trace!("func:\n{:?}", func);
+    let env = small_machine_env();
+    let solution = match optimal::run(&func, &env, &optimal::Limits::default()) {
+        Ok(solution) => solution,
+        // Too few registers for the operands may also leave no
+        // allocation at all.
+        Err(optimal::Error::TooLarge)
+        | Err(optimal::Error::Unsupported(_))
+        | Err(optimal::Error::NoSolution) => return,
+        Err(e) => panic!("optimal allocation failed: {:?}", e),
+    };
+    let mut checker = Checker::new(&func, &env);
+    checker.prepare(&solution.output);
+    checker.run().expect("checker failed on optimal allocation");
+
+    let out = match regalloc2::fuzzing::ion::run(&func, &env, false) {
+        Ok(out) => out,
+        Err(_) => return,
+    };
+    let mut checker = Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().expect("checker failed");
+
+    // The reference is optimal only within its model (see
+    // `fuzzing::optimal`), so the gap is logged rather than checked.
+    let ion_cost = optimal::cost(&func, &out);
+    log::info!(
+        "ion cost {} optimal cost {} gap {}",
+        ion_cost,
+        solution.cost,
+        ion_cost as i64 - solution.cost as i64
+    );
+});
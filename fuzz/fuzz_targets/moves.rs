@@ -83,7 +83,9 @@ fuzz_target!(|testcase: TestCase| {
         Allocation::stack(SpillSlot::new(slot, RegClass::Int))
     };
     let preferred_victim = PReg::new(0, RegClass::Int);
-    let scratch_resolver = MoveAndScratchResolver::new(get_reg, get_stackslot, preferred_victim);
+    let is_stack_alloc = |alloc: Allocation| alloc.is_stack();
+    let scratch_resolver =
+        MoveAndScratchResolver::new(get_reg, get_stackslot, is_stack_alloc, preferred_victim);
     let moves = scratch_resolver.compute(moves);
     log::trace!("resolved moves: {:?}", moves);
 
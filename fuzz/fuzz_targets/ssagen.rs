@@ -30,6 +30,10 @@ impl Arbitrary for TestCase {
                     always_local_uses: false,
                     block_params: true,
                     reftypes: true,
+                    multiple_classes: true,
+                    remat: true,
+                    fixed_stack_locs: true,
+                    reg_subsets: true,
                 },
             )?,
         })
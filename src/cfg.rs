@@ -8,7 +8,7 @@
 use crate::{domtree, postorder, Block, Function, Inst, OperandKind, ProgPoint, RegAllocError};
 use smallvec::{smallvec, SmallVec};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct CFGInfo {
     /// Postorder traversal of blocks.
     pub postorder: Vec<Block>,
@@ -36,22 +36,50 @@ pub struct CFGInfo {
     pub approx_loop_depth: Vec<u32>,
 }
 
+/// Clear `v` and fill it with `n` copies of `value`, keeping its
+/// allocation.
+fn refill<T: Clone>(v: &mut Vec<T>, n: usize, value: T) {
+    v.clear();
+    v.resize(n, value);
+}
+
 impl CFGInfo {
     pub fn new<F: Function>(f: &F) -> Result<CFGInfo, RegAllocError> {
-        let postorder = postorder::calculate(f.num_blocks(), f.entry_block(), |block| {
+        let mut info = CFGInfo::default();
+        info.init(f)?;
+        Ok(info)
+    }
+
+    /// Compute the CFG info for `f`, reusing the storage from any
+    /// earlier function.
+    pub fn init<F: Function>(&mut self, f: &F) -> Result<(), RegAllocError> {
+        self.postorder = postorder::calculate(f.num_blocks(), f.entry_block(), |block| {
             f.block_succs(block)
         });
-        let domtree = domtree::calculate(
+        self.domtree = domtree::calculate(
             f.num_blocks(),
             |block| f.block_preds(block),
-            &postorder[..],
+            &self.postorder[..],
             f.entry_block(),
         );
-        let mut insn_block = vec![Block::invalid(); f.num_insts()];
-        let mut vreg_def_inst = vec![Inst::invalid(); f.num_vregs()];
-        let mut vreg_def_blockparam = vec![(Block::invalid(), 0); f.num_vregs()];
-        let mut block_entry = vec![ProgPoint::before(Inst::invalid()); f.num_blocks()];
-        let mut block_exit = vec![ProgPoint::before(Inst::invalid()); f.num_blocks()];
+        let insn_block = &mut self.insn_block;
+        let vreg_def_inst = &mut self.vreg_def_inst;
+        let vreg_def_blockparam = &mut self.vreg_def_blockparam;
+        let block_entry = &mut self.block_entry;
+        let block_exit = &mut self.block_exit;
+        refill(insn_block, f.num_insts(), Block::invalid());
+        refill(vreg_def_inst, f.num_vregs(), Inst::invalid());
+        refill(vreg_def_blockparam, f.num_vregs(), (Block::invalid(), 0));
+        refill(
+            block_entry,
+            f.num_blocks(),
+            ProgPoint::before(Inst::invalid()),
+        );
+        refill(
+            block_exit,
+            f.num_blocks(),
+            ProgPoint::before(Inst::invalid()),
+        );
         let mut backedge_in = vec![0; f.num_blocks()];
         let mut backedge_out = vec![0; f.num_blocks()];
 
@@ -114,7 +142,8 @@ impl CFGInfo {
             }
         }
 
-        let mut approx_loop_depth = vec![];
+        let approx_loop_depth = &mut self.approx_loop_depth;
+        approx_loop_depth.clear();
         let mut backedge_stack: SmallVec<[usize; 4]> = smallvec![];
         let mut cur_depth = 0;
         for block in 0..f.num_blocks() {
@@ -135,19 +164,17 @@ impl CFGInfo {
             }
         }
 
-        Ok(CFGInfo {
-            postorder,
-            domtree,
-            insn_block,
-            vreg_def_inst,
-            vreg_def_blockparam,
-            block_entry,
-            block_exit,
-            approx_loop_depth,
-        })
+        Ok(())
     }
 
     pub fn dominates(&self, a: Block, b: Block) -> bool {
         domtree::dominates(&self.domtree[..], a, b)
     }
+
+    /// An estimate of how often `block` runs relative to the entry
+    /// block: 8 times more for each level of loop nesting, up to 4
+    /// levels.
+    pub fn block_weight(&self, block: Block) -> u64 {
+        8u64.pow(std::cmp::min(self.approx_loop_depth[block.index()], 4))
+    }
 }
@@ -39,7 +39,16 @@
 //! not arbitrary programs.)
 //!
 //! Operand constraints (fixed register, register, any) are also checked
-//! at each operand.
+//! at each operand, and each debug-value-label location in the output
+//! is checked to hold its vreg at every program point it covers.
+//! The frame is checked as well: spillslots must lie within
+//! `Output::num_spillslots` and be aligned to their class's size,
+//! every allocation must be of its value's class, registers in
+//! `MachineEnv::fixed_stack_slots` may only be used as stack
+//! locations, no move may go from one stack location to another, and
+//! edits must be in order.
+//! At each safepoint, every reference-typed vreg that is live there
+//! must be held by some allocation in the stackmap.
 //!
 //! ## Formal Definition
 //!
@@ -67,6 +76,17 @@
 //!
 //!       A' = A[alloc_d → A[alloc_s]]
 //!
+//!     Any register that overlaps (aliases) `alloc_d` loses its
+//!     value, as does any alias of a def or clobber below. So does
+//!     the spillslot `alloc_d` when named with another class, as
+//!     classes of equal size share spillslots.
+//!
+//!   - `Edit::Remat` inserted by RA:      [ alloc_d := remat V_i ]
+//!
+//!       A' = A[alloc_d → {V_i}]
+//!
+//!     as for a move, aliases of `alloc_d` lose their values.
+//!
 //!   - statement in pre-regalloc function [ V_i := op V_j, V_k, ... ]
 //!     with allocated form                [ A_i := op A_j, A_k, ... ]
 //!
@@ -97,7 +117,8 @@
 
 use crate::{
     Allocation, AllocationKind, Block, Edit, Function, Inst, InstOrEdit, InstPosition, MachineEnv,
-    Operand, OperandConstraint, OperandKind, OperandPos, Output, PReg, VReg,
+    Operand, OperandConstraint, OperandKind, OperandPos, Output, PReg, ProgPoint, RegClass,
+    SpillSlot, VReg, REMAT_RELOAD_COST,
 };
 use fxhash::{FxHashMap, FxHashSet};
 use smallvec::{smallvec, SmallVec};
@@ -108,7 +129,125 @@ use std::result::Result;
 /// A set of errors detected by the regalloc checker.
 #[derive(Clone, Debug)]
 pub struct CheckerErrors {
-    errors: Vec<CheckerError>,
+    errors: Vec<CheckerDiagnostic>,
+}
+
+impl CheckerErrors {
+    /// The errors, in block order.
+    pub fn errors(&self) -> &[CheckerDiagnostic] {
+        &self.errors
+    }
+}
+
+impl std::fmt::Display for CheckerErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} checker error(s)", self.errors.len())?;
+        for error in &self.errors {
+            write!(f, "\n\n{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CheckerErrors {}
+
+/// A checker error, with the context needed to explain it.
+#[derive(Clone, Debug)]
+pub struct CheckerDiagnostic {
+    /// The error itself.
+    pub error: CheckerError,
+    /// The block in which the error was found.
+    pub block: Block,
+    /// The dataflow state where the error was found: each allocation
+    /// that holds a known value, with the vregs it holds, sorted.
+    pub state: Vec<(Allocation, Vec<VReg>)>,
+    /// The writes that put the value into the error's allocation,
+    /// most recent first: each move is followed by the write of its
+    /// source. Empty if the error does not concern an allocation.
+    pub history: Vec<CheckerWrite>,
+}
+
+impl std::fmt::Display for CheckerDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "block{}: {}", self.block.index(), self.error)?;
+        write!(f, "\n  state: {{")?;
+        for (i, (alloc, vregs)) in self.state.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(f, "{} {} := {{", sep, alloc)?;
+            for vreg in vregs {
+                write!(f, " {}", vreg)?;
+            }
+            write!(f, " }}")?;
+        }
+        write!(f, " }}")?;
+        if let Some(alloc) = self.error.alloc() {
+            write!(f, "\n  {} got its value from:", alloc)?;
+        }
+        for write in &self.history {
+            write!(f, "\n    {}", write)?;
+        }
+        Ok(())
+    }
+}
+
+/// A write to an allocation, in the history of a `CheckerDiagnostic`.
+#[derive(Clone, Debug)]
+pub enum CheckerWrite {
+    /// A move or rematerialization inserted by the allocator.
+    Edit { pos: ProgPoint, edit: Edit },
+    /// A def of `op` into `alloc`.
+    Def {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
+    /// A clobber of `preg`.
+    Clobber { inst: Inst, preg: PReg },
+    /// The value was already in `alloc` at the start of `block`, and
+    /// `block` is the entry block or has several predecessors.
+    BlockEntry { block: Block, alloc: Allocation },
+}
+
+impl CheckerWrite {
+    /// The allocation written.
+    fn alloc(&self) -> Allocation {
+        match *self {
+            CheckerWrite::Edit {
+                edit: Edit::Move { to, .. },
+                ..
+            }
+            | CheckerWrite::Edit {
+                edit: Edit::Remat { to, .. },
+                ..
+            } => to,
+            CheckerWrite::Def { alloc, .. } | CheckerWrite::BlockEntry { alloc, .. } => alloc,
+            CheckerWrite::Clobber { preg, .. } => Allocation::reg(preg),
+        }
+    }
+}
+
+impl std::fmt::Display for CheckerWrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CheckerWrite::Edit {
+                pos,
+                edit: Edit::Move { from, to },
+            } => write!(f, "{:?}: move {} -> {}", pos, from, to),
+            CheckerWrite::Edit {
+                pos,
+                edit: Edit::Remat { vreg, to },
+            } => write!(f, "{:?}: remat {} -> {}", pos, vreg, to),
+            CheckerWrite::Def { inst, op, alloc } => {
+                write!(f, "inst{}: {} into {}", inst.index(), op, alloc)
+            }
+            CheckerWrite::Clobber { inst, preg } => {
+                write!(f, "inst{}: clobber of {}", inst.index(), preg)
+            }
+            CheckerWrite::BlockEntry { block, alloc } => {
+                write!(f, "{} on entry to block{}", alloc, block.index())
+            }
+        }
+    }
 }
 
 /// A single error detected by the regalloc checker.
@@ -160,17 +299,339 @@ pub enum CheckerError {
         op: Operand,
         alloc: Allocation,
     },
+    AllocationIsNotFixedStackLoc {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
+    AllocationIsNotInRegSubset {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
     ConflictedValueInStackmap {
         inst: Inst,
         alloc: Allocation,
     },
+    DisallowedRegInStackmap {
+        inst: Inst,
+        alloc: Allocation,
+    },
     NonRefValuesInStackmap {
         inst: Inst,
         alloc: Allocation,
         vregs: FxHashSet<VReg>,
     },
+    /// `vreg` is reference-typed and live at the safepoint `inst`, but
+    /// no allocation in the stackmap holds it.
+    MissingRefInStackmap {
+        inst: Inst,
+        vreg: VReg,
+    },
+    InvalidRemat {
+        vreg: VReg,
+        alloc: Allocation,
+    },
+    /// `alloc`, used at `point` for a value of class `class`, is of
+    /// another class.
+    ClassMismatch {
+        point: ProgPoint,
+        alloc: Allocation,
+        class: RegClass,
+    },
+    /// The spillslot `alloc`, used at `point`, extends beyond the
+    /// `Output::num_spillslots` slots of the frame.
+    SpillSlotOutOfRange {
+        point: ProgPoint,
+        alloc: Allocation,
+        num_spillslots: usize,
+    },
+    /// The spillslot `alloc`, used at `point`, is not aligned to
+    /// `Function::spillslot_size` for its class.
+    MisalignedSpillSlot {
+        point: ProgPoint,
+        alloc: Allocation,
+    },
+    /// A move at `pos` from one stack location to another.
+    StackToStackMove {
+        pos: ProgPoint,
+        from: Allocation,
+        to: Allocation,
+    },
+    /// An edit at `pos` that follows one at the later point `prev` in
+    /// `Output::edits`.
+    UnsortedEdit {
+        pos: ProgPoint,
+        prev: ProgPoint,
+    },
+    /// `alloc` is given as the location of `label` at `point`, but
+    /// holds none of `vregs`, the vregs to which the label applies
+    /// there.
+    IncorrectDebugLocation {
+        label: u32,
+        vregs: Vec<VReg>,
+        point: ProgPoint,
+        alloc: Allocation,
+    },
+    /// A debug location that is not within any range for which
+    /// `Function::debug_value_labels` requested `label`.
+    UnrequestedDebugLocation {
+        label: u32,
+        from: ProgPoint,
+        to: ProgPoint,
+        alloc: Allocation,
+    },
+    /// A debug location that comes before the previous one in
+    /// label and program-point order.
+    UnsortedDebugLocation {
+        label: u32,
+        from: ProgPoint,
+        to: ProgPoint,
+    },
+    /// A debug location that overlaps an earlier one for the same
+    /// label and vreg.
+    OverlappingDebugLocation {
+        label: u32,
+        from: ProgPoint,
+        to: ProgPoint,
+    },
+}
+
+impl CheckerError {
+    /// The allocation the error concerns, if any.
+    pub fn alloc(&self) -> Option<Allocation> {
+        match *self {
+            CheckerError::MissingAllocation { .. } | CheckerError::MissingRefInStackmap { .. } => {
+                None
+            }
+            CheckerError::UnknownValueInAllocation { alloc, .. }
+            | CheckerError::ConflictedValueInAllocation { alloc, .. }
+            | CheckerError::IncorrectValuesInAllocation { alloc, .. }
+            | CheckerError::ConstraintViolated { alloc, .. }
+            | CheckerError::AllocationIsNotReg { alloc, .. }
+            | CheckerError::AllocationIsNotFixedReg { alloc, .. }
+            | CheckerError::AllocationIsNotReuse { alloc, .. }
+            | CheckerError::AllocationIsNotStack { alloc, .. }
+            | CheckerError::AllocationIsNotFixedStackLoc { alloc, .. }
+            | CheckerError::AllocationIsNotInRegSubset { alloc, .. }
+            | CheckerError::ConflictedValueInStackmap { alloc, .. }
+            | CheckerError::DisallowedRegInStackmap { alloc, .. }
+            | CheckerError::NonRefValuesInStackmap { alloc, .. }
+            | CheckerError::InvalidRemat { alloc, .. }
+            | CheckerError::ClassMismatch { alloc, .. }
+            | CheckerError::SpillSlotOutOfRange { alloc, .. }
+            | CheckerError::MisalignedSpillSlot { alloc, .. }
+            | CheckerError::StackToStackMove { to: alloc, .. }
+            | CheckerError::IncorrectDebugLocation { alloc, .. } => Some(alloc),
+            CheckerError::UnsortedEdit { .. }
+            | CheckerError::UnrequestedDebugLocation { .. }
+            | CheckerError::UnsortedDebugLocation { .. }
+            | CheckerError::OverlappingDebugLocation { .. } => None,
+        }
+    }
+}
+
+/// Write `vregs` sorted, for stable output.
+fn fmt_vregs(f: &mut std::fmt::Formatter, vregs: &FxHashSet<VReg>) -> std::fmt::Result {
+    let mut vregs: Vec<_> = vregs.iter().collect();
+    vregs.sort();
+    write!(f, "{{")?;
+    for vreg in vregs {
+        write!(f, " {}", vreg)?;
+    }
+    write!(f, " }}")
 }
 
+impl std::fmt::Display for CheckerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CheckerError::MissingAllocation { inst, op } => {
+                write!(f, "inst{}: {} has no allocation", inst.index(), op)
+            }
+            CheckerError::UnknownValueInAllocation { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is allocated to {}, which holds no known value",
+                inst.index(),
+                op,
+                alloc
+            ),
+            CheckerError::ConflictedValueInAllocation { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is allocated to {}, which holds conflicting values",
+                inst.index(),
+                op,
+                alloc
+            ),
+            CheckerError::IncorrectValuesInAllocation {
+                inst,
+                op,
+                alloc,
+                actual,
+            } => {
+                write!(
+                    f,
+                    "inst{}: {} is allocated to {}, which holds ",
+                    inst.index(),
+                    op,
+                    alloc
+                )?;
+                fmt_vregs(f, actual)?;
+                write!(f, " instead of {}", op.vreg())
+            }
+            CheckerError::ConstraintViolated { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is allocated to {}, which violates its constraint",
+                inst.index(),
+                op,
+                alloc
+            ),
+            CheckerError::AllocationIsNotReg { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is allocated to {}, which is not a register",
+                inst.index(),
+                op,
+                alloc
+            ),
+            CheckerError::AllocationIsNotFixedReg { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is allocated to {}, which is not its fixed register",
+                inst.index(),
+                op,
+                alloc
+            ),
+            CheckerError::AllocationIsNotReuse {
+                inst,
+                op,
+                alloc,
+                expected_alloc,
+            } => write!(
+                f,
+                "inst{}: {} is allocated to {}, but must reuse {}",
+                inst.index(),
+                op,
+                alloc,
+                expected_alloc
+            ),
+            CheckerError::AllocationIsNotStack { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is allocated to {}, which is not on the stack",
+                inst.index(),
+                op,
+                alloc
+            ),
+            CheckerError::AllocationIsNotFixedStackLoc { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is allocated to {}, which is not its fixed stack location",
+                inst.index(),
+                op,
+                alloc
+            ),
+            CheckerError::AllocationIsNotInRegSubset { inst, op, alloc } => write!(
+                f,
+                "inst{}: {} is allocated to {}, which is not in its register subset",
+                inst.index(),
+                op,
+                alloc
+            ),
+            CheckerError::ConflictedValueInStackmap { inst, alloc } => write!(
+                f,
+                "inst{}: stackmap slot {} holds conflicting values",
+                inst.index(),
+                alloc
+            ),
+            CheckerError::DisallowedRegInStackmap { inst, alloc } => write!(
+                f,
+                "inst{}: stackmap contains {}, which is not a stackmap register",
+                inst.index(),
+                alloc
+            ),
+            CheckerError::NonRefValuesInStackmap { inst, alloc, vregs } => {
+                write!(f, "inst{}: stackmap slot {} holds ", inst.index(), alloc)?;
+                fmt_vregs(f, vregs)?;
+                write!(f, ", none of them reference-typed")
+            }
+            CheckerError::MissingRefInStackmap { inst, vreg } => write!(
+                f,
+                "inst{}: {} is live and reference-typed, but not in the stackmap",
+                inst.index(),
+                vreg
+            ),
+            CheckerError::InvalidRemat { vreg, alloc } => {
+                write!(f, "{} cannot be rematerialized into {}", vreg, alloc)
+            }
+            CheckerError::ClassMismatch {
+                point,
+                alloc,
+                class,
+            } => write!(
+                f,
+                "{:?}: {} is used for a value of class {:?}",
+                point, alloc, class
+            ),
+            CheckerError::SpillSlotOutOfRange {
+                point,
+                alloc,
+                num_spillslots,
+            } => write!(
+                f,
+                "{:?}: {} is beyond the {} spillslots of the frame",
+                point, alloc, num_spillslots
+            ),
+            CheckerError::MisalignedSpillSlot { point, alloc } => write!(
+                f,
+                "{:?}: {} is not aligned to the spillslot size of its class",
+                point, alloc
+            ),
+            CheckerError::StackToStackMove { pos, from, to } => write!(
+                f,
+                "{:?}: move {} -> {} is from stack to stack",
+                pos, from, to
+            ),
+            CheckerError::UnsortedEdit { pos, prev } => {
+                write!(f, "the edit at {:?} comes after one at {:?}", pos, prev)
+            }
+            CheckerError::IncorrectDebugLocation {
+                label,
+                vregs,
+                point,
+                alloc,
+            } => {
+                write!(
+                    f,
+                    "{:?}: label {} is located in {}, which does not hold",
+                    point, label, alloc
+                )?;
+                for (i, vreg) in vregs.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { "" } else { " or" }, vreg)?;
+                }
+                Ok(())
+            }
+            CheckerError::UnrequestedDebugLocation {
+                label,
+                from,
+                to,
+                alloc,
+            } => write!(
+                f,
+                "label {} is located in {} from {:?} to {:?}, which was not requested",
+                label, alloc, from, to
+            ),
+            CheckerError::UnsortedDebugLocation { label, from, to } => write!(
+                f,
+                "the location of label {} from {:?} to {:?} is out of order",
+                label, from, to
+            ),
+            CheckerError::OverlappingDebugLocation { label, from, to } => write!(
+                f,
+                "the location of label {} from {:?} to {:?} overlaps an earlier one",
+                label, from, to
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CheckerError {}
+
 /// Abstract state for an allocation.
 ///
 /// Equivalent to a set of virtual register names, with the
@@ -283,6 +744,88 @@ fn visit_all_vregs<F: Function, V: FnMut(VReg)>(f: &F, mut v: V) {
     }
 }
 
+/// Compute, for each safepoint in `f`, the vregs in `reftyped_vregs`
+/// that are live into it, including any it uses.
+fn live_refs_at_safepoints<F: Function>(
+    f: &F,
+    reftyped_vregs: &FxHashSet<VReg>,
+) -> FxHashMap<Inst, Vec<VReg>> {
+    // Step backward through `block` from its live-outs, given the
+    // live-ins of all blocks, calling `at_safepoint` at each safepoint,
+    // and return the block's live-ins.
+    let step = |block: Block,
+                live_ins: &[FxHashSet<VReg>],
+                at_safepoint: &mut dyn FnMut(Inst, &FxHashSet<VReg>)| {
+        let mut live = FxHashSet::default();
+        for (i, &succ) in f.block_succs(block).iter().enumerate() {
+            let params = f.block_params(succ);
+            let branch = f.block_insns(block).last();
+            let args = f.branch_blockparams(block, branch, i);
+            for &vreg in &live_ins[succ.index()] {
+                match params.iter().position(|&param| param == vreg) {
+                    Some(idx) if reftyped_vregs.contains(&args[idx]) => {
+                        live.insert(args[idx]);
+                    }
+                    Some(_) => {}
+                    None => {
+                        live.insert(vreg);
+                    }
+                }
+            }
+        }
+        for inst in f.block_insns(block).rev().iter() {
+            let mut defs: SmallVec<[VReg; 4]> = smallvec![];
+            let mut uses: SmallVec<[VReg; 4]> = smallvec![];
+            for op in f.inst_operands(inst) {
+                match op.kind() {
+                    OperandKind::Def => defs.push(op.vreg()),
+                    OperandKind::Use | OperandKind::Mod => uses.push(op.vreg()),
+                }
+            }
+            if let Some((src, dst)) = f.is_move(inst) {
+                defs.push(dst.vreg());
+                uses.push(src.vreg());
+            }
+            for vreg in defs {
+                live.remove(&vreg);
+            }
+            live.extend(
+                uses.into_iter()
+                    .filter(|vreg| reftyped_vregs.contains(vreg)),
+            );
+            if f.requires_refs_on_stack(inst) {
+                at_safepoint(inst, &live);
+            }
+        }
+        live
+    };
+
+    let mut live_refs = FxHashMap::default();
+    if reftyped_vregs.is_empty() {
+        return live_refs;
+    }
+    let mut live_ins = vec![FxHashSet::default(); f.num_blocks()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in (0..f.num_blocks()).rev() {
+            let live_in = step(Block::new(block), &live_ins, &mut |_, _| {});
+            if live_in != live_ins[block] {
+                live_ins[block] = live_in;
+                changed = true;
+            }
+        }
+    }
+    for block in 0..f.num_blocks() {
+        step(Block::new(block), &live_ins, &mut |inst, live| {
+            let mut vregs: Vec<_> = live.iter().copied().collect();
+            vregs.sort();
+            live_refs.insert(inst, vregs);
+        });
+    }
+    live_refs
+}
+
 /// State that steps through program points as we scan over the instruction stream.
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum CheckerState {
@@ -362,6 +905,23 @@ impl CheckerState {
         }
     }
 
+    /// The allocations holding known values, with their vregs, sorted.
+    fn summary(&self) -> Vec<(Allocation, Vec<VReg>)> {
+        let mut summary: Vec<_> = match self {
+            CheckerState::Top => vec![],
+            CheckerState::Allocations(allocs) => allocs
+                .iter()
+                .filter_map(|(&alloc, value)| {
+                    let mut vregs: Vec<_> = value.vregs()?.iter().copied().collect();
+                    vregs.sort();
+                    Some((alloc, vregs))
+                })
+                .collect(),
+        };
+        summary.sort_by_key(|&(alloc, _)| alloc);
+        summary
+    }
+
     fn initial_with_pinned_vregs<F: Function>(f: &F) -> CheckerState {
         // Scan the function, looking for all vregs that are pinned
         // vregs, gathering them with their PRegs.
@@ -497,6 +1057,16 @@ impl CheckerState {
                 ref allocs,
                 ..
             } => {
+                if pos == InstPosition::Before {
+                    for (op, &alloc) in operands.iter().zip(allocs.iter()) {
+                        let point = match op.pos() {
+                            OperandPos::Early => ProgPoint::before(inst),
+                            OperandPos::Late => ProgPoint::after(inst),
+                        };
+                        checker.check_frame_alloc(point, alloc, op.class())?;
+                    }
+                }
+
                 // Skip Use-checks at the After point if there are any
                 // reused inputs: the Def which reuses the input
                 // happens early.
@@ -536,6 +1106,18 @@ impl CheckerState {
             }
             &CheckerInst::Safepoint { inst, ref allocs } => {
                 for &alloc in allocs {
+                    if pos == InstPosition::Before {
+                        checker.check_frame_alloc(ProgPoint::before(inst), alloc, alloc.class())?;
+                    }
+                    // Registers that stand for fixed stack slots are
+                    // always allowed.
+                    if let Some(preg) = alloc.as_reg() {
+                        if !checker.machine_env.stackmap_regs.contains(preg)
+                            && !checker.machine_env.fixed_stack_slots.contains(&preg)
+                        {
+                            return Err(CheckerError::DisallowedRegInStackmap { inst, alloc });
+                        }
+                    }
                     let val = self.get_value(&alloc).unwrap_or(&default_val);
                     trace!(
                         "checker: checkinst {:?}: safepoint slot {}, checker value {:?}",
@@ -557,12 +1139,59 @@ impl CheckerState {
                         });
                     }
                 }
+
+                // Every live reference-typed value must be in the
+                // stackmap, or the collector will not know about it.
+                // The state is the same at both points, so only
+                // report a missing value once.
+                if pos == InstPosition::Before {
+                    for &vreg in checker.live_refs.get(&inst).into_iter().flatten() {
+                        let found = allocs.iter().any(|alloc| {
+                            matches!(self.get_value(alloc), Some(CheckerValue::VRegs(vregs)) if vregs.contains(&vreg))
+                        });
+                        if !found {
+                            return Err(CheckerError::MissingRefInStackmap { inst, vreg });
+                        }
+                    }
+                }
+            }
+            &CheckerInst::Move {
+                pos: at,
+                into,
+                from,
+            } if pos == InstPosition::Before => {
+                // The locations must be within the frame, and at most
+                // one of them in memory.
+                if checker.in_memory(from) && checker.in_memory(into) {
+                    return Err(CheckerError::StackToStackMove {
+                        pos: at,
+                        from,
+                        to: into,
+                    });
+                }
+                checker.check_frame_alloc(at, from, from.class())?;
+                checker.check_frame_alloc(at, into, from.class())?;
             }
             &CheckerInst::ParallelMove { .. } | &CheckerInst::Move { .. } => {
                 // This doesn't need verification; we just update
                 // according to the move semantics in the step
                 // function below.
             }
+            &CheckerInst::Remat { into, vreg, .. } => {
+                // The vreg must be one that the program said could be
+                // recomputed more cheaply than reloaded, into a
+                // register of its class.
+                let cheap = matches!(
+                    checker.f.remat_cost(vreg),
+                    Some(cost) if cost < REMAT_RELOAD_COST
+                );
+                let valid = cheap
+                    && !checker.in_memory(into)
+                    && matches!(into.as_reg(), Some(preg) if preg.class() == vreg.class());
+                if !valid {
+                    return Err(CheckerError::InvalidRemat { vreg, alloc: into });
+                }
+            }
             &CheckerInst::ProgramMove { inst, src, dst: _ } => {
                 // Validate that the fixed-reg constraint, if any, on
                 // `src` is satisfied.
@@ -591,7 +1220,7 @@ impl CheckerState {
         self.become_defined();
 
         match checkinst {
-            &CheckerInst::Move { into, from } => {
+            &CheckerInst::Move { into, from, .. } => {
                 // Value may not be present if this move is part of
                 // the parallel move resolver's fallback sequence that
                 // saves a victim register elsewhere. (In other words,
@@ -608,6 +1237,11 @@ impl CheckerState {
                     );
                     self.set_value(into, val);
                 }
+                self.remove_aliases(into, checker);
+            }
+            &CheckerInst::Remat { into, vreg, .. } => {
+                self.set_value(into, CheckerValue::from_reg(vreg));
+                self.remove_aliases(into, checker);
             }
             &CheckerInst::ParallelMove { ref moves } => {
                 // First, build map of actions for each vreg in an
@@ -662,16 +1296,18 @@ impl CheckerState {
                     }
                     self.remove_vreg(op.vreg());
                     self.set_value(*alloc, CheckerValue::from_reg(op.vreg()));
+                    self.remove_aliases(*alloc, checker);
                 }
                 for clobber in clobbers {
                     self.remove_value(&Allocation::reg(*clobber));
+                    self.remove_aliases(Allocation::reg(*clobber), checker);
                 }
             }
             &CheckerInst::Safepoint { ref allocs, .. } => {
+                // Reference-typed values may have been moved by the
+                // collector, so any copies it was not told about,
+                // whether on the stack or in registers, are stale.
                 for (alloc, value) in self.get_mappings_mut() {
-                    if alloc.is_reg() {
-                        continue;
-                    }
                     if !allocs.contains(&alloc) {
                         // Remove all reftyped vregs as labels.
                         let new_vregs = value
@@ -702,6 +1338,21 @@ impl CheckerState {
         }
     }
 
+    /// Remove the values held in all registers that overlap `alloc`,
+    /// or if it is a spillslot, in the same slot named with another
+    /// class: a write to `alloc` changes their contents as well.
+    fn remove_aliases<'a, F: Function>(&mut self, alloc: Allocation, checker: &Checker<'a, F>) {
+        if let Some(preg) = alloc.as_reg() {
+            for &alias in checker.aliases(preg) {
+                self.remove_value(&Allocation::reg(alias));
+            }
+        } else if let Some(slot) = alloc.as_stack() {
+            for &class in RegClass::ALL.iter().filter(|&&class| class != slot.class()) {
+                self.remove_value(&Allocation::stack(SpillSlot::new(slot.index(), class)));
+            }
+        }
+    }
+
     fn check_constraint<'a, F: Function>(
         &self,
         inst: Inst,
@@ -737,8 +1388,17 @@ impl CheckerState {
                     return Err(CheckerError::AllocationIsNotFixedReg { inst, op, alloc });
                 }
             }
+            OperandConstraint::FixedStackLoc(loc) => {
+                if alloc != Allocation::stack_loc(loc) {
+                    return Err(CheckerError::AllocationIsNotFixedStackLoc { inst, op, alloc });
+                }
+            }
+            OperandConstraint::RegSubset(subset) => match alloc.as_reg() {
+                Some(preg) if checker.machine_env.reg_subsets[subset].contains(preg) => {}
+                _ => return Err(CheckerError::AllocationIsNotInRegSubset { inst, op, alloc }),
+            },
             OperandConstraint::Reuse(idx) => {
-                if alloc.kind() != AllocationKind::Reg {
+                if alloc.kind() != AllocationKind::Reg || checker.in_memory(alloc) {
                     return Err(CheckerError::AllocationIsNotReg { inst, op, alloc });
                 }
                 if alloc != allocs[idx] {
@@ -759,8 +1419,20 @@ impl CheckerState {
 #[derive(Clone, Debug)]
 pub(crate) enum CheckerInst {
     /// A move between allocations (these could be registers or
-    /// spillslots).
-    Move { into: Allocation, from: Allocation },
+    /// spillslots), inserted at `pos`.
+    Move {
+        pos: ProgPoint,
+        into: Allocation,
+        from: Allocation,
+    },
+
+    /// A recomputation of a vreg's value into an allocation, inserted
+    /// at `pos`.
+    Remat {
+        pos: ProgPoint,
+        into: Allocation,
+        vreg: VReg,
+    },
 
     /// A parallel move in the original program. Simultaneously moves
     /// from all source vregs to all corresponding dest vregs,
@@ -803,6 +1475,29 @@ pub(crate) enum CheckerInst {
     },
 }
 
+/// A write to an allocation, recorded to explain checker errors.
+#[derive(Clone, Debug)]
+struct WriteRecord {
+    write: CheckerWrite,
+    block: Block,
+    /// For a move, its source, and the index of the last write to the
+    /// source earlier in the block, if any.
+    source: Option<(Allocation, Option<usize>)>,
+}
+
+/// An error found by `Checker::find_errors`, with its block, the
+/// state where it was found, and the last write to its allocation.
+type FoundError = (
+    CheckerError,
+    Block,
+    Vec<(Allocation, Vec<VReg>)>,
+    Option<usize>,
+);
+
+/// A debug label to check at a program point: the label, the vregs to
+/// which it may apply, and its allocation.
+type DebugCheck = (u32, SmallVec<[VReg; 2]>, Allocation);
+
 #[derive(Debug)]
 pub struct Checker<'a, F: Function> {
     f: &'a F,
@@ -811,6 +1506,18 @@ pub struct Checker<'a, F: Function> {
     edge_insts: FxHashMap<(Block, Block), Vec<CheckerInst>>,
     reftyped_vregs: FxHashSet<VReg>,
     machine_env: &'a MachineEnv,
+    /// For each register with aliases, the registers that overlap
+    /// it, per `MachineEnv::aliases`.
+    preg_aliases: FxHashMap<PReg, SmallVec<[PReg; 4]>>,
+    /// The `Output::debug_locations` to verify.
+    debug_locations: Vec<(u32, ProgPoint, ProgPoint, Allocation)>,
+    /// For each safepoint, the reference-typed vregs live at it.
+    live_refs: FxHashMap<Inst, Vec<VReg>>,
+    /// The `Output::num_spillslots` of the frame.
+    num_spillslots: usize,
+    /// The first edit in `Output::edits` at an earlier point than the
+    /// one before it, as (that point, its point).
+    unsorted_edit: Option<(ProgPoint, ProgPoint)>,
 }
 
 impl<'a, F: Function> Checker<'a, F> {
@@ -837,7 +1544,14 @@ impl<'a, F: Function> Checker<'a, F> {
             reftyped_vregs.insert(vreg);
         }
 
+        let mut preg_aliases: FxHashMap<PReg, SmallVec<[PReg; 4]>> = FxHashMap::default();
+        for &(a, b) in &machine_env.aliases {
+            preg_aliases.entry(a).or_default().push(b);
+            preg_aliases.entry(b).or_default().push(a);
+        }
+
         bb_in.insert(f.entry_block(), CheckerState::initial_with_pinned_vregs(f));
+        let live_refs = live_refs_at_safepoints(f, &reftyped_vregs);
 
         Checker {
             f,
@@ -846,13 +1560,37 @@ impl<'a, F: Function> Checker<'a, F> {
             edge_insts,
             reftyped_vregs,
             machine_env,
+            preg_aliases,
+            debug_locations: vec![],
+            live_refs,
+            num_spillslots: 0,
+            unsorted_edit: None,
         }
     }
 
+    /// The registers that overlap `preg`.
+    fn aliases(&self, preg: PReg) -> &[PReg] {
+        self.preg_aliases
+            .get(&preg)
+            .map_or(&[], |aliases| &aliases[..])
+    }
+
     /// Build the list of checker instructions based on the given func
     /// and allocation results.
     pub fn prepare(&mut self, out: &Output) {
         trace!("checker: out = {:?}", out);
+        self.debug_locations = out.debug_locations.clone();
+        self.num_spillslots = out.num_spillslots;
+        // Edits out of order cannot be placed among the instructions,
+        // so there is nothing more to check.
+        self.unsorted_edit = out
+            .edits
+            .windows(2)
+            .find(|pair| pair[1].0 < pair[0].0)
+            .map(|pair| (pair[0].0, pair[1].0));
+        if self.unsorted_edit.is_some() {
+            return;
+        }
         // Preprocess safepoint stack-maps into per-inst vecs.
         let mut safepoint_slots: FxHashMap<Inst, Vec<Allocation>> = FxHashMap::default();
         for &(progpoint, slot) in &out.safepoint_slots {
@@ -862,6 +1600,9 @@ impl<'a, F: Function> Checker<'a, F> {
                 .push(slot);
         }
 
+        // The edits come out of `block_insts_and_edits` in order; find
+        // each one's position by scanning along with it.
+        let mut edits = out.edits.iter();
         let mut last_inst = None;
         for block in 0..self.f.num_blocks() {
             let block = Block::new(block);
@@ -872,7 +1613,13 @@ impl<'a, F: Function> Checker<'a, F> {
                         last_inst = Some(inst);
                         self.handle_inst(block, inst, &mut safepoint_slots, out);
                     }
-                    InstOrEdit::Edit(edit) => self.handle_edit(block, edit),
+                    InstOrEdit::Edit(edit) => {
+                        let pos = edits
+                            .find(|(_, e)| std::ptr::eq(e, edit))
+                            .map(|&(pos, _)| pos)
+                            .unwrap();
+                        self.handle_edit(block, pos, edit);
+                    }
                 }
             }
         }
@@ -971,18 +1718,89 @@ impl<'a, F: Function> Checker<'a, F> {
         }
     }
 
-    fn handle_edit(&mut self, block: Block, edit: &Edit) {
-        trace!("checker: adding edit {:?}", edit);
-        match edit {
-            &Edit::Move { from, to } => {
+    fn handle_edit(&mut self, block: Block, pos: ProgPoint, edit: &Edit) {
+        trace!("checker: adding edit {:?} at {:?}", edit, pos);
+        match *edit {
+            Edit::Move { from, to } => {
+                self.bb_insts
+                    .get_mut(&block)
+                    .unwrap()
+                    .push(CheckerInst::Move {
+                        pos,
+                        into: to,
+                        from,
+                    });
+            }
+            Edit::Remat { vreg, to } => {
                 self.bb_insts
                     .get_mut(&block)
                     .unwrap()
-                    .push(CheckerInst::Move { into: to, from });
+                    .push(CheckerInst::Remat {
+                        pos,
+                        into: to,
+                        vreg,
+                    });
             }
         }
     }
 
+    /// Is `alloc` in memory: a spillslot, a client-defined stack
+    /// location, or a register that stands for a fixed stack slot?
+    fn in_memory(&self, alloc: Allocation) -> bool {
+        match alloc.kind() {
+            AllocationKind::Stack | AllocationKind::StackLoc => true,
+            AllocationKind::Reg => self
+                .machine_env
+                .fixed_stack_slots
+                .contains(&alloc.as_reg().unwrap()),
+            AllocationKind::None => false,
+        }
+    }
+
+    /// Check that `alloc`, used at `point` for a value of class
+    /// `class`, is of that class, and if it is a spillslot, that it
+    /// lies within the frame and is aligned to its size.
+    fn check_frame_alloc(
+        &self,
+        point: ProgPoint,
+        alloc: Allocation,
+        class: RegClass,
+    ) -> Result<(), CheckerError> {
+        if alloc.is_none() {
+            return Ok(());
+        }
+        if alloc.class() != class {
+            return Err(CheckerError::ClassMismatch {
+                point,
+                alloc,
+                class,
+            });
+        }
+        let slot = match alloc.as_stack() {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+        let size = self.f.spillslot_size(slot.class());
+        let first = if self.f.multi_spillslot_named_by_last_slot() {
+            (slot.index() + 1).checked_sub(size)
+        } else {
+            Some(slot.index())
+        };
+        match first {
+            Some(first) if first % size == 0 => {
+                if first + size > self.num_spillslots {
+                    return Err(CheckerError::SpillSlotOutOfRange {
+                        point,
+                        alloc,
+                        num_spillslots: self.num_spillslots,
+                    });
+                }
+            }
+            _ => return Err(CheckerError::MisalignedSpillSlot { point, alloc }),
+        }
+        Ok(())
+    }
+
     /// Perform the dataflow analysis to compute checker state at each BB entry.
     fn analyze(&mut self) {
         let mut queue = Vec::new();
@@ -1046,29 +1864,326 @@ impl<'a, F: Function> Checker<'a, F> {
     /// through each BB and check each instruction's register allocations
     /// for errors.
     fn find_errors(&self) -> Result<(), CheckerErrors> {
+        // Alongside the state, track the last write to each allocation
+        // in each block, to explain any errors found.
+        if let Some((prev, pos)) = self.unsorted_edit {
+            let block = (0..self.f.num_blocks())
+                .map(Block::new)
+                .find(|&block| pos.inst() <= self.f.block_insns(block).last())
+                .unwrap_or(self.f.entry_block());
+            return Err(CheckerErrors {
+                errors: vec![CheckerDiagnostic {
+                    error: CheckerError::UnsortedEdit { pos, prev },
+                    block,
+                    state: vec![],
+                    history: vec![],
+                }],
+            });
+        }
+
         let mut errors = vec![];
-        for (block, input) in &self.bb_in {
-            let mut state = input.clone();
-            for inst in self.bb_insts.get(block).unwrap() {
-                if let Err(e) = state.check(InstPosition::Before, inst, self) {
+        let mut writes = vec![];
+        let mut last_writes = vec![];
+        let debug_points = self.debug_points(&mut errors);
+        let debug_errors = |point: ProgPoint, state: &CheckerState| {
+            debug_points
+                .get(&point)
+                .into_iter()
+                .flatten()
+                .filter_map(move |(label, vregs, alloc)| {
+                    self.check_debug_location(point, state, *label, vregs, *alloc)
+                        .err()
+                })
+                .collect::<Vec<_>>()
+        };
+        for block in 0..self.f.num_blocks() {
+            let block = Block::new(block);
+            let mut state = self.bb_in.get(&block).unwrap().clone();
+            let mut last_write = FxHashMap::default();
+            let mut found =
+                |e: CheckerError, state: &CheckerState, last_write: &FxHashMap<_, _>| {
                     trace!("Checker error: {:?}", e);
-                    errors.push(e);
+                    let last = e.alloc().and_then(|alloc| last_write.get(&alloc).copied());
+                    errors.push((e, block, state.summary(), last));
+                };
+            // The state at a program point is the one after all edits
+            // at that point, and for an `After` point, after the
+            // instruction itself.
+            let insts = self.bb_insts.get(&block).unwrap();
+            let mut point = ProgPoint::before(self.f.block_insns(block).first());
+            for inst in insts {
+                let at = match *inst {
+                    CheckerInst::Move { pos, .. } | CheckerInst::Remat { pos, .. } => pos,
+                    CheckerInst::Op { inst, .. }
+                    | CheckerInst::Safepoint { inst, .. }
+                    | CheckerInst::ProgramMove { inst, .. } => ProgPoint::after(inst),
+                    CheckerInst::ParallelMove { .. } => unreachable!(),
+                };
+                while point < at {
+                    for e in debug_errors(point, &state) {
+                        found(e, &state, &last_write);
+                    }
+                    point = point.next();
+                }
+
+                if let Err(e) = state.check(InstPosition::Before, inst, self) {
+                    found(e, &state, &last_write);
                 }
                 state.update(inst, self);
+                self.record_writes(block, inst, &mut writes, &mut last_write);
                 if let Err(e) = state.check(InstPosition::After, inst, self) {
-                    trace!("Checker error: {:?}", e);
-                    errors.push(e);
+                    found(e, &state, &last_write);
                 }
             }
+            while point <= ProgPoint::after(self.f.block_insns(block).last()) {
+                for e in debug_errors(point, &state) {
+                    found(e, &state, &last_write);
+                }
+                point = point.next();
+            }
+            last_writes.push(last_write);
         }
 
         if errors.is_empty() {
+            return Ok(());
+        }
+        errors.sort_by_key(|&(_, block, ..)| block);
+        let errors = errors
+            .into_iter()
+            .map(|(error, block, state, last)| {
+                let history = match error.alloc() {
+                    Some(alloc) => self.history(block, alloc, last, &writes, &last_writes),
+                    None => vec![],
+                };
+                CheckerDiagnostic {
+                    error,
+                    block,
+                    state,
+                    history,
+                }
+            })
+            .collect();
+        Err(CheckerErrors { errors })
+    }
+
+    /// Check that the debug locations are sorted, disjoint, and
+    /// within requested ranges, adding errors to `errors`, and return
+    /// the labels, with their vregs and allocations, to check at each
+    /// program point.
+    fn debug_points(&self, errors: &mut Vec<FoundError>) -> FxHashMap<ProgPoint, Vec<DebugCheck>> {
+        let mut inst_blocks = vec![self.f.entry_block(); self.f.num_insts()];
+        for block in 0..self.f.num_blocks() {
+            let block = Block::new(block);
+            for inst in self.f.block_insns(block).iter() {
+                inst_blocks[inst.index()] = block;
+            }
+        }
+        let mut found = |e: CheckerError, at: ProgPoint| {
+            trace!("Checker error: {:?}", e);
+            let block = inst_blocks
+                .get(at.inst().index())
+                .copied()
+                .unwrap_or(self.f.entry_block());
+            errors.push((e, block, vec![], None));
+        };
+
+        let mut points: FxHashMap<ProgPoint, Vec<_>> = FxHashMap::default();
+        let mut prev: Option<(u32, ProgPoint)> = None;
+        let mut ends: FxHashMap<(u32, VReg), ProgPoint> = FxHashMap::default();
+        for &(label, from, to, alloc) in &self.debug_locations {
+            if matches!(prev, Some(prev) if (label, from) < prev) {
+                found(
+                    CheckerError::UnsortedDebugLocation { label, from, to },
+                    from,
+                );
+            }
+            prev = Some((label, from));
+
+            // The client may request the same label for several vregs
+            // over overlapping ranges, in which case any of them may
+            // be the one located here.
+            let vregs: SmallVec<[VReg; 2]> = self
+                .f
+                .debug_value_labels()
+                .iter()
+                .filter(|&&(_, label_from, label_to, label_label)| {
+                    label_label == label
+                        && ProgPoint::before(label_from) <= from
+                        && to <= ProgPoint::before(label_to)
+                })
+                .map(|&(vreg, ..)| vreg)
+                .collect();
+            match vregs[..] {
+                [] => {
+                    let e = CheckerError::UnrequestedDebugLocation {
+                        label,
+                        from,
+                        to,
+                        alloc,
+                    };
+                    found(e, from);
+                    continue;
+                }
+                [vreg] => {
+                    if matches!(ends.get(&(label, vreg)), Some(&end) if from < end) {
+                        found(
+                            CheckerError::OverlappingDebugLocation { label, from, to },
+                            from,
+                        );
+                    }
+                    ends.insert((label, vreg), to);
+                }
+                _ => {}
+            }
+            let mut point = from;
+            while point < to {
+                points
+                    .entry(point)
+                    .or_default()
+                    .push((label, vregs.clone(), alloc));
+                point = point.next();
+            }
+        }
+        points
+    }
+
+    /// Check that `alloc` holds one of `vregs` at `point`, given the
+    /// `state` there.
+    fn check_debug_location(
+        &self,
+        point: ProgPoint,
+        state: &CheckerState,
+        label: u32,
+        vregs: &[VReg],
+        alloc: Allocation,
+    ) -> Result<(), CheckerError> {
+        let held = matches!(
+            state.get_value(&alloc).and_then(|value| value.vregs()),
+            Some(held) if vregs.iter().any(|vreg| held.contains(vreg))
+        );
+        if held {
+            return Ok(());
+        }
+        // A vreg defined by the instruction, e.g. by an early def,
+        // has no value before it, wherever it is located.
+        let defined_here = point.pos() == InstPosition::Before
+            && self
+                .f
+                .inst_operands(point.inst())
+                .iter()
+                .any(|op| op.kind() == OperandKind::Def && vregs.contains(&op.vreg()));
+        if defined_here {
             Ok(())
         } else {
-            Err(CheckerErrors { errors })
+            Err(CheckerError::IncorrectDebugLocation {
+                label,
+                vregs: vregs.to_vec(),
+                point,
+                alloc,
+            })
+        }
+    }
+
+    /// Record the allocations written by `inst` in `writes`, and make
+    /// it the last writer of each in `last_write`.
+    fn record_writes(
+        &self,
+        block: Block,
+        inst: &CheckerInst,
+        writes: &mut Vec<WriteRecord>,
+        last_write: &mut FxHashMap<Allocation, usize>,
+    ) {
+        let move_source = match inst {
+            &CheckerInst::Move { from, .. } => Some((from, last_write.get(&from).copied())),
+            _ => None,
+        };
+        let mut write = |write: CheckerWrite, source: Option<(Allocation, Option<usize>)>| {
+            let alloc = write.alloc();
+            last_write.insert(alloc, writes.len());
+            if let Some(preg) = alloc.as_reg() {
+                for &alias in self.aliases(preg) {
+                    last_write.insert(Allocation::reg(alias), writes.len());
+                }
+            }
+            writes.push(WriteRecord {
+                write,
+                block,
+                source,
+            });
+        };
+        match inst {
+            &CheckerInst::Move { pos, into, from } => {
+                let edit = Edit::Move { from, to: into };
+                write(CheckerWrite::Edit { pos, edit }, move_source);
+            }
+            &CheckerInst::Remat { pos, into, vreg } => {
+                let edit = Edit::Remat { vreg, to: into };
+                write(CheckerWrite::Edit { pos, edit }, None);
+            }
+            &CheckerInst::Op {
+                inst,
+                ref operands,
+                ref allocs,
+                ref clobbers,
+            } => {
+                for (&op, &alloc) in operands.iter().zip(allocs.iter()) {
+                    if op.kind() == OperandKind::Def {
+                        write(CheckerWrite::Def { inst, op, alloc }, None);
+                    }
+                }
+                for &preg in clobbers {
+                    write(CheckerWrite::Clobber { inst, preg }, None);
+                }
+            }
+            &CheckerInst::Safepoint { .. }
+            | &CheckerInst::ProgramMove { .. }
+            | &CheckerInst::ParallelMove { .. } => {}
         }
     }
 
+    /// Follow the writes that put the value into `alloc` in `block`,
+    /// starting from the write `last`, through the sources of moves,
+    /// and into the predecessor of any block with only one.
+    fn history(
+        &self,
+        mut block: Block,
+        mut alloc: Allocation,
+        mut last: Option<usize>,
+        writes: &[WriteRecord],
+        last_writes: &[FxHashMap<Allocation, usize>],
+    ) -> Vec<CheckerWrite> {
+        let mut history = vec![];
+        let mut visited = FxHashSet::default();
+        loop {
+            match last {
+                Some(idx) => {
+                    let record = &writes[idx];
+                    history.push(record.write.clone());
+                    // A write to an overlapping register, or anything
+                    // but a move, ends the chain.
+                    match record.source {
+                        Some((from, from_last)) if record.write.alloc() == alloc => {
+                            alloc = from;
+                            last = from_last;
+                            block = record.block;
+                        }
+                        _ => break,
+                    }
+                }
+                None => {
+                    let preds = self.f.block_preds(block);
+                    if block == self.f.entry_block() || preds.len() != 1 || !visited.insert(block) {
+                        history.push(CheckerWrite::BlockEntry { block, alloc });
+                        break;
+                    }
+                    block = preds[0];
+                    last = last_writes[block.index()].get(&alloc).copied();
+                }
+            }
+        }
+        history
+    }
+
     /// Find any errors, returning `Err(CheckerErrors)` with all errors found
     /// or `Ok(())` otherwise.
     pub fn run(mut self) -> Result<(), CheckerErrors> {
@@ -1110,9 +2225,12 @@ impl<'a, F: Function> Checker<'a, F> {
                             clobbers
                         );
                     }
-                    &CheckerInst::Move { from, into } => {
+                    &CheckerInst::Move { from, into, .. } => {
                         trace!("    {} -> {}", from, into);
                     }
+                    &CheckerInst::Remat { into, vreg, .. } => {
+                        trace!("    remat {} -> {}", vreg, into);
+                    }
                     &CheckerInst::Safepoint { ref allocs, .. } => {
                         let mut slotargs = vec![];
                         for &slot in allocs {
@@ -1154,3 +2272,355 @@ impl<'a, F: Function> Checker<'a, F> {
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text::{parse_function, parse_machine_env, TextFunction};
+    use crate::{RegallocOptions, SpillSlot};
+
+    /// A function with a spill, a reload, a reference live across a
+    /// safepoint and a debug label, so its output exercises each rule.
+    const INPUT: &str = "
+machine_env {
+    preferred(p0i, p1i, p0f)
+}
+
+function {
+    entry block0
+    vregs 5
+    spillslot_sizes(i: 1, f: 2, v: 4, p: 1)
+    reftype v0i
+    debug_label v1i inst1 inst5 7
+    block0():
+        inst0: op(Def: v0i reg, Def: v3f reg)
+        inst1: op(Def: v1i reg, Use: v0i reg)
+        inst2: op(Def: v2i reg, Use: v1i reg)
+        inst3: op(Use: v1i reg, Use: v2i reg) safepoint
+        inst4: op(Def: v4i reg, Use: v2i reg, Use: v3f reg)
+        inst5: ret(Use: v0i reg, Use: v1i any, Use: v4i any)
+}
+";
+
+    fn allocate(input: &str) -> (TextFunction, MachineEnv, Output) {
+        let func = parse_function(input).unwrap();
+        let env = parse_machine_env(input).unwrap();
+        let out = crate::run(&func, &env, &RegallocOptions::default()).unwrap();
+        (func, env, out)
+    }
+
+    fn check(func: &TextFunction, env: &MachineEnv, out: &Output) -> Result<(), CheckerErrors> {
+        let mut checker = Checker::new(func, env);
+        checker.prepare(out);
+        checker.run()
+    }
+
+    fn errors(func: &TextFunction, env: &MachineEnv, out: &Output) -> Vec<CheckerError> {
+        match check(func, env, out) {
+            Ok(()) => vec![],
+            Err(errors) => errors.errors().iter().map(|e| e.error.clone()).collect(),
+        }
+    }
+
+    #[test]
+    fn valid_output() {
+        let (func, env, out) = allocate(INPUT);
+        check(&func, &env, &out).unwrap();
+    }
+
+    #[test]
+    fn incorrect_debug_location() {
+        let (func, env, mut out) = allocate(INPUT);
+        let p1 = Allocation::reg(PReg::new(1, RegClass::Int));
+        assert_eq!(out.debug_locations[0].0, 7);
+        out.debug_locations[0].3 = p1;
+        let errors = errors(&func, &env, &out);
+        assert!(!errors.is_empty());
+        for e in errors {
+            assert!(matches!(
+                e,
+                CheckerError::IncorrectDebugLocation { label: 7, alloc, .. } if alloc == p1
+            ));
+        }
+    }
+
+    #[test]
+    fn unsorted_debug_location() {
+        let (func, env, mut out) = allocate(INPUT);
+        out.debug_locations.swap(0, 1);
+        let (_, from, to, _) = out.debug_locations[1];
+        // The earlier location also ends after the later one starts,
+        // so it overlaps it as well.
+        let errors = errors(&func, &env, &out);
+        assert!(matches!(
+            errors[..],
+            [
+                CheckerError::UnsortedDebugLocation { label: 7, from: f, to: t },
+                CheckerError::OverlappingDebugLocation { label: 7, .. },
+            ] if (f, t) == (from, to)
+        ));
+    }
+
+    #[test]
+    fn overlapping_debug_location() {
+        let (func, env, mut out) = allocate(INPUT);
+        let (_, from, to, _) = out.debug_locations[0];
+        out.debug_locations.insert(1, out.debug_locations[0]);
+        let errors = errors(&func, &env, &out);
+        assert!(matches!(
+            errors[..],
+            [CheckerError::OverlappingDebugLocation { label: 7, from: f, to: t }] if (f, t) == (from, to)
+        ));
+    }
+
+    #[test]
+    fn missing_ref_in_stackmap() {
+        let (func, env, mut out) = allocate(INPUT);
+        assert_eq!(out.safepoint_slots.len(), 1);
+        out.safepoint_slots.clear();
+        let errors = errors(&func, &env, &out);
+        // The collector may move v0, so its use after the safepoint
+        // is also wrong.
+        let v0 = VReg::new(0, RegClass::Int);
+        assert!(matches!(
+            errors[..],
+            [
+                CheckerError::MissingRefInStackmap { inst, vreg },
+                CheckerError::IncorrectValuesInAllocation { inst: use_inst, .. },
+            ] if inst == Inst::new(3) && vreg == v0 && use_inst == Inst::new(5)
+        ));
+    }
+
+    #[test]
+    fn spillslot_out_of_range() {
+        let (func, env, mut out) = allocate(INPUT);
+        out.num_spillslots = 0;
+        let found = errors(&func, &env, &out)
+            .into_iter()
+            .map(|e| match e {
+                CheckerError::SpillSlotOutOfRange {
+                    point,
+                    alloc,
+                    num_spillslots: 0,
+                } => (point, alloc),
+                e => panic!("unexpected error: {}", e),
+            })
+            .collect::<Vec<_>>();
+        // Every use of a slot, including the safepoint's, is reported
+        // once.
+        let stack0 = Allocation::stack(SpillSlot::new(0, RegClass::Int));
+        let stack1 = Allocation::stack(SpillSlot::new(1, RegClass::Int));
+        let before = |i| ProgPoint::before(Inst::new(i));
+        assert_eq!(
+            found,
+            [
+                (before(2), stack0),
+                (before(3), stack0),
+                (before(4), stack1),
+                (before(5), stack0),
+                (before(5), stack1),
+            ]
+        );
+    }
+
+    #[test]
+    fn misaligned_spillslot() {
+        let (func, env, mut out) = allocate(INPUT);
+        // Keep v3f in stack1, which is not aligned to the two slots
+        // of a float.
+        let stack1 = Allocation::stack(SpillSlot::new(1, RegClass::Float));
+        out.allocs[1] = stack1;
+        out.allocs[10] = stack1;
+        let errors = errors(&func, &env, &out);
+        assert!(matches!(
+            errors[..],
+            [
+                CheckerError::MisalignedSpillSlot { point: def, alloc: a },
+                CheckerError::MisalignedSpillSlot { point: use_, alloc: b },
+            ] if def == ProgPoint::after(Inst::new(0))
+                && use_ == ProgPoint::before(Inst::new(4))
+                && a == stack1
+                && b == stack1
+        ));
+    }
+
+    #[test]
+    fn class_mismatch() {
+        let (func, env, mut out) = allocate(INPUT);
+        // Keep v3f in p1f, but give the integer p1i to its use.
+        let p1i = Allocation::reg(PReg::new(1, RegClass::Int));
+        out.allocs[1] = Allocation::reg(PReg::new(1, RegClass::Float));
+        out.allocs[10] = p1i;
+        let errors = errors(&func, &env, &out);
+        assert!(matches!(
+            errors[..],
+            [CheckerError::ClassMismatch { point, alloc, class: RegClass::Float }]
+                if point == ProgPoint::before(Inst::new(4)) && alloc == p1i
+        ));
+    }
+
+    #[test]
+    fn stack_to_stack_move() {
+        let (func, env, mut out) = allocate(INPUT);
+        let stack0 = Allocation::stack(SpillSlot::new(0, RegClass::Int));
+        let stack2 = Allocation::stack(SpillSlot::new(2, RegClass::Int));
+        let pos = ProgPoint::before(Inst::new(5));
+        out.num_spillslots = 3;
+        out.edits.push((
+            pos,
+            Edit::Move {
+                from: stack0,
+                to: stack2,
+            },
+        ));
+        let errors = errors(&func, &env, &out);
+        assert!(matches!(
+            errors[..],
+            [CheckerError::StackToStackMove { pos: p, from, to }]
+                if p == pos && from == stack0 && to == stack2
+        ));
+    }
+
+    #[test]
+    fn unsorted_edit() {
+        let (func, env, mut out) = allocate(INPUT);
+        assert_eq!(out.edits.len(), 3);
+        out.edits.reverse();
+        let errors = errors(&func, &env, &out);
+        assert!(matches!(
+            errors[..],
+            [CheckerError::UnsortedEdit { pos, prev }]
+                if pos == out.edits[1].0 && prev == out.edits[0].0
+        ));
+    }
+
+    /// Only vregs that are cheaper to recompute than to reload may be
+    /// rematerialized, as in the allocator.
+    #[test]
+    fn remat_at_reload_cost() {
+        let input = "
+machine_env {
+    preferred(p0i, p1i)
+}
+
+function {
+    entry block0
+    vregs 4
+    remat v0i 10
+    block0():
+        inst0: op(Def: v0i reg)
+        inst1: op(Def: v1i reg, Def: v2i reg)
+        inst2: op(Def: v3i reg, Use: v1i reg, Use: v2i reg)
+        inst3: ret(Use: v0i reg, Use: v3i reg)
+}
+";
+        let (mut func, env, out) = allocate(input);
+        let v0 = VReg::new(0, RegClass::Int);
+        assert!(out
+            .edits
+            .iter()
+            .any(|(_, edit)| matches!(edit, Edit::Remat { vreg, .. } if *vreg == v0)));
+        check(&func, &env, &out).unwrap();
+        func.remat_costs.insert(v0, REMAT_RELOAD_COST);
+        let errors = errors(&func, &env, &out);
+        assert!(!errors.is_empty());
+        for e in errors {
+            assert!(matches!(e, CheckerError::InvalidRemat { vreg, .. } if vreg == v0));
+        }
+    }
+
+    /// An early def's location starts at the `Before` point of its
+    /// instruction, where the register does not hold it yet.
+    #[test]
+    fn early_def_debug_location() {
+        let (func, env, out) = allocate(
+            "
+machine_env {
+    preferred(p0i, p1i)
+}
+
+function {
+    entry block0
+    vregs 1
+    debug_label v0i inst0 inst2 7
+    block0():
+        inst0: op(Def@Early: v0i fixed(loc5))
+        inst1: op(Use: v0i reg)
+        inst2: ret()
+}
+",
+        );
+        check(&func, &env, &out).unwrap();
+    }
+
+    /// The move of v1's reused input into p2f overwrites its alias p0f,
+    /// which must not be given as v0's location at inst2.
+    #[test]
+    fn overwritten_debug_location() {
+        let (func, env, mut out) = allocate(
+            "
+machine_env {
+    preferred(p0f, p1f, p2f)
+    alias(p2f, p0f)
+    alias(p2f, p1f)
+}
+
+function {
+    entry block0
+    vregs 3
+    debug_label v0f inst0 inst4 9
+    block0():
+        inst0: op(Def: v0f fixed(p0f))
+        inst1: op(Def: v2f fixed(p2f))
+        inst2: op(Def: v1f reuse(1), Use: v0f reg)
+        inst3: op(Use: v1f fixed(p2f))
+        inst4: ret()
+}
+",
+        );
+        let p0f = Allocation::reg(PReg::new(0, RegClass::Float));
+        let p2f = Allocation::reg(PReg::new(2, RegClass::Float));
+        let reload = out.edits.iter().position(|&(pos, ref edit)| {
+            pos == ProgPoint::before(Inst::new(2))
+                && matches!(*edit, Edit::Move { to, .. } if to == p0f)
+        });
+        let reuse = out.edits.iter().position(|&(pos, ref edit)| {
+            pos == ProgPoint::before(Inst::new(2))
+                && matches!(*edit, Edit::Move { to, .. } if to == p2f)
+        });
+        assert!(reload.unwrap() < reuse.unwrap());
+        let slot = Allocation::stack(SpillSlot::new(0, RegClass::Float));
+        out.debug_locations = vec![
+            (
+                9,
+                ProgPoint::after(Inst::new(0)),
+                ProgPoint::before(Inst::new(1)),
+                p0f,
+            ),
+            (
+                9,
+                ProgPoint::before(Inst::new(1)),
+                ProgPoint::before(Inst::new(2)),
+                slot,
+            ),
+        ];
+        check(&func, &env, &out).unwrap();
+
+        out.debug_locations.push((
+            9,
+            ProgPoint::before(Inst::new(2)),
+            ProgPoint::after(Inst::new(2)),
+            p0f,
+        ));
+        let errors = errors(&func, &env, &out);
+        assert!(
+            matches!(
+                errors[..],
+                [CheckerError::IncorrectDebugLocation { label: 9, point, alloc, .. }]
+                    if point == ProgPoint::before(Inst::new(2)) && alloc == p0f
+            ),
+            "{:?}",
+            errors
+        );
+    }
+}
@@ -0,0 +1,146 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Liveness for the fast allocator: the usual per-block livein and
+//! liveout sets, plus the last use of each value within the block
+//! being allocated.
+
+use super::{Env, VRegData};
+use crate::indexset::IndexSet;
+use crate::{Block, Function, Inst, OperandKind, RegAllocError, VReg};
+use fxhash::FxHashSet;
+use std::collections::VecDeque;
+
+impl<'a, F: Function> Env<'a, F> {
+    /// Record the vreg (with its class) for its index.
+    fn observe_vreg(&mut self, vreg: VReg) {
+        self.vregs[vreg.vreg()].vreg = vreg;
+    }
+
+    /// Compute the livein and liveout sets of every block with a
+    /// worklist algorithm.
+    pub(super) fn compute_liveness(&mut self) -> Result<(), RegAllocError> {
+        self.vregs.clear();
+        self.vregs
+            .resize(self.func.num_vregs(), VRegData::default());
+        for &vreg in self.func.reftype_vregs() {
+            self.vregs[vreg.vreg()].is_ref = true;
+        }
+        for _ in 0..self.func.num_blocks() {
+            self.liveins.push(IndexSet::new());
+            self.liveouts.push(IndexSet::new());
+        }
+
+        let mut workqueue = VecDeque::new();
+        let mut workqueue_set = FxHashSet::default();
+        for &block in &self.cfginfo.postorder[..] {
+            workqueue.push_back(block);
+            workqueue_set.insert(block);
+        }
+
+        while let Some(block) = workqueue.pop_front() {
+            workqueue_set.remove(&block);
+            let insns = self.func.block_insns(block);
+            let mut live = self.liveouts[block.index()].clone();
+
+            if self.func.is_branch(insns.last()) {
+                for i in 0..self.func.block_succs(block).len() {
+                    for &arg in self.func.branch_blockparams(block, insns.last(), i) {
+                        live.set(arg.vreg(), true);
+                        self.observe_vreg(arg);
+                    }
+                }
+            }
+
+            for inst in insns.rev().iter() {
+                if let Some((src, dst)) = self.func.is_move(inst) {
+                    live.set(dst.vreg().vreg(), false);
+                    live.set(src.vreg().vreg(), true);
+                    self.observe_vreg(src.vreg());
+                    self.observe_vreg(dst.vreg());
+                    continue;
+                }
+                let operands = self.func.inst_operands(inst);
+                for op in operands {
+                    if op.kind() == OperandKind::Def {
+                        live.set(op.vreg().vreg(), false);
+                    }
+                }
+                for op in operands {
+                    if op.kind() != OperandKind::Def {
+                        live.set(op.vreg().vreg(), true);
+                    }
+                    self.observe_vreg(op.vreg());
+                }
+            }
+            for &param in self.func.block_params(block) {
+                live.set(param.vreg(), false);
+                self.observe_vreg(param);
+            }
+
+            for &pred in self.func.block_preds(block) {
+                if self.liveouts[pred.index()].union_with(&live) && workqueue_set.insert(pred) {
+                    workqueue.push_back(pred);
+                }
+            }
+            self.liveins[block.index()] = live;
+        }
+
+        // As in ion, only pinned vregs may be live into the entry
+        // block.
+        for livein in self.liveins[self.func.entry_block().index()].iter() {
+            if self.func.is_pinned_vreg(self.vregs[livein].vreg).is_none() {
+                return Err(RegAllocError::EntryLivein);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the last use within `block` of every value it uses, and
+    /// mark the values live out of it as used past its end.
+    pub(super) fn compute_last_uses(&mut self, block: Block) {
+        let stamp = block.index() as u32 + 1;
+        for vreg in self.liveouts[block.index()].iter() {
+            let data = &mut self.vregs[vreg];
+            data.block_stamp = stamp;
+            data.last_use = u32::MAX;
+        }
+        let insns = self.func.block_insns(block);
+        let mark = |vregs: &mut [VRegData], vreg: VReg, inst: Inst| {
+            let data = &mut vregs[vreg.vreg()];
+            if data.block_stamp != stamp {
+                data.block_stamp = stamp;
+                data.last_use = inst.index() as u32;
+            }
+        };
+        if self.func.is_branch(insns.last()) {
+            for i in 0..self.func.block_succs(block).len() {
+                for &arg in self.func.branch_blockparams(block, insns.last(), i) {
+                    mark(&mut self.vregs, arg, insns.last());
+                }
+            }
+        }
+        for inst in insns.rev().iter() {
+            if let Some((src, _)) = self.func.is_move(inst) {
+                mark(&mut self.vregs, src.vreg(), inst);
+                continue;
+            }
+            for op in self.func.inst_operands(inst) {
+                if op.kind() != OperandKind::Def {
+                    mark(&mut self.vregs, op.vreg(), inst);
+                }
+            }
+        }
+    }
+
+    /// Is the value of `vreg` used after `inst`, in the block being
+    /// allocated or a later one?
+    #[inline(always)]
+    pub(super) fn live_after(&self, vreg: VReg, inst: Inst) -> bool {
+        let data = &self.vregs[vreg.vreg()];
+        data.block_stamp == self.block_stamp && data.last_use > inst.index() as u32
+    }
+}
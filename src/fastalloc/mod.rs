@@ -0,0 +1,1174 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Fast single-pass register allocator.
+//!
+//! This allocator trades code quality for allocation speed, for use
+//! in baseline JIT tiers. It visits each instruction once, in order,
+//! and never splits or coalesces live ranges. Between blocks, every
+//! value lives in a spillslot of its own (its "home"), except for
+//! pinned vregs, which always live in their register; blocks can thus
+//! be allocated independently, and blockparam moves only go between
+//! homes. Within a block, registers cache the homes: a value is
+//! loaded into a register at its first use that needs one and stays
+//! there until the register is needed for something else, and a
+//! value defined into a register is written back to its home only if
+//! it is evicted while still live, or if it is live out of the block.
+//!
+//! In spill-everything mode, meant for debugging, no value stays in a
+//! register from one instruction to the next: each instruction loads
+//! its uses from their homes, and its defs are stored to theirs right
+//! after it.
+
+use crate::cfg::CFGInfo;
+use crate::indexset::IndexSet;
+use crate::ion::data_structures::u64_key;
+use crate::ion::reg_traversal::RegTraversalIter;
+use crate::moves::{MoveAndScratchResolver, ParallelMoves};
+use crate::{
+    Allocation, Block, Edit, Function, Inst, MachineEnv, Operand, OperandConstraint, OperandKind,
+    OperandPos, Output, PReg, PRegSet, ProgPoint, RegAllocError, RegClass, SpillSlot, VReg,
+};
+use smallvec::{smallvec, SmallVec};
+
+mod liveness;
+
+#[derive(Clone, Copy, Debug)]
+struct VRegData {
+    vreg: VReg,
+    /// The register holding the value, if any.
+    reg: PReg,
+    /// Whether the value in `reg` has not been written to the home.
+    dirty: bool,
+    /// The spillslot of the value, allocated when first needed.
+    home: SpillSlot,
+    /// The block (plus one) whose allocation computed `last_use`.
+    block_stamp: u32,
+    /// The last instruction in that block that uses the value, or
+    /// `u32::MAX` if the value is live out of the block.
+    last_use: u32,
+    is_ref: bool,
+    /// The block (plus one) for which the vreg is in `block_refs`.
+    ref_stamp: u32,
+}
+
+impl Default for VRegData {
+    fn default() -> Self {
+        Self {
+            vreg: VReg::invalid(),
+            reg: PReg::invalid(),
+            dirty: false,
+            home: SpillSlot::invalid(),
+            block_stamp: 0,
+            last_use: 0,
+            is_ref: false,
+            ref_stamp: 0,
+        }
+    }
+}
+
+/// Buffers that the fast allocator keeps between runs; see
+/// `RegallocContext`.
+#[derive(Debug, Default)]
+pub struct Ctx {
+    cfginfo: CFGInfo,
+    liveins: Vec<IndexSet>,
+    liveouts: Vec<IndexSet>,
+    vregs: Vec<VRegData>,
+    pregs: Vec<VReg>,
+    preg_aliases: Vec<SmallVec<[PReg; 4]>>,
+    block_refs: Vec<VReg>,
+}
+
+struct Env<'a, F: Function> {
+    func: &'a F,
+    env: &'a MachineEnv,
+    cfginfo: CFGInfo,
+    liveins: Vec<IndexSet>,
+    liveouts: Vec<IndexSet>,
+    vregs: Vec<VRegData>,
+    /// The vreg whose value each register holds, indexed by
+    /// `PReg::index()`.
+    pregs: Vec<VReg>,
+    preg_aliases: Vec<SmallVec<[PReg; 4]>>,
+    /// The registers that hold a value.
+    occupied: PRegSet,
+    fixed_stack_slots: PRegSet,
+    victims: [PReg; RegClass::COUNT],
+    /// Empty the registers after every instruction.
+    spill_everything: bool,
+    extra_spillslots: [SmallVec<[Allocation; 2]>; RegClass::COUNT],
+    num_spillslots: u32,
+
+    /// The block (plus one) being allocated.
+    block_stamp: u32,
+    /// The reference-typed vregs that are live into or defined in the
+    /// block being allocated.
+    block_refs: Vec<VReg>,
+
+    // State for the instruction being allocated.
+    clobbers: PRegSet,
+    /// Registers that operands use at the early point.
+    early_regs: PRegSet,
+    /// Registers that operands use at the late point.
+    late_regs: PRegSet,
+    /// Registers that operands only read, with the value they read: a
+    /// read of the same value at the early point may share them.
+    read_regs: SmallVec<[(PReg, VReg); 8]>,
+
+    output: &'a mut Output,
+}
+
+impl<'a, F: Function> Env<'a, F> {
+    fn new(
+        func: &'a F,
+        env: &'a MachineEnv,
+        spill_everything: bool,
+        ctx: &mut Ctx,
+        output: &'a mut Output,
+    ) -> Self {
+        let mut fixed_stack_slots = PRegSet::empty();
+        for &preg in &env.fixed_stack_slots {
+            fixed_stack_slots.add(preg);
+        }
+        let mut liveins = std::mem::take(&mut ctx.liveins);
+        liveins.clear();
+        let mut liveouts = std::mem::take(&mut ctx.liveouts);
+        liveouts.clear();
+        let mut preg_aliases = std::mem::take(&mut ctx.preg_aliases);
+        preg_aliases.clear();
+        let mut block_refs = std::mem::take(&mut ctx.block_refs);
+        block_refs.clear();
+        let mut pregs = std::mem::take(&mut ctx.pregs);
+        pregs.clear();
+        pregs.resize(PReg::NUM_INDEX, VReg::invalid());
+        Self {
+            func,
+            env,
+            cfginfo: std::mem::take(&mut ctx.cfginfo),
+            liveins,
+            liveouts,
+            vregs: std::mem::take(&mut ctx.vregs),
+            pregs,
+            preg_aliases,
+            occupied: PRegSet::empty(),
+            fixed_stack_slots,
+            victims: [PReg::invalid(); RegClass::COUNT],
+            spill_everything,
+            extra_spillslots: Default::default(),
+            num_spillslots: 0,
+            block_stamp: 0,
+            block_refs,
+            clobbers: PRegSet::empty(),
+            early_regs: PRegSet::empty(),
+            late_regs: PRegSet::empty(),
+            read_regs: smallvec![],
+            output,
+        }
+    }
+
+    /// Give the buffers back to `ctx`.
+    fn finish(self, ctx: &mut Ctx) {
+        ctx.cfginfo = self.cfginfo;
+        ctx.liveins = self.liveins;
+        ctx.liveouts = self.liveouts;
+        ctx.vregs = self.vregs;
+        ctx.pregs = self.pregs;
+        ctx.preg_aliases = self.preg_aliases;
+        ctx.block_refs = self.block_refs;
+    }
+
+    fn init_pregs(&mut self) {
+        for &(a, b) in &self.env.aliases {
+            debug_assert_eq!(a.class(), b.class());
+            debug_assert_ne!(a, b);
+            let len = std::cmp::max(a.index(), b.index()) + 1;
+            if self.preg_aliases.len() < len {
+                self.preg_aliases.resize(len, smallvec![]);
+            }
+            if !self.preg_aliases[a.index()].contains(&b) {
+                self.preg_aliases[a.index()].push(b);
+                self.preg_aliases[b.index()].push(a);
+            }
+        }
+        // As in ion, prefer a victim without aliases for
+        // stack-to-stack moves.
+        for class in 0..RegClass::COUNT {
+            let env = self.env;
+            let candidates = || {
+                env.non_preferred_regs_by_class[class]
+                    .iter()
+                    .rev()
+                    .chain(env.preferred_regs_by_class[class].iter().rev())
+            };
+            self.victims[class] = candidates()
+                .find(|&&preg| self.aliases(preg).is_empty())
+                .or_else(|| candidates().next())
+                .cloned()
+                .unwrap_or(PReg::invalid());
+        }
+    }
+
+    fn init_output(&mut self) {
+        let output = &mut *self.output;
+        output.edits.clear();
+        output.allocs.clear();
+        output.inst_alloc_offsets.clear();
+        output.safepoint_slots.clear();
+        output.debug_locations.clear();
+        output.stats = Default::default();
+        output.quality = Default::default();
+        for inst in 0..self.func.num_insts() {
+            let n = self.func.inst_operands(Inst::new(inst)).len();
+            output.inst_alloc_offsets.push(output.allocs.len() as u32);
+            output
+                .allocs
+                .resize(output.allocs.len() + n, Allocation::none());
+        }
+    }
+
+    fn run(&mut self) -> Result<(), RegAllocError> {
+        self.cfginfo.init(self.func)?;
+        self.init_pregs();
+        self.compute_liveness()?;
+        self.init_output();
+        for block in 0..self.func.num_blocks() {
+            self.alloc_block(Block::new(block))?;
+        }
+        // Edits are generated in order, except that blocks need not be
+        // laid out in index order. The sort must be stable to keep
+        // the order of the edits at each point.
+        self.output.edits.sort_by_key(|&(pos, _)| pos);
+        self.output
+            .safepoint_slots
+            .sort_unstable_by_key(|&(pos, slot)| u64_key(pos.to_index(), slot.bits()));
+        self.output.num_spillslots = self.num_spillslots as usize;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn aliases(&self, preg: PReg) -> &[PReg] {
+        self.preg_aliases
+            .get(preg.index())
+            .map(|aliases| &aliases[..])
+            .unwrap_or(&[])
+    }
+
+    /// Does `set` contain `preg` or a register overlapping it?
+    fn overlaps(&self, set: &PRegSet, preg: PReg) -> bool {
+        set.contains(preg) || self.aliases(preg).iter().any(|&alias| set.contains(alias))
+    }
+
+    fn allocs_overlap(&self, alloc: Allocation, preg: PReg) -> bool {
+        match alloc.as_reg() {
+            Some(reg) => reg == preg || self.aliases(preg).contains(&reg),
+            None => false,
+        }
+    }
+
+    /// Is `alloc` in memory, including fixed stack slots?
+    fn in_memory(&self, alloc: Allocation) -> bool {
+        match alloc.as_reg() {
+            Some(preg) => self.fixed_stack_slots.contains(preg),
+            None => true,
+        }
+    }
+
+    /// The constraint of `op`, with pinned vregs constrained to their
+    /// register.
+    fn constraint(&self, op: Operand) -> OperandConstraint {
+        match self.func.is_pinned_vreg(op.vreg()) {
+            Some(preg) => OperandConstraint::FixedReg(preg),
+            None => op.constraint(),
+        }
+    }
+
+    fn alloc_of(&self, inst: Inst, slot: usize) -> Allocation {
+        self.output.allocs[self.output.inst_alloc_offsets[inst.index()] as usize + slot]
+    }
+
+    fn set_alloc(&mut self, inst: Inst, slot: usize, alloc: Allocation) {
+        let offset = self.output.inst_alloc_offsets[inst.index()] as usize;
+        self.output.allocs[offset + slot] = alloc;
+    }
+
+    fn add_move(&mut self, pos: ProgPoint, from: Allocation, to: Allocation) {
+        if from != to {
+            self.output.edits.push((pos, Edit::Move { from, to }));
+        }
+    }
+
+    fn allocate_spillslot(&mut self, class: RegClass) -> Allocation {
+        let size = self.func.spillslot_size(class) as u32;
+        let mut offset = self.num_spillslots;
+        // Align up to `size`.
+        debug_assert!(size.is_power_of_two());
+        offset = (offset + size - 1) & !(size - 1);
+        let slot = if self.func.multi_spillslot_named_by_last_slot() {
+            offset + size - 1
+        } else {
+            offset
+        };
+        offset += size;
+        self.num_spillslots = offset;
+        Allocation::stack(SpillSlot::new(slot as usize, class))
+    }
+
+    /// The location of `vreg` between blocks: the register of a pinned
+    /// vreg, or else its spillslot.
+    fn home(&mut self, vreg: VReg) -> Allocation {
+        if let Some(preg) = self.func.is_pinned_vreg(vreg) {
+            return Allocation::reg(preg);
+        }
+        if self.vregs[vreg.vreg()].home.is_invalid() {
+            let slot = self.allocate_spillslot(vreg.class());
+            self.vregs[vreg.vreg()].home = slot.as_stack().unwrap();
+        }
+        Allocation::stack(self.vregs[vreg.vreg()].home)
+    }
+
+    /// Record that `preg` holds the value of `vreg`, which must not be
+    /// in another register.
+    fn map(&mut self, vreg: VReg, preg: PReg, dirty: bool) {
+        debug_assert_eq!(self.vregs[vreg.vreg()].reg, PReg::invalid());
+        debug_assert_eq!(self.pregs[preg.index()], VReg::invalid());
+        let data = &mut self.vregs[vreg.vreg()];
+        data.reg = preg;
+        data.dirty = dirty;
+        self.pregs[preg.index()] = vreg;
+        self.occupied.add(preg);
+    }
+
+    /// Forget the register holding `vreg`, if any.
+    fn unmap(&mut self, vreg: VReg) {
+        let data = &mut self.vregs[vreg.vreg()];
+        let preg = data.reg;
+        if preg != PReg::invalid() {
+            data.reg = PReg::invalid();
+            data.dirty = false;
+            self.pregs[preg.index()] = VReg::invalid();
+            self.occupied.remove(preg);
+        }
+    }
+
+    /// Store the value of `vreg` to its home at `pos` if only its
+    /// register has it.
+    fn write_back(&mut self, vreg: VReg, pos: ProgPoint) {
+        let data = self.vregs[vreg.vreg()];
+        if data.dirty {
+            let home = self.home(vreg);
+            self.add_move(pos, Allocation::reg(data.reg), home);
+            self.vregs[vreg.vreg()].dirty = false;
+        }
+    }
+
+    fn note_ref(&mut self, vreg: VReg) {
+        let data = &mut self.vregs[vreg.vreg()];
+        if data.is_ref && data.ref_stamp != self.block_stamp {
+            data.ref_stamp = self.block_stamp;
+            self.block_refs.push(vreg);
+        }
+    }
+
+    /// Is the value of `vreg` needed after `pos`, which is in `inst`?
+    /// Before the instruction, this includes its operands that are
+    /// not allocated yet.
+    fn is_needed(&self, vreg: VReg, inst: Inst, pos: ProgPoint) -> bool {
+        if self.live_after(vreg, inst) {
+            return true;
+        }
+        pos == ProgPoint::before(inst)
+            && self
+                .func
+                .inst_operands(inst)
+                .iter()
+                .enumerate()
+                .any(|(i, op)| {
+                    op.vreg() == vreg
+                        && op.kind() != OperandKind::Def
+                        && self.alloc_of(inst, i).is_none()
+                })
+    }
+
+    /// Free `reg` itself, writing back its value at `pos` if needed.
+    fn evict_one(&mut self, reg: PReg, inst: Inst, pos: ProgPoint) {
+        let vreg = self.pregs[reg.index()];
+        if vreg != VReg::invalid() {
+            if self.is_needed(vreg, inst, pos) {
+                self.write_back(vreg, pos);
+            }
+            self.unmap(vreg);
+        }
+    }
+
+    /// Free `preg` and the registers overlapping it.
+    fn evict(&mut self, preg: PReg, inst: Inst, pos: ProgPoint) {
+        self.evict_one(preg, inst, pos);
+        for i in 0..self.aliases(preg).len() {
+            let alias = self.aliases(preg)[i];
+            self.evict_one(alias, inst, pos);
+        }
+    }
+
+    /// What it costs to free `preg` and the registers overlapping it:
+    /// zero if they are free, one if their values are no longer
+    /// needed, and otherwise more for values that must be reloaded
+    /// (and perhaps written back first), less for those used later.
+    /// `None` if one of them holds a pinned vreg.
+    fn eviction_cost(&self, preg: PReg, inst: Inst, pos: ProgPoint) -> Option<u64> {
+        let mut cost = 0;
+        for reg in std::iter::once(preg).chain(self.aliases(preg).iter().copied()) {
+            let vreg = self.pregs[reg.index()];
+            if vreg == VReg::invalid() {
+                continue;
+            }
+            if self.func.is_pinned_vreg(vreg).is_some() {
+                return None;
+            }
+            let reg_cost = if self.is_needed(vreg, inst, pos) {
+                let data = &self.vregs[vreg.vreg()];
+                let reload = if data.dirty { 3 } else { 2 };
+                (reload << 32) | u64::from(u32::MAX - data.last_use)
+            } else {
+                1
+            };
+            cost = std::cmp::max(cost, reg_cost);
+        }
+        Some(cost)
+    }
+
+    /// Find the cheapest register of `class` (in `subset`, if given)
+    /// that overlaps nothing in `avoid`, and free it. With `max_cost`,
+    /// only consider registers no more expensive to free.
+    fn find_reg(
+        &mut self,
+        class: RegClass,
+        subset: Option<&PRegSet>,
+        avoid: PRegSet,
+        max_cost: u64,
+        inst: Inst,
+        pos: ProgPoint,
+    ) -> Option<PReg> {
+        let mut best = None;
+        let mut best_cost = u64::MAX;
+        let iter = RegTraversalIter::new(
+            self.env,
+            class,
+            PReg::invalid(),
+            PReg::invalid(),
+            0,
+            None,
+            subset,
+        );
+        for preg in iter {
+            if self.overlaps(&avoid, preg) {
+                continue;
+            }
+            match self.eviction_cost(preg, inst, pos) {
+                Some(0) => {
+                    best = Some(preg);
+                    break;
+                }
+                Some(cost) if cost < best_cost && cost <= max_cost => {
+                    best = Some(preg);
+                    best_cost = cost;
+                }
+                _ => {}
+            }
+        }
+        let preg = best?;
+        self.evict(preg, inst, pos);
+        Some(preg)
+    }
+
+    /// Like `find_reg`, but willing to evict any value but a pinned
+    /// one.
+    fn take_reg(
+        &mut self,
+        class: RegClass,
+        subset: Option<&PRegSet>,
+        avoid: PRegSet,
+        inst: Inst,
+        pos: ProgPoint,
+    ) -> Result<PReg, RegAllocError> {
+        self.find_reg(class, subset, avoid, u64::MAX, inst, pos)
+            .ok_or(RegAllocError::TooManyLiveRegs)
+    }
+
+    fn alloc_block(&mut self, block: Block) -> Result<(), RegAllocError> {
+        self.block_stamp = block.index() as u32 + 1;
+        self.compute_last_uses(block);
+        self.block_refs.clear();
+
+        // Values live into the block are in their homes.
+        let liveins = std::mem::replace(&mut self.liveins[block.index()], IndexSet::new());
+        for vreg in liveins.iter() {
+            let vreg = self.vregs[vreg].vreg;
+            self.note_ref(vreg);
+            if let Some(preg) = self.func.is_pinned_vreg(vreg) {
+                self.map(vreg, preg, false);
+            }
+        }
+        self.liveins[block.index()] = liveins;
+        for &param in self.func.block_params(block) {
+            self.note_ref(param);
+            if let Some(preg) = self.func.is_pinned_vreg(param) {
+                self.map(param, preg, false);
+            }
+        }
+
+        // Blockparam moves go at the start of the successor if it has
+        // only one predecessor, and otherwise at the end of the
+        // predecessor (which then has only one successor).
+        let insns = self.func.block_insns(block);
+        let preds = self.func.block_preds(block);
+        if preds.len() == 1 && block != self.func.entry_block() {
+            self.edge_moves(preds[0], block, ProgPoint::before(insns.first()))?;
+        }
+
+        for inst in insns.iter() {
+            if inst == insns.last() {
+                self.alloc_block_end(block, inst)?;
+            } else {
+                self.alloc_inst(inst)?;
+                if self.spill_everything {
+                    self.spill_all(inst);
+                }
+            }
+        }
+
+        for preg in self.occupied {
+            let vreg = self.pregs[preg.index()];
+            self.unmap(vreg);
+        }
+        Ok(())
+    }
+
+    fn alloc_block_end(&mut self, block: Block, inst: Inst) -> Result<(), RegAllocError> {
+        let succs = self.func.block_succs(block);
+        if succs.is_empty() {
+            return self.alloc_inst(inst);
+        }
+
+        // Write back the values that the successors need.
+        let pos = ProgPoint::before(inst);
+        let is_branch = self.func.is_branch(inst);
+        for preg in self.occupied {
+            let vreg = self.pregs[preg.index()];
+            let is_arg = is_branch
+                && (0..succs.len())
+                    .any(|i| self.func.branch_blockparams(block, inst, i).contains(&vreg));
+            let data = &self.vregs[vreg.vreg()];
+            let live_out = data.block_stamp == self.block_stamp && data.last_use == u32::MAX;
+            if live_out || is_arg {
+                self.write_back(vreg, pos);
+            }
+        }
+
+        if succs.len() == 1 && self.func.block_preds(succs[0]).len() > 1 {
+            // The branch has no operands here (see `CFGInfo::init`),
+            // so nothing needs the registers from now on.
+            for preg in self.occupied {
+                let vreg = self.pregs[preg.index()];
+                if self.func.is_pinned_vreg(vreg).is_none() {
+                    self.unmap(vreg);
+                }
+            }
+            self.edge_moves(block, succs[0], pos)?;
+        }
+        self.alloc_inst(inst)
+    }
+
+    /// Move the blockparams of the edge from `pred` to `succ` between
+    /// homes, at `pos`.
+    fn edge_moves(
+        &mut self,
+        pred: Block,
+        succ: Block,
+        pos: ProgPoint,
+    ) -> Result<(), RegAllocError> {
+        let func = self.func;
+        let last = func.block_insns(pred).last();
+        if !func.is_branch(last) {
+            return Ok(());
+        }
+        let first_edit = self.output.edits.len();
+        let succ_idx = func
+            .block_succs(pred)
+            .iter()
+            .position(|&b| b == succ)
+            .unwrap();
+        let args = func.branch_blockparams(pred, last, succ_idx);
+        let params = func.block_params(succ);
+        for &class in &RegClass::ALL {
+            let mut moves: SmallVec<[(Allocation, Allocation); 8]> = smallvec![];
+            for (&arg, &param) in args.iter().zip(params) {
+                if arg.class() == class {
+                    let from = self.home(arg);
+                    let to = self.home(param);
+                    moves.push((from, to));
+                }
+            }
+            if moves.is_empty() {
+                continue;
+            }
+
+            let mut parallel_moves = ParallelMoves::new();
+            for &(from, to) in &moves {
+                parallel_moves.add(from, to, ());
+            }
+            let resolved = parallel_moves.resolve();
+            let mut stackslot_idx = 0;
+            let resolved = {
+                let this = &*self;
+                let mut scratch_iter = RegTraversalIter::new(
+                    this.env,
+                    class,
+                    PReg::invalid(),
+                    PReg::invalid(),
+                    0,
+                    None,
+                    None,
+                );
+                // Only pinned vregs are in registers here.
+                let get_reg = || {
+                    for preg in scratch_iter.by_ref() {
+                        if this.overlaps(&this.occupied, preg)
+                            || moves.iter().any(|&(from, to)| {
+                                this.allocs_overlap(from, preg) || this.allocs_overlap(to, preg)
+                            })
+                        {
+                            continue;
+                        }
+                        return Some(Allocation::reg(preg));
+                    }
+                    None
+                };
+                // Placeholders, as in ion, until we can borrow `self`
+                // mutably to allocate the slots.
+                let get_stackslot = || {
+                    let idx = stackslot_idx;
+                    stackslot_idx += 1;
+                    Allocation::stack(SpillSlot::new(SpillSlot::MAX - idx, class))
+                };
+                MoveAndScratchResolver::new(
+                    get_reg,
+                    get_stackslot,
+                    |alloc| this.in_memory(alloc),
+                    this.victims[class as usize],
+                )
+                .compute(resolved)
+            };
+
+            for i in self.extra_spillslots[class as usize].len()..stackslot_idx {
+                let slot = self.allocate_spillslot(class);
+                debug_assert_eq!(self.extra_spillslots[class as usize].len(), i);
+                self.extra_spillslots[class as usize].push(slot);
+            }
+            let rewrite = |extra: &[Allocation], alloc: Allocation| match alloc.as_stack() {
+                Some(slot) if slot.index() > SpillSlot::MAX - stackslot_idx => {
+                    extra[SpillSlot::MAX - slot.index()]
+                }
+                _ => alloc,
+            };
+            for (from, to, ()) in resolved {
+                let from = rewrite(&self.extra_spillslots[class as usize], from);
+                let to = rewrite(&self.extra_spillslots[class as usize], to);
+                self.add_move(pos, from, to);
+            }
+        }
+        for _ in first_edit..self.output.edits.len() {
+            self.output.quality.add_edge_move(&self.cfginfo, pos);
+        }
+        Ok(())
+    }
+
+    fn alloc_inst(&mut self, inst: Inst) -> Result<(), RegAllocError> {
+        if self.func.requires_refs_on_stack(inst) {
+            self.spill_refs(inst);
+        }
+        if let Some((src, dst)) = self.func.is_move(inst) {
+            return self.alloc_move(inst, src.vreg(), dst.vreg());
+        }
+
+        self.clobbers = self.func.inst_clobbers(inst);
+        self.early_regs = PRegSet::empty();
+        self.late_regs = PRegSet::empty();
+        self.read_regs.clear();
+
+        // Allocate the most constrained operands first, and uses
+        // before defs of the same constraint.
+        let operands = self.func.inst_operands(inst);
+        let mut order: SmallVec<[(u8, usize); 8]> = smallvec![];
+        for (i, &op) in operands.iter().enumerate() {
+            let rank = match self.constraint(op) {
+                OperandConstraint::FixedReg(_) | OperandConstraint::FixedStackLoc(_) => 0,
+                OperandConstraint::RegSubset(_) => 1,
+                OperandConstraint::Reg | OperandConstraint::Reuse(_) => 2,
+                OperandConstraint::Any | OperandConstraint::Stack => 3,
+            };
+            order.push((2 * rank + (op.kind() == OperandKind::Def) as u8, i));
+        }
+        order.sort_unstable();
+
+        for (_, i) in order {
+            let op = operands[i];
+            if op.kind() == OperandKind::Def {
+                self.alloc_def(inst, i, op)?;
+            } else {
+                let reused = operands
+                    .iter()
+                    .any(|def| def.constraint() == OperandConstraint::Reuse(i));
+                self.alloc_use(inst, i, op, reused)?;
+            }
+        }
+
+        self.finish_inst(inst)
+    }
+
+    /// Write back the values in registers after `inst` and forget
+    /// them, except for pinned vregs.
+    fn spill_all(&mut self, inst: Inst) {
+        let pos = ProgPoint::after(inst);
+        for preg in self.occupied {
+            let vreg = self.pregs[preg.index()];
+            if self.func.is_pinned_vreg(vreg).is_some() {
+                continue;
+            }
+            if self.live_after(vreg, inst) {
+                self.write_back(vreg, pos);
+            }
+            self.unmap(vreg);
+        }
+    }
+
+    /// At a safepoint, move the reference-typed values that are live
+    /// across it to their homes, and list them there.
+    fn spill_refs(&mut self, inst: Inst) {
+        let pos = ProgPoint::before(inst);
+        for i in 0..self.block_refs.len() {
+            let vreg = self.block_refs[i];
+            let defined_here = self
+                .func
+                .inst_operands(inst)
+                .iter()
+                .any(|op| op.vreg() == vreg && op.kind() != OperandKind::Use);
+            if !self.live_after(vreg, inst)
+                || defined_here
+                || self.func.is_pinned_vreg(vreg).is_some()
+            {
+                continue;
+            }
+            self.write_back(vreg, pos);
+            // The collector may move the object, so the register
+            // copy is stale after the safepoint.
+            self.unmap(vreg);
+            let home = self.home(vreg);
+            self.output.safepoint_slots.push((pos, home));
+        }
+    }
+
+    fn alloc_move(&mut self, inst: Inst, src: VReg, dst: VReg) -> Result<(), RegAllocError> {
+        let pos = ProgPoint::before(inst);
+        if src == dst {
+            self.output.quality.coalesced_moves += 1;
+            return Ok(());
+        }
+        let src_reg = self.vregs[src.vreg()].reg;
+        let from = if src_reg != PReg::invalid() {
+            Allocation::reg(src_reg)
+        } else {
+            self.home(src)
+        };
+
+        if let Some(preg) = self.func.is_pinned_vreg(dst) {
+            self.evict(preg, inst, pos);
+            self.add_move(pos, from, Allocation::reg(preg));
+            self.map(dst, preg, false);
+        } else {
+            self.unmap(dst);
+            if !self.live_after(dst, inst) {
+                // Nothing reads the destination.
+                self.output.quality.coalesced_moves += 1;
+            } else if src_reg != PReg::invalid()
+                && !self.live_after(src, inst)
+                && self.func.is_pinned_vreg(src).is_none()
+            {
+                // The source dies here, so its register can simply
+                // take the destination's value.
+                self.output.quality.coalesced_moves += 1;
+                self.unmap(src);
+                self.map(dst, src_reg, true);
+            } else {
+                let mut avoid = PRegSet::empty();
+                if let Some(reg) = from.as_reg() {
+                    avoid.add(reg);
+                }
+                let preg = self.take_reg(dst.class(), None, avoid, inst, pos)?;
+                self.add_move(pos, from, Allocation::reg(preg));
+                self.map(dst, preg, true);
+            }
+        }
+
+        if !self.live_after(src, inst) && self.func.is_pinned_vreg(src).is_none() {
+            self.unmap(src);
+        }
+        self.note_ref(dst);
+        Ok(())
+    }
+
+    fn alloc_use(
+        &mut self,
+        inst: Inst,
+        slot: usize,
+        op: Operand,
+        reused: bool,
+    ) -> Result<(), RegAllocError> {
+        let vreg = op.vreg();
+        let pos = ProgPoint::before(inst);
+        // An operand that the instruction writes must be in a register
+        // of its own until the late point.
+        let written = reused || op.kind() == OperandKind::Mod;
+        let late = written || op.pos() == OperandPos::Late;
+        let env = self.env;
+        let alloc = match self.constraint(op) {
+            OperandConstraint::FixedReg(preg) if self.fixed_stack_slots.contains(preg) => {
+                self.use_in_memory(vreg, Allocation::reg(preg), inst)?
+            }
+            OperandConstraint::FixedStackLoc(loc) => {
+                self.use_in_memory(vreg, Allocation::stack_loc(loc), inst)?
+            }
+            OperandConstraint::FixedReg(preg) => {
+                self.use_in_reg(vreg, Some(preg), None, late, written, inst)?
+            }
+            OperandConstraint::RegSubset(subset) => {
+                let subset = &env.reg_subsets[subset];
+                self.use_in_reg(vreg, None, Some(subset), late, written, inst)?
+            }
+            OperandConstraint::Reg | OperandConstraint::Reuse(_) => {
+                self.use_in_reg(vreg, None, None, late, written, inst)?
+            }
+            OperandConstraint::Any => match self.usable_reg(vreg, None, None, late) {
+                Some(preg) => Allocation::reg(preg),
+                None => {
+                    self.write_back(vreg, pos);
+                    self.home(vreg)
+                }
+            },
+            OperandConstraint::Stack => {
+                self.write_back(vreg, pos);
+                self.home(vreg)
+            }
+        };
+        self.set_alloc(inst, slot, alloc);
+
+        if let Some(preg) = alloc.as_reg() {
+            if self.fixed_stack_slots.contains(preg) {
+                return Ok(());
+            }
+            self.early_regs.add(preg);
+            if late {
+                self.late_regs.add(preg);
+            }
+            if !written {
+                self.read_regs.push((preg, vreg));
+                return Ok(());
+            }
+            // The instruction overwrites the registers overlapping
+            // this one, and for a reused input, the value itself.
+            for i in 0..self.aliases(preg).len() {
+                let alias = self.aliases(preg)[i];
+                self.evict_one(alias, inst, pos);
+            }
+            if reused && self.vregs[vreg.vreg()].reg == preg {
+                self.evict_one(preg, inst, pos);
+            }
+        }
+        Ok(())
+    }
+
+    /// The register holding `vreg`, if a use with the given
+    /// constraints may read it there.
+    fn usable_reg(
+        &self,
+        vreg: VReg,
+        fixed: Option<PReg>,
+        subset: Option<&PRegSet>,
+        late: bool,
+    ) -> Option<PReg> {
+        let preg = self.vregs[vreg.vreg()].reg;
+        if preg == PReg::invalid() {
+            return None;
+        }
+        if let Some(fixed) = fixed {
+            if fixed != preg {
+                return None;
+            }
+        }
+        if let Some(subset) = subset {
+            if !subset.contains(preg) {
+                return None;
+            }
+        }
+        let shared = !late && self.read_regs.contains(&(preg, vreg));
+        if self.overlaps(&self.early_regs, preg) && !shared {
+            return None;
+        }
+        if late && (self.overlaps(&self.late_regs, preg) || self.overlaps(&self.clobbers, preg)) {
+            return None;
+        }
+        Some(preg)
+    }
+
+    fn use_in_reg(
+        &mut self,
+        vreg: VReg,
+        fixed: Option<PReg>,
+        subset: Option<&PRegSet>,
+        late: bool,
+        written: bool,
+        inst: Inst,
+    ) -> Result<Allocation, RegAllocError> {
+        let pos = ProgPoint::before(inst);
+        if let Some(preg) = self.usable_reg(vreg, fixed, subset, late) {
+            return Ok(Allocation::reg(preg));
+        }
+
+        let preg = match fixed {
+            Some(preg) => {
+                // Another read of the value may have put it there.
+                if !late && self.read_regs.contains(&(preg, vreg)) {
+                    return Ok(Allocation::reg(preg));
+                }
+                let mut taken = self.early_regs;
+                if late {
+                    taken.union_from(self.late_regs);
+                }
+                if self.overlaps(&taken, preg) {
+                    return Err(RegAllocError::TooManyLiveRegs);
+                }
+                self.evict(preg, inst, pos);
+                preg
+            }
+            None => {
+                let mut avoid = self.early_regs;
+                if late {
+                    avoid.union_from(self.late_regs);
+                    avoid.union_from(self.clobbers);
+                }
+                self.take_reg(vreg.class(), subset, avoid, inst, pos)?
+            }
+        };
+
+        // Copy the value from its register if it is in an unsuitable
+        // one, leaving it there; otherwise load it, and keep it in
+        // the new register unless the instruction overwrites it.
+        let cached = self.vregs[vreg.vreg()].reg;
+        if cached != PReg::invalid() {
+            self.add_move(pos, Allocation::reg(cached), Allocation::reg(preg));
+        } else {
+            let home = self.home(vreg);
+            self.add_move(pos, home, Allocation::reg(preg));
+            if !written {
+                self.map(vreg, preg, false);
+            }
+        }
+        Ok(Allocation::reg(preg))
+    }
+
+    /// Copy `vreg` to the memory location `to` for a use there.
+    fn use_in_memory(
+        &mut self,
+        vreg: VReg,
+        to: Allocation,
+        inst: Inst,
+    ) -> Result<Allocation, RegAllocError> {
+        let pos = ProgPoint::before(inst);
+        let mut preg = self.vregs[vreg.vreg()].reg;
+        if preg == PReg::invalid() {
+            // There are no memory-to-memory moves, so load the value
+            // into a register first.
+            let mut avoid = self.early_regs;
+            avoid.union_from(self.late_regs);
+            preg = self.take_reg(vreg.class(), None, avoid, inst, pos)?;
+            let home = self.home(vreg);
+            self.add_move(pos, home, Allocation::reg(preg));
+            self.map(vreg, preg, false);
+        }
+        self.add_move(pos, Allocation::reg(preg), to);
+        Ok(to)
+    }
+
+    fn alloc_def(&mut self, inst: Inst, slot: usize, op: Operand) -> Result<(), RegAllocError> {
+        let vreg = op.vreg();
+        let pos = ProgPoint::before(inst);
+        let early = op.pos() == OperandPos::Early;
+
+        // All defs of a vreg by one instruction share an allocation.
+        let operands = self.func.inst_operands(inst);
+        for (i, other) in operands.iter().enumerate() {
+            let alloc = self.alloc_of(inst, i);
+            if other.vreg() == vreg && other.kind() == OperandKind::Def && alloc.is_some() {
+                self.set_alloc(inst, slot, alloc);
+                return Ok(());
+            }
+        }
+
+        let mut taken = self.late_regs;
+        if early {
+            taken.union_from(self.early_regs);
+        }
+        let mut avoid = taken;
+        avoid.union_from(self.clobbers);
+        let env = self.env;
+        let class = vreg.class();
+        let preg = match self.constraint(op) {
+            OperandConstraint::FixedReg(preg) if self.fixed_stack_slots.contains(preg) => {
+                self.set_alloc(inst, slot, Allocation::reg(preg));
+                return Ok(());
+            }
+            OperandConstraint::FixedReg(preg) => {
+                if self.overlaps(&taken, preg) {
+                    return Err(RegAllocError::TooManyLiveRegs);
+                }
+                self.evict(preg, inst, pos);
+                preg
+            }
+            OperandConstraint::Reg => self.take_reg(class, None, avoid, inst, pos)?,
+            OperandConstraint::RegSubset(subset) => {
+                let subset = &env.reg_subsets[subset];
+                self.take_reg(class, Some(subset), avoid, inst, pos)?
+            }
+            OperandConstraint::Reuse(input) => {
+                let alloc = self.alloc_of(inst, input);
+                self.set_alloc(inst, slot, alloc);
+                return Ok(());
+            }
+            // Take a register only if it is free for the asking, and
+            // not if it would be written back right away.
+            OperandConstraint::Any if self.spill_everything => {
+                let home = self.home(vreg);
+                self.set_alloc(inst, slot, home);
+                return Ok(());
+            }
+            OperandConstraint::Any => match self.find_reg(class, None, avoid, 1, inst, pos) {
+                Some(preg) => preg,
+                None => {
+                    let home = self.home(vreg);
+                    self.set_alloc(inst, slot, home);
+                    return Ok(());
+                }
+            },
+            OperandConstraint::Stack => {
+                let home = self.home(vreg);
+                self.set_alloc(inst, slot, home);
+                return Ok(());
+            }
+            OperandConstraint::FixedStackLoc(loc) => {
+                self.set_alloc(inst, slot, Allocation::stack_loc(loc));
+                return Ok(());
+            }
+        };
+        self.late_regs.add(preg);
+        if early {
+            self.early_regs.add(preg);
+        }
+        self.set_alloc(inst, slot, Allocation::reg(preg));
+        Ok(())
+    }
+
+    /// Update the register state for the effects of `inst`.
+    fn finish_inst(&mut self, inst: Inst) -> Result<(), RegAllocError> {
+        let before = ProgPoint::before(inst);
+        let after = ProgPoint::after(inst);
+        let operands = self.func.inst_operands(inst);
+        let defines = |vreg: VReg| {
+            operands
+                .iter()
+                .any(|op| op.vreg() == vreg && op.kind() != OperandKind::Use)
+        };
+
+        // Clobbers destroy the values in their registers.
+        if !self.clobbers.is_empty() {
+            for preg in self.occupied {
+                if !self.overlaps(&self.clobbers, preg) {
+                    continue;
+                }
+                let vreg = self.pregs[preg.index()];
+                if self.live_after(vreg, inst) && !defines(vreg) {
+                    self.write_back(vreg, before);
+                }
+                self.unmap(vreg);
+            }
+        }
+
+        for op in operands {
+            if !self.live_after(op.vreg(), inst) {
+                self.unmap(op.vreg());
+            }
+        }
+
+        let mut memory_defs: SmallVec<[(VReg, Allocation); 2]> = smallvec![];
+        for (i, op) in operands.iter().enumerate() {
+            if op.kind() == OperandKind::Use {
+                continue;
+            }
+            let vreg = op.vreg();
+            let alloc = self.alloc_of(inst, i);
+            // Whatever register held the old value is stale now.
+            self.unmap(vreg);
+            self.note_ref(vreg);
+            let pinned = self.func.is_pinned_vreg(vreg).is_some();
+            if alloc.is_stack() {
+                // The def went straight to the home.
+                continue;
+            }
+            if self.in_memory(alloc) {
+                if self.live_after(vreg, inst) {
+                    memory_defs.push((vreg, alloc));
+                }
+                continue;
+            }
+            let preg = alloc.as_reg().unwrap();
+            self.evict_one(preg, inst, after);
+            for j in 0..self.aliases(preg).len() {
+                let alias = self.aliases(preg)[j];
+                self.evict_one(alias, inst, after);
+            }
+            if self.live_after(vreg, inst) || pinned {
+                self.map(vreg, preg, !pinned);
+            }
+        }
+
+        // Values defined in fixed memory locations go to a register,
+        // from where they are written back to their homes if needed.
+        for (vreg, from) in memory_defs {
+            let preg = self.take_reg(vreg.class(), None, PRegSet::empty(), inst, after)?;
+            self.add_move(after, from, Allocation::reg(preg));
+            self.map(vreg, preg, true);
+        }
+        Ok(())
+    }
+}
+
+/// Allocate `func` with the fast allocator, leaving the results in
+/// `output`. With `spill_everything`, keep no value in a register
+/// between instructions.
+pub fn run<F: Function>(
+    func: &F,
+    mach_env: &MachineEnv,
+    spill_everything: bool,
+    ctx: &mut Ctx,
+    output: &mut Output,
+) -> Result<(), RegAllocError> {
+    let mut env = Env::new(func, mach_env, spill_everything, ctx, output);
+    let result = env.run();
+    env.finish(ctx);
+    if result.is_ok() {
+        output.compute_def_regs(func, mach_env);
+        output.compute_quality(mach_env, &ctx.cfginfo);
+    }
+    result
+}
@@ -5,7 +5,7 @@
 
 use crate::{
     domtree, postorder, Allocation, Block, Function, Inst, InstRange, MachineEnv, Operand,
-    OperandConstraint, OperandKind, OperandPos, PReg, PRegSet, RegClass, VReg,
+    OperandConstraint, OperandKind, OperandPos, PReg, PRegSet, RegClass, StackLoc, VReg,
 };
 
 use arbitrary::Result as ArbitraryResult;
@@ -67,6 +67,7 @@ pub struct Func {
     block_params_out: Vec<Vec<Vec<VReg>>>,
     num_vregs: usize,
     reftype_vregs: Vec<VReg>,
+    remat_costs: Vec<(VReg, u32)>,
     debug_value_labels: Vec<(VReg, Inst, Inst, u32)>,
 }
 
@@ -124,6 +125,13 @@ impl Function for Func {
         &self.debug_value_labels[..]
     }
 
+    fn remat_cost(&self, vreg: VReg) -> Option<u32> {
+        self.remat_costs
+            .iter()
+            .find(|&&(v, _)| v == vreg)
+            .map(|&(_, cost)| cost)
+    }
+
     fn is_move(&self, _: Inst) -> Option<(Operand, Operand)> {
         None
     }
@@ -148,6 +156,8 @@ impl Function for Func {
         match regclass {
             RegClass::Int => 1,
             RegClass::Float => 2,
+            RegClass::Vector => 4,
+            RegClass::Predicate => 1,
         }
     }
 }
@@ -173,6 +183,7 @@ impl FuncBuilder {
                 blocks: vec![],
                 num_vregs: 0,
                 reftype_vregs: vec![],
+                remat_costs: vec![],
                 debug_value_labels: vec![],
             },
             insts_per_block: vec![],
@@ -276,6 +287,10 @@ pub struct Options {
     pub block_params: bool,
     pub always_local_uses: bool,
     pub reftypes: bool,
+    pub multiple_classes: bool,
+    pub remat: bool,
+    pub fixed_stack_locs: bool,
+    pub reg_subsets: bool,
 }
 
 impl std::default::Default for Options {
@@ -289,6 +304,10 @@ impl std::default::Default for Options {
             block_params: true,
             always_local_uses: false,
             reftypes: false,
+            multiple_classes: false,
+            remat: false,
+            fixed_stack_locs: false,
+            reg_subsets: false,
         }
     }
 }
@@ -310,6 +329,7 @@ impl Func {
         //      already defined (via blockparam or inst) in this block,
         //      or one defined in a dominating block.
 
+        let aliases = aliases();
         let mut builder = FuncBuilder::new();
         for _ in 0..u.int_in_range(1..=100)? {
             builder.add_block();
@@ -379,12 +399,21 @@ impl Func {
         for block in 0..num_blocks {
             let mut vregs = vec![];
             for _ in 0..u.int_in_range(5..=15)? {
-                let vreg = VReg::new(builder.f.num_vregs, RegClass::Int);
+                let class = if opts.multiple_classes {
+                    *u.choose(&RegClass::ALL[..])?
+                } else {
+                    RegClass::Int
+                };
+                let vreg = VReg::new(builder.f.num_vregs, class);
                 builder.f.num_vregs += 1;
                 vregs.push(vreg);
-                if opts.reftypes && bool::arbitrary(u)? {
+                if opts.reftypes && class == RegClass::Int && bool::arbitrary(u)? {
                     builder.f.reftype_vregs.push(vreg);
                 }
+                if opts.remat && bool::arbitrary(u)? {
+                    let cost = u.int_in_range(0..=150)?;
+                    builder.f.remat_costs.push((vreg, cost));
+                }
                 if bool::arbitrary(u)? {
                     let assumed_end_inst = 10 * num_blocks;
                     let mut start = u.int_in_range::<usize>(0..=assumed_end_inst)?;
@@ -467,25 +496,34 @@ impl Func {
                     allocations.push(Allocation::none());
                 }
                 let mut clobbers: Vec<PReg> = vec![];
-                if operands.len() > 1 && opts.reused_inputs && bool::arbitrary(u)? {
+                let reusable = operands
+                    .iter()
+                    .skip(1)
+                    .any(|op| op.class() == operands[0].class());
+                if reusable && opts.reused_inputs && bool::arbitrary(u)? {
                     // Make the def a reused input.
                     let op = operands[0];
                     debug_assert_eq!(op.kind(), OperandKind::Def);
-                    let reused = u.int_in_range(1..=(operands.len() - 1))?;
+                    let candidates: Vec<usize> = (1..operands.len())
+                        .filter(|&i| operands[i].class() == op.class())
+                        .collect();
+                    let reused = *u.choose(&candidates[..])?;
                     operands[0] = Operand::new(
                         op.vreg(),
                         OperandConstraint::Reuse(reused),
                         op.kind(),
                         OperandPos::Late,
                     );
-                    // Make sure reused input is a Reg.
+                    // Make sure reused input is a Reg, or a register
+                    // subset that then applies to the def as well.
                     let op = operands[reused];
-                    operands[reused] = Operand::new(
-                        op.vreg(),
-                        OperandConstraint::Reg,
-                        op.kind(),
-                        OperandPos::Early,
-                    );
+                    let constraint = if opts.reg_subsets && bool::arbitrary(u)? {
+                        OperandConstraint::RegSubset(u.choose(&reg_subsets_of(op.class()))?.0)
+                    } else {
+                        OperandConstraint::Reg
+                    };
+                    operands[reused] =
+                        Operand::new(op.vreg(), constraint, op.kind(), OperandPos::Early);
                 } else if opts.fixed_regs && bool::arbitrary(u)? {
                     let mut fixed_early = vec![];
                     let mut fixed_late = vec![];
@@ -493,12 +531,25 @@ impl Func {
                         // Pick an operand and make it a fixed reg.
                         let i = u.int_in_range(0..=(operands.len() - 1))?;
                         let op = operands[i];
-                        let fixed_reg = PReg::new(u.int_in_range(0..=62)?, RegClass::Int);
+                        let max_fixed = match op.class() {
+                            RegClass::Int => 62,
+                            _ => 31,
+                        };
+                        let hw_enc = match u.int_in_range(0..=max_fixed)? {
+                            // Int registers past the allocatable
+                            // ones name fixed stack slots.
+                            i if i >= 32 => FIXED_STACK_SLOTS.start + i - 32,
+                            i => i,
+                        };
+                        let fixed_reg = PReg::new(hw_enc, op.class());
                         let fixed_list = match op.pos() {
                             OperandPos::Early => &mut fixed_early,
                             OperandPos::Late => &mut fixed_late,
                         };
-                        if fixed_list.contains(&fixed_reg) {
+                        if fixed_list
+                            .iter()
+                            .any(|&reg| reg == fixed_reg || regs_overlap(&aliases, reg, fixed_reg))
+                        {
                             break;
                         }
                         if op.kind() != OperandKind::Def && op.pos() == OperandPos::Late {
@@ -525,13 +576,58 @@ impl Func {
                             op.pos(),
                         );
                     }
+                } else if opts.fixed_stack_locs && bool::arbitrary(u)? {
+                    let mut used_locs = vec![];
+                    for _ in 0..u.int_in_range(0..=operands.len() - 1)? {
+                        // Pick a use or def and place it in a stack
+                        // location not otherwise used by this inst.
+                        let i = u.int_in_range(0..=(operands.len() - 1))?;
+                        let op = operands[i];
+                        if op.kind() == OperandKind::Mod {
+                            break;
+                        }
+                        let loc = StackLoc::new(u.int_in_range(0..=StackLoc::MAX)?, op.class());
+                        if used_locs.contains(&loc) {
+                            break;
+                        }
+                        used_locs.push(loc);
+                        operands[i] = Operand::new(
+                            op.vreg(),
+                            OperandConstraint::FixedStackLoc(loc),
+                            op.kind(),
+                            op.pos(),
+                        );
+                    }
+                } else if opts.reg_subsets && bool::arbitrary(u)? {
+                    for _ in 0..u.int_in_range(0..=operands.len() - 1)? {
+                        // Pick an operand whose vreg is not mentioned
+                        // again by this inst, so that its constraints
+                        // never conflict, and limit it to a subset.
+                        let i = u.int_in_range(0..=(operands.len() - 1))?;
+                        let op = operands[i];
+                        if operands.iter().filter(|o| o.vreg() == op.vreg()).count() > 1 {
+                            break;
+                        }
+                        let (subset, _) = *u.choose(&reg_subsets_of(op.class()))?;
+                        operands[i] = Operand::new(
+                            op.vreg(),
+                            OperandConstraint::RegSubset(subset),
+                            op.kind(),
+                            op.pos(),
+                        );
+                    }
                 } else if opts.clobbers && bool::arbitrary(u)? {
                     for _ in 0..u.int_in_range(0..=5)? {
-                        let reg = u.int_in_range(0..=30)?;
-                        if clobbers.iter().any(|r| r.hw_enc() == reg) {
+                        let class = if opts.multiple_classes {
+                            *u.choose(&RegClass::ALL[..])?
+                        } else {
+                            RegClass::Int
+                        };
+                        let reg = PReg::new(u.int_in_range(0..=30)?, class);
+                        if clobbers.contains(&reg) {
                             break;
                         }
-                        clobbers.push(PReg::new(reg, RegClass::Int));
+                        clobbers.push(reg);
                     }
                 }
 
@@ -557,21 +653,46 @@ impl Func {
             // the block.
             if builder.f.block_succs[block].len() > 0 {
                 let mut params = vec![];
-                for &succ in &builder.f.block_succs[block] {
+                for succ in builder.f.block_succs[block].clone() {
                     let mut args = vec![];
-                    for _ in 0..builder.f.block_params_in[succ.index()].len() {
+                    for i in 0..builder.f.block_params_in[succ.index()].len() {
+                        let class = builder.f.block_params_in[succ.index()][i].class();
                         let dom_block = choose_dominating_block(
                             &builder.idom[..],
                             Block::new(block),
                             false,
                             u,
                         )?;
-                        let vreg = if dom_block.is_valid() && bool::arbitrary(u)? {
-                            u.choose(&vregs_by_block[dom_block.index()][..])?
+                        let candidates: Vec<VReg> =
+                            if dom_block.is_valid() && bool::arbitrary(u)? {
+                                &vregs_by_block[dom_block.index()][..]
+                            } else {
+                                &avail[..]
+                            }
+                            .iter()
+                            .cloned()
+                            .filter(|vreg| vreg.class() == class)
+                            .collect();
+                        let vreg = if candidates.is_empty() {
+                            // No value of the right class is available:
+                            // define a fresh one just for this arg.
+                            let vreg = VReg::new(builder.f.num_vregs, class);
+                            builder.f.num_vregs += 1;
+                            builder.add_inst(
+                                Block::new(block),
+                                InstData {
+                                    op: InstOpcode::Op,
+                                    operands: vec![Operand::reg_def(vreg)],
+                                    clobbers: vec![],
+                                    is_safepoint: false,
+                                },
+                            );
+                            avail.push(vreg);
+                            vreg
                         } else {
-                            u.choose(&avail[..])?
+                            *u.choose(&candidates[..])?
                         };
-                        args.push(*vreg);
+                        args.push(vreg);
                     }
                     params.push(args);
                 }
@@ -594,6 +715,9 @@ impl std::fmt::Debug for Func {
         for vreg in self.reftype_vregs() {
             write!(f, "  REF: {}\n", vreg)?;
         }
+        for &(vreg, cost) in &self.remat_costs {
+            writeln!(f, "  REMAT: {} cost {}", vreg, cost)?;
+        }
         for (i, blockrange) in self.blocks.iter().enumerate() {
             let succs = self.block_succs[i]
                 .iter()
@@ -649,16 +773,63 @@ impl std::fmt::Debug for Func {
     }
 }
 
+/// The overlapping register pairs of `machine_env()`: each of the
+/// non-preferred float registers 24..32 overlaps a pair of the
+/// preferred float registers 0..16, like ARM32's `d0` and `s0`/`s1`.
+fn aliases() -> Vec<(PReg, PReg)> {
+    (0..8)
+        .flat_map(|i| {
+            let double = PReg::new(24 + i, RegClass::Float);
+            let lo = PReg::new(2 * i, RegClass::Float);
+            let hi = PReg::new(2 * i + 1, RegClass::Float);
+            vec![(double, lo), (double, hi)]
+        })
+        .collect()
+}
+
+/// Int register encodings used for fixed stack slots. These are placed
+/// above the first 64 registers to exercise the full `PReg` encoding.
+const FIXED_STACK_SLOTS: std::ops::Range<usize> = 192..224;
+
+/// The register subsets of `machine_env()`, with their indices. Each
+/// class has a small subset of preferred registers, another that
+/// partially overlaps it, and one that spans preferred and
+/// non-preferred registers (and, for floats, some aliased ones).
+fn reg_subsets_of(class: RegClass) -> Vec<(usize, PRegSet)> {
+    [0..4, 2..6, 20..28]
+        .iter()
+        .enumerate()
+        .map(|(i, range)| {
+            let set = range.clone().fold(PRegSet::empty(), |set, hw_enc| {
+                set.with(PReg::new(hw_enc, class))
+            });
+            (class as usize * 3 + i, set)
+        })
+        .collect()
+}
+
+/// Do `a` and `b` overlap, according to the pairs of `aliases()`?
+fn regs_overlap(aliases: &[(PReg, PReg)], a: PReg, b: PReg) -> bool {
+    aliases.iter().any(|&pair| pair == (a, b) || pair == (b, a))
+}
+
 pub fn machine_env() -> MachineEnv {
-    fn regs(r: std::ops::Range<usize>) -> Vec<PReg> {
-        r.map(|i| PReg::new(i, RegClass::Int)).collect()
+    fn regs(r: std::ops::Range<usize>, class: RegClass) -> Vec<PReg> {
+        r.map(|i| PReg::new(i, class)).collect()
     }
-    let preferred_regs_by_class: [Vec<PReg>; 2] = [regs(0..24), vec![]];
-    let non_preferred_regs_by_class: [Vec<PReg>; 2] = [regs(24..32), vec![]];
-    let fixed_stack_slots = regs(32..64);
+    let preferred_regs_by_class = RegClass::ALL.map(|class| regs(0..24, class));
+    let non_preferred_regs_by_class = RegClass::ALL.map(|class| regs(24..32, class));
+    let fixed_stack_slots = regs(FIXED_STACK_SLOTS, RegClass::Int);
     MachineEnv {
         preferred_regs_by_class,
         non_preferred_regs_by_class,
         fixed_stack_slots,
+        aliases: aliases(),
+        reg_subsets: RegClass::ALL
+            .iter()
+            .flat_map(|&class| reg_subsets_of(class))
+            .map(|(_, set)| set)
+            .collect(),
+        stackmap_regs: PRegSet::empty(),
     }
 }
@@ -6,6 +6,8 @@
 //! Utilities for fuzzing.
 
 pub mod func;
+pub mod optimal;
+pub mod reduce;
 
 // Re-exports for fuzz targets.
 
@@ -24,6 +26,9 @@ pub mod cfg {
 pub mod ssa {
     pub use crate::ssa::*;
 }
+pub mod fastalloc {
+    pub use crate::fastalloc::*;
+}
 pub mod ion {
     pub use crate::ion::*;
 }
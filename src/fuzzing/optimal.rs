@@ -0,0 +1,1713 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Exhaustive reference allocator for tiny functions.
+//!
+//! This allocator finds an allocation of minimal cost, where the cost
+//! of an allocation is the number of moves, spills and reloads that it
+//! inserts, each weighted by the loop depth of its block (see `cost`).
+//! It is far too slow for real use, but measures how far the
+//! heuristics of the real allocators are from optimal.
+//!
+//! The search space is the following. At every program point, each
+//! live value has a location: a register, its spillslot, or both.
+//! Each value has a spillslot of its own, except that, as in ion, a
+//! block parameter shares one with its arguments when their lifetimes
+//! allow. Before every instruction, and on every CFG edge, a parallel
+//! move may change any of these locations; each instruction then
+//! reads its uses from their locations, or from copies made just
+//! before it (which may also keep a used value in a register that the
+//! instruction does not write), and writes its defs to registers or
+//! spillslots that satisfy their constraints. Register classes never interact, so
+//! each is solved on its own, by dynamic programming over the
+//! locations at each program point. The locations on entry to blocks
+//! with several predecessors are enumerated; all other blocks form
+//! trees, which the dynamic program handles exactly.
+//!
+//! The result is optimal within this model only. In particular, ion
+//! packs values with disjoint lifetimes into shared spillslots more
+//! freely, which occasionally saves it a move on an edge, so its
+//! allocation can be slightly cheaper than the "optimal" one.
+//!
+//! The model does not cover every feature of the input: `run` returns
+//! `Error::Unsupported` for program moves, pinned vregs, `Mod`
+//! operands, fixed stack locations, and a few other cases, and
+//! `Error::TooLarge` when the search exceeds its `Limits`.
+
+use crate::cfg::CFGInfo;
+use crate::moves::{MoveAndScratchResolver, ParallelMoves};
+use crate::{
+    Allocation, Block, Edit, Function, Inst, MachineEnv, Operand, OperandConstraint, OperandKind,
+    OperandPos, Output, PReg, ProgPoint, RegAllocError, RegClass, SpillSlot, VReg,
+};
+use fxhash::FxHashMap;
+use smallvec::{smallvec, SmallVec};
+use std::collections::BTreeSet;
+
+/// The cost of a register-to-register move.
+pub const MOVE_COST: u64 = 1;
+/// The cost of storing a register to a spillslot.
+pub const SPILL_COST: u64 = 2;
+/// The cost of loading a register from a spillslot, or of
+/// rematerializing it.
+pub const RELOAD_COST: u64 = 2;
+/// The factor by which each level of loop nesting multiplies costs.
+pub const LOOP_WEIGHT: u64 = 8;
+
+const INFINITE: u64 = u64::MAX / 4;
+const NO_REG: u8 = u8::MAX;
+
+/// Bounds on the size of the search.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// The largest number of instructions to accept.
+    pub max_insts: usize,
+    /// The largest number of location combinations at one program
+    /// point, in one class.
+    pub max_states: usize,
+    /// The largest number of transitions between combinations to
+    /// evaluate, in one class.
+    pub max_work: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_insts: 32,
+            max_states: 4096,
+            max_work: 50_000_000,
+        }
+    }
+}
+
+/// Why `run` found no allocation.
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// The function is invalid.
+    Alloc(RegAllocError),
+    /// The function uses a feature that the model does not cover.
+    Unsupported(&'static str),
+    /// The search would exceed the limits.
+    TooLarge,
+    /// No allocation exists within the model, for example because
+    /// there are too few registers.
+    NoSolution,
+}
+
+impl From<RegAllocError> for Error {
+    fn from(e: RegAllocError) -> Self {
+        Error::Alloc(e)
+    }
+}
+
+/// An optimal allocation.
+#[derive(Clone, Debug)]
+pub struct Solution {
+    pub output: Output,
+    /// The cost of the allocation in the model. `cost(func, &output)`
+    /// may be slightly higher, since it also counts the moves that
+    /// resolving cycles and stack-to-stack moves needs.
+    pub cost: u64,
+}
+
+fn block_weight(cfginfo: &CFGInfo, block: Block) -> u64 {
+    let depth = std::cmp::min(cfginfo.approx_loop_depth[block.index()], 4);
+    LOOP_WEIGHT.pow(depth)
+}
+
+fn move_cost(from: Allocation, to: Allocation) -> u64 {
+    match (from.is_reg(), to.is_reg()) {
+        (true, true) => MOVE_COST,
+        (true, false) => SPILL_COST,
+        (false, true) => RELOAD_COST,
+        (false, false) => RELOAD_COST + SPILL_COST,
+    }
+}
+
+/// The cost of the edits in `output`, an allocation of `func`, with
+/// the weights of this module.
+pub fn cost<F: Function>(func: &F, output: &Output) -> u64 {
+    let cfginfo = CFGInfo::new(func).expect("allocated function should be valid");
+    output
+        .edits
+        .iter()
+        .map(|&(pos, ref edit)| {
+            let block = cfginfo.insn_block[pos.inst().index()];
+            let cost = match *edit {
+                Edit::Move { from, to } => move_cost(from, to),
+                Edit::Remat { .. } => RELOAD_COST,
+            };
+            cost * block_weight(&cfginfo, block)
+        })
+        .sum()
+}
+
+/// Find an optimal allocation of `func`.
+pub fn run<F: Function>(func: &F, env: &MachineEnv, limits: &Limits) -> Result<Solution, Error> {
+    if func.num_insts() > limits.max_insts {
+        return Err(Error::TooLarge);
+    }
+    let cfginfo = CFGInfo::new(func)?;
+    check_supported(func, env, &cfginfo)?;
+    let liveness = Liveness::compute(func);
+    if !liveness.livein(func, func.entry_block()).is_empty() {
+        return Err(RegAllocError::EntryLivein.into());
+    }
+
+    let mut output = Output::default();
+    for inst in 0..func.num_insts() {
+        let n = func.inst_operands(Inst::new(inst)).len();
+        output.inst_alloc_offsets.push(output.allocs.len() as u32);
+        output
+            .allocs
+            .resize(output.allocs.len() + n, Allocation::none());
+    }
+    let mut homes = Homes {
+        slots: FxHashMap::default(),
+        num_spillslots: 0,
+    };
+    let mut total = 0;
+    for &class in &RegClass::ALL {
+        let mut solver = ClassSolver::new(func, env, &cfginfo, &liveness, class, limits)?;
+        total += solver.solve(limits)?;
+        solver.emit(&mut homes, &mut output);
+    }
+
+    output.edits.sort_by_key(|&(pos, _)| pos);
+    output
+        .safepoint_slots
+        .sort_by_key(|&(pos, slot)| (pos, slot.bits()));
+    output.num_spillslots = homes.num_spillslots as usize;
+    output.compute_def_regs(func, env);
+    output.compute_quality(env, &cfginfo);
+    Ok(Solution {
+        output,
+        cost: total,
+    })
+}
+
+fn check_supported<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    cfginfo: &CFGInfo,
+) -> Result<(), Error> {
+    if cfginfo.postorder.len() != func.num_blocks() {
+        return Err(Error::Unsupported("unreachable blocks"));
+    }
+    if !env.stackmap_regs.is_empty() && !func.reftype_vregs().is_empty() {
+        return Err(Error::Unsupported("stackmap registers"));
+    }
+    if !func.block_preds(func.entry_block()).is_empty() {
+        return Err(Error::Unsupported("branches to the entry block"));
+    }
+    if !func.block_params(func.entry_block()).is_empty() {
+        return Err(Error::Unsupported("entry block parameters"));
+    }
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        let last = func.block_insns(block).last();
+        let succs = func.block_succs(block);
+        if succs.len() == 1
+            && func.block_preds(succs[0]).len() > 1
+            && (!func.inst_clobbers(last).is_empty() || func.requires_refs_on_stack(last))
+        {
+            return Err(Error::Unsupported(
+                "clobbers or safepoint on a branch to a join",
+            ));
+        }
+    }
+    for inst in 0..func.num_insts() {
+        let inst = Inst::new(inst);
+        if func.is_move(inst).is_some() {
+            return Err(Error::Unsupported("program moves"));
+        }
+        let operands = func.inst_operands(inst);
+        for (i, op) in operands.iter().enumerate() {
+            if func.is_pinned_vreg(op.vreg()).is_some() {
+                return Err(Error::Unsupported("pinned vregs"));
+            }
+            if op.kind() == OperandKind::Mod {
+                return Err(Error::Unsupported("mod operands"));
+            }
+            match op.constraint() {
+                OperandConstraint::FixedStackLoc(_) => {
+                    return Err(Error::Unsupported("fixed stack locations"));
+                }
+                OperandConstraint::FixedReg(preg) if env.fixed_stack_slots.contains(&preg) => {
+                    return Err(Error::Unsupported("fixed stack locations"));
+                }
+                _ => {}
+            }
+            if op.kind() == OperandKind::Def
+                && operands[..i]
+                    .iter()
+                    .any(|other| other.kind() == OperandKind::Def && other.vreg() == op.vreg())
+            {
+                return Err(Error::Unsupported(
+                    "multiple defs of a vreg by one instruction",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Which values are live where, for all classes.
+struct Liveness {
+    /// The values live into each instruction, sorted.
+    before: Vec<Vec<VReg>>,
+    /// The values live out of each instruction, sorted. For the last
+    /// instruction of a block, this includes the branch arguments.
+    after: Vec<Vec<VReg>>,
+    /// The value whose spillslot each value shares, if not its own.
+    slots: FxHashMap<VReg, VReg>,
+}
+
+impl Liveness {
+    fn compute<F: Function>(func: &F) -> Self {
+        let mut liveins = vec![BTreeSet::new(); func.num_blocks()];
+        let mut before = vec![vec![]; func.num_insts()];
+        let mut after = vec![vec![]; func.num_insts()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in (0..func.num_blocks()).rev() {
+                let block = Block::new(block);
+                let insns = func.block_insns(block);
+                let mut live = BTreeSet::new();
+                for (i, &succ) in func.block_succs(block).iter().enumerate() {
+                    live.extend(liveins[succ.index()].iter().copied());
+                    live.extend(
+                        func.branch_blockparams(block, insns.last(), i)
+                            .iter()
+                            .copied(),
+                    );
+                }
+                for inst in insns.rev().iter() {
+                    after[inst.index()] = live.iter().copied().collect();
+                    for op in func.inst_operands(inst) {
+                        if op.kind() == OperandKind::Def {
+                            live.remove(&op.vreg());
+                        }
+                    }
+                    for op in func.inst_operands(inst) {
+                        if op.kind() != OperandKind::Def {
+                            live.insert(op.vreg());
+                        }
+                    }
+                    before[inst.index()] = live.iter().copied().collect();
+                }
+                for param in func.block_params(block) {
+                    live.remove(param);
+                }
+                if live != liveins[block.index()] {
+                    liveins[block.index()] = live;
+                    changed = true;
+                }
+            }
+        }
+        let mut liveness = Liveness {
+            before,
+            after,
+            slots: FxHashMap::default(),
+        };
+        liveness.share_slots(func);
+        liveness
+    }
+
+    /// As ion does, let each block parameter share a spillslot with
+    /// its arguments where no two of the values sharing it are ever
+    /// live at once, so that edges need not copy between slots.
+    fn share_slots<F: Function>(&mut self, func: &F) {
+        // The operands live at the early and the late point of each
+        // instruction. A def is live from its point to the end of the
+        // instruction, and a reused input until the late point.
+        let mut operands: Vec<Vec<VReg>> = vec![];
+        for inst in 0..func.num_insts() {
+            let ops = func.inst_operands(Inst::new(inst));
+            let reused = |slot: usize| {
+                ops.iter()
+                    .any(|op| op.constraint() == OperandConstraint::Reuse(slot))
+            };
+            let at = |late: bool| {
+                ops.iter()
+                    .enumerate()
+                    .filter(|&(slot, op)| {
+                        let is_late = op.pos() == OperandPos::Late || reused(slot);
+                        if late {
+                            is_late || op.kind() == OperandKind::Def
+                        } else {
+                            !is_late
+                        }
+                    })
+                    .map(|(_, op)| op.vreg())
+                    .collect()
+            };
+            operands.push(at(false));
+            operands.push(at(true));
+        }
+        // Try the pairs in the order that ion merges them, since which
+        // merges succeed depends on it.
+        let mut pairs = vec![];
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            for &pred in func.block_preds(block) {
+                let last = func.block_insns(pred).last();
+                let succ = func
+                    .block_succs(pred)
+                    .iter()
+                    .position(|&succ| succ == block)
+                    .unwrap();
+                let args = func.branch_blockparams(pred, last, succ);
+                for (&arg, &param) in args.iter().zip(func.block_params(block)) {
+                    pairs.push((arg, pred, block, param));
+                }
+            }
+        }
+        pairs.sort_by_key(|&(arg, pred, block, param)| (arg.vreg(), pred, block, param.vreg()));
+        let mut members: FxHashMap<VReg, Vec<VReg>> = FxHashMap::default();
+        for (arg, _, _, param) in pairs {
+            let (a, b) = (self.slot(param), self.slot(arg));
+            if a == b {
+                continue;
+            }
+            let members_of = |vreg| members.get(&vreg).cloned().unwrap_or_else(|| vec![vreg]);
+            let (a_members, b_members) = (members_of(a), members_of(b));
+            let mut sets = self.before.iter().chain(&self.after).chain(&operands);
+            let interferes = sets.any(|live| {
+                a_members.iter().any(|vreg| live.contains(vreg))
+                    && b_members.iter().any(|vreg| live.contains(vreg))
+            });
+            if interferes {
+                continue;
+            }
+            for &vreg in &b_members {
+                self.slots.insert(vreg, a);
+            }
+            members.remove(&b);
+            members.insert(a, a_members.into_iter().chain(b_members).collect());
+        }
+    }
+
+    /// The value whose spillslot `vreg` uses.
+    fn slot(&self, vreg: VReg) -> VReg {
+        self.slots.get(&vreg).copied().unwrap_or(vreg)
+    }
+
+    fn livein<F: Function>(&self, func: &F, block: Block) -> Vec<VReg> {
+        let params = func.block_params(block);
+        self.before[func.block_insns(block).first().index()]
+            .iter()
+            .copied()
+            .filter(|vreg| !params.contains(vreg))
+            .collect()
+    }
+}
+
+/// The location of a value: a register (an index into
+/// `ClassSolver::regs`), its spillslot, or both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Loc {
+    reg: u8,
+    stack: bool,
+}
+
+/// Where an operand is allocated, in terms of the model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Place {
+    Reg(u8),
+    Stack,
+}
+
+/// All the location combinations of a set of values.
+struct Space {
+    vars: Vec<VReg>,
+    /// The combinations, `vars.len()` locations each.
+    states: Vec<Loc>,
+    index: FxHashMap<u64, usize>,
+}
+
+impl Space {
+    fn len(&self) -> usize {
+        if self.vars.is_empty() {
+            1
+        } else {
+            self.states.len() / self.vars.len()
+        }
+    }
+
+    fn state(&self, i: usize) -> &[Loc] {
+        let n = self.vars.len();
+        &self.states[i * n..(i + 1) * n]
+    }
+
+    fn var(&self, vreg: VReg) -> Option<usize> {
+        self.vars.binary_search(&vreg).ok()
+    }
+}
+
+/// The choices for one operand or value, in the order tried.
+type Options<T> = SmallVec<[T; 16]>;
+
+/// A place that a use may read, with the cost of copying it there.
+type PlaceCost = (Place, u64);
+
+/// Advance `choice` to the next combination of indices, each below
+/// `len` of its position, returning false after the last.
+fn next_combination(choice: &mut [usize], len: impl Fn(usize) -> usize) -> bool {
+    for (i, c) in choice.iter_mut().enumerate() {
+        *c += 1;
+        if *c < len(i) {
+            return true;
+        }
+        *c = 0;
+    }
+    false
+}
+
+fn encode(locs: &[Loc]) -> u64 {
+    locs.iter().fold(0, |code, loc| {
+        let reg = if loc.reg == NO_REG {
+            0
+        } else {
+            loc.reg as u64 + 1
+        };
+        (code << 9) | (reg << 1) | loc.stack as u64
+    })
+}
+
+/// A transition between the locations of consecutive layers.
+enum Link {
+    /// A parallel move before the instruction.
+    Move { pos: ProgPoint },
+    /// The instruction itself.
+    Inst { inst: Inst },
+}
+
+/// The program points of a block, as layers of location
+/// combinations, in order.
+struct BlockLayers {
+    /// Space indices; the first is the entry of the block, and the last
+    /// is where its edges start.
+    layers: Vec<usize>,
+    /// `links[i]` leads from `layers[i]` to `layers[i + 1]`.
+    links: Vec<Link>,
+    /// Whether the block's single successor has several
+    /// predecessors, so that the edge moves go before the branch.
+    to_join: bool,
+}
+
+/// The choices that an allocation makes, for emission.
+struct Choices {
+    /// The state of every layer of every block.
+    states: Vec<Vec<usize>>,
+    /// The place of every operand of the class.
+    operand_places: FxHashMap<(Inst, usize), Place>,
+}
+
+struct Homes {
+    slots: FxHashMap<VReg, Allocation>,
+    num_spillslots: u32,
+}
+
+impl Homes {
+    fn get<F: Function>(&mut self, func: &F, vreg: VReg) -> Allocation {
+        if let Some(&slot) = self.slots.get(&vreg) {
+            return slot;
+        }
+        let slot = self.allocate(func, vreg.class());
+        self.slots.insert(vreg, slot);
+        slot
+    }
+
+    fn allocate<F: Function>(&mut self, func: &F, class: RegClass) -> Allocation {
+        let size = func.spillslot_size(class) as u32;
+        let mut offset = self.num_spillslots;
+        // Align up to `size`.
+        debug_assert!(size.is_power_of_two());
+        offset = (offset + size - 1) & !(size - 1);
+        let slot = if func.multi_spillslot_named_by_last_slot() {
+            offset + size - 1
+        } else {
+            offset
+        };
+        offset += size;
+        self.num_spillslots = offset;
+        Allocation::stack(SpillSlot::new(slot as usize, class))
+    }
+}
+
+struct ClassSolver<'a, F: Function> {
+    func: &'a F,
+    env: &'a MachineEnv,
+    cfginfo: &'a CFGInfo,
+    liveness: &'a Liveness,
+    class: RegClass,
+    /// The registers that values may be in: the allocatable ones,
+    /// then those that only fixed-register operands name.
+    regs: Vec<PReg>,
+    num_allocatable: usize,
+    /// The registers beyond the allocatable ones that each value may
+    /// be in: as in ion, those that its fixed-register operands name.
+    extra_regs: FxHashMap<VReg, SmallVec<[u8; 2]>>,
+    /// Whether two registers (by index) are equal or alias.
+    overlap: Vec<Vec<bool>>,
+    spaces: Vec<Space>,
+    blocks: Vec<BlockLayers>,
+    /// The blocks with several predecessors, whose entry states are
+    /// enumerated.
+    joins: Vec<Block>,
+    /// Blocks in an order that puts every block after its successors
+    /// that have a single predecessor.
+    order: Vec<Block>,
+    /// The dynamic program: the cheapest cost from each state of
+    /// each layer to the end of the function, for the current
+    /// `join_states`.
+    costs: Vec<Vec<Vec<u64>>>,
+    join_states: Vec<usize>,
+    choices: Option<Choices>,
+}
+
+impl<'a, F: Function> ClassSolver<'a, F> {
+    fn new(
+        func: &'a F,
+        env: &'a MachineEnv,
+        cfginfo: &'a CFGInfo,
+        liveness: &'a Liveness,
+        class: RegClass,
+        limits: &Limits,
+    ) -> Result<Self, Error> {
+        let mut regs: Vec<PReg> = env.preferred_regs_by_class[class as usize]
+            .iter()
+            .chain(env.non_preferred_regs_by_class[class as usize].iter())
+            .copied()
+            .collect();
+        let num_allocatable = regs.len();
+        let mut extra_regs: FxHashMap<VReg, SmallVec<[u8; 2]>> = FxHashMap::default();
+        let mut related = vec![];
+        for inst in 0..func.num_insts() {
+            let operands = func.inst_operands(Inst::new(inst));
+            for op in operands {
+                if op.class() != class {
+                    continue;
+                }
+                if let OperandConstraint::Reuse(input) = op.constraint() {
+                    related.push((operands[input].vreg(), op.vreg()));
+                }
+                if let OperandConstraint::FixedReg(preg) = op.constraint() {
+                    let reg = match regs.iter().position(|&r| r == preg) {
+                        Some(reg) => reg,
+                        None => {
+                            regs.push(preg);
+                            regs.len() - 1
+                        }
+                    };
+                    if reg >= num_allocatable {
+                        let extra = extra_regs.entry(op.vreg()).or_default();
+                        if !extra.contains(&(reg as u8)) {
+                            extra.push(reg as u8);
+                        }
+                    }
+                }
+            }
+        }
+        // Block parameters and their arguments, and reused inputs and
+        // their defs, may be in each other's registers, as they would
+        // be in the same bundle in ion.
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            let last = func.block_insns(block).last();
+            for (i, &succ) in func.block_succs(block).iter().enumerate() {
+                let args = func.branch_blockparams(block, last, i);
+                related.extend(
+                    args.iter()
+                        .copied()
+                        .zip(func.block_params(succ).iter().copied()),
+                );
+            }
+        }
+        related.retain(|(a, _)| a.class() == class);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &(a, b) in &related {
+                let mut union: SmallVec<[u8; 2]> = extra_regs.get(&a).cloned().unwrap_or_default();
+                for &reg in extra_regs.get(&b).into_iter().flatten() {
+                    if !union.contains(&reg) {
+                        union.push(reg);
+                    }
+                }
+                for vreg in [a, b] {
+                    let extra = extra_regs.entry(vreg).or_default();
+                    if extra.len() != union.len() {
+                        *extra = union.clone();
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if regs.len() >= NO_REG as usize {
+            return Err(Error::TooLarge);
+        }
+        let overlap = regs
+            .iter()
+            .map(|&a| {
+                regs.iter()
+                    .map(|&b| {
+                        a == b || env.aliases.contains(&(a, b)) || env.aliases.contains(&(b, a))
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut solver = ClassSolver {
+            func,
+            env,
+            cfginfo,
+            liveness,
+            class,
+            regs,
+            num_allocatable,
+            extra_regs,
+            overlap,
+            spaces: vec![],
+            blocks: vec![],
+            joins: vec![],
+            order: vec![],
+            costs: vec![],
+            join_states: vec![],
+            choices: None,
+        };
+        let mut space_ids = FxHashMap::default();
+        for block in 0..func.num_blocks() {
+            let layers =
+                solver.block_layers(Block::new(block), liveness, &mut space_ids, limits)?;
+            solver.blocks.push(layers);
+            if func.block_preds(Block::new(block)).len() > 1 {
+                solver.joins.push(Block::new(block));
+            }
+        }
+        solver.compute_order();
+        Ok(solver)
+    }
+
+    fn reg_index(&self, preg: PReg) -> u8 {
+        self.regs.iter().position(|&r| r == preg).unwrap() as u8
+    }
+
+    /// The registers that `vreg` may be in.
+    fn var_regs(&self, vreg: VReg) -> SmallVec<[u8; 16]> {
+        let mut regs: SmallVec<[u8; 16]> = (0..self.num_allocatable as u8).collect();
+        if let Some(extra) = self.extra_regs.get(&vreg) {
+            regs.extend(extra.iter().copied());
+        }
+        regs
+    }
+
+    fn home(&self, homes: &mut Homes, vreg: VReg) -> Allocation {
+        homes.get(self.func, self.liveness.slot(vreg))
+    }
+
+    fn is_join(&self, block: Block) -> bool {
+        self.func.block_preds(block).len() > 1
+    }
+
+    fn space(
+        &mut self,
+        vars: &[VReg],
+        space_ids: &mut FxHashMap<Vec<VReg>, usize>,
+        limits: &Limits,
+    ) -> Result<usize, Error> {
+        let vars: Vec<VReg> = vars
+            .iter()
+            .copied()
+            .filter(|vreg| vreg.class() == self.class)
+            .collect();
+        if let Some(&id) = space_ids.get(&vars) {
+            return Ok(id);
+        }
+        // Each value is in a register, its spillslot, or both.
+        let bound: f64 = vars
+            .iter()
+            .map(|&vreg| (2 * self.var_regs(vreg).len() + 1) as f64)
+            .product();
+        if bound > limits.max_states as f64 * 64.0 {
+            return Err(Error::TooLarge);
+        }
+        let mut space = Space {
+            vars: vars.clone(),
+            states: vec![],
+            index: FxHashMap::default(),
+        };
+        let mut current = vec![];
+        self.enumerate(&mut space, &mut current);
+        if space.len() > limits.max_states {
+            return Err(Error::TooLarge);
+        }
+        for i in 0..space.len() {
+            space.index.insert(encode(space.state(i)), i);
+        }
+        let id = self.spaces.len();
+        self.spaces.push(space);
+        space_ids.insert(vars, id);
+        Ok(id)
+    }
+
+    fn enumerate(&self, space: &mut Space, current: &mut Vec<Loc>) {
+        if current.len() == space.vars.len() {
+            space.states.extend_from_slice(current);
+            return;
+        }
+        let mut options: SmallVec<[Loc; 16]> = smallvec![Loc {
+            reg: NO_REG,
+            stack: true
+        }];
+        for reg in self.var_regs(space.vars[current.len()]) {
+            if current
+                .iter()
+                .any(|loc| loc.reg != NO_REG && self.overlap[loc.reg as usize][reg as usize])
+            {
+                continue;
+            }
+            options.push(Loc { reg, stack: false });
+            options.push(Loc { reg, stack: true });
+        }
+        for loc in options {
+            current.push(loc);
+            self.enumerate(space, current);
+            current.pop();
+        }
+    }
+
+    fn block_layers(
+        &mut self,
+        block: Block,
+        liveness: &Liveness,
+        space_ids: &mut FxHashMap<Vec<VReg>, usize>,
+        limits: &Limits,
+    ) -> Result<BlockLayers, Error> {
+        let func = self.func;
+        let insns = func.block_insns(block);
+        let succs = func.block_succs(block);
+        let to_join = succs.len() == 1 && self.is_join(succs[0]);
+        let entry = self.space(&liveness.before[insns.first().index()], space_ids, limits)?;
+        let mut layers = vec![entry];
+        let mut links = vec![];
+        for inst in insns.iter() {
+            if to_join && inst == insns.last() {
+                break;
+            }
+            let pre = self.space(&liveness.before[inst.index()], space_ids, limits)?;
+            // The moves into a block with a single predecessor are
+            // the edge moves.
+            if inst != insns.first() || self.is_join(block) {
+                links.push(Link::Move {
+                    pos: ProgPoint::before(inst),
+                });
+                layers.push(pre);
+            }
+            let post = self.space(&liveness.after[inst.index()], space_ids, limits)?;
+            links.push(Link::Inst { inst });
+            layers.push(post);
+        }
+        Ok(BlockLayers {
+            layers,
+            links,
+            to_join,
+        })
+    }
+
+    fn compute_order(&mut self) {
+        let mut roots = vec![self.func.entry_block()];
+        roots.extend(self.joins.iter().copied());
+        for root in roots {
+            let mut stack = vec![(root, 0)];
+            while let Some((block, succ)) = stack.pop() {
+                let succs = self.func.block_succs(block);
+                if succ < succs.len() {
+                    stack.push((block, succ + 1));
+                    if !self.is_join(succs[succ]) {
+                        stack.push((succs[succ], 0));
+                    }
+                } else {
+                    self.order.push(block);
+                }
+            }
+        }
+    }
+
+    fn weight(&self, block: Block) -> u64 {
+        block_weight(self.cfginfo, block)
+    }
+
+    /// For each value of `to`, the index in `from` of the value that it
+    /// takes: the same vreg, or on an edge, the branch argument for a
+    /// blockparam.
+    fn move_sources(&self, from: usize, to: usize, edge: Option<(Block, Block)>) -> Vec<usize> {
+        let (from, to) = (&self.spaces[from], &self.spaces[to]);
+        to.vars
+            .iter()
+            .map(|&vreg| {
+                let mut src = vreg;
+                if let Some((pred, succ)) = edge {
+                    let params = self.func.block_params(succ);
+                    if let Some(i) = params.iter().position(|&p| p == vreg) {
+                        let last = self.func.block_insns(pred).last();
+                        let succ_idx = self
+                            .func
+                            .block_succs(pred)
+                            .iter()
+                            .position(|&s| s == succ)
+                            .unwrap();
+                        src = self.func.branch_blockparams(pred, last, succ_idx)[i];
+                    }
+                }
+                from.var(src).expect("move source should be live")
+            })
+            .collect()
+    }
+
+    /// The cost of moving the values from state `a` of space `from` to
+    /// state `b` of space `to`.
+    fn move_cost(&self, from: &Space, a: usize, to: &Space, b: usize, sources: &[usize]) -> u64 {
+        let (a, b) = (from.state(a), to.state(b));
+        let mut cost = 0;
+        for (t, (&dst, &s)) in b.iter().zip(sources).enumerate() {
+            let src = a[s];
+            let same_slot = self.liveness.slot(from.vars[s]) == self.liveness.slot(to.vars[t]);
+            if dst.reg != NO_REG && dst.reg != src.reg {
+                cost += if src.reg != NO_REG {
+                    MOVE_COST
+                } else {
+                    RELOAD_COST
+                };
+            }
+            if dst.stack && !(same_slot && src.stack) {
+                cost += if src.reg != NO_REG {
+                    SPILL_COST
+                } else {
+                    RELOAD_COST + SPILL_COST
+                };
+            }
+        }
+        cost
+    }
+
+    /// Apply `inst` to state `a` of space `pre`, calling `f` with each
+    /// resulting state of space `post`, the places of the operands of
+    /// this class, and the cost of the copies that the uses read.
+    ///
+    /// A use in a register may read a copy of its value, made just
+    /// before the instruction in a register that no value occupies,
+    /// so that the value stays where it is: for example, for a
+    /// fixed-register use, or for a reused input that is live after
+    /// the instruction.
+    fn step(
+        &self,
+        inst: Inst,
+        pre: &Space,
+        a: usize,
+        post: &Space,
+        mut f: impl FnMut(usize, &[(usize, Place)], u64),
+    ) {
+        let func = self.func;
+        let state = pre.state(a);
+        if func.requires_refs_on_stack(inst) {
+            let reftypes = func.reftype_vregs();
+            for (vreg, loc) in pre.vars.iter().zip(state) {
+                if reftypes.contains(vreg) && (loc.reg != NO_REG || !loc.stack) {
+                    return;
+                }
+            }
+        }
+
+        let operands = func.inst_operands(inst);
+        let reused = |slot: usize| {
+            operands
+                .iter()
+                .any(|op| op.constraint() == OperandConstraint::Reuse(slot))
+        };
+        let clobbers = func.inst_clobbers(inst);
+        let clobbered = |reg: u8| {
+            let preg = self.regs[reg as usize];
+            clobbers.contains(preg)
+                || self.env.aliases.iter().any(|&(x, y)| {
+                    (x == preg && clobbers.contains(y)) || (y == preg && clobbers.contains(x))
+                })
+        };
+        let free = |reg: u8| {
+            !state
+                .iter()
+                .any(|loc| loc.reg != NO_REG && self.overlap[reg as usize][loc.reg as usize])
+        };
+
+        // The places that each use may read, with the cost of the copy.
+        let mut uses: Vec<(usize, bool, Options<PlaceCost>)> = vec![];
+        for (slot, op) in operands.iter().enumerate() {
+            if op.class() != self.class || op.kind() != OperandKind::Use {
+                continue;
+            }
+            let loc = state[pre.var(op.vreg()).unwrap()];
+            let late = op.pos() == OperandPos::Late || reused(slot);
+            let copy_cost = if loc.reg != NO_REG {
+                MOVE_COST
+            } else {
+                RELOAD_COST
+            };
+            let in_reg = |reg: u8| {
+                if loc.reg == reg {
+                    Some((Place::Reg(reg), 0))
+                } else if free(reg) {
+                    Some((Place::Reg(reg), copy_cost))
+                } else {
+                    None
+                }
+            };
+            let any_reg = || {
+                self.var_regs(op.vreg())
+                    .into_iter()
+                    .filter_map(in_reg)
+                    .collect()
+            };
+            let options: Options<PlaceCost> = match op.constraint() {
+                OperandConstraint::FixedReg(preg) => {
+                    in_reg(self.reg_index(preg)).into_iter().collect()
+                }
+                OperandConstraint::Reg => any_reg(),
+                OperandConstraint::RegSubset(subset) => self
+                    .var_regs(op.vreg())
+                    .into_iter()
+                    .filter(|&reg| self.env.reg_subsets[subset].contains(self.regs[reg as usize]))
+                    .filter_map(in_reg)
+                    .collect(),
+                // A reused input must be in a register.
+                OperandConstraint::Any if reused(slot) => any_reg(),
+                // The spillslot never conflicts with defs or clobbers.
+                OperandConstraint::Any if loc.stack => smallvec![(Place::Stack, 0)],
+                OperandConstraint::Any => smallvec![(Place::Reg(loc.reg), 0)],
+                OperandConstraint::Stack if loc.stack => smallvec![(Place::Stack, 0)],
+                _ => return,
+            };
+            uses.push((slot, late, options));
+        }
+
+        let mut use_choice: SmallVec<[usize; 4]> = smallvec![0; uses.len()];
+        loop {
+            let mut places: SmallVec<[(usize, Place); 8]> = smallvec![];
+            // Registers read by uses, and whether until the late point.
+            let mut use_regs: SmallVec<[(u8, bool, usize); 8]> = smallvec![];
+            let mut copies: SmallVec<[(u8, VReg); 4]> = smallvec![];
+            let mut copy_cost = 0;
+            let mut valid = true;
+            for (&(slot, late, ref options), &c) in uses.iter().zip(&use_choice) {
+                let (place, cost) = match options.get(c) {
+                    Some(&option) => option,
+                    None => return,
+                };
+                if let Place::Reg(reg) = place {
+                    if cost > 0 {
+                        if copies
+                            .iter()
+                            .any(|&(other, _)| self.overlap[reg as usize][other as usize])
+                        {
+                            valid = false;
+                        }
+                        copies.push((reg, operands[slot].vreg()));
+                    }
+                    if late && clobbered(reg) {
+                        valid = false;
+                    }
+                    use_regs.push((reg, late, slot));
+                }
+                copy_cost += cost;
+                places.push((slot, place));
+            }
+            if valid {
+                self.step_defs(
+                    operands, pre, state, post, &places, &use_regs, &copies, &clobbered, copy_cost,
+                    &mut f,
+                );
+            }
+            if !next_combination(&mut use_choice, |i| uses[i].2.len()) {
+                return;
+            }
+        }
+    }
+
+    /// Continue `step` for one choice of places for the uses, with
+    /// each choice of places for the defs.
+    #[allow(clippy::too_many_arguments)]
+    fn step_defs(
+        &self,
+        operands: &[Operand],
+        pre: &Space,
+        state: &[Loc],
+        post: &Space,
+        places: &[(usize, Place)],
+        use_regs: &[(u8, bool, usize)],
+        copies: &[(u8, VReg)],
+        clobbered: &impl Fn(u8) -> bool,
+        copy_cost: u64,
+        f: &mut impl FnMut(usize, &[(usize, Place)], u64),
+    ) {
+        let mut defs: SmallVec<[(usize, Operand, Options<Place>); 4]> = smallvec![];
+        for (slot, &op) in operands.iter().enumerate() {
+            if op.class() != self.class || op.kind() != OperandKind::Def {
+                continue;
+            }
+            let regs = self.var_regs(op.vreg()).into_iter();
+            let mut candidates: Options<Place> = match op.constraint() {
+                OperandConstraint::FixedReg(preg) => smallvec![Place::Reg(self.reg_index(preg))],
+                OperandConstraint::Reg => regs.map(Place::Reg).collect(),
+                OperandConstraint::RegSubset(subset) => regs
+                    .filter(|&reg| self.env.reg_subsets[subset].contains(self.regs[reg as usize]))
+                    .map(Place::Reg)
+                    .collect(),
+                OperandConstraint::Any => regs
+                    .map(Place::Reg)
+                    .chain(std::iter::once(Place::Stack))
+                    .collect(),
+                OperandConstraint::Stack => smallvec![Place::Stack],
+                OperandConstraint::Reuse(input) => {
+                    match places.iter().find(|&&(slot, _)| slot == input) {
+                        Some(&(_, Place::Reg(reg))) => smallvec![Place::Reg(reg)],
+                        _ => return,
+                    }
+                }
+                OperandConstraint::FixedStackLoc(_) => unreachable!(),
+            };
+            // Only a fixed-register def may be in a clobbered register.
+            if !matches!(op.constraint(), OperandConstraint::FixedReg(_)) {
+                candidates.retain(|place| match *place {
+                    Place::Reg(reg) => !clobbered(reg),
+                    Place::Stack => true,
+                });
+            }
+            if candidates.is_empty() {
+                return;
+            }
+            defs.push((slot, op, candidates));
+        }
+
+        let mut choice: SmallVec<[usize; 4]> = smallvec![0; defs.len()];
+        let mut post_state: SmallVec<[Loc; 8]> = smallvec![];
+        let mut options: Vec<(bool, Options<(u8, u64)>)> = vec![];
+        let free = |reg: u8| {
+            !state
+                .iter()
+                .any(|loc| loc.reg != NO_REG && self.overlap[reg as usize][loc.reg as usize])
+        };
+        loop {
+            let chosen: SmallVec<[(usize, Operand, Place); 4]> = defs
+                .iter()
+                .zip(&choice)
+                .map(|((slot, op, candidates), &c)| (*slot, *op, candidates[c]))
+                .collect();
+            if self.defs_valid(&chosen, use_regs) {
+                let written = |reg: u8| {
+                    clobbered(reg)
+                        || chosen.iter().any(|&(_, _, place)| match place {
+                            Place::Reg(other) => self.overlap[reg as usize][other as usize],
+                            Place::Stack => false,
+                        })
+                };
+                // The registers that each value may be in after the
+                // instruction, with the cost of getting there: values
+                // live across it lose registers that it writes, and a
+                // value that it uses may instead stay in a copy, either
+                // one that a use reads or one made to keep it in a
+                // register while its own is overwritten.
+                options.clear();
+                for &vreg in &post.vars {
+                    let mut regs: Options<(u8, u64)> = smallvec![];
+                    let stack = match chosen.iter().find(|(_, op, _)| op.vreg() == vreg) {
+                        Some(&(_, _, Place::Reg(reg))) => {
+                            regs.push((reg, 0));
+                            false
+                        }
+                        Some(&(_, _, Place::Stack)) => {
+                            regs.push((NO_REG, 0));
+                            true
+                        }
+                        None => {
+                            let loc = state[pre.var(vreg).unwrap()];
+                            if loc.reg != NO_REG && !written(loc.reg) {
+                                regs.push((loc.reg, 0));
+                            } else if loc.stack {
+                                regs.push((NO_REG, 0));
+                            }
+                            if operands.iter().any(|op| op.vreg() == vreg) {
+                                let keep_cost = if loc.reg != NO_REG {
+                                    MOVE_COST
+                                } else {
+                                    RELOAD_COST
+                                };
+                                for reg in self.var_regs(vreg) {
+                                    if written(reg) || regs.iter().any(|&(r, _)| r == reg) {
+                                        continue;
+                                    }
+                                    if copies.iter().any(|&(r, v)| r == reg && v == vreg) {
+                                        regs.push((reg, 0));
+                                    } else if free(reg)
+                                        && !copies
+                                            .iter()
+                                            .any(|&(r, _)| self.overlap[reg as usize][r as usize])
+                                    {
+                                        regs.push((reg, keep_cost));
+                                    }
+                                }
+                            }
+                            loc.stack
+                        }
+                    };
+                    if regs.is_empty() {
+                        break;
+                    }
+                    options.push((stack, regs));
+                }
+                if options.len() == post.vars.len() {
+                    let mut reg_choice: SmallVec<[usize; 8]> = smallvec![0; options.len()];
+                    loop {
+                        post_state.clear();
+                        let mut keeps: SmallVec<[u8; 4]> = smallvec![];
+                        let mut keep_cost = 0;
+                        let mut valid = true;
+                        for ((stack, regs), &c) in options.iter().zip(&reg_choice) {
+                            let (reg, cost) = regs[c];
+                            if cost > 0 {
+                                if keeps
+                                    .iter()
+                                    .any(|&other| self.overlap[reg as usize][other as usize])
+                                {
+                                    valid = false;
+                                }
+                                keeps.push(reg);
+                                keep_cost += cost;
+                            }
+                            post_state.push(Loc { reg, stack: *stack });
+                        }
+                        if valid {
+                            if let Some(&b) = post.index.get(&encode(&post_state)) {
+                                let mut all: SmallVec<[(usize, Place); 8]> =
+                                    places.iter().copied().collect();
+                                all.extend(chosen.iter().map(|&(slot, _, place)| (slot, place)));
+                                f(b, &all, copy_cost + keep_cost);
+                            }
+                        }
+                        if !next_combination(&mut reg_choice, |i| options[i].1.len()) {
+                            break;
+                        }
+                    }
+                }
+            }
+            if !next_combination(&mut choice, |i| defs[i].2.len()) {
+                return;
+            }
+        }
+    }
+
+    /// Do the def places in `chosen` conflict with each other or with
+    /// the uses?
+    fn defs_valid(
+        &self,
+        chosen: &[(usize, Operand, Place)],
+        use_regs: &[(u8, bool, usize)],
+    ) -> bool {
+        for (i, &(_, op, place)) in chosen.iter().enumerate() {
+            let reg = match place {
+                Place::Reg(reg) => reg,
+                Place::Stack => continue,
+            };
+            let input = match op.constraint() {
+                OperandConstraint::Reuse(input) => Some(input),
+                _ => None,
+            };
+            let early = op.pos() == OperandPos::Early;
+            for &(use_reg, late, slot) in use_regs {
+                if Some(slot) != input
+                    && (early || late)
+                    && self.overlap[reg as usize][use_reg as usize]
+                {
+                    return false;
+                }
+            }
+            for &(_, _, other) in &chosen[..i] {
+                if let Place::Reg(other) = other {
+                    if self.overlap[reg as usize][other as usize] {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Compute `costs` for the current `join_states`, returning the
+    /// cost of the whole function.
+    fn compute_costs(&mut self) -> u64 {
+        let mut costs = std::mem::take(&mut self.costs);
+        costs.resize(self.func.num_blocks(), vec![]);
+        for &block in &self.order {
+            let layers = &self.blocks[block.index()];
+            let mut block_costs = std::mem::take(&mut costs[block.index()]);
+            block_costs.resize(layers.layers.len(), vec![]);
+
+            // The edges out of the block.
+            let last = *layers.layers.last().unwrap();
+            let exit = &mut block_costs[layers.layers.len() - 1];
+            exit.clear();
+            exit.resize(self.spaces[last].len(), 0);
+            for &succ in self.func.block_succs(block) {
+                let entry = self.blocks[succ.index()].layers[0];
+                let sources = self.move_sources(last, entry, Some((block, succ)));
+                let (from, to) = (&self.spaces[last], &self.spaces[entry]);
+                if self.is_join(succ) {
+                    let weight = self.weight(block);
+                    let b = self.join_states[self.joins.iter().position(|&j| j == succ).unwrap()];
+                    for (a, cost) in exit.iter_mut().enumerate() {
+                        let m = self.move_cost(from, a, to, b, &sources);
+                        *cost = cost.saturating_add(m.saturating_mul(weight));
+                    }
+                } else {
+                    let weight = self.weight(succ);
+                    let next = &costs[succ.index()][0];
+                    for (a, cost) in exit.iter_mut().enumerate() {
+                        let best = (0..to.len())
+                            .map(|b| {
+                                self.move_cost(from, a, to, b, &sources)
+                                    .saturating_mul(weight)
+                                    .saturating_add(next[b])
+                            })
+                            .min()
+                            .unwrap();
+                        *cost = cost.saturating_add(best);
+                    }
+                }
+            }
+
+            for (i, link) in layers.links.iter().enumerate().rev() {
+                let (from, to) = (layers.layers[i], layers.layers[i + 1]);
+                let (before, after) = block_costs.split_at_mut(i + 1);
+                let (current, next) = (&mut before[i], &after[0]);
+                current.clear();
+                current.resize(self.spaces[from].len(), INFINITE);
+                let weight = self.weight(block);
+                match *link {
+                    Link::Move { .. } => {
+                        let sources = self.move_sources(from, to, None);
+                        let (from, to) = (&self.spaces[from], &self.spaces[to]);
+                        for (a, cost) in current.iter_mut().enumerate() {
+                            for (b, &next_cost) in next.iter().enumerate() {
+                                let m = self.move_cost(from, a, to, b, &sources);
+                                let c = m.saturating_mul(weight).saturating_add(next_cost);
+                                *cost = std::cmp::min(*cost, c);
+                            }
+                        }
+                    }
+                    Link::Inst { inst } => {
+                        let (from, to) = (&self.spaces[from], &self.spaces[to]);
+                        for (a, cost) in current.iter_mut().enumerate() {
+                            self.step(inst, from, a, to, |b, _, copy_cost| {
+                                let c = copy_cost.saturating_mul(weight).saturating_add(next[b]);
+                                *cost = std::cmp::min(*cost, c);
+                            });
+                        }
+                    }
+                }
+            }
+            costs[block.index()] = block_costs;
+        }
+
+        let mut total = costs[self.func.entry_block().index()][0][0];
+        for (&join, &state) in self.joins.iter().zip(&self.join_states) {
+            total = total.saturating_add(costs[join.index()][0][state]);
+        }
+        self.costs = costs;
+        total
+    }
+
+    /// Find the cheapest allocation of this class, returning its cost.
+    fn solve(&mut self, limits: &Limits) -> Result<u64, Error> {
+        // Estimate the work first.
+        let mut work: u64 = 0;
+        for (block, layers) in self.blocks.iter().enumerate() {
+            for (i, link) in layers.links.iter().enumerate() {
+                let (from, to) = (layers.layers[i], layers.layers[i + 1]);
+                work += match link {
+                    Link::Move { .. } => (self.spaces[from].len() * self.spaces[to].len()) as u64,
+                    Link::Inst { .. } => self.spaces[from].len() as u64,
+                };
+            }
+            let last = *layers.layers.last().unwrap();
+            for &succ in self.func.block_succs(Block::new(block)) {
+                let entry = self.blocks[succ.index()].layers[0];
+                work += (self.spaces[last].len() * self.spaces[entry].len()) as u64;
+            }
+        }
+        for &join in &self.joins {
+            let entry = self.blocks[join.index()].layers[0];
+            work = work.saturating_mul(self.spaces[entry].len() as u64);
+        }
+        if work > limits.max_work {
+            return Err(Error::TooLarge);
+        }
+
+        // Try every combination of states at the joins.
+        self.join_states = vec![0; self.joins.len()];
+        let mut best = INFINITE;
+        let mut best_states = self.join_states.clone();
+        loop {
+            let total = self.compute_costs();
+            if total < best {
+                best = total;
+                best_states = self.join_states.clone();
+            }
+            let (blocks, spaces, joins) = (&self.blocks, &self.spaces, &self.joins);
+            let len = |i: usize| spaces[blocks[joins[i].index()].layers[0]].len();
+            if !next_combination(&mut self.join_states, len) {
+                break;
+            }
+        }
+        if best >= INFINITE {
+            return Err(Error::NoSolution);
+        }
+        self.join_states = best_states;
+        self.compute_costs();
+        self.choose();
+        Ok(best)
+    }
+
+    /// Follow the cheapest choices through `costs`.
+    fn choose(&mut self) {
+        let mut choices = Choices {
+            states: vec![vec![]; self.func.num_blocks()],
+            operand_places: FxHashMap::default(),
+        };
+        let mut roots: Vec<(Block, usize)> = vec![(self.func.entry_block(), 0)];
+        roots.extend(
+            self.joins
+                .iter()
+                .copied()
+                .zip(self.join_states.iter().copied()),
+        );
+        let mut worklist = roots;
+        while let Some((block, entry_state)) = worklist.pop() {
+            let layers = &self.blocks[block.index()];
+            let costs = &self.costs[block.index()];
+            let mut states = vec![entry_state];
+            let mut a = entry_state;
+            for (i, link) in layers.links.iter().enumerate() {
+                let (from, to) = (layers.layers[i], layers.layers[i + 1]);
+                let target = costs[i][a];
+                let next = &costs[i + 1];
+                let weight = self.weight(block);
+                let mut chosen = None;
+                match *link {
+                    Link::Move { .. } => {
+                        let sources = self.move_sources(from, to, None);
+                        let (from, to) = (&self.spaces[from], &self.spaces[to]);
+                        chosen = (0..next.len()).find(|&b| {
+                            let m = self.move_cost(from, a, to, b, &sources);
+                            m.saturating_mul(weight).saturating_add(next[b]) == target
+                        });
+                    }
+                    Link::Inst { inst } => {
+                        let operand_places = &mut choices.operand_places;
+                        let (from, to) = (&self.spaces[from], &self.spaces[to]);
+                        self.step(inst, from, a, to, |b, places, copy_cost| {
+                            if chosen.is_none()
+                                && copy_cost.saturating_mul(weight).saturating_add(next[b])
+                                    == target
+                            {
+                                chosen = Some(b);
+                                for &(slot, place) in places {
+                                    operand_places.insert((inst, slot), place);
+                                }
+                            }
+                        });
+                    }
+                }
+                a = chosen.expect("optimal choice should exist");
+                states.push(a);
+            }
+
+            let last = *layers.layers.last().unwrap();
+            for &succ in self.func.block_succs(block) {
+                if self.is_join(succ) {
+                    continue;
+                }
+                let entry = self.blocks[succ.index()].layers[0];
+                let sources = self.move_sources(last, entry, Some((block, succ)));
+                let weight = self.weight(succ);
+                let next = &self.costs[succ.index()][0];
+                let (from, to) = (&self.spaces[last], &self.spaces[entry]);
+                let b = (0..to.len())
+                    .min_by_key(|&b| {
+                        self.move_cost(from, a, to, b, &sources)
+                            .saturating_mul(weight)
+                            .saturating_add(next[b])
+                    })
+                    .unwrap();
+                worklist.push((succ, b));
+            }
+            choices.states[block.index()] = states;
+        }
+        self.choices = Some(choices);
+    }
+
+    /// Write the chosen allocation of this class into `output`.
+    fn emit(&self, homes: &mut Homes, output: &mut Output) {
+        let func = self.func;
+        let choices = self.choices.as_ref().unwrap();
+
+        for (&(inst, slot), &place) in &choices.operand_places {
+            let vreg = func.inst_operands(inst)[slot].vreg();
+            let alloc = match place {
+                Place::Reg(reg) => Allocation::reg(self.regs[reg as usize]),
+                Place::Stack => self.home(homes, vreg),
+            };
+            let offset = output.inst_alloc_offsets[inst.index()] as usize;
+            output.allocs[offset + slot] = alloc;
+        }
+
+        // The copies that uses read come after all other moves before
+        // their instruction.
+        let mut copies = vec![];
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            let layers = &self.blocks[block.index()];
+            let states = &choices.states[block.index()];
+            for (i, link) in layers.links.iter().enumerate() {
+                let (from, to) = (layers.layers[i], layers.layers[i + 1]);
+                match *link {
+                    Link::Move { pos } => {
+                        let sources = self.move_sources(from, to, None);
+                        self.emit_moves(
+                            homes,
+                            output,
+                            pos,
+                            from,
+                            states[i],
+                            to,
+                            states[i + 1],
+                            &sources,
+                        );
+                    }
+                    Link::Inst { inst } => {
+                        let space = &self.spaces[from];
+                        let state = space.state(states[i]);
+                        for (slot, op) in func.inst_operands(inst).iter().enumerate() {
+                            if op.class() != self.class || op.kind() != OperandKind::Use {
+                                continue;
+                            }
+                            let loc = state[space.var(op.vreg()).unwrap()];
+                            match choices.operand_places[&(inst, slot)] {
+                                Place::Reg(reg) if reg != loc.reg => {
+                                    let from = if loc.reg != NO_REG {
+                                        Allocation::reg(self.regs[loc.reg as usize])
+                                    } else {
+                                        self.home(homes, op.vreg())
+                                    };
+                                    let to = Allocation::reg(self.regs[reg as usize]);
+                                    copies.push((ProgPoint::before(inst), Edit::Move { from, to }));
+                                }
+                                _ => {}
+                            }
+                        }
+                        // Copies made to keep a used value in a register.
+                        let (post, after) =
+                            (&self.spaces[to], self.spaces[to].state(states[i + 1]));
+                        let operands = func.inst_operands(inst);
+                        for (&vreg, loc) in post.vars.iter().zip(after) {
+                            let before = match space.var(vreg) {
+                                Some(var) => state[var],
+                                None => continue,
+                            };
+                            let kept = loc.reg != NO_REG
+                                && loc.reg != before.reg
+                                && !operands.iter().enumerate().any(|(slot, op)| {
+                                    op.vreg() == vreg
+                                        && (op.kind() == OperandKind::Def
+                                            || choices.operand_places[&(inst, slot)]
+                                                == Place::Reg(loc.reg))
+                                });
+                            if kept {
+                                let from = if before.reg != NO_REG {
+                                    Allocation::reg(self.regs[before.reg as usize])
+                                } else {
+                                    self.home(homes, vreg)
+                                };
+                                let to = Allocation::reg(self.regs[loc.reg as usize]);
+                                copies.push((ProgPoint::before(inst), Edit::Move { from, to }));
+                            }
+                        }
+                        if func.requires_refs_on_stack(inst) {
+                            for &vreg in &space.vars {
+                                if func.reftype_vregs().contains(&vreg) {
+                                    let home = self.home(homes, vreg);
+                                    output.safepoint_slots.push((ProgPoint::before(inst), home));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Edge moves go before the branch into a join, and
+            // otherwise at the start of the successor.
+            let last = *layers.layers.last().unwrap();
+            let a = *states.last().unwrap();
+            for &succ in func.block_succs(block) {
+                let entry = self.blocks[succ.index()].layers[0];
+                let b = if layers.to_join {
+                    self.join_states[self.joins.iter().position(|&j| j == succ).unwrap()]
+                } else {
+                    choices.states[succ.index()][0]
+                };
+                let pos = if layers.to_join {
+                    ProgPoint::before(func.block_insns(block).last())
+                } else {
+                    ProgPoint::before(func.block_insns(succ).first())
+                };
+                let sources = self.move_sources(last, entry, Some((block, succ)));
+                let first_edit = output.edits.len();
+                self.emit_moves(homes, output, pos, last, a, entry, b, &sources);
+                for _ in first_edit..output.edits.len() {
+                    output.quality.add_edge_move(self.cfginfo, pos);
+                }
+            }
+        }
+        output.edits.extend(copies);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn emit_moves(
+        &self,
+        homes: &mut Homes,
+        output: &mut Output,
+        pos: ProgPoint,
+        from: usize,
+        a: usize,
+        to: usize,
+        b: usize,
+        sources: &[usize],
+    ) {
+        let func = self.func;
+        let (from, to) = (&self.spaces[from], &self.spaces[to]);
+        let (a, b) = (from.state(a), to.state(b));
+        let mut moves: SmallVec<[(Allocation, Allocation); 8]> = smallvec![];
+        for (t, (&dst, &s)) in b.iter().zip(sources).enumerate() {
+            let src = a[s];
+            let (src_vreg, dst_vreg) = (from.vars[s], to.vars[t]);
+            let src_alloc = if src.reg != NO_REG {
+                Allocation::reg(self.regs[src.reg as usize])
+            } else {
+                self.home(homes, src_vreg)
+            };
+            if dst.reg != NO_REG && dst.reg != src.reg {
+                moves.push((src_alloc, Allocation::reg(self.regs[dst.reg as usize])));
+            }
+            let same_slot = self.liveness.slot(src_vreg) == self.liveness.slot(dst_vreg);
+            if dst.stack && !(same_slot && src.stack) {
+                moves.push((src_alloc, self.home(homes, dst_vreg)));
+            }
+        }
+
+        // As in ion, a source that overlaps another move's
+        // destination without being equal to it is first saved to a
+        // temporary spillslot.
+        let overlaps = |x: Allocation, y: Allocation| match (x.as_reg(), y.as_reg()) {
+            (Some(x), Some(y)) => {
+                self.env.aliases.contains(&(x, y)) || self.env.aliases.contains(&(y, x))
+            }
+            _ => false,
+        };
+        let class = self.class;
+        let mut stackslot_idx = 0;
+        let mut alias_saves: SmallVec<[(Allocation, Allocation); 2]> = smallvec![];
+        let mut parallel_moves = ParallelMoves::new();
+        for &(mut from, to) in &moves {
+            if moves.iter().any(|&(_, other)| overlaps(from, other)) {
+                from = match alias_saves.iter().find(|&&(src, _)| src == from) {
+                    Some(&(_, temp)) => temp,
+                    None => {
+                        let temp = Allocation::stack(SpillSlot::new(
+                            SpillSlot::MAX - stackslot_idx,
+                            class,
+                        ));
+                        stackslot_idx += 1;
+                        alias_saves.push((from, temp));
+                        temp
+                    }
+                };
+            }
+            parallel_moves.add(from, to, ());
+        }
+        let resolved = parallel_moves.resolve();
+
+        // Scratch registers must not hold any value on either side.
+        let in_use = |preg: PReg| {
+            a.iter().chain(b).any(|loc| {
+                loc.reg != NO_REG && {
+                    let reg = self.regs[loc.reg as usize];
+                    reg == preg
+                        || self.env.aliases.contains(&(reg, preg))
+                        || self.env.aliases.contains(&(preg, reg))
+                }
+            })
+        };
+        let mut candidates = self.regs[..self.num_allocatable]
+            .iter()
+            .copied()
+            .filter(|&preg| !in_use(preg));
+        let victim = self.regs[..self.num_allocatable]
+            .iter()
+            .rev()
+            .copied()
+            .find(|&preg| {
+                !self
+                    .env
+                    .aliases
+                    .iter()
+                    .any(|&(x, y)| x == preg || y == preg)
+            })
+            .or_else(|| self.regs.first().copied())
+            .unwrap_or(PReg::invalid());
+        let resolved = MoveAndScratchResolver::new(
+            || candidates.next().map(Allocation::reg),
+            || {
+                let idx = stackslot_idx;
+                stackslot_idx += 1;
+                Allocation::stack(SpillSlot::new(SpillSlot::MAX - idx, class))
+            },
+            |alloc| {
+                alloc.is_stack()
+                    || alloc.is_stack_loc()
+                    || matches!(alloc.as_reg(), Some(preg) if self.env.fixed_stack_slots.contains(&preg))
+            },
+            victim,
+        )
+        .compute(resolved);
+        let extra: Vec<Allocation> = (0..stackslot_idx)
+            .map(|_| homes.allocate(func, class))
+            .collect();
+        let rewrite = |alloc: Allocation| match alloc.as_stack() {
+            Some(slot) if slot.index() > SpillSlot::MAX - stackslot_idx => {
+                extra[SpillSlot::MAX - slot.index()]
+            }
+            _ => alloc,
+        };
+        let saves = alias_saves.into_iter().map(|(src, temp)| (src, temp, ()));
+        for (from, to, ()) in saves.chain(resolved) {
+            output.edits.push((
+                pos,
+                Edit::Move {
+                    from: rewrite(from),
+                    to: rewrite(to),
+                },
+            ));
+        }
+    }
+}
@@ -0,0 +1,820 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Test-case reduction.
+//!
+//! A function on which the allocator or the checker fails is often
+//! far larger than the failure needs. `reduce` shrinks it while a
+//! given predicate (e.g. "the checker still fails") keeps holding,
+//! in the spirit of delta debugging: it tries to delete ever smaller
+//! chunks of blocks, edges, instructions, vregs and operands, and to
+//! relax constraints and drop attributes, and keeps every change
+//! after which the predicate still holds.
+//!
+//! Every change keeps the function well-formed, as the allocator
+//! would otherwise fail on it for unrelated reasons. Deleting a vreg
+//! deletes its uses too, and deleting a block parameter deletes the
+//! branch arguments passed to it (and vice versa), so the function
+//! stays in SSA form. Blocks only become unreachable, and are then
+//! deleted, by dropping edges or turning branches into returns, and
+//! are only merged into a sole predecessor that has no other
+//! successor, so no critical edges appear. As a safety net, a change
+//! after which the function fails `CFGInfo` or SSA validation is
+//! discarded without asking the predicate, unless the original
+//! function failed it too.
+
+use crate::cfg::CFGInfo;
+use crate::ssa::validate_ssa;
+use crate::text::{BlockData, InstData, InstKind, TextFunction};
+use crate::{
+    Block, Function, Inst, InstRange, Operand, OperandConstraint, OperandKind, PRegSet, VReg,
+};
+use fxhash::{FxHashMap, FxHashSet};
+
+/// Shrink `func` to a function for which `predicate` still holds.
+///
+/// `predicate` is typically "allocation fails" or "the checker
+/// fails", perhaps narrowed to the original failure. If the failure
+/// is a panic, `predicate` should catch it with
+/// `std::panic::catch_unwind`. It must hold for `func`; if it does
+/// not, `func` is returned unchanged. The result can be printed in
+/// the text format of `crate::text` to reproduce the failure.
+pub fn reduce<F: Function>(
+    func: &F,
+    mut predicate: impl FnMut(&TextFunction) -> bool,
+) -> TextFunction {
+    let func = TextFunction::from_function(func);
+    if !predicate(&func) {
+        return func;
+    }
+    let (cfg_valid, ssa_valid) = validity(&func);
+    let mut reducer = Reducer {
+        predicate,
+        best: Candidate::new(&func),
+        best_func: func,
+        cfg_valid,
+        ssa_valid,
+    };
+    while reducer.pass() {}
+    let mut compacted = reducer.best.clone();
+    compacted.compact_vregs();
+    reducer.try_candidate(compacted);
+    reducer.best_func
+}
+
+/// Whether `func` passes the `CFGInfo` checks (e.g. for critical
+/// edges) and SSA validation.
+fn validity(func: &TextFunction) -> (bool, bool) {
+    match CFGInfo::new(func) {
+        Ok(cfginfo) => (true, validate_ssa(func, &cfginfo).is_ok()),
+        Err(_) => (false, false),
+    }
+}
+
+struct Reducer<P> {
+    predicate: P,
+    best: Candidate,
+    best_func: TextFunction,
+    cfg_valid: bool,
+    ssa_valid: bool,
+}
+
+impl<P: FnMut(&TextFunction) -> bool> Reducer<P> {
+    /// Keep `candidate` if it is valid and `predicate` holds for it.
+    fn try_candidate(&mut self, candidate: Candidate) -> bool {
+        let func = candidate.build();
+        let (cfg_valid, ssa_valid) = validity(&func);
+        if (self.cfg_valid && !cfg_valid) || (self.ssa_valid && !ssa_valid) {
+            return false;
+        }
+        if !(self.predicate)(&func) {
+            return false;
+        }
+        self.best = candidate;
+        self.best_func = func;
+        true
+    }
+
+    /// Apply every kind of change once; return whether any was kept.
+    fn pass(&mut self) -> bool {
+        let mut progress = false;
+        progress |= self.reduce_items(Candidate::branches, Candidate::make_returns);
+        progress |= self.reduce_items(Candidate::edges, Candidate::remove_edges);
+        progress |= self.reduce_items(Candidate::mergeable_blocks, Candidate::merge_blocks);
+        progress |= self.reduce_items(Candidate::ops, Candidate::remove_insts);
+        progress |= self.reduce_items(Candidate::defined_vregs, Candidate::remove_vregs);
+        progress |= self.reduce_items(Candidate::uses, Candidate::remove_operands);
+        progress |= self.reduce_items(Candidate::constrained, Candidate::relax_constraints);
+        progress |= self.reduce_items(Candidate::clobbering, Candidate::remove_clobbers);
+        progress |= self.reduce_items(Candidate::safepoints, Candidate::remove_safepoints);
+        progress |= self.reduce_items(Candidate::moves, Candidate::remove_moves);
+        progress |= self.reduce_items(Candidate::attributed_vregs, Candidate::remove_attributes);
+        progress |= self.reduce_items(Candidate::debug_labels, Candidate::remove_debug_labels);
+        progress
+    }
+
+    /// Try `apply` on chunks of `items(&self.best)`, starting with all
+    /// of them and halving the chunk size down to single items.
+    /// `apply` must make each item it acts on disappear from `items`.
+    fn reduce_items<T: Clone>(
+        &mut self,
+        items: fn(&Candidate) -> Vec<T>,
+        apply: fn(&mut Candidate, &[T]),
+    ) -> bool {
+        let mut progress = false;
+        let mut chunk = items(&self.best).len();
+        while chunk > 0 {
+            let mut start = 0;
+            loop {
+                let list = items(&self.best);
+                if start >= list.len() {
+                    break;
+                }
+                let end = std::cmp::min(start + chunk, list.len());
+                let mut candidate = self.best.clone();
+                apply(&mut candidate, &list[start..end]);
+                // On success the chunk is gone, and the next one has
+                // moved up to `start`.
+                if self.try_candidate(candidate) && items(&self.best).len() < list.len() {
+                    progress = true;
+                } else {
+                    start = end;
+                }
+            }
+            chunk /= 2;
+        }
+        progress
+    }
+}
+
+/// A function being reduced, with the instructions of each block kept
+/// together so that they are easy to delete. Each instruction is
+/// identified by its index in the original function, and each block
+/// by that of its last instruction, which is never deleted.
+#[derive(Clone)]
+struct Candidate {
+    /// Everything but the blocks and instructions. Its debug labels
+    /// refer to the original instruction indices.
+    func: TextFunction,
+    blocks: Vec<CandidateBlock>,
+}
+
+#[derive(Clone, Default)]
+struct CandidateBlock {
+    params: Vec<VReg>,
+    insts: Vec<(usize, InstData)>,
+    succs: Vec<Block>,
+    preds: Vec<Block>,
+    branch_args: Vec<Vec<VReg>>,
+}
+
+impl CandidateBlock {
+    fn key(&self) -> usize {
+        self.insts.last().unwrap().0
+    }
+
+    fn terminator(&self) -> &InstData {
+        &self.insts.last().unwrap().1
+    }
+}
+
+/// `op` with its constraint replaced by `constraint`.
+fn with_constraint(op: Operand, constraint: OperandConstraint) -> Operand {
+    Operand::new(op.vreg(), constraint, op.kind(), op.pos())
+}
+
+/// Delete the operand at `idx` of `inst`, renumbering the reuse
+/// constraints of the others.
+fn remove_operand(inst: &mut InstData, idx: usize) {
+    inst.operands.remove(idx);
+    for op in &mut inst.operands {
+        if let OperandConstraint::Reuse(which) = op.constraint() {
+            if which == idx {
+                *op = with_constraint(*op, OperandConstraint::Reg);
+            } else if which > idx {
+                *op = with_constraint(*op, OperandConstraint::Reuse(which - 1));
+            }
+        }
+    }
+    inst.is_move = None;
+}
+
+/// The vregs that `inst` defines.
+fn defs(inst: &InstData) -> impl Iterator<Item = usize> + '_ {
+    inst.operands
+        .iter()
+        .filter(|op| op.kind() != OperandKind::Use)
+        .map(|op| op.vreg().vreg())
+}
+
+impl Candidate {
+    fn new(func: &TextFunction) -> Candidate {
+        let blocks = func
+            .blocks
+            .iter()
+            .map(|block| CandidateBlock {
+                params: block.params.clone(),
+                insts: block
+                    .insts
+                    .iter()
+                    .map(|inst| (inst.index(), func.insts[inst.index()].clone()))
+                    .collect(),
+                succs: block.succs.clone(),
+                preds: block.preds.clone(),
+                branch_args: block.branch_args.clone(),
+            })
+            .collect();
+        let mut func = func.clone();
+        func.blocks.clear();
+        func.insts.clear();
+        Candidate { func, blocks }
+    }
+
+    fn build(&self) -> TextFunction {
+        let mut func = self.func.clone();
+        let mut keys = vec![];
+        for block in &self.blocks {
+            let first = func.insts.len();
+            for (key, inst) in &block.insts {
+                keys.push(*key);
+                func.insts.push(inst.clone());
+            }
+            func.blocks.push(BlockData {
+                insts: InstRange::forward(Inst::new(first), Inst::new(func.insts.len())),
+                params: block.params.clone(),
+                succs: block.succs.clone(),
+                preds: block.preds.clone(),
+                branch_args: block.branch_args.clone(),
+            });
+        }
+        // Each debug label keeps the instructions that remain of its
+        // range.
+        let new_index = |inst: Inst| {
+            let index = keys.iter().position(|&key| key >= inst.index());
+            Inst::new(index.unwrap_or(keys.len()))
+        };
+        func.debug_value_labels = self
+            .func
+            .debug_value_labels
+            .iter()
+            .map(|&(vreg, from, to, label)| (vreg, new_index(from), new_index(to), label))
+            .filter(|&(_, from, to, _)| from < to)
+            .collect();
+        func
+    }
+
+    fn block_of(&self, key: usize) -> Option<usize> {
+        self.blocks.iter().position(|block| block.key() == key)
+    }
+
+    /// The block and position of each instruction, by its key.
+    fn inst_positions(&self) -> FxHashMap<usize, (usize, usize)> {
+        let mut positions = FxHashMap::default();
+        for (b, block) in self.blocks.iter().enumerate() {
+            for (i, &(key, _)) in block.insts.iter().enumerate() {
+                positions.insert(key, (b, i));
+            }
+        }
+        positions
+    }
+
+    fn remove_pred(&mut self, block: Block, pred: Block) {
+        let preds = &mut self.blocks[block.index()].preds;
+        if let Some(i) = preds.iter().position(|&p| p == pred) {
+            preds.remove(i);
+        }
+    }
+
+    /// Delete the blocks in `removed`, none of which may be a
+    /// successor of a remaining block, and renumber the others.
+    fn remove_blocks(&mut self, removed: &FxHashSet<usize>) {
+        let mut new_index = vec![None; self.blocks.len()];
+        let mut next = 0;
+        for (b, index) in new_index.iter_mut().enumerate() {
+            if !removed.contains(&b) {
+                *index = Some(Block::new(next));
+                next += 1;
+            }
+        }
+        let blocks = std::mem::take(&mut self.blocks);
+        for (b, mut block) in blocks.into_iter().enumerate() {
+            if new_index[b].is_none() {
+                continue;
+            }
+            for succ in &mut block.succs {
+                *succ = new_index[succ.index()].unwrap();
+            }
+            block.preds = block
+                .preds
+                .iter()
+                .filter_map(|pred| new_index[pred.index()])
+                .collect();
+            self.blocks.push(block);
+        }
+        self.func.entry = new_index[self.func.entry.index()].unwrap();
+    }
+
+    /// Delete the blocks that the entry block no longer reaches, and
+    /// the vregs that they define.
+    fn remove_unreachable(&mut self) {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack = vec![self.func.entry];
+        while let Some(block) = stack.pop() {
+            if !reachable[block.index()] {
+                reachable[block.index()] = true;
+                stack.extend_from_slice(&self.blocks[block.index()].succs);
+            }
+        }
+        let mut removed = FxHashSet::default();
+        let mut vregs = vec![];
+        for (b, block) in self.blocks.iter().enumerate() {
+            if !reachable[b] {
+                removed.insert(b);
+                vregs.extend(block.params.iter().map(|param| param.vreg()));
+                for (_, inst) in &block.insts {
+                    vregs.extend(defs(inst));
+                }
+            }
+        }
+        if !removed.is_empty() {
+            self.remove_blocks(&removed);
+            self.remove_vregs(&vregs);
+        }
+    }
+
+    /// Delete the parameter at `idx` of `block`, and the arguments
+    /// passed to it.
+    fn remove_param(&mut self, block: Block, idx: usize) {
+        self.blocks[block.index()].params.remove(idx);
+        let mut preds = self.blocks[block.index()].preds.clone();
+        preds.sort_unstable();
+        preds.dedup();
+        for pred in preds {
+            let pred = &mut self.blocks[pred.index()];
+            for (succ, args) in pred.succs.iter().zip(pred.branch_args.iter_mut()) {
+                if *succ == block && idx < args.len() {
+                    args.remove(idx);
+                }
+            }
+        }
+    }
+
+    /// Rename every mention of a vreg, except in the attributes of
+    /// `self.func`.
+    fn map_vregs(&mut self, f: impl Fn(VReg) -> VReg) {
+        let map_op = |op: Operand| Operand::new(f(op.vreg()), op.constraint(), op.kind(), op.pos());
+        for block in &mut self.blocks {
+            for param in &mut block.params {
+                *param = f(*param);
+            }
+            for (_, inst) in &mut block.insts {
+                for op in &mut inst.operands {
+                    *op = map_op(*op);
+                }
+                if let Some((src, dst)) = inst.is_move {
+                    inst.is_move = Some((map_op(src), map_op(dst)));
+                }
+            }
+            for args in &mut block.branch_args {
+                for arg in args {
+                    *arg = f(*arg);
+                }
+            }
+        }
+    }
+
+    /// Branches, by the key of their block.
+    fn branches(&self) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|block| block.terminator().kind == InstKind::Branch)
+            .map(|block| block.key())
+            .collect()
+    }
+
+    /// Turn the branches at the end of `blocks` into returns.
+    fn make_returns(&mut self, blocks: &[usize]) {
+        for &key in blocks {
+            let b = match self.block_of(key) {
+                Some(b) => b,
+                None => continue,
+            };
+            let block = &mut self.blocks[b];
+            block.insts.last_mut().unwrap().1.kind = InstKind::Ret;
+            block.branch_args.clear();
+            for succ in std::mem::take(&mut block.succs) {
+                self.remove_pred(succ, Block::new(b));
+            }
+        }
+        self.remove_unreachable();
+    }
+
+    /// Edges out of blocks with more than one successor, by the keys
+    /// of both blocks. Removing any other edge would leave a branch
+    /// without successors.
+    fn edges(&self) -> Vec<(usize, usize)> {
+        let mut edges = vec![];
+        for block in &self.blocks {
+            if block.succs.len() > 1 {
+                for succ in &block.succs {
+                    edges.push((block.key(), self.blocks[succ.index()].key()));
+                }
+            }
+        }
+        edges
+    }
+
+    fn remove_edges(&mut self, edges: &[(usize, usize)]) {
+        for &(from, to) in edges {
+            let (from, to) = match (self.block_of(from), self.block_of(to)) {
+                (Some(from), Some(to)) => (from, to),
+                _ => continue,
+            };
+            let block = &mut self.blocks[from];
+            if block.succs.len() < 2 {
+                continue;
+            }
+            if let Some(i) = block.succs.iter().position(|succ| succ.index() == to) {
+                block.succs.remove(i);
+                block.branch_args.remove(i);
+                self.remove_pred(Block::new(to), Block::new(from));
+            }
+        }
+        self.remove_unreachable();
+    }
+
+    /// Blocks that are the only successor of their only predecessor,
+    /// by their key.
+    fn mergeable_blocks(&self) -> Vec<usize> {
+        (0..self.blocks.len())
+            .filter(|&b| self.can_merge(b))
+            .map(|b| self.blocks[b].key())
+            .collect()
+    }
+
+    fn can_merge(&self, b: usize) -> bool {
+        let block = &self.blocks[b];
+        if b == self.func.entry.index() || block.preds.len() != 1 {
+            return false;
+        }
+        let pred = &self.blocks[block.preds[0].index()];
+        block.preds[0].index() != b
+            && pred.succs.len() == 1
+            && pred.branch_args[0].len() == block.params.len()
+    }
+
+    /// Append each of `blocks` to its predecessor, in place of the
+    /// branch to it, and pass the branch arguments directly to the
+    /// uses of its parameters.
+    fn merge_blocks(&mut self, blocks: &[usize]) {
+        for &key in blocks {
+            let b = match self.block_of(key) {
+                Some(b) if self.can_merge(b) => b,
+                _ => continue,
+            };
+            let p = self.blocks[b].preds[0].index();
+            let renames: FxHashMap<usize, VReg> = self.blocks[b]
+                .params
+                .iter()
+                .map(|param| param.vreg())
+                .zip(self.blocks[p].branch_args[0].iter().copied())
+                .collect();
+            self.map_vregs(|vreg| renames.get(&vreg.vreg()).copied().unwrap_or(vreg));
+
+            let block = std::mem::take(&mut self.blocks[b]);
+            let (_, branch) = self.blocks[p].insts.pop().unwrap();
+            let branch_defs: Vec<usize> = defs(&branch).collect();
+            for succ in &block.succs {
+                for pred in &mut self.blocks[succ.index()].preds {
+                    if pred.index() == b {
+                        *pred = Block::new(p);
+                    }
+                }
+            }
+            let pred = &mut self.blocks[p];
+            pred.insts.extend(block.insts);
+            pred.succs = block.succs;
+            pred.branch_args = block.branch_args;
+
+            let mut removed = FxHashSet::default();
+            removed.insert(b);
+            self.remove_blocks(&removed);
+            // This only drops the attributes of the parameters, which
+            // are no longer mentioned.
+            let params: Vec<usize> = renames.keys().copied().collect();
+            self.remove_vregs(&params);
+            self.remove_vregs(&branch_defs);
+        }
+    }
+
+    /// Non-branch, non-return instructions, by key.
+    fn ops(&self) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .flat_map(|block| block.insts.iter())
+            .filter(|(_, inst)| inst.kind == InstKind::Op)
+            .map(|&(key, _)| key)
+            .collect()
+    }
+
+    /// Delete `insts`, and the vregs that they define.
+    fn remove_insts(&mut self, insts: &[usize]) {
+        let insts: FxHashSet<usize> = insts.iter().copied().collect();
+        let mut vregs = vec![];
+        for block in &mut self.blocks {
+            block.insts.retain(|(key, inst)| {
+                if insts.contains(key) && inst.kind == InstKind::Op {
+                    vregs.extend(defs(inst));
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.remove_vregs(&vregs);
+    }
+
+    /// Vregs that are block parameters or defined by an instruction.
+    fn defined_vregs(&self) -> Vec<usize> {
+        let mut vregs = vec![];
+        for block in &self.blocks {
+            vregs.extend(block.params.iter().map(|param| param.vreg()));
+            for (_, inst) in &block.insts {
+                vregs.extend(defs(inst));
+            }
+        }
+        vregs.sort_unstable();
+        vregs.dedup();
+        vregs
+    }
+
+    /// Delete every mention of `vregs`. Deleting a block parameter
+    /// deletes the arguments passed to it, and deleting a branch
+    /// argument deletes the parameter that it is passed to, and so on.
+    fn remove_vregs(&mut self, vregs: &[usize]) {
+        let mut removed = FxHashSet::default();
+        let mut work = vregs.to_vec();
+        while let Some(vreg) = work.pop() {
+            if !removed.insert(vreg) {
+                continue;
+            }
+            for b in 0..self.blocks.len() {
+                for (_, inst) in &mut self.blocks[b].insts {
+                    while let Some(idx) =
+                        inst.operands.iter().position(|op| op.vreg().vreg() == vreg)
+                    {
+                        remove_operand(inst, idx);
+                    }
+                    if let Some((src, dst)) = inst.is_move {
+                        if src.vreg().vreg() == vreg || dst.vreg().vreg() == vreg {
+                            inst.is_move = None;
+                        }
+                    }
+                }
+                while let Some(idx) = self.blocks[b]
+                    .params
+                    .iter()
+                    .position(|param| param.vreg() == vreg)
+                {
+                    self.remove_param(Block::new(b), idx);
+                }
+                let block = &self.blocks[b];
+                for (succ, args) in block.succs.iter().zip(block.branch_args.iter()) {
+                    for (idx, arg) in args.iter().enumerate() {
+                        if arg.vreg() == vreg {
+                            if let Some(param) = self.blocks[succ.index()].params.get(idx) {
+                                work.push(param.vreg());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let func = &mut self.func;
+        func.reftype_vregs
+            .retain(|vreg| !removed.contains(&vreg.vreg()));
+        func.pinned_vregs
+            .retain(|vreg, _| !removed.contains(&vreg.vreg()));
+        func.remat_costs
+            .retain(|vreg, _| !removed.contains(&vreg.vreg()));
+        func.debug_value_labels
+            .retain(|(vreg, ..)| !removed.contains(&vreg.vreg()));
+    }
+
+    /// Use operands, by instruction key and operand index.
+    fn uses(&self) -> Vec<(usize, usize)> {
+        self.operands(|op| op.kind() == OperandKind::Use)
+    }
+
+    fn operands(&self, filter: impl Fn(&Operand) -> bool) -> Vec<(usize, usize)> {
+        let mut operands = vec![];
+        for block in &self.blocks {
+            for (key, inst) in &block.insts {
+                for (idx, op) in inst.operands.iter().enumerate() {
+                    if filter(op) {
+                        operands.push((*key, idx));
+                    }
+                }
+            }
+        }
+        operands
+    }
+
+    fn remove_operands(&mut self, operands: &[(usize, usize)]) {
+        let positions = self.inst_positions();
+        let mut operands = operands.to_vec();
+        // Delete the later operands of each instruction first, so
+        // that the indices of the others stay the same.
+        operands.sort_unstable_by(|a, b| b.cmp(a));
+        for (key, idx) in operands {
+            let (b, i) = positions[&key];
+            remove_operand(&mut self.blocks[b].insts[i].1, idx);
+        }
+    }
+
+    /// Operands with a constraint other than `Reg`, by instruction key
+    /// and operand index.
+    fn constrained(&self) -> Vec<(usize, usize)> {
+        self.operands(|op| op.constraint() != OperandConstraint::Reg)
+    }
+
+    fn relax_constraints(&mut self, operands: &[(usize, usize)]) {
+        let positions = self.inst_positions();
+        for &(key, idx) in operands {
+            let (b, i) = positions[&key];
+            let inst = &mut self.blocks[b].insts[i].1;
+            inst.operands[idx] = with_constraint(inst.operands[idx], OperandConstraint::Reg);
+            inst.is_move = None;
+        }
+    }
+
+    fn insts_where(&self, filter: impl Fn(&InstData) -> bool) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .flat_map(|block| block.insts.iter())
+            .filter(|(_, inst)| filter(inst))
+            .map(|&(key, _)| key)
+            .collect()
+    }
+
+    fn edit_insts(&mut self, insts: &[usize], edit: impl Fn(&mut InstData)) {
+        let positions = self.inst_positions();
+        for key in insts {
+            let (b, i) = positions[key];
+            edit(&mut self.blocks[b].insts[i].1);
+        }
+    }
+
+    fn clobbering(&self) -> Vec<usize> {
+        self.insts_where(|inst| !inst.clobbers.is_empty())
+    }
+
+    fn remove_clobbers(&mut self, insts: &[usize]) {
+        self.edit_insts(insts, |inst| inst.clobbers = PRegSet::empty());
+    }
+
+    fn safepoints(&self) -> Vec<usize> {
+        self.insts_where(|inst| inst.is_safepoint)
+    }
+
+    fn remove_safepoints(&mut self, insts: &[usize]) {
+        self.edit_insts(insts, |inst| inst.is_safepoint = false);
+    }
+
+    fn moves(&self) -> Vec<usize> {
+        self.insts_where(|inst| inst.is_move.is_some())
+    }
+
+    fn remove_moves(&mut self, insts: &[usize]) {
+        self.edit_insts(insts, |inst| inst.is_move = None);
+    }
+
+    /// Vregs that are reference-typed, pinned or rematerializable.
+    fn attributed_vregs(&self) -> Vec<VReg> {
+        let func = &self.func;
+        let mut vregs: Vec<VReg> = func
+            .reftype_vregs
+            .iter()
+            .chain(func.pinned_vregs.keys())
+            .chain(func.remat_costs.keys())
+            .copied()
+            .collect();
+        vregs.sort_unstable();
+        vregs.dedup();
+        vregs
+    }
+
+    fn remove_attributes(&mut self, vregs: &[VReg]) {
+        let func = &mut self.func;
+        func.reftype_vregs.retain(|vreg| !vregs.contains(vreg));
+        func.pinned_vregs.retain(|vreg, _| !vregs.contains(vreg));
+        func.remat_costs.retain(|vreg, _| !vregs.contains(vreg));
+    }
+
+    fn debug_labels(&self) -> Vec<(VReg, Inst, Inst, u32)> {
+        self.func.debug_value_labels.clone()
+    }
+
+    fn remove_debug_labels(&mut self, labels: &[(VReg, Inst, Inst, u32)]) {
+        self.func
+            .debug_value_labels
+            .retain(|label| !labels.contains(label));
+    }
+
+    /// Renumber the vregs that are still mentioned densely, keeping
+    /// their order.
+    fn compact_vregs(&mut self) {
+        let mut vregs = vec![];
+        for block in &self.blocks {
+            vregs.extend(block.params.iter().map(|param| param.vreg()));
+            for (_, inst) in &block.insts {
+                vregs.extend(inst.operands.iter().map(|op| op.vreg().vreg()));
+                if let Some((src, dst)) = inst.is_move {
+                    vregs.push(src.vreg().vreg());
+                    vregs.push(dst.vreg().vreg());
+                }
+            }
+            for args in &block.branch_args {
+                vregs.extend(args.iter().map(|arg| arg.vreg()));
+            }
+        }
+        vregs.sort_unstable();
+        vregs.dedup();
+        let new_index: FxHashMap<usize, usize> = vregs
+            .iter()
+            .enumerate()
+            .map(|(new, &old)| (old, new))
+            .collect();
+        let rename = |vreg: VReg| VReg::new(new_index[&vreg.vreg()], vreg.class());
+        self.map_vregs(rename);
+
+        let func = &mut self.func;
+        let mentioned = |vreg: &VReg| new_index.contains_key(&vreg.vreg());
+        func.num_vregs = vregs.len();
+        func.reftype_vregs = func
+            .reftype_vregs
+            .iter()
+            .filter(|vreg| mentioned(vreg))
+            .map(|&vreg| rename(vreg))
+            .collect();
+        func.pinned_vregs = func
+            .pinned_vregs
+            .iter()
+            .filter(|(vreg, _)| mentioned(vreg))
+            .map(|(&vreg, &preg)| (rename(vreg), preg))
+            .collect();
+        func.remat_costs = func
+            .remat_costs
+            .iter()
+            .filter(|(vreg, _)| mentioned(vreg))
+            .map(|(&vreg, &cost)| (rename(vreg), cost))
+            .collect();
+        func.debug_value_labels = func
+            .debug_value_labels
+            .iter()
+            .filter(|(vreg, ..)| mentioned(vreg))
+            .map(|&(vreg, from, to, label)| (rename(vreg), from, to, label))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzzing::func::{machine_env, Func};
+    use arbitrary::{Arbitrary, Unstructured};
+
+    #[test]
+    fn reduce_random_function() {
+        // A deterministic stream of bytes to build a function from.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let bytes: Vec<u8> = (0..1_000_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let func = Func::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+        let env = machine_env();
+        let inserts_moves = |func: &TextFunction| match crate::ion::run(func, &env, false) {
+            Ok(output) => !output.edits.is_empty(),
+            Err(_) => false,
+        };
+        assert!(inserts_moves(&TextFunction::from_function(&func)));
+
+        let reduced = reduce(&func, inserts_moves);
+        assert!(inserts_moves(&reduced));
+        assert!(reduced.num_insts() < func.num_insts());
+        let cfginfo = CFGInfo::new(&reduced).unwrap();
+        validate_ssa(&reduced, &cfginfo).unwrap();
+        // The result is in the text format, and reads back the same.
+        let text = reduced.to_string();
+        let parsed = crate::text::parse_function(&text).unwrap();
+        assert_eq!(parsed.to_string(), text);
+    }
+}
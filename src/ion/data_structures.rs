@@ -17,12 +17,13 @@ use crate::cfg::CFGInfo;
 use crate::index::ContainerComparator;
 use crate::indexset::IndexSet;
 use crate::{
-    define_index, Allocation, Block, Edit, Function, Inst, MachineEnv, Operand, PReg, ProgPoint,
-    RegClass, VReg,
+    define_index, Allocation, AllocationObserver, Block, Edit, Function, Inst, MachineEnv, Operand,
+    OperandConstraint, OperandKind, OperandPos, PReg, PRegSet, ProgPoint, QualityMetrics, RegClass,
+    StackLoc, VReg, REMAT_RELOAD_COST,
 };
 use smallvec::SmallVec;
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 
 /// A range from `from` (inclusive) to `to` (exclusive).
@@ -74,7 +75,8 @@ impl std::cmp::Ord for CodeRange {
     }
 }
 
-define_index!(LiveBundleIndex);
+// Bundles are public so that `AllocationEvent`s can name them.
+pub use crate::Bundle as LiveBundleIndex;
 define_index!(LiveRangeIndex);
 define_index!(SpillSetIndex);
 define_index!(UseIndex);
@@ -153,9 +155,70 @@ impl LiveRange {
     }
 }
 
+/// An `Operand` without its vreg, which is that of the live range
+/// holding the use: the low word of `Operand::bits()`. This keeps a
+/// `Use` at 12 bytes even though operands take 64 bits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct UseOperand {
+    bits: u32,
+}
+
+impl UseOperand {
+    #[inline(always)]
+    pub fn new(operand: Operand) -> Self {
+        Self {
+            bits: operand.bits() as u32,
+        }
+    }
+
+    /// The fields as an operand of vreg 0 of the right class.
+    #[inline(always)]
+    fn operand(self) -> Operand {
+        Operand::from_bits(self.bits as u64)
+    }
+
+    #[inline(always)]
+    pub fn kind(self) -> OperandKind {
+        self.operand().kind()
+    }
+
+    #[inline(always)]
+    pub fn pos(self) -> OperandPos {
+        self.operand().pos()
+    }
+
+    #[inline(always)]
+    pub fn constraint(self) -> OperandConstraint {
+        self.operand().constraint()
+    }
+
+    /// The same use with another constraint.
+    pub fn with_constraint(self, constraint: OperandConstraint) -> Self {
+        let operand = self.operand();
+        Self::new(Operand::new(
+            operand.vreg(),
+            constraint,
+            operand.kind(),
+            operand.pos(),
+        ))
+    }
+}
+
+impl std::fmt::Debug for UseOperand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?}@{:?}: {}",
+            self.kind(),
+            self.pos(),
+            self.constraint()
+        )
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Use {
-    pub operand: Operand,
+    pub operand: UseOperand,
     pub pos: ProgPoint,
     pub slot: u8,
     pub weight: u16,
@@ -165,7 +228,7 @@ impl Use {
     #[inline(always)]
     pub fn new(operand: Operand, pos: ProgPoint, slot: u8) -> Self {
         Self {
-            operand,
+            operand: UseOperand::new(operand),
             pos,
             slot,
             // Weight is updated on insertion into LR.
@@ -291,6 +354,15 @@ pub enum FixedRegFixupLevel {
     Secondary,
 }
 
+#[derive(Clone, Debug)]
+pub struct StackLocFixup {
+    pub inst: Inst,
+    pub slot: u8,
+    pub kind: OperandKind,
+    pub loc: StackLoc,
+    pub vreg: VRegIndex,
+}
+
 /// The field order is significant: these are sorted so that a
 /// scan over vregs, then blocks in each range, can scan in
 /// order through this (sorted) list and add allocs to the
@@ -355,17 +427,33 @@ pub struct Env<'a, F: Function> {
     pub bundles: Vec<LiveBundle>,
     pub spillsets: Vec<SpillSet>,
     pub vregs: Vec<VRegData>,
+    /// Empty use lists and range lists with heap storage, left over
+    /// from earlier runs, for new ranges, bundles and vregs to take
+    /// before allocating their own.
+    pub free_use_lists: Vec<UseList>,
+    pub free_range_lists: Vec<LiveRangeList>,
     pub pregs: Vec<PRegData>,
+    /// Registers that overlap each register; see `MachineEnv::aliases`.
+    /// Indexed by `PReg::index()`, but only as long as needed to cover
+    /// the registers that have any aliases at all, so that targets
+    /// without overlapping registers pay nothing for it.
+    pub preg_aliases: Vec<SmallVec<[PReg; 4]>>,
+    /// `MachineEnv::reg_subsets`, followed by
+    /// `MachineEnv::stackmap_regs` if it is not empty, so that
+    /// requirements can refer to either by index.
+    pub reg_subsets: Vec<PRegSet>,
+    /// The index of `MachineEnv::stackmap_regs` in `reg_subsets`, if
+    /// reference-typed values may stay in registers at safepoints.
+    pub stackmap_regs_subset: Option<usize>,
     pub allocation_queue: PrioQueue,
     pub safepoints: Vec<Inst>, // Sorted list of safepoint insts.
-    pub safepoints_per_vreg: HashMap<usize, HashSet<Inst>>,
 
     pub spilled_bundles: Vec<LiveBundleIndex>,
     pub spillslots: Vec<SpillSlotData>,
     pub slots_by_size: Vec<SpillSlotList>,
 
-    pub extra_spillslots_by_class: [SmallVec<[Allocation; 2]>; 2],
-    pub preferred_victim_by_class: [PReg; 2],
+    pub extra_spillslots_by_class: [SmallVec<[Allocation; 2]>; RegClass::COUNT],
+    pub preferred_victim_by_class: [PReg; RegClass::COUNT],
 
     // Program moves: these are moves in the provided program that we
     // handle with our internal machinery, in order to avoid the
@@ -392,6 +480,13 @@ pub struct Env<'a, F: Function> {
     // was to the approprate PReg.
     pub multi_fixed_reg_fixups: Vec<MultiFixedRegFixup>,
 
+    // Operands constrained to a fixed stack location are allocated
+    // as `Any` operands, and noted here; when we produce the final
+    // edit-list, we insert a copy between the operand's allocation
+    // and the stack location and then rewrite the operand's
+    // allocation to the location itself.
+    pub stack_loc_fixups: Vec<StackLocFixup>,
+
     pub inserted_moves: Vec<InsertedMove>,
 
     // Output:
@@ -404,12 +499,67 @@ pub struct Env<'a, F: Function> {
 
     pub allocated_bundle_count: usize,
 
+    /// The bundle-processing steps left before the allocator falls
+    /// back to splitting bundles around their uses and spilling.
+    pub budget: usize,
+
     pub stats: Stats,
+    /// The quality metrics that only the allocator knows; see
+    /// `Output::compute_quality`.
+    pub quality: QualityMetrics,
 
     // For debug output only: a list of textual annotations at every
     // ProgPoint to insert into the final allocated program listing.
     pub debug_annotations: std::collections::HashMap<ProgPoint, Vec<String>>,
     pub annotations_enabled: bool,
+
+    // For visualization only: the HTML rendering of the state so far,
+    // if requested, and whether to render intermediate states too.
+    pub html: Option<String>,
+    pub html_intermediate: bool,
+
+    // The client's observer of allocation decisions, if any.
+    pub observer: Option<&'a dyn AllocationObserver>,
+}
+
+/// Storage kept between allocator runs so that its buffers can be
+/// reused rather than allocated afresh for every function. Each run
+/// moves these buffers into its `Env`, clears them, and moves them
+/// back when it is done.
+#[derive(Debug, Default)]
+pub struct Ctx {
+    pub(crate) cfginfo: CFGInfo,
+    pub(crate) liveins: Vec<IndexSet>,
+    pub(crate) liveouts: Vec<IndexSet>,
+    pub(crate) blockparam_outs: Vec<BlockparamOut>,
+    pub(crate) blockparam_ins: Vec<BlockparamIn>,
+
+    pub(crate) ranges: Vec<LiveRange>,
+    pub(crate) bundles: Vec<LiveBundle>,
+    pub(crate) spillsets: Vec<SpillSet>,
+    pub(crate) vregs: Vec<VRegData>,
+    pub(crate) free_use_lists: Vec<UseList>,
+    pub(crate) free_range_lists: Vec<LiveRangeList>,
+    pub(crate) pregs: Vec<PRegData>,
+    pub(crate) preg_aliases: Vec<SmallVec<[PReg; 4]>>,
+    pub(crate) reg_subsets: Vec<PRegSet>,
+    pub(crate) allocation_queue: PrioQueue,
+    pub(crate) safepoints: Vec<Inst>,
+    pub(crate) spilled_bundles: Vec<LiveBundleIndex>,
+    pub(crate) spillslots: Vec<SpillSlotData>,
+    pub(crate) slots_by_size: Vec<SpillSlotList>,
+    pub(crate) extra_spillslots_by_class: [SmallVec<[Allocation; 2]>; RegClass::COUNT],
+
+    pub(crate) prog_move_srcs: Vec<((VRegIndex, Inst), Allocation)>,
+    pub(crate) prog_move_dsts: Vec<((VRegIndex, Inst), Allocation)>,
+    pub(crate) prog_move_merges: Vec<(LiveRangeIndex, LiveRangeIndex)>,
+
+    pub(crate) multi_fixed_reg_fixups: Vec<MultiFixedRegFixup>,
+    pub(crate) stack_loc_fixups: Vec<StackLocFixup>,
+    pub(crate) inserted_moves: Vec<InsertedMove>,
+    pub(crate) edits: Vec<(PosWithPrio, Edit)>,
+
+    pub(crate) debug_annotations: std::collections::HashMap<ProgPoint, Vec<String>>,
 }
 
 impl<'a, F: Function> Env<'a, F> {
@@ -422,6 +572,14 @@ impl<'a, F: Function> Env<'a, F> {
         VReg::new(index.index(), class)
     }
 
+    /// Get the registers that overlap the given one.
+    #[inline]
+    pub fn aliases(&self, preg: PReg) -> &[PReg] {
+        self.preg_aliases
+            .get(preg.index())
+            .map_or(&[], |aliases| &aliases[..])
+    }
+
     /// Record the class of a VReg. We learn this only when we observe
     /// the VRegs in use.
     pub fn observe_vreg_class(&mut self, vreg: VReg) {
@@ -435,6 +593,14 @@ impl<'a, F: Function> Env<'a, F> {
     pub fn is_vreg_used(&self, index: VRegIndex) -> bool {
         self.vregs[index.index()].class.is_some()
     }
+
+    /// Get the cost of rematerializing a vreg, if it is cheaper than
+    /// a reload (see `Function::remat_cost`).
+    pub fn remat_cost(&self, vreg: VReg) -> Option<u32> {
+        self.func
+            .remat_cost(vreg)
+            .filter(|&cost| cost < REMAT_RELOAD_COST)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -444,7 +610,7 @@ pub struct SpillSlotData {
     pub alloc: Allocation,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct SpillSlotList {
     pub slots: SmallVec<[SpillSlotIndex; 32]>,
     pub probe_start: usize,
@@ -463,7 +629,7 @@ impl SpillSlotList {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct PrioQueue {
     pub heap: std::collections::BinaryHeap<PrioQueueEntry>,
 }
@@ -565,6 +731,10 @@ impl PrioQueue {
     pub fn pop(&mut self) -> Option<(LiveBundleIndex, PReg)> {
         self.heap.pop().map(|entry| (entry.bundle, entry.reg_hint))
     }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+    }
 }
 
 impl LiveRangeSet {
@@ -581,6 +751,9 @@ pub struct InsertedMove {
     pub from_alloc: Allocation,
     pub to_alloc: Allocation,
     pub to_vreg: Option<VReg>,
+    /// Recompute the value of `to_vreg` into `to_alloc` rather than
+    /// reloading it from `from_alloc`.
+    pub remat: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -592,6 +765,7 @@ pub enum InsertMovePrio {
     MultiFixedRegInitial,
     MultiFixedRegSecondary,
     ReusedInput,
+    FixedStackLoc,
     OutEdgeMoves,
 }
 
@@ -632,6 +806,11 @@ pub struct Stats {
     pub process_bundle_reg_success_any: usize,
     pub evict_bundle_event: usize,
     pub evict_bundle_count: usize,
+    /// Bundles split around their uses because the budget ran out.
+    pub budget_fallback_bundles: usize,
+    /// Spill bundles left in their spillslots, without trying
+    /// registers for them, because the budget ran out.
+    pub budget_spilled_bundles: usize,
     pub splits: usize,
     pub splits_clobbers: usize,
     pub splits_hot: usize,
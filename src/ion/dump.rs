@@ -1,7 +1,9 @@
 //! Debugging output.
 
-use super::Env;
-use crate::{Block, Function, ProgPoint};
+use super::{Env, VRegIndex};
+use crate::{Allocation, AllocationEvent, Block, Edit, Function, InstPosition, PReg, ProgPoint};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
 
 impl<'a, F: Function> Env<'a, F> {
     pub fn dump_state(&self) {
@@ -59,6 +61,13 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 
+    /// Report `event` to the client's observer, if any.
+    pub fn observe(&self, event: AllocationEvent<'_>) {
+        if let Some(observer) = self.observer {
+            observer.event(&event);
+        }
+    }
+
     pub fn dump_results(&self) {
         log::info!("=== REGALLOC RESULTS ===");
         for block in 0..self.func.num_blocks() {
@@ -137,3 +146,267 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 }
+
+/// A column of the HTML timeline: a register, a spillslot, or the
+/// place of ranges whose bundle has no location yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Column {
+    Reg(PReg),
+    Slot(Allocation),
+    Unassigned,
+}
+
+/// A background color for a vreg, so that its ranges can be told
+/// apart from their neighbors' at a glance.
+fn vreg_color(vreg: VRegIndex) -> String {
+    format!("hsl({}, 70%, 85%)", vreg.index() * 47 % 360)
+}
+
+fn progpoint_name(point: ProgPoint) -> String {
+    format!(
+        "inst{}-{}",
+        point.inst().index(),
+        match point.pos() {
+            InstPosition::Before => "pre",
+            InstPosition::After => "post",
+        }
+    )
+}
+
+/// The start of the page that `Env::visualize` appends sections to.
+pub(crate) const HTML_HEADER: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>regalloc2 state</title>
+<style>
+body { font-family: monospace; font-size: 12px; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { border: 1px solid #ccc; padding: 0 4px; white-space: nowrap; }
+th { background: #eee; position: sticky; top: 0; }
+tr.block td { background: #ddd; font-weight: bold; }
+td.reserved { background: #aaa; }
+summary { font-size: 16px; font-weight: bold; margin: 1em 0; }
+</style>
+</head>
+<body>
+"#;
+
+/// The end of the page.
+pub(crate) const HTML_FOOTER: &str = "</body>\n</html>\n";
+
+impl<'a, F: Function> Env<'a, F> {
+    /// Append the current live ranges, bundles, spillsets, register
+    /// commitment maps and inserted moves to `self.html`, as a
+    /// section titled `title`, if a visualization was requested. The
+    /// state at the end of allocation is always shown, and earlier
+    /// states only if `self.html_intermediate` is set.
+    pub fn visualize(&mut self, title: &str, is_final: bool) {
+        if self.html.is_none() || !(is_final || self.html_intermediate) {
+            return;
+        }
+        let mut html = String::new();
+        self.render_html(&mut html, title, is_final)
+            .expect("writing to a String cannot fail");
+        self.html.as_mut().unwrap().push_str(&html);
+    }
+
+    fn render_html(&self, w: &mut String, title: &str, is_final: bool) -> std::fmt::Result {
+        writeln!(
+            w,
+            "<details{}><summary>{}</summary>",
+            if is_final { " open" } else { "" },
+            title
+        )?;
+
+        // Which vregs are where at each program point: first from the
+        // bundles, which know the location of each of their ranges,
+        // then from the commitment maps for the reservations of fixed
+        // registers and clobbers, which belong to no range.
+        let mut cells: HashMap<(Column, u32), Vec<VRegIndex>> = HashMap::new();
+        let mut reserved: HashSet<(Column, u32)> = HashSet::new();
+        let mut columns: BTreeSet<Column> = BTreeSet::new();
+        for bundle in &self.bundles {
+            let slot = self.spillsets[bundle.spillset.index()].slot;
+            let column = if let Some(preg) = bundle.allocation.as_reg() {
+                Column::Reg(preg)
+            } else if bundle.allocation.is_some() {
+                Column::Slot(bundle.allocation)
+            } else if slot.is_valid() {
+                Column::Slot(self.spillslots[slot.index()].alloc)
+            } else {
+                Column::Unassigned
+            };
+            for entry in &bundle.ranges {
+                columns.insert(column);
+                let vreg = self.ranges[entry.index.index()].vreg;
+                for point in entry.range.from.to_index()..entry.range.to.to_index() {
+                    cells.entry((column, point)).or_default().push(vreg);
+                }
+            }
+        }
+        for (i, preg) in self.pregs.iter().enumerate() {
+            for (key, lr) in &preg.allocations.btree {
+                if lr.is_invalid() {
+                    let column = Column::Reg(PReg::from_index(i));
+                    columns.insert(column);
+                    reserved.extend((key.from..key.to).map(|point| (column, point)));
+                }
+            }
+        }
+        let mut edits: HashMap<ProgPoint, Vec<String>> = HashMap::new();
+        for (pos_prio, edit) in &self.edits {
+            let text = match edit {
+                Edit::Move { from, to } => format!("{} &rarr; {}", from, to),
+                Edit::Remat { vreg, to } => format!("remat {} &rarr; {}", vreg, to),
+            };
+            edits.entry(pos_prio.pos).or_default().push(text);
+        }
+
+        // The timeline: a row per program point, a column per
+        // location.
+        write!(w, "<table>\n<tr><th>point</th><th>instruction</th>")?;
+        for column in &columns {
+            match column {
+                Column::Reg(preg) => write!(w, "<th>{}</th>", preg)?,
+                Column::Slot(alloc) => write!(w, "<th>{}</th>", alloc)?,
+                Column::Unassigned => write!(w, "<th>unassigned</th>")?,
+            }
+        }
+        writeln!(w, "<th>edits</th></tr>")?;
+        for block in 0..self.func.num_blocks() {
+            let block = Block::new(block);
+            writeln!(
+                w,
+                "<tr class=\"block\"><td colspan=\"{}\">block{}</td></tr>",
+                columns.len() + 3,
+                block.index()
+            )?;
+            for inst in self.func.block_insns(block).iter() {
+                for point in [ProgPoint::before(inst), ProgPoint::after(inst)] {
+                    write!(w, "<tr><td>{}</td><td>", progpoint_name(point))?;
+                    if point.pos() == InstPosition::Before {
+                        let ops = self
+                            .func
+                            .inst_operands(inst)
+                            .iter()
+                            .enumerate()
+                            .map(|(i, op)| {
+                                if is_final {
+                                    format!("{} [{}]", op, self.get_alloc(inst, i))
+                                } else {
+                                    format!("{}", op)
+                                }
+                            })
+                            .collect::<Vec<_>>();
+                        write!(w, "{}", ops.join(", "))?;
+                    }
+                    write!(w, "</td>")?;
+                    for &column in &columns {
+                        let index = point.to_index();
+                        match cells.get(&(column, index)) {
+                            Some(vregs) => {
+                                let names = vregs
+                                    .iter()
+                                    .map(|v| format!("v{}", v.index()))
+                                    .collect::<Vec<_>>();
+                                write!(
+                                    w,
+                                    "<td style=\"background: {}\">{}</td>",
+                                    vreg_color(vregs[0]),
+                                    names.join(" ")
+                                )?;
+                            }
+                            None if reserved.contains(&(column, index)) => {
+                                write!(w, "<td class=\"reserved\">fixed</td>")?;
+                            }
+                            None => write!(w, "<td></td>")?,
+                        }
+                    }
+                    let point_edits = edits.get(&point).map(|e| &e[..]).unwrap_or(&[]);
+                    writeln!(w, "<td>{}</td></tr>", point_edits.join("<br>"))?;
+                }
+            }
+        }
+        writeln!(w, "</table>")?;
+
+        writeln!(
+            w,
+            "<table>\n<tr><th>bundle</th><th>spillset</th><th>allocation</th>\
+             <th>spill weight</th><th>prio</th><th>ranges</th></tr>"
+        )?;
+        for (i, bundle) in self.bundles.iter().enumerate() {
+            if bundle.ranges.is_empty() {
+                continue;
+            }
+            let ranges = bundle
+                .ranges
+                .iter()
+                .map(|entry| {
+                    let range = &self.ranges[entry.index.index()];
+                    format!(
+                        "v{} {}..{} ({} uses)",
+                        range.vreg.index(),
+                        progpoint_name(entry.range.from),
+                        progpoint_name(entry.range.to),
+                        range.uses.len()
+                    )
+                })
+                .collect::<Vec<_>>();
+            writeln!(
+                w,
+                "<tr><td>bundle{}</td><td>spillset{}</td><td>{}</td><td>{}</td>\
+                 <td>{}</td><td>{}</td></tr>",
+                i,
+                bundle.spillset.index(),
+                bundle.allocation,
+                bundle.cached_spill_weight(),
+                bundle.prio,
+                ranges.join("<br>")
+            )?;
+        }
+        writeln!(w, "</table>")?;
+
+        writeln!(
+            w,
+            "<table>\n<tr><th>spillset</th><th>vregs</th><th>class</th><th>slot</th>\
+             <th>hint</th><th>required</th><th>splits</th><th>spill bundle</th></tr>"
+        )?;
+        for (i, spillset) in self.spillsets.iter().enumerate() {
+            let vregs = spillset
+                .vregs
+                .iter()
+                .map(|v| format!("v{}", v.index()))
+                .collect::<Vec<_>>();
+            let slot = if spillset.slot.is_valid() {
+                format!("{}", self.spillslots[spillset.slot.index()].alloc)
+            } else {
+                "-".to_string()
+            };
+            let hint = if spillset.reg_hint != PReg::invalid() {
+                format!("{}", spillset.reg_hint)
+            } else {
+                "-".to_string()
+            };
+            let spill_bundle = if spillset.spill_bundle.is_valid() {
+                format!("bundle{}", spillset.spill_bundle.index())
+            } else {
+                "-".to_string()
+            };
+            writeln!(
+                w,
+                "<tr><td>spillset{}</td><td>{}</td><td>{:?}</td><td>{}</td><td>{}</td>\
+                 <td>{}</td><td>{}</td><td>{}</td></tr>",
+                i,
+                vregs.join(" "),
+                spillset.class,
+                slot,
+                hint,
+                spillset.required,
+                spillset.splits,
+                spill_bundle
+            )?;
+        }
+        writeln!(w, "</table>\n</details>")
+    }
+}
@@ -19,16 +19,16 @@ use super::{
 };
 use crate::indexset::IndexSet;
 use crate::ion::data_structures::{
-    BlockparamIn, BlockparamOut, FixedRegFixupLevel, MultiFixedRegFixup,
+    BlockparamIn, BlockparamOut, FixedRegFixupLevel, MultiFixedRegFixup, StackLocFixup,
 };
 use crate::{
     Allocation, Block, Function, Inst, InstPosition, Operand, OperandConstraint, OperandKind,
-    OperandPos, PReg, ProgPoint, RegAllocError, VReg,
+    OperandPos, PReg, ProgPoint, RegAllocError, VReg, REMAT_RELOAD_COST,
 };
 use fxhash::{FxHashMap, FxHashSet};
 use slice_group_by::GroupByMut;
 use smallvec::{smallvec, SmallVec};
-use std::collections::{HashSet, VecDeque};
+use std::collections::VecDeque;
 
 /// A spill weight computed for a certain Use.
 #[derive(Clone, Copy, Debug)]
@@ -47,7 +47,9 @@ pub fn spill_weight_from_constraint(
     let def_bonus: f32 = if is_def { 2000.0 } else { 0.0 };
     let constraint_bonus: f32 = match constraint {
         OperandConstraint::Any => 1000.0,
-        OperandConstraint::Reg | OperandConstraint::FixedReg(_) => 2000.0,
+        OperandConstraint::Reg
+        | OperandConstraint::FixedReg(_)
+        | OperandConstraint::RegSubset(_) => 2000.0,
         _ => 0.0,
     };
     SpillWeight(hot_bonus + def_bonus + constraint_bonus)
@@ -101,31 +103,59 @@ impl std::ops::Add<SpillWeight> for SpillWeight {
 impl<'a, F: Function> Env<'a, F> {
     pub fn create_pregs_and_vregs(&mut self) {
         // Create PRegs from the env.
-        self.pregs.resize(
-            PReg::NUM_INDEX,
-            PRegData {
-                allocations: LiveRangeSet::new(),
-                is_stack: false,
-            },
-        );
+        self.pregs.resize_with(PReg::NUM_INDEX, || PRegData {
+            allocations: LiveRangeSet::new(),
+            is_stack: false,
+        });
         for &preg in &self.env.fixed_stack_slots {
             self.pregs[preg.index()].is_stack = true;
         }
+        for &(a, b) in &self.env.aliases {
+            debug_assert_eq!(a.class(), b.class());
+            debug_assert_ne!(a, b);
+            let len = std::cmp::max(a.index(), b.index()) + 1;
+            if self.preg_aliases.len() < len {
+                self.preg_aliases.resize(len, smallvec![]);
+            }
+            if !self.preg_aliases[a.index()].contains(&b) {
+                self.preg_aliases[a.index()].push(b);
+                self.preg_aliases[b.index()].push(a);
+            }
+        }
+        self.reg_subsets.extend_from_slice(&self.env.reg_subsets);
+        if !self.env.stackmap_regs.is_empty() {
+            self.stackmap_regs_subset = Some(self.reg_subsets.len());
+            self.reg_subsets.push(self.env.stackmap_regs);
+        }
         for class in 0..self.preferred_victim_by_class.len() {
-            self.preferred_victim_by_class[class] = self.env.non_preferred_regs_by_class[class]
-                .last()
-                .or(self.env.preferred_regs_by_class[class].last())
+            // The victim is saved to and restored from a spillslot
+            // around its use. Prefer one without aliases: otherwise,
+            // live values in overlapping registers survive only
+            // because the restore writes back the same bits, which
+            // the checker cannot verify.
+            let env = self.env;
+            let candidates = || {
+                env.non_preferred_regs_by_class[class]
+                    .iter()
+                    .rev()
+                    .chain(env.preferred_regs_by_class[class].iter().rev())
+            };
+            let victim = candidates()
+                .find(|&&preg| self.aliases(preg).is_empty())
+                .or_else(|| candidates().next())
                 .cloned()
                 .unwrap_or(PReg::invalid());
+            self.preferred_victim_by_class[class] = victim;
         }
         // Create VRegs from the vreg count.
         for idx in 0..self.func.num_vregs() {
             // We'll fill in the real details when we see the def.
             let reg = VReg::new(idx, RegClass::Int);
+            let ranges = self.free_range_lists.pop().unwrap_or_default();
             self.add_vreg(
                 reg,
                 VRegData {
-                    ranges: smallvec![],
+                    ranges,
                     blockparam: Block::invalid(),
                     is_ref: false,
                     // We'll learn the RegClass as we scan the code.
@@ -155,9 +185,10 @@ impl<'a, F: Function> Env<'a, F> {
 
     pub fn create_bundle(&mut self) -> LiveBundleIndex {
         let bundle = self.bundles.len();
+        let ranges = self.free_range_lists.pop().unwrap_or_default();
         self.bundles.push(LiveBundle {
             allocation: Allocation::none(),
-            ranges: smallvec![],
+            ranges,
             spillset: SpillSetIndex::invalid(),
             prio: 0,
             spill_weight_and_props: 0,
@@ -167,6 +198,7 @@ impl<'a, F: Function> Env<'a, F> {
 
     pub fn create_liverange(&mut self, range: CodeRange) -> LiveRangeIndex {
         let idx = self.ranges.len();
+        let uses = self.free_use_lists.pop().unwrap_or_default();
 
         self.ranges.push(LiveRange {
             range,
@@ -174,7 +206,7 @@ impl<'a, F: Function> Env<'a, F> {
             bundle: LiveBundleIndex::invalid(),
             uses_spill_weight_and_flags: 0,
 
-            uses: smallvec![],
+            uses,
 
             merged_into: LiveRangeIndex::invalid(),
         });
@@ -262,11 +294,21 @@ impl<'a, F: Function> Env<'a, F> {
         let constraint = operand.constraint();
         let block = self.cfginfo.insn_block[u.pos.inst().index()];
         let loop_depth = self.cfginfo.approx_loop_depth[block.index()] as usize;
-        let weight = spill_weight_from_constraint(
+        let mut weight = spill_weight_from_constraint(
             constraint,
             loop_depth,
             operand.kind() != OperandKind::Use,
         );
+        // Spilling a value that can be cheaply recomputed costs a
+        // remat rather than a reload at each use.
+        if operand.kind() == OperandKind::Use {
+            let vreg = self.vreg(self.ranges[into.index()].vreg);
+            if let Some(cost) = self.remat_cost(vreg) {
+                weight = SpillWeight::from_f32(
+                    weight.to_f32() * (cost as f32) / (REMAT_RELOAD_COST as f32),
+                );
+            }
+        }
         u.weight = weight.to_bits();
 
         trace!(
@@ -415,9 +457,7 @@ impl<'a, F: Function> Env<'a, F> {
     }
 
     pub fn build_liveranges(&mut self) {
-        for &vreg in self.func.reftype_vregs() {
-            self.safepoints_per_vreg.insert(vreg.vreg(), HashSet::new());
-        }
+        let has_aliases = !self.env.aliases.is_empty();
 
         // Create Uses and Defs referring to VRegs, and place the Uses
         // in LiveRanges.
@@ -509,9 +549,11 @@ impl<'a, F: Function> Env<'a, F> {
                 // proper interference wrt other inputs. We note the
                 // *vreg* that is reused, not the index.
                 let mut reused_input = None;
+                let mut reused_input_slot = None;
                 for op in self.func.inst_operands(inst) {
                     if let OperandConstraint::Reuse(i) = op.constraint() {
                         reused_input = Some(self.func.inst_operands(inst)[i].vreg());
+                        reused_input_slot = Some(i);
                         break;
                     }
                 }
@@ -528,6 +570,11 @@ impl<'a, F: Function> Env<'a, F> {
                         debug_assert_eq!(src.pos(), OperandPos::Early);
                         debug_assert_eq!(dst.kind(), OperandKind::Def);
                         debug_assert_eq!(dst.pos(), OperandPos::Late);
+                        debug_assert!(
+                            !matches!(src.constraint(), OperandConstraint::FixedStackLoc(_))
+                                && !matches!(dst.constraint(), OperandConstraint::FixedStackLoc(_)),
+                            "fixed stack location constraints are not supported on moves"
+                        );
 
                         let src_pinned = self.func.is_pinned_vreg(src.vreg());
                         let dst_pinned = self.func.is_pinned_vreg(dst.vreg());
@@ -928,8 +975,10 @@ impl<'a, F: Function> Env<'a, F> {
                 // for the use, (ii) rewrite the use to have an Any
                 // constraint, and (ii) move the def to Early position
                 // to reserve the register for the whole instruction.
+                // The same applies if the two pregs merely overlap.
                 let mut operand_rewrites: FxHashMap<usize, Operand> = FxHashMap::default();
                 let mut late_def_fixed: SmallVec<[(PReg, Operand, usize); 2]> = smallvec![];
+                let mut alias_reservations: SmallVec<[PReg; 2]> = smallvec![];
                 for (i, &operand) in self.func.inst_operands(inst).iter().enumerate() {
                     if let OperandConstraint::FixedReg(preg) = operand.constraint() {
                         match operand.pos() {
@@ -978,10 +1027,25 @@ impl<'a, F: Function> Env<'a, F> {
                                 // conflicting constraints for the
                                 // same vreg in a separate pass (see
                                 // `fixup_multi_fixed_vregs` below).
-                                if let Some((_, def_op, def_slot)) = late_def_fixed
-                                    .iter()
-                                    .find(|(def_preg, _, _)| *def_preg == preg)
+                                if let Some(&(def_preg, def_op, def_slot)) =
+                                    late_def_fixed.iter().find(|(def_preg, _, _)| {
+                                        *def_preg == preg || self.aliases(preg).contains(def_preg)
+                                    })
                                 {
+                                    // The early-def reserves only
+                                    // `def_preg`, so also reserve any
+                                    // part of `preg` that it does not
+                                    // overlap, where the fixup move
+                                    // will write the used value.
+                                    if def_preg != preg {
+                                        let def_aliases = self.aliases(def_preg);
+                                        alias_reservations.extend(
+                                            self.aliases(preg).iter().copied().filter(|&alias| {
+                                                alias != def_preg && !def_aliases.contains(&alias)
+                                            }),
+                                        );
+                                    }
+
                                     let pos = ProgPoint::before(inst);
                                     self.multi_fixed_reg_fixups.push(MultiFixedRegFixup {
                                         pos,
@@ -1002,7 +1066,7 @@ impl<'a, F: Function> Env<'a, F> {
                                         ),
                                     );
                                     operand_rewrites.insert(
-                                        *def_slot,
+                                        def_slot,
                                         Operand::new(
                                             def_op.vreg(),
                                             def_op.constraint(),
@@ -1016,6 +1080,44 @@ impl<'a, F: Function> Env<'a, F> {
                         }
                     }
                 }
+                for alias in alias_reservations {
+                    self.add_liverange_to_preg(
+                        CodeRange {
+                            from: ProgPoint::before(inst),
+                            to: ProgPoint::before(inst.next()),
+                        },
+                        alias,
+                    );
+                }
+
+                // Operands constrained to a fixed stack location are
+                // allocated as `Any` operands; a fixup move then
+                // copies the value into the location before a use,
+                // or out of it after a def.
+                for (i, &operand) in self.func.inst_operands(inst).iter().enumerate() {
+                    if let OperandConstraint::FixedStackLoc(loc) = operand.constraint() {
+                        assert!(
+                            operand.kind() != OperandKind::Mod,
+                            "Invalid operand: fixed stack location constraint on Mod"
+                        );
+                        self.stack_loc_fixups.push(StackLocFixup {
+                            inst,
+                            slot: i as u8,
+                            kind: operand.kind(),
+                            loc,
+                            vreg: VRegIndex::new(operand.vreg().vreg()),
+                        });
+                        operand_rewrites.insert(
+                            i,
+                            Operand::new(
+                                operand.vreg(),
+                                OperandConstraint::Any,
+                                operand.kind(),
+                                operand.pos(),
+                            ),
+                        );
+                    }
+                }
 
                 // Process defs and uses.
                 for &cur_pos in &[InstPosition::After, InstPosition::Before] {
@@ -1043,6 +1145,17 @@ impl<'a, F: Function> Env<'a, F> {
                             {
                                 ProgPoint::after(inst)
                             }
+                            // Another use of the reused vreg may be
+                            // allocated elsewhere; if registers
+                            // overlap, the copy into the output could
+                            // clobber it, so it must interfere too.
+                            (OperandKind::Use, OperandPos::Early)
+                                if has_aliases
+                                    && reused_input_slot.is_some()
+                                    && reused_input_slot != Some(i) =>
+                            {
+                                ProgPoint::after(inst)
+                            }
                             (OperandKind::Use, OperandPos::Early) => ProgPoint::before(inst),
                         };
 
@@ -1142,12 +1255,6 @@ impl<'a, F: Function> Env<'a, F> {
                 if self.func.requires_refs_on_stack(inst) {
                     trace!("inst{} is safepoint", inst.index());
                     self.safepoints.push(inst);
-                    for vreg in live.iter() {
-                        if let Some(safepoints) = self.safepoints_per_vreg.get_mut(&vreg) {
-                            trace!("vreg v{} live at safepoint inst{}", vreg, inst.index());
-                            safepoints.insert(inst);
-                        }
-                    }
                 }
             }
 
@@ -1232,7 +1339,9 @@ impl<'a, F: Function> Env<'a, F> {
                 while safepoint_idx < self.safepoints.len()
                     && range.contains_point(ProgPoint::before(self.safepoints[safepoint_idx]))
                 {
-                    // Create a virtual use.
+                    // Create a virtual use. Its `Stack` constraint is
+                    // relaxed to allow `MachineEnv::stackmap_regs`, if
+                    // any, when computing requirements.
                     let pos = ProgPoint::before(self.safepoints[safepoint_idx]);
                     let operand = Operand::new(
                         self.vreg(vreg),
@@ -1321,7 +1430,9 @@ impl<'a, F: Function> Env<'a, F> {
                                 first_reg_slot.get_or_insert(u.slot);
                                 first_stack_slot.get_or_insert(u.slot);
                             }
-                            OperandConstraint::Reg | OperandConstraint::Reuse(_) => {
+                            OperandConstraint::Reg
+                            | OperandConstraint::Reuse(_)
+                            | OperandConstraint::RegSubset(_) => {
                                 first_reg_slot.get_or_insert(u.slot);
                                 requires_reg = true;
                             }
@@ -1335,6 +1446,9 @@ impl<'a, F: Function> Env<'a, F> {
                                     first_reg_slot.get_or_insert(u.slot);
                                 }
                             }
+                            OperandConstraint::FixedStackLoc(_) => {
+                                unreachable!("stack-location operands were rewritten to Any")
+                            }
                             // Maybe this could be supported in this future...
                             OperandConstraint::Stack => panic!(
                                 "multiple uses of vreg with a Stack constraint are not supported"
@@ -1361,7 +1475,7 @@ impl<'a, F: Function> Env<'a, F> {
                     let mut first_preg = None;
                     for u in uses.iter_mut() {
                         if let OperandConstraint::FixedReg(preg) = u.operand.constraint() {
-                            let vreg_idx = VRegIndex::new(u.operand.vreg().vreg());
+                            let vreg_idx = VRegIndex::new(vreg);
                             let preg_idx = PRegIndex::new(preg.index());
                             trace!(
                                 "at pos {:?}, vreg {:?} has fixed constraint to preg {:?}",
@@ -1391,12 +1505,7 @@ impl<'a, F: Function> Env<'a, F> {
                                 vreg: vreg_idx,
                                 level: FixedRegFixupLevel::Secondary,
                             });
-                            u.operand = Operand::new(
-                                u.operand.vreg(),
-                                OperandConstraint::Any,
-                                u.operand.kind(),
-                                u.operand.pos(),
-                            );
+                            u.operand = u.operand.with_constraint(OperandConstraint::Any);
                             trace!(" -> extra clobber {} at inst{}", preg, u.pos.inst().index());
                             extra_clobbers.push((preg, u.pos.inst()));
                         }
@@ -16,7 +16,9 @@ use super::{
     Env, LiveBundleIndex, LiveRangeIndex, LiveRangeKey, SpillSet, SpillSetIndex, SpillSlotIndex,
     VRegIndex,
 };
-use crate::{ion::data_structures::BlockparamOut, Function, Inst, OperandConstraint, PReg};
+use crate::{
+    ion::data_structures::BlockparamOut, AllocationEvent, Function, Inst, OperandConstraint, PReg,
+};
 use smallvec::smallvec;
 
 impl<'a, F: Function> Env<'a, F> {
@@ -106,6 +108,7 @@ impl<'a, F: Function> Env<'a, F> {
         }
 
         trace!(" -> committing to merge");
+        self.observe(AllocationEvent::Merge { from, into: to });
 
         // If we reach here, then the bundles do not overlap -- merge
         // them!  We do this with a merge-sort-like scan over both
@@ -248,6 +251,10 @@ impl<'a, F: Function> Env<'a, F> {
             let bundle = self.create_bundle();
             self.bundles[bundle.index()].ranges = self.vregs[vreg.index()].ranges.clone();
             trace!("vreg v{} gets bundle{}", vreg.index(), bundle.index());
+            self.observe(AllocationEvent::Create {
+                bundle,
+                vreg: self.vreg(vreg),
+            });
             for entry in &self.bundles[bundle.index()].ranges {
                 trace!(
                     " -> with LR range{}: {:?}",
@@ -261,7 +268,9 @@ impl<'a, F: Function> Env<'a, F> {
             let mut stack = false;
             for entry in &self.bundles[bundle.index()].ranges {
                 for u in &self.ranges[entry.index.index()].uses {
-                    if let OperandConstraint::FixedReg(_) = u.operand.constraint() {
+                    if let OperandConstraint::FixedReg(_) | OperandConstraint::RegSubset(_) =
+                        u.operand.constraint()
+                    {
                         fixed = true;
                     }
                     if let OperandConstraint::Stack = u.operand.constraint() {
@@ -14,12 +14,14 @@
 //! its design.
 
 use crate::cfg::CFGInfo;
-use crate::{Function, MachineEnv, Output, PReg, ProgPoint, RegAllocError, RegClass};
-use std::collections::HashMap;
+use crate::{
+    AllocationObserver, Function, MachineEnv, Output, PReg, ProgPoint, QualityMetrics,
+    RegAllocError, RegClass,
+};
 
 pub(crate) mod data_structures;
-pub use data_structures::Stats;
 use data_structures::*;
+pub use data_structures::{Ctx, Stats};
 pub(crate) mod reg_traversal;
 use reg_traversal::*;
 pub(crate) mod requirement;
@@ -31,65 +33,179 @@ use liveranges::*;
 pub(crate) mod merge;
 pub(crate) mod process;
 use process::*;
-use smallvec::smallvec;
 pub(crate) mod dump;
 pub(crate) mod moves;
 pub(crate) mod spill;
 pub(crate) mod stackmap;
 
+/// Take the buffer out of `v`, emptied but with its allocation, and
+/// with room for at least `capacity` elements.
+fn reuse<T>(v: &mut Vec<T>, capacity: usize) -> Vec<T> {
+    let mut v = std::mem::take(v);
+    v.clear();
+    v.reserve(capacity);
+    v
+}
+
 impl<'a, F: Function> Env<'a, F> {
+    /// Create an environment for allocating `func`, taking its buffers
+    /// from `ctx` and `output`. `cfginfo` must already be computed for
+    /// `func`. Give the buffers back with `Env::finish`.
     pub(crate) fn new(
         func: &'a F,
         env: &'a MachineEnv,
         cfginfo: CFGInfo,
+        ctx: &mut Ctx,
+        output: &mut Output,
         annotations_enabled: bool,
+        budget: Option<usize>,
     ) -> Self {
         let n = func.num_insts();
+        let mut allocation_queue = std::mem::take(&mut ctx.allocation_queue);
+        allocation_queue.clear();
+        let mut slots_by_size = std::mem::take(&mut ctx.slots_by_size);
+        for slots in &mut slots_by_size {
+            slots.slots.clear();
+            slots.probe_start = 0;
+        }
+        let mut extra_spillslots_by_class = std::mem::take(&mut ctx.extra_spillslots_by_class);
+        for slots in &mut extra_spillslots_by_class {
+            slots.clear();
+        }
+        let mut debug_annotations = std::mem::take(&mut ctx.debug_annotations);
+        debug_annotations.clear();
         Self {
             func,
             env,
             cfginfo,
 
-            liveins: Vec::with_capacity(func.num_blocks()),
-            liveouts: Vec::with_capacity(func.num_blocks()),
-            blockparam_outs: vec![],
-            blockparam_ins: vec![],
-            bundles: Vec::with_capacity(n),
-            ranges: Vec::with_capacity(4 * n),
-            spillsets: Vec::with_capacity(n),
-            vregs: Vec::with_capacity(n),
-            pregs: vec![],
-            allocation_queue: PrioQueue::new(),
-            safepoints: vec![],
-            safepoints_per_vreg: HashMap::new(),
-            spilled_bundles: vec![],
-            spillslots: vec![],
-            slots_by_size: vec![],
+            liveins: reuse(&mut ctx.liveins, func.num_blocks()),
+            liveouts: reuse(&mut ctx.liveouts, func.num_blocks()),
+            blockparam_outs: reuse(&mut ctx.blockparam_outs, 0),
+            blockparam_ins: reuse(&mut ctx.blockparam_ins, 0),
+            bundles: reuse(&mut ctx.bundles, n),
+            ranges: reuse(&mut ctx.ranges, 4 * n),
+            spillsets: reuse(&mut ctx.spillsets, n),
+            vregs: reuse(&mut ctx.vregs, n),
+            free_use_lists: std::mem::take(&mut ctx.free_use_lists),
+            free_range_lists: std::mem::take(&mut ctx.free_range_lists),
+            pregs: reuse(&mut ctx.pregs, 0),
+            preg_aliases: reuse(&mut ctx.preg_aliases, 0),
+            reg_subsets: reuse(&mut ctx.reg_subsets, 0),
+            stackmap_regs_subset: None,
+            allocation_queue,
+            safepoints: reuse(&mut ctx.safepoints, 0),
+            spilled_bundles: reuse(&mut ctx.spilled_bundles, 0),
+            spillslots: reuse(&mut ctx.spillslots, 0),
+            slots_by_size,
             allocated_bundle_count: 0,
+            budget: budget.unwrap_or(usize::MAX),
 
-            extra_spillslots_by_class: [smallvec![], smallvec![]],
-            preferred_victim_by_class: [PReg::invalid(), PReg::invalid()],
+            extra_spillslots_by_class,
+            preferred_victim_by_class: [PReg::invalid(); RegClass::COUNT],
 
-            prog_move_srcs: Vec::with_capacity(n / 2),
-            prog_move_dsts: Vec::with_capacity(n / 2),
-            prog_move_merges: Vec::with_capacity(n / 2),
+            prog_move_srcs: reuse(&mut ctx.prog_move_srcs, n / 2),
+            prog_move_dsts: reuse(&mut ctx.prog_move_dsts, n / 2),
+            prog_move_merges: reuse(&mut ctx.prog_move_merges, n / 2),
 
-            multi_fixed_reg_fixups: vec![],
-            inserted_moves: vec![],
-            edits: Vec::with_capacity(n),
-            allocs: Vec::with_capacity(4 * n),
-            inst_alloc_offsets: vec![],
+            multi_fixed_reg_fixups: reuse(&mut ctx.multi_fixed_reg_fixups, 0),
+            stack_loc_fixups: reuse(&mut ctx.stack_loc_fixups, 0),
+            inserted_moves: reuse(&mut ctx.inserted_moves, 0),
+            edits: reuse(&mut ctx.edits, n),
+            allocs: reuse(&mut output.allocs, 4 * n),
+            inst_alloc_offsets: reuse(&mut output.inst_alloc_offsets, 0),
             num_spillslots: 0,
-            safepoint_slots: vec![],
-            debug_locations: vec![],
+            safepoint_slots: reuse(&mut output.safepoint_slots, 0),
+            debug_locations: reuse(&mut output.debug_locations, 0),
 
             stats: Stats::default(),
+            quality: QualityMetrics::default(),
 
-            debug_annotations: std::collections::HashMap::new(),
+            debug_annotations,
             annotations_enabled,
+
+            html: None,
+            html_intermediate: false,
+
+            observer: None,
         }
     }
 
+    /// Move the results into `output` and the remaining buffers back
+    /// into `ctx` for the next run.
+    pub(crate) fn finish(mut self, ctx: &mut Ctx, output: &mut Output) {
+        output.num_spillslots = self.num_spillslots as usize;
+        for &(pos_prio, _) in &self.edits {
+            if pos_prio.prio == InsertMovePrio::InEdgeMoves as u32
+                || pos_prio.prio == InsertMovePrio::OutEdgeMoves as u32
+            {
+                self.quality.add_edge_move(&self.cfginfo, pos_prio.pos);
+            }
+        }
+        output.edits.clear();
+        output.edits.extend(
+            self.edits
+                .drain(..)
+                .map(|(pos_prio, edit)| (pos_prio.pos, edit)),
+        );
+        output.allocs = self.allocs;
+        output.inst_alloc_offsets = self.inst_alloc_offsets;
+        output.safepoint_slots = self.safepoint_slots;
+        output.debug_locations = self.debug_locations;
+        output.stats = self.stats;
+        output.quality = self.quality;
+
+        // Keep the heap storage of the use and range lists for the
+        // next run's ranges, bundles and vregs.
+        for range in self.ranges.drain(..) {
+            if range.uses.spilled() {
+                let mut uses = range.uses;
+                uses.clear();
+                self.free_use_lists.push(uses);
+            }
+        }
+        let range_lists = self
+            .bundles
+            .drain(..)
+            .map(|bundle| bundle.ranges)
+            .chain(self.vregs.drain(..).map(|vreg| vreg.ranges));
+        for mut ranges in range_lists {
+            if ranges.spilled() {
+                ranges.clear();
+                self.free_range_lists.push(ranges);
+            }
+        }
+
+        ctx.cfginfo = self.cfginfo;
+        ctx.liveins = self.liveins;
+        ctx.liveouts = self.liveouts;
+        ctx.blockparam_outs = self.blockparam_outs;
+        ctx.blockparam_ins = self.blockparam_ins;
+        ctx.ranges = self.ranges;
+        ctx.bundles = self.bundles;
+        ctx.spillsets = self.spillsets;
+        ctx.vregs = self.vregs;
+        ctx.free_use_lists = self.free_use_lists;
+        ctx.free_range_lists = self.free_range_lists;
+        ctx.pregs = self.pregs;
+        ctx.preg_aliases = self.preg_aliases;
+        ctx.reg_subsets = self.reg_subsets;
+        ctx.allocation_queue = self.allocation_queue;
+        ctx.safepoints = self.safepoints;
+        ctx.spilled_bundles = self.spilled_bundles;
+        ctx.spillslots = self.spillslots;
+        ctx.slots_by_size = self.slots_by_size;
+        ctx.extra_spillslots_by_class = self.extra_spillslots_by_class;
+        ctx.prog_move_srcs = self.prog_move_srcs;
+        ctx.prog_move_dsts = self.prog_move_dsts;
+        ctx.prog_move_merges = self.prog_move_merges;
+        ctx.multi_fixed_reg_fixups = self.multi_fixed_reg_fixups;
+        ctx.stack_loc_fixups = self.stack_loc_fixups;
+        ctx.inserted_moves = self.inserted_moves;
+        ctx.edits = self.edits;
+        ctx.debug_annotations = self.debug_annotations;
+    }
+
     pub(crate) fn init(&mut self) -> Result<(), RegAllocError> {
         self.create_pregs_and_vregs();
         self.compute_liveness()?;
@@ -100,16 +216,20 @@ impl<'a, F: Function> Env<'a, F> {
         if log::log_enabled!(log::Level::Trace) {
             self.dump_state();
         }
+        self.visualize("After bundle merging", false);
         Ok(())
     }
 
     pub(crate) fn run(&mut self) -> Result<(), RegAllocError> {
         self.process_bundles()?;
+        self.visualize("After the main allocation loop", false);
         self.try_allocating_regs_for_spilled_bundles();
         self.allocate_spillslots();
+        self.visualize("After second-chance and spillslot allocation", false);
         self.apply_allocations_and_insert_moves();
         self.resolve_inserted_moves();
         self.compute_stackmaps();
+        self.visualize("Final state", true);
         Ok(())
     }
 }
@@ -119,28 +239,739 @@ pub fn run<F: Function>(
     mach_env: &MachineEnv,
     enable_annotations: bool,
 ) -> Result<Output, RegAllocError> {
-    let cfginfo = CFGInfo::new(func)?;
+    let mut output = Output::default();
+    run_with_ctx(
+        func,
+        mach_env,
+        enable_annotations,
+        None,
+        None,
+        &mut Ctx::default(),
+        &mut output,
+    )?;
+    Ok(output)
+}
 
-    let mut env = Env::new(func, mach_env, cfginfo, enable_annotations);
-    env.init()?;
+/// Like `run`, but reuses the buffers in `ctx` and `output` from any
+/// earlier run, and leaves the results in `output`. `budget` bounds
+/// the search for registers, and `observer` receives the allocator's
+/// decisions; see `RegallocOptions`.
+pub fn run_with_ctx<F: Function>(
+    func: &F,
+    mach_env: &MachineEnv,
+    enable_annotations: bool,
+    budget: Option<usize>,
+    observer: Option<&dyn AllocationObserver>,
+    ctx: &mut Ctx,
+    output: &mut Output,
+) -> Result<(), RegAllocError> {
+    run_inner(
+        func,
+        mach_env,
+        enable_annotations,
+        budget,
+        observer,
+        None,
+        ctx,
+        output,
+    )
+    .map(|_| ())
+}
+
+/// Like `run`, but also renders the allocator's state as an HTML
+/// page; see `crate::visualize`.
+pub fn visualize<F: Function>(
+    func: &F,
+    mach_env: &MachineEnv,
+    enable_annotations: bool,
+    budget: Option<usize>,
+    observer: Option<&dyn AllocationObserver>,
+    intermediate: bool,
+) -> Result<(Output, String), RegAllocError> {
+    let mut output = Output::default();
+    let html = run_inner(
+        func,
+        mach_env,
+        enable_annotations,
+        budget,
+        observer,
+        Some(intermediate),
+        &mut Ctx::default(),
+        &mut output,
+    )?;
+    Ok((output, html.unwrap()))
+}
 
-    env.run()?;
+/// Run the allocator, rendering its state as HTML, including
+/// intermediate states if `html` is `Some(true)`, unless `html` is
+/// `None`.
+#[allow(clippy::too_many_arguments)]
+fn run_inner<F: Function>(
+    func: &F,
+    mach_env: &MachineEnv,
+    enable_annotations: bool,
+    budget: Option<usize>,
+    observer: Option<&dyn AllocationObserver>,
+    html: Option<bool>,
+    ctx: &mut Ctx,
+    output: &mut Output,
+) -> Result<Option<String>, RegAllocError> {
+    let mut cfginfo = std::mem::take(&mut ctx.cfginfo);
+    if let Err(e) = cfginfo.init(func) {
+        ctx.cfginfo = cfginfo;
+        return Err(e);
+    }
+
+    let mut env = Env::new(
+        func,
+        mach_env,
+        cfginfo,
+        ctx,
+        output,
+        enable_annotations,
+        budget,
+    );
+    env.observer = observer;
+    if let Some(intermediate) = html {
+        env.html = Some(dump::HTML_HEADER.to_string());
+        env.html_intermediate = intermediate;
+    }
+    let result = env.init().and_then(|()| env.run());
 
-    if enable_annotations {
+    if result.is_ok() && enable_annotations {
         env.dump_results();
     }
 
-    Ok(Output {
-        edits: env
+    let html = env.html.take().map(|html| html + dump::HTML_FOOTER);
+    env.finish(ctx, output);
+    result?;
+    output.compute_def_regs(func, mach_env);
+    output.compute_quality(mach_env, &ctx.cfginfo);
+    Ok(html)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text::{parse_function, parse_machine_env};
+    use crate::{
+        Algorithm, Allocation, AllocationEvent, Block, Bundle, Edit, Inst, InstRange, Operand,
+        PRegSet, ProbeResult, RegallocOptions, SpillSlot, VReg,
+    };
+    use std::cell::RefCell;
+
+    const INPUT: &str = "
+machine_env {
+    preferred(p0i, p1i)
+    non_preferred(p2i)
+}
+
+function {
+    entry block0
+    vregs 8
+    block0():
+        inst0: op(Def: v0i reg, Def: v1i reg)
+        inst1: op(Def: v2i reg, Use: v0i reg, Use: v1i reg)
+        inst2: op(Def: v3i reg, Use: v1i reg, Use: v2i reg)
+        inst3: op(Use: v0i reg, Use: v2i reg, Use: v3i reg)
+        inst4: op(Def: v4i reg, Use: v0i reg, Use: v1i reg)
+        inst5: op(Use: v1i reg, Use: v3i reg, Use: v4i reg)
+        inst6: branch() -> block1(v0i, v2i)
+    block1(v5i, v6i) preds(block0):
+        inst7: op(Def: v7i reg, Use: v5i reg, Use: v6i reg, Use: v4i reg)
+        inst8: op(Use: v1i reg, Use: v3i reg, Use: v7i reg)
+        inst9: ret(Use: v5i fixed(p0i), Use: v6i any)
+}
+";
+
+    #[test]
+    fn reuses_ctx_buffers() {
+        let func = parse_function(INPUT).unwrap();
+        let mach_env = parse_machine_env(INPUT).unwrap();
+        let mut ctx = Ctx::default();
+        let mut output = Output::default();
+        run_with_ctx(&func, &mach_env, true, None, None, &mut ctx, &mut output).unwrap();
+        assert!(!ctx.free_use_lists.is_empty());
+        assert!(!ctx.free_range_lists.is_empty());
+        assert!(!ctx.slots_by_size[1].slots.is_empty());
+        assert!(!ctx.debug_annotations.is_empty());
+
+        // The buffers that are kept whole, by address, so that a
+        // reallocation shows up as a change.
+        let buffers = |ctx: &Ctx, output: &Output| {
+            [
+                ctx.ranges.as_ptr() as usize,
+                ctx.bundles.as_ptr() as usize,
+                ctx.spillsets.as_ptr() as usize,
+                ctx.vregs.as_ptr() as usize,
+                ctx.edits.as_ptr() as usize,
+                output.allocs.as_ptr() as usize,
+            ]
+        };
+        let first = buffers(&ctx, &output);
+        let free_lists = ctx.free_use_lists.len() + ctx.free_range_lists.len();
+
+        // Run again step by step, to see the new ranges, bundles and
+        // vregs take the kept lists.
+        let mut cfginfo = std::mem::take(&mut ctx.cfginfo);
+        cfginfo.init(&func).unwrap();
+        let mut env = Env::new(&func, &mach_env, cfginfo, &mut ctx, &mut output, true, None);
+        assert!(env.debug_annotations.capacity() > 0);
+        env.init().unwrap();
+        assert!(env.free_use_lists.len() + env.free_range_lists.len() < free_lists);
+        env.run().unwrap();
+        env.finish(&mut ctx, &mut output);
+        assert_eq!(buffers(&ctx, &output), first);
+    }
+
+    /// Ion renders its state, including ranges that splitting left
+    /// empty in a spill bundle. The other algorithms have none.
+    #[test]
+    fn visualize_only_ion() {
+        let func = parse_function(INPUT).unwrap();
+        let mach_env = parse_machine_env(INPUT).unwrap();
+        let (_, html) =
+            crate::visualize(&func, &mach_env, &RegallocOptions::default(), false).unwrap();
+        assert!(!html.is_empty());
+        let options = RegallocOptions {
+            algorithm: Algorithm::Fastalloc,
+            ..RegallocOptions::default()
+        };
+        assert!(matches!(
+            crate::visualize(&func, &mach_env, &options, false),
+            Err(RegAllocError::UnsupportedAlgorithm(Algorithm::Fastalloc))
+        ));
+    }
+
+    /// A tiny budget still gives a valid allocation when many values
+    /// compete for few registers.
+    #[test]
+    fn tiny_budget() {
+        const N: usize = 16;
+        let mut input =
+            String::from("machine_env {\n    preferred(p0i, p1i)\n    non_preferred(p2i)\n}\n");
+        input += &format!(
+            "function {{\n    entry block0\n    vregs {}\n    block0():\n",
+            N
+        );
+        for i in 0..N {
+            input += &format!("        inst{}: op(Def: v{}i reg)\n", i, i);
+        }
+        for i in 0..N {
+            input += &format!(
+                "        inst{}: op(Use: v{}i reg, Use: v{}i reg, Use: v{}i reg)\n",
+                N + i,
+                i,
+                (i + 5) % N,
+                (i + 11) % N
+            );
+        }
+        input += &format!("        inst{}: ret()\n}}\n", 2 * N);
+        let func = parse_function(&input).unwrap();
+        let mach_env = parse_machine_env(&input).unwrap();
+
+        let unbounded = crate::run_checked(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        let options = RegallocOptions {
+            budget: Some(1),
+            ..RegallocOptions::default()
+        };
+        let output = crate::run_checked(&func, &mach_env, &options).unwrap();
+        assert_eq!(unbounded.stats.budget_fallback_bundles, 0);
+        assert!(output.stats.budget_fallback_bundles > 0);
+        assert!(output.stats.budget_spilled_bundles > 0);
+    }
+
+    /// Once the budget is spent, a reference at a safepoint goes to
+    /// the stack rather than to a stackmap register, and the result is
+    /// still correct: here, a dead rematerializable blockparam with a
+    /// debug label.
+    #[test]
+    fn budget_with_stackmap_regs() {
+        let input = "
+machine_env {
+    preferred(p0i, p1i, p0f, p1f)
+    non_preferred(p2i)
+    stackmap_regs(p2i)
+}
+
+function {
+    entry block0
+    vregs 4
+    reftype v3i
+    remat v3i 10
+    debug_label v3i inst0 inst10 70
+    block0():
+        inst0: op(Def@Early: v0i reg)
+        inst1: op(Def@Early: v1f reg)
+        inst2: op()
+        inst3: op()
+        inst4: op(Use: v0i reg)
+        inst5: op(Def@Early: v2i reg)
+        inst6: op(Use: v1f reg)
+        inst7: branch() -> block1(v2i)
+    block1(v3i) preds(block0):
+        inst8: op() safepoint
+        inst9: ret()
+}
+";
+        let func = parse_function(input).unwrap();
+        let mach_env = parse_machine_env(input).unwrap();
+        for budget in 0..=3 {
+            let options = RegallocOptions {
+                budget: Some(budget),
+                ..RegallocOptions::default()
+            };
+            crate::run_checked(&func, &mach_env, &options).unwrap();
+        }
+
+        let options = RegallocOptions {
+            budget: Some(2),
+            ..RegallocOptions::default()
+        };
+        let output = crate::run_checked(&func, &mach_env, &options).unwrap();
+        assert!(output.stats.budget_fallback_bundles > 0);
+        assert_eq!(output.safepoint_slots.len(), 1);
+        assert!(output.safepoint_slots[0].1.is_stack());
+    }
+
+    /// Spillsets of different classes but the same slot size share
+    /// a slot, and each names it with its own class.
+    #[test]
+    fn spillslot_shared_across_classes() {
+        let input = "
+machine_env {
+    preferred(p0i, p0f)
+}
+
+function {
+    entry block0
+    vregs 2
+    block0():
+        inst0: op(Def: v0i reg)
+        inst1: op() clobbers(p0i)
+        inst2: op(Use: v0i reg)
+        inst3: op(Def: v1f reg)
+        inst4: op() clobbers(p0f)
+        inst5: op(Use: v1f reg)
+        inst6: ret()
+}
+";
+        let func = parse_function(input).unwrap();
+        let mach_env = parse_machine_env(input).unwrap();
+        let output = crate::run_checked(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        assert_eq!(output.num_spillslots, 1);
+        let mut slots: Vec<_> = output
+            .edits
+            .iter()
+            .filter_map(|(_, edit)| match *edit {
+                Edit::Move { from, to } => from.as_stack().or(to.as_stack()),
+                Edit::Remat { .. } => None,
+            })
+            .collect();
+        slots.dedup();
+        let slot = |class| SpillSlot::new(0, class);
+        assert_eq!(slots, [slot(RegClass::Int), slot(RegClass::Float)]);
+    }
+
+    /// The blockparams swap the two slots across classes, so the
+    /// moves of each class read a slot that the other class writes:
+    /// one class saves its source to a temporary slot first.
+    #[test]
+    fn spillslot_swapped_across_classes() {
+        let input = "
+machine_env {
+    preferred(p0i, p1i, p0p, p1p)
+}
+
+function {
+    entry block0
+    vregs 4
+    block0():
+        inst0: op(Def: v0i fixed(p0i))
+        inst1: op(Def: v1p fixed(p0p))
+        inst2: op() clobbers(p0i, p1i, p0p, p1p)
+        inst3: branch() -> block1(v0i, v1p)
+    block1(v2i, v3p) preds(block0):
+        inst4: op() clobbers(p0i, p1i, p0p, p1p)
+        inst5: op(Use: v2i fixed(p1i), Use: v3p fixed(p1p))
+        inst6: ret()
+}
+";
+        let func = parse_function(input).unwrap();
+        let mach_env = parse_machine_env(input).unwrap();
+        let output = crate::run_checked(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        assert_eq!(output.num_spillslots, 3);
+    }
+
+    /// The edge moves of block1 swap p0i and p1i before its branch,
+    /// while v0 is still live in p0i, so v0's location there ends at
+    /// inst3 and resumes in block2. It used to run on to inst5.
+    #[test]
+    fn debug_location_overwritten_by_edge_move() {
+        let input = "
+machine_env {
+    preferred(p0i, p1i)
+}
+
+function {
+    entry block0
+    vregs 4
+    debug_label v0i inst0 inst6 5
+    block0():
+        inst0: op(Def: v0i fixed(p0i))
+        inst1: op(Def: v1i fixed(p1i))
+        inst2: branch() -> block1(), block2()
+    block1() preds(block0):
+        inst3: branch() -> block3(v1i, v0i)
+    block2() preds(block0):
+        inst4: branch() -> block3(v0i, v1i)
+    block3(v2i, v3i) preds(block1, block2):
+        inst5: op(Use: v2i fixed(p0i), Use: v3i fixed(p1i))
+        inst6: ret()
+}
+";
+        let func = parse_function(input).unwrap();
+        let mach_env = parse_machine_env(input).unwrap();
+        let output = crate::run_checked(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        let p0 = Allocation::reg(PReg::new(0, RegClass::Int));
+        assert_eq!(
+            output.debug_locations,
+            [
+                (
+                    5,
+                    ProgPoint::after(Inst::new(0)),
+                    ProgPoint::before(Inst::new(3)),
+                    p0
+                ),
+                (
+                    5,
+                    ProgPoint::before(Inst::new(4)),
+                    ProgPoint::before(Inst::new(5)),
+                    p0
+                ),
+            ]
+        );
+    }
+
+    /// The move of v1's reused input into p2f overwrites its alias
+    /// p0f, where v0 was just reloaded, so v0 has no location at
+    /// inst2. It used to be given as p0f there.
+    #[test]
+    fn debug_location_overwritten_by_reused_input() {
+        let input = "
+machine_env {
+    preferred(p0f, p1f, p2f)
+    alias(p2f, p0f)
+    alias(p2f, p1f)
+}
+
+function {
+    entry block0
+    vregs 3
+    debug_label v0f inst0 inst4 9
+    block0():
+        inst0: op(Def: v0f fixed(p0f))
+        inst1: op(Def: v2f fixed(p2f))
+        inst2: op(Def: v1f reuse(1), Use: v0f reg)
+        inst3: op(Use: v1f fixed(p2f))
+        inst4: ret()
+}
+";
+        let func = parse_function(input).unwrap();
+        let mach_env = parse_machine_env(input).unwrap();
+        let output = crate::run_checked(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        let p0 = Allocation::reg(PReg::new(0, RegClass::Float));
+        let slot = Allocation::stack(SpillSlot::new(0, RegClass::Float));
+        assert_eq!(
+            output.debug_locations,
+            [
+                (
+                    9,
+                    ProgPoint::after(Inst::new(0)),
+                    ProgPoint::before(Inst::new(1)),
+                    p0
+                ),
+                (
+                    9,
+                    ProgPoint::before(Inst::new(1)),
+                    ProgPoint::before(Inst::new(2)),
+                    slot
+                ),
+            ]
+        );
+    }
+
+    /// Records each event, for the tests.
+    struct Record(RefCell<Vec<String>>);
+
+    impl AllocationObserver for Record {
+        fn event(&self, event: &AllocationEvent<'_>) {
+            self.0.borrow_mut().push(format!("{:?}", event));
+        }
+    }
+
+    #[test]
+    fn observer_events() {
+        let input = "
+machine_env {
+    preferred(p0i, p1i)
+}
+
+function {
+    entry block0
+    vregs 3
+    block0():
+        inst0: op(Def: v0i reg)
+        inst1: op(Def: v1i reg, Use: v0i reg)
+        inst2: op(Def: v2i reg, Use: v0i reg, Use: v1i reg)
+        inst3: ret(Use: v2i fixed(p0i))
+}
+";
+        let func = parse_function(input).unwrap();
+        let mach_env = parse_machine_env(input).unwrap();
+        let record = Record(RefCell::new(vec![]));
+        let options = RegallocOptions {
+            observer: Some(&record),
+            ..RegallocOptions::default()
+        };
+        crate::run_checked(&func, &mach_env, &options).unwrap();
+
+        let bundle = Bundle::new;
+        let vreg = |vreg| VReg::new(vreg, RegClass::Int);
+        let preg = |hw_enc| PReg::new(hw_enc, RegClass::Int);
+        let expected = [
+            AllocationEvent::Create {
+                bundle: bundle(0),
+                vreg: vreg(0),
+            },
+            AllocationEvent::Create {
+                bundle: bundle(1),
+                vreg: vreg(1),
+            },
+            AllocationEvent::Create {
+                bundle: bundle(2),
+                vreg: vreg(2),
+            },
+            AllocationEvent::Probe {
+                bundle: bundle(0),
+                preg: preg(0),
+                result: ProbeResult::Allocated,
+            },
+            AllocationEvent::Probe {
+                bundle: bundle(2),
+                preg: preg(0),
+                result: ProbeResult::Allocated,
+            },
+            AllocationEvent::Probe {
+                bundle: bundle(1),
+                preg: preg(0),
+                result: ProbeResult::Conflict {
+                    bundles: &[bundle(0)],
+                    point: ProgPoint::after(Inst::new(1)),
+                },
+            },
+            AllocationEvent::Probe {
+                bundle: bundle(1),
+                preg: preg(1),
+                result: ProbeResult::Allocated,
+            },
+        ];
+        let expected: Vec<String> = expected.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(*record.0.borrow(), expected);
+    }
+
+    /// A reftyped blockparam that is never used still has a range,
+    /// and so an allocation, across the safepoint that starts its
+    /// block. It must be in the stackmap wherever it was put, with or
+    /// without a budget.
+    #[test]
+    fn dead_ref_blockparam_in_stackmap() {
+        let input = "
+machine_env {
+    preferred(p0i, p1i, p0f, p1f)
+    non_preferred(p2i)
+    stackmap_regs(p2i)
+}
+
+function {
+    entry block0
+    vregs 4
+    reftype v3i
+    debug_label v3i inst0 inst10 70
+    block0():
+        inst0: op(Def@Early: v0i reg)
+        inst1: op(Def@Early: v1f reg)
+        inst2: op()
+        inst3: op()
+        inst4: op(Use: v0i reg)
+        inst5: op(Def@Early: v2i reg)
+        inst6: op(Use: v1f reg)
+        inst7: branch() -> block1(v2i)
+    block1(v3i) preds(block0):
+        inst8: op() safepoint
+        inst9: ret()
+}
+";
+        let func = parse_function(input).unwrap();
+        let mach_env = parse_machine_env(input).unwrap();
+        for budget in [None, Some(2)] {
+            let options = RegallocOptions {
+                budget,
+                ..RegallocOptions::default()
+            };
+            let output = crate::run_checked(&func, &mach_env, &options).unwrap();
+            let safepoint = ProgPoint::before(Inst::new(8));
+            let slots: Vec<_> = output
+                .safepoint_slots
+                .iter()
+                .filter(|&&(point, _)| point == safepoint)
+                .collect();
+            assert_eq!(slots.len(), 1, "budget {:?}", budget);
+        }
+    }
+
+    /// A chain of blocks, each passing its blockparam to the next,
+    /// with the blockparams of even and odd blocks fixed to different
+    /// registers so that every edge needs a move. `block0` defines the
+    /// first value in `inst0` and branches in `inst1`; every other
+    /// block has just its branch (or, last, its return).
+    ///
+    /// A few blocks take a second blockparam, fixed to a third
+    /// register, whose index is that of the first plus 2^21: only a
+    /// key that keeps the full vreg index tells the two apart.
+    struct Chain {
+        blocks: Vec<Block>,
+        params: Vec<Vec<VReg>>,
+        args: Vec<Vec<VReg>>,
+        operands: Vec<Vec<Operand>>,
+        num_vregs: usize,
+    }
+
+    impl Chain {
+        const ALIASED: std::ops::Range<usize> = 10..14;
+
+        fn new(num_blocks: usize) -> Self {
+            let vreg = |i| VReg::new(i, RegClass::Int);
+            let preg = |i| PReg::new(i, RegClass::Int);
+            let params: Vec<Vec<VReg>> = (0..num_blocks)
+                .map(|block| match block {
+                    0 => vec![],
+                    b if Self::ALIASED.contains(&b) => vec![vreg(b), vreg(b + (1 << 21))],
+                    b => vec![vreg(b)],
+                })
+                .collect();
+            // The first blockparam takes the first value of the block
+            // before. A second one takes the second value, or in the
+            // first such block the first value again.
+            let args = (0..num_blocks)
+                .map(|block| match params.get(block + 1).map(Vec::as_slice) {
+                    None => vec![],
+                    Some([_]) => vec![vreg(block)],
+                    Some(_) => vec![vreg(block), *params[block].get(1).unwrap_or(&vreg(block))],
+                })
+                .collect();
+            let mut operands = vec![vec![Operand::reg_fixed_def(vreg(0), preg(0))]];
+            for (block, params) in params.iter().enumerate() {
+                let mut uses = vec![Operand::reg_fixed_use(vreg(block), preg(block % 2))];
+                uses.extend(params.get(1).map(|&c| Operand::reg_fixed_use(c, preg(2))));
+                operands.push(uses);
+            }
+            Chain {
+                blocks: (0..num_blocks).map(Block::new).collect(),
+                params,
+                args,
+                operands,
+                num_vregs: (1 << 21) + Self::ALIASED.end,
+            }
+        }
+    }
+
+    impl Function for Chain {
+        fn num_insts(&self) -> usize {
+            self.operands.len()
+        }
+        fn num_blocks(&self) -> usize {
+            self.blocks.len()
+        }
+        fn entry_block(&self) -> Block {
+            Block::new(0)
+        }
+        fn block_insns(&self, block: Block) -> InstRange {
+            let first = if block.index() == 0 {
+                0
+            } else {
+                block.index() + 1
+            };
+            InstRange::forward(Inst::new(first), Inst::new(block.index() + 2))
+        }
+        fn block_succs(&self, block: Block) -> &[Block] {
+            let i = block.index();
+            &self.blocks[i + 1..std::cmp::min(i + 2, self.blocks.len())]
+        }
+        fn block_preds(&self, block: Block) -> &[Block] {
+            let i = block.index();
+            &self.blocks[i.saturating_sub(1)..i]
+        }
+        fn block_params(&self, block: Block) -> &[VReg] {
+            &self.params[block.index()]
+        }
+        fn is_ret(&self, insn: Inst) -> bool {
+            insn.index() == self.operands.len() - 1
+        }
+        fn is_branch(&self, insn: Inst) -> bool {
+            insn.index() > 0 && !self.is_ret(insn)
+        }
+        fn branch_blockparams(&self, block: Block, _: Inst, _: usize) -> &[VReg] {
+            &self.args[block.index()]
+        }
+        fn is_move(&self, _: Inst) -> Option<(Operand, Operand)> {
+            None
+        }
+        fn inst_operands(&self, insn: Inst) -> &[Operand] {
+            &self.operands[insn.index()]
+        }
+        fn inst_clobbers(&self, _: Inst) -> PRegSet {
+            PRegSet::empty()
+        }
+        fn num_vregs(&self) -> usize {
+            self.num_vregs
+        }
+        fn spillslot_size(&self, _: RegClass) -> usize {
+            1
+        }
+    }
+
+    /// Block and vreg indices past 2^21 no longer fit the narrow
+    /// half-move key, so this function's edge moves go through the
+    /// wide one. Each edge needs exactly one move, at the head of its
+    /// successor, from the register of one blockparam to the other,
+    /// and the first block with a second blockparam one more.
+    #[test]
+    fn over_2m_blocks_and_vregs() {
+        let num_blocks = (1 << 21) + Chain::ALIASED.end;
+        let func = Chain::new(num_blocks);
+        let mach_env = parse_machine_env("machine_env { preferred(p0i, p1i, p2i) }").unwrap();
+        let output = crate::run(&func, &mach_env, &RegallocOptions::default()).unwrap();
+        let num_params = num_blocks - 1 + Chain::ALIASED.len();
+        assert_eq!(output.stats.halfmoves_count, 2 * num_params);
+
+        let reg = |i| Allocation::reg(PReg::new(i, RegClass::Int));
+        let head = |block: usize| ProgPoint::before(Inst::new(block + 1));
+        let mut expected: Vec<_> = (1..num_blocks)
+            .map(|block| (head(block), reg((block - 1) % 2), reg(block % 2)))
+            .collect();
+        let first = Chain::ALIASED.start;
+        expected.push((head(first), reg((first - 1) % 2), reg(2)));
+        expected.sort_unstable();
+        let mut moves: Vec<_> = output
             .edits
-            .into_iter()
-            .map(|(pos_prio, edit)| (pos_prio.pos, edit))
-            .collect(),
-        allocs: env.allocs,
-        inst_alloc_offsets: env.inst_alloc_offsets,
-        num_spillslots: env.num_spillslots as usize,
-        debug_locations: env.debug_locations,
-        safepoint_slots: env.safepoint_slots,
-        stats: env.stats,
-    })
+            .iter()
+            .map(|(point, edit)| match *edit {
+                Edit::Move { from, to } => (*point, from, to),
+                Edit::Remat { .. } => panic!("unexpected remat at {:?}", point),
+            })
+            .collect();
+        moves.sort_unstable();
+        assert!(moves == expected);
+    }
 }
@@ -17,7 +17,7 @@ use super::{
     VRegIndex, SLOT_NONE,
 };
 use crate::ion::data_structures::{
-    BlockparamIn, BlockparamOut, CodeRange, FixedRegFixupLevel, LiveRangeKey, PosWithPrio,
+    u64_key, BlockparamIn, BlockparamOut, CodeRange, FixedRegFixupLevel, LiveRangeKey, PosWithPrio,
 };
 use crate::ion::reg_traversal::RegTraversalIter;
 use crate::moves::{MoveAndScratchResolver, ParallelMoves};
@@ -39,8 +39,20 @@ impl<'a, F: Function> Env<'a, F> {
         pos == self.cfginfo.block_exit[block.index()]
     }
 
+    /// Do the two allocations overlap, i.e., are they the same
+    /// location or registers that alias each other?
+    pub fn allocs_overlap(&self, a: Allocation, b: Allocation) -> bool {
+        if a == b {
+            return true;
+        }
+        match (a.as_reg(), b.as_reg()) {
+            (Some(a), Some(b)) => self.aliases(a).contains(&b),
+            _ => false,
+        }
+    }
+
     fn allocation_is_stack(&self, alloc: Allocation) -> bool {
-        if alloc.is_stack() {
+        if alloc.is_stack() || alloc.is_stack_loc() {
             true
         } else if let Some(preg) = alloc.as_reg() {
             self.pregs[preg.index()].is_stack
@@ -78,9 +90,48 @@ impl<'a, F: Function> Env<'a, F> {
             from_alloc,
             to_alloc,
             to_vreg,
+            remat: false,
         });
     }
 
+    /// Insert a move of a vreg's value between two of its own
+    /// allocations. A reload from the stack into a register becomes
+    /// a rematerialization if that is cheaper.
+    pub fn insert_vreg_move(
+        &mut self,
+        pos: ProgPoint,
+        prio: InsertMovePrio,
+        from_alloc: Allocation,
+        to_alloc: Allocation,
+        vreg: VRegIndex,
+    ) {
+        let vreg = self.vreg(vreg);
+        if self.allocation_is_stack(from_alloc)
+            && !self.allocation_is_stack(to_alloc)
+            && self.remat_cost(vreg).is_some()
+        {
+            trace!(
+                "insert_vreg_move: pos {:?} prio {:?} remat {} into {:?}",
+                pos,
+                prio,
+                vreg,
+                to_alloc
+            );
+            self.inserted_moves.push(InsertedMove {
+                pos_prio: PosWithPrio {
+                    pos,
+                    prio: prio as u32,
+                },
+                from_alloc,
+                to_alloc,
+                to_vreg: Some(vreg),
+                remat: true,
+            });
+        } else {
+            self.insert_move(pos, prio, from_alloc, to_alloc, Some(vreg));
+        }
+    }
+
     pub fn get_alloc(&self, inst: Inst, slot: usize) -> Allocation {
         let inst_allocs = &self.allocs[self.inst_alloc_offsets[inst.index()] as usize..];
         inst_allocs[slot]
@@ -101,11 +152,17 @@ impl<'a, F: Function> Env<'a, F> {
             bundledata.allocation
         } else {
             trace!(" -> spillset {:?}", bundledata.spillset);
-            trace!(
-                " -> spill slot {:?}",
-                self.spillsets[bundledata.spillset.index()].slot
-            );
-            self.spillslots[self.spillsets[bundledata.spillset.index()].slot.index()].alloc
+            let spillset = &self.spillsets[bundledata.spillset.index()];
+            trace!(" -> spill slot {:?}", spillset.slot);
+            // Spillsets of the same size but different classes may
+            // share a slot. Name it with this range's class, so that
+            // its moves are resolved, and any scratch register taken,
+            // in that class.
+            let slot = self.spillslots[spillset.slot.index()]
+                .alloc
+                .as_stack()
+                .unwrap();
+            Allocation::stack(SpillSlot::new(slot.index(), spillset.class))
         }
     }
 
@@ -123,67 +180,13 @@ impl<'a, F: Function> Env<'a, F> {
             vreg.ranges.sort_unstable_by_key(|entry| entry.range.from);
         }
 
-        /// We create "half-moves" in order to allow a single-scan
-        /// strategy with a subsequent sort. Basically, the key idea
-        /// is that as our single scan through a range for a vreg hits
-        /// upon the source or destination of an edge-move, we emit a
-        /// "half-move". These half-moves are carefully keyed in a
-        /// particular sort order (the field order below is
-        /// significant!) so that all half-moves on a given (from, to)
-        /// block-edge appear contiguously, and then all moves from a
-        /// given vreg appear contiguously. Within a given from-vreg,
-        /// pick the first `Source` (there should only be one, but
-        /// imprecision in liveranges due to loop handling sometimes
-        /// means that a blockparam-out is also recognized as a normal-out),
-        /// and then for each `Dest`, copy the source-alloc to that
-        /// dest-alloc.
-        #[derive(Clone, Debug, PartialEq, Eq)]
-        struct HalfMove {
-            key: u64,
-            alloc: Allocation,
-        }
-        #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-        #[repr(u8)]
-        enum HalfMoveKind {
-            Source = 0,
-            Dest = 1,
-        }
-        fn half_move_key(
-            from_block: Block,
-            to_block: Block,
-            to_vreg: VRegIndex,
-            kind: HalfMoveKind,
-        ) -> u64 {
-            debug_assert!(from_block.index() < 1 << 21);
-            debug_assert!(to_block.index() < 1 << 21);
-            debug_assert!(to_vreg.index() < 1 << 21);
-            ((from_block.index() as u64) << 43)
-                | ((to_block.index() as u64) << 22)
-                | ((to_vreg.index() as u64) << 1)
-                | (kind as u8 as u64)
-        }
-        impl HalfMove {
-            fn from_block(&self) -> Block {
-                Block::new(((self.key >> 43) & ((1 << 21) - 1)) as usize)
-            }
-            fn to_block(&self) -> Block {
-                Block::new(((self.key >> 22) & ((1 << 21) - 1)) as usize)
-            }
-            fn to_vreg(&self) -> VRegIndex {
-                VRegIndex::new(((self.key >> 1) & ((1 << 21) - 1)) as usize)
-            }
-            fn kind(&self) -> HalfMoveKind {
-                if self.key & 1 == 1 {
-                    HalfMoveKind::Dest
-                } else {
-                    HalfMoveKind::Source
-                }
-            }
-        }
-
         let debug_labels = self.func.debug_value_labels();
 
-        let mut half_moves: Vec<HalfMove> = Vec::with_capacity(6 * self.func.num_insts());
+        let mut half_moves = HalfMoves::new(
+            self.func.num_blocks(),
+            self.vregs.len(),
+            6 * self.func.num_insts(),
+        );
         let mut reuse_input_insts = Vec::with_capacity(self.func.num_insts() / 2);
 
         let mut blockparam_in_idx = 0;
@@ -285,12 +288,12 @@ impl<'a, F: Function> Env<'a, F> {
                             vreg.index()
                         );
                         debug_assert_eq!(range.from.pos(), InstPosition::Before);
-                        self.insert_move(
+                        self.insert_vreg_move(
                             range.from,
                             InsertMovePrio::Regular,
                             prev_alloc,
                             alloc,
-                            Some(self.vreg(vreg)),
+                            vreg,
                         );
                     }
                 }
@@ -323,10 +326,7 @@ impl<'a, F: Function> Env<'a, F> {
                             trace!(" -> out of this range, requires half-move if live");
                             if self.is_live_in(succ, vreg) {
                                 trace!("  -> live at input to succ, adding halfmove");
-                                half_moves.push(HalfMove {
-                                    key: half_move_key(block, succ, vreg, HalfMoveKind::Source),
-                                    alloc,
-                                });
+                                half_moves.push(block, succ, vreg, HalfMoveKind::Source, alloc);
                             }
                         }
 
@@ -357,15 +357,13 @@ impl<'a, F: Function> Env<'a, F> {
                                     to_vreg.index(),
                                     to_vreg.index()
                                 );
-                                half_moves.push(HalfMove {
-                                    key: half_move_key(
-                                        from_block,
-                                        to_block,
-                                        to_vreg,
-                                        HalfMoveKind::Source,
-                                    ),
+                                half_moves.push(
+                                    from_block,
+                                    to_block,
+                                    to_vreg,
+                                    HalfMoveKind::Source,
                                     alloc,
-                                });
+                                );
 
                                 if self.annotations_enabled {
                                     self.annotate(
@@ -418,15 +416,13 @@ impl<'a, F: Function> Env<'a, F> {
                                 break;
                             }
                             if (to_vreg, to_block) == (vreg, block) {
-                                half_moves.push(HalfMove {
-                                    key: half_move_key(
-                                        from_block,
-                                        to_block,
-                                        to_vreg,
-                                        HalfMoveKind::Dest,
-                                    ),
+                                half_moves.push(
+                                    from_block,
+                                    to_block,
+                                    to_vreg,
+                                    HalfMoveKind::Dest,
                                     alloc,
-                                });
+                                );
                                 trace!(
                                     "match: blockparam_in: v{} in block{} from block{} into {}",
                                     to_vreg.index(),
@@ -476,10 +472,7 @@ impl<'a, F: Function> Env<'a, F> {
                                 continue;
                             }
                             trace!(" -> requires half-move");
-                            half_moves.push(HalfMove {
-                                key: half_move_key(pred, block, vreg, HalfMoveKind::Dest),
-                                alloc,
-                            });
+                            half_moves.push(pred, block, vreg, HalfMoveKind::Dest, alloc);
                         }
 
                         block = block.next();
@@ -636,106 +629,9 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
 
-        // Sort the half-moves list. For each (from, to,
-        // from-vreg) tuple, find the from-alloc and all the
-        // to-allocs, and insert moves on the block edge.
-        half_moves.sort_unstable_by_key(|h| h.key);
-        trace!("halfmoves: {:?}", half_moves);
-        self.stats.halfmoves_count = half_moves.len();
-
-        let mut i = 0;
-        while i < half_moves.len() {
-            // Find a Source.
-            while i < half_moves.len() && half_moves[i].kind() != HalfMoveKind::Source {
-                i += 1;
-            }
-            if i >= half_moves.len() {
-                break;
-            }
-            let src = &half_moves[i];
-            i += 1;
-
-            // Find all Dests.
-            let dest_key = src.key | 1;
-            let first_dest = i;
-            while i < half_moves.len() && half_moves[i].key == dest_key {
-                i += 1;
-            }
-            let last_dest = i;
-
-            trace!(
-                "halfmove match: src {:?} dests {:?}",
-                src,
-                &half_moves[first_dest..last_dest]
-            );
-
-            // Determine the ProgPoint where moves on this (from, to)
-            // edge should go:
-            // - If there is more than one in-edge to `to`, then
-            //   `from` must have only one out-edge; moves go at tail of
-            //   `from` just before last Branch/Ret.
-            // - Otherwise, there must be at most one in-edge to `to`,
-            //   and moves go at start of `to`.
-            let from_last_insn = self.func.block_insns(src.from_block()).last();
-            let to_first_insn = self.func.block_insns(src.to_block()).first();
-            let from_is_ret = self.func.is_ret(from_last_insn);
-            let to_is_entry = self.func.entry_block() == src.to_block();
-            let from_outs =
-                self.func.block_succs(src.from_block()).len() + if from_is_ret { 1 } else { 0 };
-            let to_ins =
-                self.func.block_preds(src.to_block()).len() + if to_is_entry { 1 } else { 0 };
-
-            let (insertion_point, prio) = if to_ins > 1 && from_outs <= 1 {
-                (
-                    // N.B.: though semantically the edge moves happen
-                    // after the branch, we must insert them before
-                    // the branch because otherwise, of course, they
-                    // would never execute. This is correct even in
-                    // the presence of branches that read register
-                    // inputs (e.g. conditional branches on some RISCs
-                    // that branch on reg zero/not-zero, or any
-                    // indirect branch), but for a very subtle reason:
-                    // all cases of such branches will (or should)
-                    // have multiple successors, and thus due to
-                    // critical-edge splitting, their successors will
-                    // have only the single predecessor, and we prefer
-                    // to insert at the head of the successor in that
-                    // case (rather than here). We make this a
-                    // requirement, in fact: the user of this library
-                    // shall not read registers in a branch
-                    // instruction of there is only one successor per
-                    // the given CFG information.
-                    ProgPoint::before(from_last_insn),
-                    InsertMovePrio::OutEdgeMoves,
-                )
-            } else if to_ins <= 1 {
-                (
-                    ProgPoint::before(to_first_insn),
-                    InsertMovePrio::InEdgeMoves,
-                )
-            } else {
-                panic!(
-                    "Critical edge: can't insert moves between blocks {:?} and {:?}",
-                    src.from_block(),
-                    src.to_block()
-                );
-            };
-
-            let mut last = None;
-            for dest in first_dest..last_dest {
-                let dest = &half_moves[dest];
-                if last == Some(dest.alloc) {
-                    continue;
-                }
-                self.insert_move(
-                    insertion_point,
-                    prio,
-                    src.alloc,
-                    dest.alloc,
-                    Some(self.vreg(dest.to_vreg())),
-                );
-                last = Some(dest.alloc);
-            }
+        match half_moves {
+            HalfMoves::Narrow(half_moves) => self.resolve_half_moves(half_moves),
+            HalfMoves::Wide(half_moves) => self.resolve_half_moves(half_moves),
         }
 
         // Handle multi-fixed-reg constraints by copying.
@@ -767,6 +663,34 @@ impl<'a, F: Function> Env<'a, F> {
             );
         }
 
+        // Handle fixed-stack-location constraints by copying into the
+        // location before a use, or out of it after a def.
+        for fixup in std::mem::take(&mut self.stack_loc_fixups) {
+            let alloc = self.get_alloc(fixup.inst, fixup.slot as usize);
+            let loc = Allocation::stack_loc(fixup.loc);
+            trace!(
+                "stack-loc constraint at {:?} slot {} for v{}: {} <-> {}",
+                fixup.inst,
+                fixup.slot,
+                fixup.vreg.index(),
+                alloc,
+                loc,
+            );
+            let (pos, from_alloc, to_alloc) = match fixup.kind {
+                OperandKind::Use => (ProgPoint::before(fixup.inst), alloc, loc),
+                OperandKind::Def => (ProgPoint::after(fixup.inst), loc, alloc),
+                OperandKind::Mod => unreachable!(),
+            };
+            self.insert_move(
+                pos,
+                InsertMovePrio::FixedStackLoc,
+                from_alloc,
+                to_alloc,
+                Some(self.vreg(fixup.vreg)),
+            );
+            self.set_alloc(fixup.inst, fixup.slot as usize, loc);
+        }
+
         // Handle outputs that reuse inputs: copy beforehand, then set
         // input's alloc to output's.
         //
@@ -878,6 +802,9 @@ impl<'a, F: Function> Env<'a, F> {
             debug_assert!(from_alloc.is_some());
             debug_assert!(to_alloc.is_some());
             debug_assert_eq!(from_inst, to_inst.prev());
+            if from_alloc == to_alloc {
+                self.quality.coalesced_moves += 1;
+            }
             // N.B.: these moves happen with the *same* priority as
             // LR-to-LR moves, because they work just like them: they
             // connect a use at one progpoint (move-After) with a def
@@ -897,6 +824,121 @@ impl<'a, F: Function> Env<'a, F> {
         self.debug_locations.sort_unstable();
     }
 
+    /// Sort the half-moves collected by
+    /// `apply_allocations_and_insert_moves` and insert the moves they
+    /// pair up into.
+    fn resolve_half_moves<K: HalfMoveKey>(&mut self, mut half_moves: Vec<HalfMove<K>>) {
+        // Sort the half-moves list. For each (from, to,
+        // from-vreg) tuple, find the from-alloc and all the
+        // to-allocs, and insert moves on the block edge.
+        half_moves.sort_unstable_by_key(|h| h.key);
+        trace!("halfmoves: {:?}", half_moves);
+        self.stats.halfmoves_count = half_moves.len();
+
+        let mut i = 0;
+        while i < half_moves.len() {
+            // Find a Source.
+            while i < half_moves.len() && half_moves[i].key.kind() != HalfMoveKind::Source {
+                i += 1;
+            }
+            if i >= half_moves.len() {
+                break;
+            }
+            let src = &half_moves[i];
+            i += 1;
+
+            // Find all Dests.
+            let dest_key = src.key.dest();
+            let first_dest = i;
+            while i < half_moves.len() && half_moves[i].key == dest_key {
+                i += 1;
+            }
+            let last_dest = i;
+
+            trace!(
+                "halfmove match: src {:?} dests {:?}",
+                src,
+                &half_moves[first_dest..last_dest]
+            );
+
+            // Determine the ProgPoint where moves on this (from, to)
+            // edge should go:
+            // - If there is more than one in-edge to `to`, then
+            //   `from` must have only one out-edge; moves go at tail of
+            //   `from` just before last Branch/Ret.
+            // - Otherwise, there must be at most one in-edge to `to`,
+            //   and moves go at start of `to`.
+            let from_last_insn = self.func.block_insns(src.key.from_block()).last();
+            let to_first_insn = self.func.block_insns(src.key.to_block()).first();
+            let from_is_ret = self.func.is_ret(from_last_insn);
+            let to_is_entry = self.func.entry_block() == src.key.to_block();
+            let from_outs =
+                self.func.block_succs(src.key.from_block()).len() + if from_is_ret { 1 } else { 0 };
+            let to_ins =
+                self.func.block_preds(src.key.to_block()).len() + if to_is_entry { 1 } else { 0 };
+
+            let (insertion_point, prio) = if to_ins > 1 && from_outs <= 1 {
+                (
+                    // N.B.: though semantically the edge moves happen
+                    // after the branch, we must insert them before
+                    // the branch because otherwise, of course, they
+                    // would never execute. This is correct even in
+                    // the presence of branches that read register
+                    // inputs (e.g. conditional branches on some RISCs
+                    // that branch on reg zero/not-zero, or any
+                    // indirect branch), but for a very subtle reason:
+                    // all cases of such branches will (or should)
+                    // have multiple successors, and thus due to
+                    // critical-edge splitting, their successors will
+                    // have only the single predecessor, and we prefer
+                    // to insert at the head of the successor in that
+                    // case (rather than here). We make this a
+                    // requirement, in fact: the user of this library
+                    // shall not read registers in a branch
+                    // instruction of there is only one successor per
+                    // the given CFG information.
+                    ProgPoint::before(from_last_insn),
+                    InsertMovePrio::OutEdgeMoves,
+                )
+            } else if to_ins <= 1 {
+                (
+                    ProgPoint::before(to_first_insn),
+                    InsertMovePrio::InEdgeMoves,
+                )
+            } else {
+                panic!(
+                    "Critical edge: can't insert moves between blocks {:?} and {:?}",
+                    src.key.from_block(),
+                    src.key.to_block()
+                );
+            };
+
+            // Unless the destination vreg is a blockparam of the
+            // successor, the source holds the same vreg.
+            let to_vreg = src.key.to_vreg();
+            let is_blockparam = self.vregs[to_vreg.index()].blockparam == src.key.to_block();
+            let mut last = None;
+            for dest in first_dest..last_dest {
+                let dest = &half_moves[dest];
+                if last == Some(dest.alloc) {
+                    continue;
+                }
+                if is_blockparam {
+                    self.insert_move(
+                        insertion_point,
+                        prio,
+                        src.alloc,
+                        dest.alloc,
+                        Some(self.vreg(to_vreg)),
+                    );
+                } else {
+                    self.insert_vreg_move(insertion_point, prio, src.alloc, dest.alloc, to_vreg);
+                }
+                last = Some(dest.alloc);
+            }
+        }
+    }
+
     pub fn resolve_inserted_moves(&mut self) {
         // For each program point, gather all moves together. Then
         // resolve (see cases below).
@@ -945,12 +987,29 @@ impl<'a, F: Function> Env<'a, F> {
                         OperandKind::Def | OperandKind::Mod => {
                             let alloc = this.get_alloc(inst, i);
                             redundant_moves.clear_alloc(alloc);
+                            redundant_move_clear_aliases(this, redundant_moves, alloc);
                         }
                         _ => {}
                     }
                 }
                 for reg in this.func.inst_clobbers(inst) {
                     redundant_moves.clear_alloc(Allocation::reg(reg));
+                    redundant_move_clear_aliases(this, redundant_moves, Allocation::reg(reg));
+                }
+            }
+        }
+
+        // A write to a register also changes the value of every
+        // register that overlaps it, so any copies tracked for those
+        // must be invalidated too.
+        fn redundant_move_clear_aliases<'a, F: Function>(
+            this: &Env<'a, F>,
+            redundant_moves: &mut RedundantMoveEliminator,
+            alloc: Allocation,
+        ) {
+            if let Some(preg) = alloc.as_reg() {
+                for &alias in this.aliases(preg) {
+                    redundant_moves.clear_alloc(Allocation::reg(alias));
                 }
             }
         }
@@ -968,14 +1027,14 @@ impl<'a, F: Function> Env<'a, F> {
             redundant_move_process_side_effects(self, &mut redundant_moves, last_pos, pos_prio.pos);
             last_pos = pos_prio.pos;
 
-            // Gather all the moves with Int class and Float class
-            // separately. These cannot interact, so it is safe to
-            // have two separate ParallelMove instances. They need to
-            // be separate because moves between the two classes are
-            // impossible. (We could enhance ParallelMoves to
-            // understand register classes, but this seems simpler.)
-            let mut int_moves: SmallVec<[InsertedMove; 8]> = smallvec![];
-            let mut float_moves: SmallVec<[InsertedMove; 8]> = smallvec![];
+            // Gather the moves of each register class separately, and
+            // resolve each class with its own ParallelMove
+            // instance. They need to be separate because moves
+            // between classes are impossible. (We could enhance
+            // ParallelMoves to understand register classes, but this
+            // seems simpler.)
+            let mut moves_by_class: [SmallVec<[InsertedMove; 8]>; RegClass::COUNT] =
+                Default::default();
 
             for m in moves {
                 if m.from_alloc.is_reg() && m.to_alloc.is_reg() {
@@ -984,32 +1043,142 @@ impl<'a, F: Function> Env<'a, F> {
                 if m.from_alloc == m.to_alloc {
                     continue;
                 }
-                match m.from_alloc.class() {
-                    RegClass::Int => {
-                        int_moves.push(m.clone());
-                    }
-                    RegClass::Float => {
-                        float_moves.push(m.clone());
+                moves_by_class[m.from_alloc.class() as usize].push(m.clone());
+            }
+
+            // The classes still interact through spillslots: a slot
+            // is shared by all classes of its size, so one class may
+            // read a slot here that another class writes. Such a
+            // class must go first. If the classes read each other's
+            // slots in a cycle, one of them first copies the sources
+            // that others overwrite to temporary slots, in a pass of
+            // its own.
+            type ClassMoves = [SmallVec<[InsertedMove; 8]>; RegClass::COUNT];
+            // Does `m` read a slot that a move of `writer` writes?
+            let clobbered_by = |by_class: &ClassMoves, m: &InsertedMove, writer: RegClass| {
+                let slot = match m.from_alloc.as_stack() {
+                    Some(slot) if !m.remat => slot.index(),
+                    _ => return false,
+                };
+                by_class[writer as usize]
+                    .iter()
+                    .any(|w| w.to_alloc.as_stack().map(|s| s.index()) == Some(slot))
+            };
+            let clobbered_source =
+                |by_class: &ClassMoves, pending: &[RegClass], class: RegClass, m: &InsertedMove| {
+                    pending
+                        .iter()
+                        .any(|&other| other != class && clobbered_by(by_class, m, other))
+                };
+            let mut pending: SmallVec<[RegClass; RegClass::COUNT]> = RegClass::ALL
+                .iter()
+                .copied()
+                .filter(|&class| !moves_by_class[class as usize].is_empty())
+                .collect();
+            let mut class_stackslot_idx = [0; RegClass::COUNT];
+            let mut passes: SmallVec<[(RegClass, SmallVec<[InsertedMove; 8]>); RegClass::COUNT]> =
+                smallvec![];
+            while !pending.is_empty() {
+                let by_class = &moves_by_class;
+                let ready = pending.iter().position(|&class| {
+                    !pending.iter().any(|&other| {
+                        other != class
+                            && by_class[other as usize]
+                                .iter()
+                                .any(|m| clobbered_by(by_class, m, class))
+                    })
+                });
+                if let Some(i) = ready {
+                    let class = pending.remove(i);
+                    passes.push((class, std::mem::take(&mut moves_by_class[class as usize])));
+                    continue;
+                }
+                let class = *pending
+                    .iter()
+                    .find(|&&class| {
+                        by_class[class as usize]
+                            .iter()
+                            .any(|m| clobbered_source(by_class, &pending, class, m))
+                    })
+                    .unwrap();
+                let mut saves: SmallVec<[InsertedMove; 8]> = smallvec![];
+                let mut class_moves = moves_by_class[class as usize].clone();
+                for m in class_moves.iter_mut() {
+                    if !clobbered_source(&moves_by_class, &pending, class, m) {
+                        continue;
                     }
+                    let temp = match saves.iter().find(|save| save.from_alloc == m.from_alloc) {
+                        Some(save) => save.to_alloc,
+                        None => {
+                            let idx = &mut class_stackslot_idx[class as usize];
+                            let temp =
+                                Allocation::stack(SpillSlot::new(SpillSlot::MAX - *idx, class));
+                            *idx += 1;
+                            saves.push(InsertedMove {
+                                pos_prio,
+                                from_alloc: m.from_alloc,
+                                to_alloc: temp,
+                                to_vreg: None,
+                                remat: false,
+                            });
+                            temp
+                        }
+                    };
+                    m.from_alloc = temp;
                 }
+                moves_by_class[class as usize] = class_moves;
+                passes.push((class, saves));
             }
 
-            for &(regclass, moves) in
-                &[(RegClass::Int, &int_moves), (RegClass::Float, &float_moves)]
-            {
+            for (regclass, moves) in &passes {
+                let regclass = *regclass;
                 // All moves in `moves` semantically happen in
                 // parallel. Let's resolve these to a sequence of moves
                 // that can be done one at a time.
+                //
+                // The parallel-move resolver only understands exact
+                // equality between locations, so a source register
+                // that merely overlaps (aliases) another move's
+                // destination is first saved to a temporary
+                // spillslot, and moved from there instead.
+                //
+                // Rematerializations read no location, so they are
+                // kept out of the parallel move and done after it,
+                // once any moves reading their destinations are done.
                 let mut parallel_moves = ParallelMoves::new();
+                let mut remats: SmallVec<[(Allocation, VReg); 2]> = smallvec![];
+                let mut stackslot_idx = class_stackslot_idx[regclass as usize];
+                let mut alias_saves: SmallVec<[(Allocation, Allocation); 2]> = smallvec![];
                 trace!(
                     "parallel moves at pos {:?} prio {:?}",
                     pos_prio.pos,
                     pos_prio.prio
                 );
                 for m in moves {
-                    if (m.from_alloc != m.to_alloc) || m.to_vreg.is_some() {
+                    if m.remat {
+                        trace!(" remat {} -> {}", m.to_vreg.unwrap(), m.to_alloc);
+                        remats.push((m.to_alloc, m.to_vreg.unwrap()));
+                    } else if (m.from_alloc != m.to_alloc) || m.to_vreg.is_some() {
                         trace!(" {} -> {}", m.from_alloc, m.to_alloc,);
-                        parallel_moves.add(m.from_alloc, m.to_alloc, m.to_vreg);
+                        let mut from = m.from_alloc;
+                        if moves.iter().any(|other| {
+                            other.to_alloc != from && self.allocs_overlap(from, other.to_alloc)
+                        }) {
+                            from = match alias_saves.iter().find(|&&(src, _)| src == from) {
+                                Some(&(_, temp)) => temp,
+                                None => {
+                                    let temp = Allocation::stack(SpillSlot::new(
+                                        SpillSlot::MAX - stackslot_idx,
+                                        regclass,
+                                    ));
+                                    stackslot_idx += 1;
+                                    alias_saves.push((from, temp));
+                                    temp
+                                }
+                            };
+                            trace!("  -> source overlaps a dest; saving to {}", from);
+                        }
+                        parallel_moves.add(from, m.to_alloc, m.to_vreg);
                     }
                 }
 
@@ -1021,6 +1190,7 @@ impl<'a, F: Function> Env<'a, F> {
                     PReg::invalid(),
                     0,
                     None,
+                    None,
                 );
                 let key = LiveRangeKey::from_range(&CodeRange {
                     from: pos_prio.pos,
@@ -1028,22 +1198,22 @@ impl<'a, F: Function> Env<'a, F> {
                 });
                 let get_reg = || {
                     while let Some(preg) = scratch_iter.next() {
-                        if !self.pregs[preg.index()]
-                            .allocations
-                            .btree
-                            .contains_key(&key)
-                        {
+                        let in_use = std::iter::once(preg)
+                            .chain(self.aliases(preg).iter().copied())
+                            .any(|reg| {
+                                self.pregs[reg.index()].allocations.btree.contains_key(&key)
+                            });
+                        if !in_use {
                             let alloc = Allocation::reg(preg);
-                            if moves
-                                .iter()
-                                .any(|m| m.from_alloc == alloc || m.to_alloc == alloc)
-                            {
-                                // Skip pregs used by moves in this
-                                // parallel move set, even if not
-                                // marked used at progpoint: edge move
-                                // liveranges meet but don't overlap
-                                // so otherwise we may incorrectly
-                                // overwrite a source reg.
+                            if passes.iter().flat_map(|(_, moves)| moves).any(|m| {
+                                self.allocs_overlap(m.from_alloc, alloc)
+                                    || self.allocs_overlap(m.to_alloc, alloc)
+                            }) {
+                                // Skip pregs used by any move at this
+                                // point, even if not marked used at
+                                // progpoint: edge move liveranges meet
+                                // but don't overlap so otherwise we may
+                                // incorrectly overwrite a source reg.
                                 continue;
                             }
                             return Some(alloc);
@@ -1051,7 +1221,6 @@ impl<'a, F: Function> Env<'a, F> {
                     }
                     None
                 };
-                let mut stackslot_idx = 0;
                 let get_stackslot = || {
                     let idx = stackslot_idx;
                     stackslot_idx += 1;
@@ -1063,8 +1232,13 @@ impl<'a, F: Function> Env<'a, F> {
                 };
                 let preferred_victim = self.preferred_victim_by_class[regclass as usize];
 
-                let scratch_resolver =
-                    MoveAndScratchResolver::new(get_reg, get_stackslot, preferred_victim);
+                let is_stack_alloc = |alloc| self.allocation_is_stack(alloc);
+                let scratch_resolver = MoveAndScratchResolver::new(
+                    get_reg,
+                    get_stackslot,
+                    is_stack_alloc,
+                    preferred_victim,
+                );
 
                 let resolved = scratch_resolver.compute(resolved);
 
@@ -1079,11 +1253,14 @@ impl<'a, F: Function> Env<'a, F> {
                         self.extra_spillslots_by_class[regclass as usize][i],
                     );
                 }
+                class_stackslot_idx[regclass as usize] = stackslot_idx;
 
-                for (src, dst, to_vreg) in resolved {
+                let saves = alias_saves.into_iter().map(|(src, temp)| (src, temp, None));
+                for (src, dst, to_vreg) in saves.chain(resolved) {
                     let src = rewrites.get(&src).cloned().unwrap_or(src);
                     let dst = rewrites.get(&dst).cloned().unwrap_or(dst);
                     trace!("  resolved: {} -> {} ({:?})", src, dst, to_vreg);
+                    redundant_move_clear_aliases(self, &mut redundant_moves, dst);
                     let action = redundant_moves.process_move(src, dst, to_vreg);
                     if !action.elide {
                         self.add_move_edit(pos_prio, src, dst);
@@ -1091,6 +1268,13 @@ impl<'a, F: Function> Env<'a, F> {
                         trace!("    -> redundant move elided");
                     }
                 }
+
+                for (to, vreg) in remats {
+                    trace!("  remat: {} -> {}", vreg, to);
+                    redundant_moves.clear_alloc(to);
+                    redundant_move_clear_aliases(self, &mut redundant_moves, to);
+                    self.edits.push((pos_prio, Edit::Remat { vreg, to }));
+                }
             }
         }
 
@@ -1101,17 +1285,85 @@ impl<'a, F: Function> Env<'a, F> {
         self.edits.sort_by_key(|&(pos_prio, _)| pos_prio.key());
         self.stats.edits_count = self.edits.len();
 
+        self.trim_debug_locations();
+
         // Add debug annotations.
         if self.annotations_enabled {
             for i in 0..self.edits.len() {
                 let &(pos_prio, ref edit) = &self.edits[i];
-                match edit {
-                    &Edit::Move { from, to } => {
+                match *edit {
+                    Edit::Move { from, to } => {
                         self.annotate(pos_prio.pos, format!("move {} -> {}", from, to));
                     }
+                    Edit::Remat { vreg, to } => {
+                        self.annotate(pos_prio.pos, format!("remat {} -> {}", vreg, to));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Edge moves at the end of a block are inserted before its
+    /// branch, so they may overwrite a value whose liverange extends
+    /// to the end of the block. Likewise, the move of a reused input
+    /// into its output's register may overwrite a value that dies at
+    /// the instruction. Cut any debug location for such a value out
+    /// until the next point at which the register may hold it.
+    fn trim_debug_locations(&mut self) {
+        // (allocation, overwritten at, may hold the value again at)
+        let mut overwritten = vec![];
+        for &(pos_prio, ref edit) in &self.edits {
+            let pos = pos_prio.pos;
+            let resume = if pos_prio.prio == InsertMovePrio::OutEdgeMoves as u32 {
+                ProgPoint::before(pos.inst().next())
+            } else if pos_prio.prio == InsertMovePrio::ReusedInput as u32 {
+                ProgPoint::after(pos.inst())
+            } else {
+                continue;
+            };
+            let to = match *edit {
+                Edit::Move { to, .. } | Edit::Remat { to, .. } => to,
+            };
+            overwritten.push((to, pos, resume));
+            if let Some(preg) = to.as_reg() {
+                for &alias in self.aliases(preg) {
+                    overwritten.push((Allocation::reg(alias), pos, resume));
+                }
+            }
+            // A spillslot is shared by classes of the same size, and
+            // each names it with its own class.
+            if let Some(slot) = to.as_stack() {
+                for &class in &RegClass::ALL {
+                    let slot = Allocation::stack(SpillSlot::new(slot.index(), class));
+                    overwritten.push((slot, pos, resume));
                 }
             }
         }
+        if overwritten.is_empty() {
+            return;
+        }
+        overwritten.sort_unstable();
+        overwritten.dedup();
+
+        let mut trimmed = Vec::with_capacity(self.debug_locations.len());
+        for &(label, from, to, alloc) in &self.debug_locations {
+            let start = overwritten.partition_point(|&(a, pos, _)| (a, pos) < (alloc, from));
+            let mut from = from;
+            for &(_, pos, resume) in overwritten[start..]
+                .iter()
+                .take_while(|&&(a, pos, _)| a == alloc && pos < to)
+            {
+                if from < pos {
+                    trimmed.push((label, from, pos, alloc));
+                }
+                from = std::cmp::max(from, resume);
+            }
+            if from < to {
+                trimmed.push((label, from, to, alloc));
+            }
+        }
+        trimmed.sort_unstable();
+        self.debug_locations = trimmed;
     }
 
     pub fn add_move_edit(&mut self, pos_prio: PosWithPrio, from: Allocation, to: Allocation) {
@@ -1123,3 +1375,166 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 }
+
+/// We create "half-moves" in order to allow a single-scan strategy
+/// with a subsequent sort. Basically, the key idea is that as our
+/// single scan through a range for a vreg hits upon the source or
+/// destination of an edge-move, we emit a "half-move". These
+/// half-moves are carefully keyed in a particular sort order so that
+/// all half-moves on a given (from, to) block-edge appear
+/// contiguously, and then all moves from a given vreg appear
+/// contiguously. Within a given from-vreg, pick the first `Source`
+/// (there should only be one, but imprecision in liveranges due to
+/// loop handling sometimes means that a blockparam-out is also
+/// recognized as a normal-out), and then for each `Dest`, copy the
+/// source-alloc to that dest-alloc.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct HalfMove<K> {
+    key: K,
+    alloc: Allocation,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+enum HalfMoveKind {
+    Source = 0,
+    Dest = 1,
+}
+
+/// The sort key of a half-move: (from-block, to-block, to-vreg,
+/// kind), most significant first.
+trait HalfMoveKey: Copy + Debug + Ord {
+    fn new(from_block: Block, to_block: Block, to_vreg: VRegIndex, kind: HalfMoveKind) -> Self;
+    fn from_block(self) -> Block;
+    fn to_block(self) -> Block;
+    fn to_vreg(self) -> VRegIndex;
+    fn kind(self) -> HalfMoveKind;
+    /// The key of the `Dest` half-moves that pair with this `Source`.
+    fn dest(self) -> Self;
+}
+
+/// A key packed into one word, with 21 bits for each index, for
+/// functions with at most 2^21 blocks and vregs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct NarrowHalfMoveKey(u64);
+
+impl NarrowHalfMoveKey {
+    const INDEX_BITS: usize = 21;
+
+    fn fits(num_blocks: usize, num_vregs: usize) -> bool {
+        num_blocks <= 1 << Self::INDEX_BITS && num_vregs <= 1 << Self::INDEX_BITS
+    }
+}
+
+impl HalfMoveKey for NarrowHalfMoveKey {
+    fn new(from_block: Block, to_block: Block, to_vreg: VRegIndex, kind: HalfMoveKind) -> Self {
+        debug_assert!(from_block.index() < 1 << Self::INDEX_BITS);
+        debug_assert!(to_block.index() < 1 << Self::INDEX_BITS);
+        debug_assert!(to_vreg.index() < 1 << Self::INDEX_BITS);
+        NarrowHalfMoveKey(
+            ((from_block.index() as u64) << 43)
+                | ((to_block.index() as u64) << 22)
+                | ((to_vreg.index() as u64) << 1)
+                | (kind as u8 as u64),
+        )
+    }
+    fn from_block(self) -> Block {
+        Block::new(((self.0 >> 43) & ((1 << 21) - 1)) as usize)
+    }
+    fn to_block(self) -> Block {
+        Block::new(((self.0 >> 22) & ((1 << 21) - 1)) as usize)
+    }
+    fn to_vreg(self) -> VRegIndex {
+        VRegIndex::new(((self.0 >> 1) & ((1 << 21) - 1)) as usize)
+    }
+    fn kind(self) -> HalfMoveKind {
+        if self.0 & 1 == 1 {
+            HalfMoveKind::Dest
+        } else {
+            HalfMoveKind::Source
+        }
+    }
+    fn dest(self) -> Self {
+        NarrowHalfMoveKey(self.0 | 1)
+    }
+}
+
+/// A key split into the block edge and the (vreg, kind) pair, so
+/// that it holds full-width block and vreg indices while a
+/// `HalfMove` still packs into 16 bytes. The derived order compares
+/// the edge first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct WideHalfMoveKey {
+    edge: u64,
+    vreg_and_kind: u32,
+}
+
+impl HalfMoveKey for WideHalfMoveKey {
+    fn new(from_block: Block, to_block: Block, to_vreg: VRegIndex, kind: HalfMoveKind) -> Self {
+        debug_assert!(to_vreg.index() < 1 << 31);
+        WideHalfMoveKey {
+            edge: u64_key(from_block.raw_u32(), to_block.raw_u32()),
+            vreg_and_kind: ((to_vreg.index() as u32) << 1) | (kind as u8 as u32),
+        }
+    }
+    fn from_block(self) -> Block {
+        Block::new((self.edge >> 32) as usize)
+    }
+    fn to_block(self) -> Block {
+        Block::new((self.edge & 0xffff_ffff) as usize)
+    }
+    fn to_vreg(self) -> VRegIndex {
+        VRegIndex::new((self.vreg_and_kind >> 1) as usize)
+    }
+    fn kind(self) -> HalfMoveKind {
+        if self.vreg_and_kind & 1 == 1 {
+            HalfMoveKind::Dest
+        } else {
+            HalfMoveKind::Source
+        }
+    }
+    fn dest(self) -> Self {
+        WideHalfMoveKey {
+            edge: self.edge,
+            vreg_and_kind: self.vreg_and_kind | 1,
+        }
+    }
+}
+
+/// The half-moves of a function, keyed in the narrow layout when all
+/// block and vreg indices fit it, which keeps sorting to one word per
+/// comparison for all but very large functions.
+enum HalfMoves {
+    Narrow(Vec<HalfMove<NarrowHalfMoveKey>>),
+    Wide(Vec<HalfMove<WideHalfMoveKey>>),
+}
+
+impl HalfMoves {
+    fn new(num_blocks: usize, num_vregs: usize, capacity: usize) -> Self {
+        if NarrowHalfMoveKey::fits(num_blocks, num_vregs) {
+            HalfMoves::Narrow(Vec::with_capacity(capacity))
+        } else {
+            HalfMoves::Wide(Vec::with_capacity(capacity))
+        }
+    }
+
+    fn push(
+        &mut self,
+        from_block: Block,
+        to_block: Block,
+        to_vreg: VRegIndex,
+        kind: HalfMoveKind,
+        alloc: Allocation,
+    ) {
+        match self {
+            HalfMoves::Narrow(half_moves) => half_moves.push(HalfMove {
+                key: HalfMoveKey::new(from_block, to_block, to_vreg, kind),
+                alloc,
+            }),
+            HalfMoves::Wide(half_moves) => half_moves.push(HalfMove {
+                key: HalfMoveKey::new(from_block, to_block, to_vreg, kind),
+                alloc,
+            }),
+        }
+    }
+}
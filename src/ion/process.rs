@@ -22,8 +22,8 @@ use crate::{
         CodeRange, BUNDLE_MAX_NORMAL_SPILL_WEIGHT, MAX_SPLITS_PER_SPILLSET,
         MINIMAL_BUNDLE_SPILL_WEIGHT, MINIMAL_FIXED_BUNDLE_SPILL_WEIGHT,
     },
-    Allocation, Function, Inst, InstPosition, OperandConstraint, OperandKind, PReg, ProgPoint,
-    RegAllocError,
+    Allocation, AllocationEvent, Function, Inst, InstPosition, OperandConstraint, OperandKind,
+    PReg, ProbeResult, ProgPoint, RegAllocError, SplitReason,
 };
 use fxhash::FxHashSet;
 use smallvec::{smallvec, SmallVec};
@@ -37,6 +37,23 @@ pub enum AllocRegResult {
     ConflictHighCost,
 }
 
+impl AllocRegResult {
+    /// The result as reported to an `AllocationObserver`.
+    pub fn probe_result(&self) -> ProbeResult<'_> {
+        match self {
+            AllocRegResult::Allocated(_) => ProbeResult::Allocated,
+            AllocRegResult::Conflict(bundles, point) => ProbeResult::Conflict {
+                bundles: &bundles[..],
+                point: *point,
+            },
+            AllocRegResult::ConflictWithFixed(_, point) => {
+                ProbeResult::ConflictWithFixed { point: *point }
+            }
+            AllocRegResult::ConflictHighCost => ProbeResult::ConflictHighCost,
+        }
+    }
+}
+
 impl<'a, F: Function> Env<'a, F> {
     pub fn process_bundles(&mut self) -> Result<(), RegAllocError> {
         while let Some((bundle, reg_hint)) = self.allocation_queue.pop() {
@@ -81,110 +98,121 @@ impl<'a, F: Function> Env<'a, F> {
             from: bundle_ranges.first().unwrap().range.from,
             to: bundle_ranges.first().unwrap().range.from,
         });
-        let mut preg_range_iter = self.pregs[reg.index()]
-            .allocations
-            .btree
-            .range(from_key..)
-            .peekable();
-        trace!(
-            "alloc map for {:?} in range {:?}..: {:?}",
-            reg,
-            from_key,
-            self.pregs[reg.index()].allocations.btree
-        );
         let mut first_conflict: Option<ProgPoint> = None;
 
-        'ranges: for entry in bundle_ranges {
-            trace!(" -> range LR {:?}: {:?}", entry.index, entry.range);
-            let key = LiveRangeKey::from_range(&entry.range);
+        // A register is also unavailable wherever any register that
+        // overlaps it is committed, so scan the commitment maps of
+        // all of its aliases as well.
+        let aliases = self
+            .aliases(PReg::from_index(reg.index()))
+            .iter()
+            .map(|alias| PRegIndex::new(alias.index()));
+        for reg in std::iter::once(reg).chain(aliases) {
+            let mut preg_range_iter = self.pregs[reg.index()]
+                .allocations
+                .btree
+                .range(from_key..)
+                .peekable();
+            trace!(
+                "alloc map for {:?} in range {:?}..: {:?}",
+                reg,
+                from_key,
+                self.pregs[reg.index()].allocations.btree
+            );
 
-            let mut skips = 0;
-            'alloc: loop {
-                trace!("  -> PReg range {:?}", preg_range_iter.peek());
+            'ranges: for entry in bundle_ranges {
+                trace!(" -> range LR {:?}: {:?}", entry.index, entry.range);
+                let key = LiveRangeKey::from_range(&entry.range);
 
-                // Advance our BTree traversal until it is >= this bundle
-                // range (i.e., skip PReg allocations in the BTree that
-                // are completely before this bundle range).
+                let mut skips = 0;
+                'alloc: loop {
+                    trace!("  -> PReg range {:?}", preg_range_iter.peek());
 
-                if preg_range_iter.peek().is_some() && *preg_range_iter.peek().unwrap().0 < key {
-                    trace!(
-                        "Skipping PReg range {:?}",
-                        preg_range_iter.peek().unwrap().0
-                    );
-                    preg_range_iter.next();
-                    skips += 1;
-                    if skips >= 16 {
-                        let from_pos = entry.range.from;
-                        let from_key = LiveRangeKey::from_range(&CodeRange {
-                            from: from_pos,
-                            to: from_pos,
-                        });
-                        preg_range_iter = self.pregs[reg.index()]
-                            .allocations
-                            .btree
-                            .range(from_key..)
-                            .peekable();
-                        skips = 0;
-                    }
-                    continue 'alloc;
-                }
-                skips = 0;
+                    // Advance our BTree traversal until it is >= this bundle
+                    // range (i.e., skip PReg allocations in the BTree that
+                    // are completely before this bundle range).
 
-                // If there are no more PReg allocations, we're done!
-                if preg_range_iter.peek().is_none() {
-                    trace!(" -> no more PReg allocations; so no conflict possible!");
-                    break 'ranges;
-                }
+                    if preg_range_iter.peek().is_some() && *preg_range_iter.peek().unwrap().0 < key
+                    {
+                        trace!(
+                            "Skipping PReg range {:?}",
+                            preg_range_iter.peek().unwrap().0
+                        );
+                        preg_range_iter.next();
+                        skips += 1;
+                        if skips >= 16 {
+                            let from_pos = entry.range.from;
+                            let from_key = LiveRangeKey::from_range(&CodeRange {
+                                from: from_pos,
+                                to: from_pos,
+                            });
+                            preg_range_iter = self.pregs[reg.index()]
+                                .allocations
+                                .btree
+                                .range(from_key..)
+                                .peekable();
+                            skips = 0;
+                        }
+                        continue 'alloc;
+                    }
+                    skips = 0;
 
-                // If the current PReg range is beyond this range, there is no conflict; continue.
-                if *preg_range_iter.peek().unwrap().0 > key {
-                    trace!(
-                        " -> next PReg allocation is at {:?}; moving to next VReg range",
-                        preg_range_iter.peek().unwrap().0
-                    );
-                    break 'alloc;
-                }
+                    // If there are no more PReg allocations, we're done!
+                    if preg_range_iter.peek().is_none() {
+                        trace!(" -> no more PReg allocations; so no conflict possible!");
+                        break 'ranges;
+                    }
 
-                // Otherwise, there is a conflict.
-                let preg_key = *preg_range_iter.peek().unwrap().0;
-                debug_assert_eq!(preg_key, key); // Assert that this range overlaps.
-                let preg_range = preg_range_iter.next().unwrap().1;
-
-                trace!(" -> btree contains range {:?} that overlaps", preg_range);
-                if preg_range.is_valid() {
-                    trace!("   -> from vreg {:?}", self.ranges[preg_range.index()].vreg);
-                    // range from an allocated bundle: find the bundle and add to
-                    // conflicts list.
-                    let conflict_bundle = self.ranges[preg_range.index()].bundle;
-                    trace!("   -> conflict bundle {:?}", conflict_bundle);
-                    if !conflict_set.contains(&conflict_bundle) {
-                        conflicts.push(conflict_bundle);
-                        conflict_set.insert(conflict_bundle);
-                        max_conflict_weight = std::cmp::max(
-                            max_conflict_weight,
-                            self.bundles[conflict_bundle.index()].cached_spill_weight(),
+                    // If the current PReg range is beyond this range, there is no conflict; continue.
+                    if *preg_range_iter.peek().unwrap().0 > key {
+                        trace!(
+                            " -> next PReg allocation is at {:?}; moving to next VReg range",
+                            preg_range_iter.peek().unwrap().0
                         );
-                        if max_allowable_cost.is_some()
-                            && max_conflict_weight > max_allowable_cost.unwrap()
-                        {
-                            trace!("   -> reached high cost, retrying early");
-                            return AllocRegResult::ConflictHighCost;
-                        }
+                        break 'alloc;
                     }
 
-                    if first_conflict.is_none() {
-                        first_conflict = Some(ProgPoint::from_index(std::cmp::max(
-                            preg_key.from,
-                            key.from,
-                        )));
+                    // Otherwise, there is a conflict.
+                    let preg_key = *preg_range_iter.peek().unwrap().0;
+                    debug_assert_eq!(preg_key, key); // Assert that this range overlaps.
+                    let preg_range = preg_range_iter.next().unwrap().1;
+
+                    trace!(" -> btree contains range {:?} that overlaps", preg_range);
+                    if preg_range.is_valid() {
+                        trace!("   -> from vreg {:?}", self.ranges[preg_range.index()].vreg);
+                        // range from an allocated bundle: find the bundle and add to
+                        // conflicts list.
+                        let conflict_bundle = self.ranges[preg_range.index()].bundle;
+                        trace!("   -> conflict bundle {:?}", conflict_bundle);
+                        if !conflict_set.contains(&conflict_bundle) {
+                            conflicts.push(conflict_bundle);
+                            conflict_set.insert(conflict_bundle);
+                            max_conflict_weight = std::cmp::max(
+                                max_conflict_weight,
+                                self.bundles[conflict_bundle.index()].cached_spill_weight(),
+                            );
+                            if max_allowable_cost.is_some()
+                                && max_conflict_weight > max_allowable_cost.unwrap()
+                            {
+                                trace!("   -> reached high cost, retrying early");
+                                return AllocRegResult::ConflictHighCost;
+                            }
+                        }
+
+                        let conflict_point =
+                            ProgPoint::from_index(std::cmp::max(preg_key.from, key.from));
+                        first_conflict = Some(match first_conflict {
+                            Some(first) => std::cmp::min(first, conflict_point),
+                            None => conflict_point,
+                        });
+                    } else {
+                        trace!("   -> conflict with fixed reservation");
+                        // range from a direct use of the PReg (due to clobber).
+                        return AllocRegResult::ConflictWithFixed(
+                            max_conflict_weight,
+                            ProgPoint::from_index(preg_key.from),
+                        );
                     }
-                } else {
-                    trace!("   -> conflict with fixed reservation");
-                    // range from a direct use of the PReg (due to clobber).
-                    return AllocRegResult::ConflictWithFixed(
-                        max_conflict_weight,
-                        ProgPoint::from_index(preg_key.from),
-                    );
                 }
             }
         }
@@ -276,7 +304,13 @@ impl<'a, F: Function> Env<'a, F> {
         } else {
             for u in &first_range_data.uses {
                 trace!("  -> use: {:?}", u);
-                if let OperandConstraint::FixedReg(_) = u.operand.constraint() {
+                // A register-subset constraint is treated like a
+                // fixed one: a minimal bundle limited to a few
+                // registers must be able to evict an unconstrained
+                // one from them.
+                if let OperandConstraint::FixedReg(_) | OperandConstraint::RegSubset(_) =
+                    u.operand.constraint()
+                {
                     trace!("  -> fixed use at {:?}: {:?}", u.pos, u.operand);
                     fixed = true;
                 }
@@ -348,6 +382,17 @@ impl<'a, F: Function> Env<'a, F> {
         self.bundles[bundle.index()].cached_minimal()
     }
 
+    /// Are all of the values in this bundle cheaper to recompute than
+    /// to reload?
+    pub fn bundle_is_rematerializable(&self, bundle: LiveBundleIndex) -> bool {
+        let spillset = self.bundles[bundle.index()].spillset;
+        let vregs = &self.spillsets[spillset.index()].vregs;
+        !vregs.is_empty()
+            && vregs
+                .iter()
+                .all(|&vreg| self.remat_cost(self.vreg(vreg)).is_some())
+    }
+
     pub fn recompute_range_properties(&mut self, range: LiveRangeIndex) {
         let rangedata = &mut self.ranges[range.index()];
         let mut w = SpillWeight::zero();
@@ -395,6 +440,7 @@ impl<'a, F: Function> Env<'a, F> {
         // Do we trim the parts around the split and put them in the
         // spill bundle?
         trim_ends_into_spill_bundle: bool,
+        reason: SplitReason,
     ) {
         self.stats.splits += 1;
         trace!(
@@ -415,7 +461,7 @@ impl<'a, F: Function> Env<'a, F> {
         // bundle. See the doc-comment on
         // `split_into_minimal_bundles()` above for more.
         if self.spillsets[spillset.index()].splits >= MAX_SPLITS_PER_SPILLSET {
-            self.split_into_minimal_bundles(bundle, reg_hint);
+            self.split_into_minimal_bundles(bundle, reg_hint, SplitReason::TooManySplits);
             return;
         }
         self.spillsets[spillset.index()].splits += 1;
@@ -573,6 +619,12 @@ impl<'a, F: Function> Env<'a, F> {
 
         let new_bundle = self.create_bundle();
         trace!(" -> creating new bundle {:?}", new_bundle);
+        self.observe(AllocationEvent::Split {
+            bundle,
+            at: Some(split_at),
+            reason,
+            into: &[new_bundle],
+        });
         self.bundles[new_bundle.index()].spillset = spillset;
         for entry in &new_lr_list {
             self.ranges[entry.index.index()].bundle = new_bundle;
@@ -635,6 +687,7 @@ impl<'a, F: Function> Env<'a, F> {
                         range,
                         index: empty_lr,
                     });
+                    self.ranges[empty_lr.index()].vreg = vreg;
                     self.ranges[empty_lr.index()].bundle = spill;
                     self.vregs[vreg.index()].ranges.push(LiveRangeListEntry {
                         range,
@@ -705,6 +758,7 @@ impl<'a, F: Function> Env<'a, F> {
                         range,
                         index: empty_lr,
                     });
+                    self.ranges[empty_lr.index()].vreg = vreg;
                     self.ranges[empty_lr.index()].bundle = spill;
                     self.vregs[vreg.index()].ranges.push(LiveRangeListEntry {
                         range,
@@ -770,7 +824,12 @@ impl<'a, F: Function> Env<'a, F> {
     /// the spill bundle; and then does minimal reservations of
     /// registers just at uses/defs and moves the "spilled" value
     /// into/out of them immediately.
-    pub fn split_into_minimal_bundles(&mut self, bundle: LiveBundleIndex, reg_hint: PReg) {
+    pub fn split_into_minimal_bundles(
+        &mut self,
+        bundle: LiveBundleIndex,
+        reg_hint: PReg,
+        reason: SplitReason,
+    ) {
         let mut removed_lrs: FxHashSet<LiveRangeIndex> = FxHashSet::default();
         let mut removed_lrs_vregs: FxHashSet<VRegIndex> = FxHashSet::default();
         let mut new_lrs: SmallVec<[(VRegIndex, LiveRangeIndex); 16]> = smallvec![];
@@ -969,6 +1028,13 @@ impl<'a, F: Function> Env<'a, F> {
             self.vregs[vreg.index()].ranges.push(entry);
         }
 
+        self.observe(AllocationEvent::Split {
+            bundle,
+            at: None,
+            reason,
+            into: &new_bundles[..],
+        });
+
         // Recompute bundle properties for all new bundles and enqueue
         // them.
         for bundle in new_bundles {
@@ -1000,6 +1066,11 @@ impl<'a, F: Function> Env<'a, F> {
 
         let req = match self.compute_requirement(bundle) {
             Ok(req) => req,
+            Err(_) if self.budget == 0 => {
+                self.stats.budget_fallback_bundles += 1;
+                self.split_into_minimal_bundles(bundle, hint_reg, SplitReason::Budget);
+                return Ok(());
+            }
             Err(conflict) => {
                 // We have to split right away. We'll find a point to
                 // split that would allow at least the first half of the
@@ -1014,6 +1085,7 @@ impl<'a, F: Function> Env<'a, F> {
                     reg_hint,
                     /* trim_ends_into_spill_bundle = */
                     conflict.should_trim_edges_around_split(),
+                    SplitReason::ConflictingRequirements,
                 );
                 return Ok(());
             }
@@ -1033,31 +1105,70 @@ impl<'a, F: Function> Env<'a, F> {
                         self.ranges[entry.index.index()].bundle = spill;
                     }
                     self.bundles[spill.index()].ranges.extend(list.drain(..));
+                    self.observe(AllocationEvent::Merge {
+                        from: bundle,
+                        into: spill,
+                    });
                     return Ok(());
                 }
             }
             _ => {}
         }
 
+        // Once the budget is spent, stop searching for a register for
+        // the whole bundle: only its uses get one, each just around its
+        // instruction, and the rest goes to the spill bundle. A bundle
+        // that may live on the stack goes there directly, as if no
+        // stackmap register could hold it.
+        if self.budget == 0 {
+            match req {
+                Requirement::FixedReg(_) | Requirement::Register | Requirement::RegSubset(_)
+                    if !self.minimal_bundle(bundle) =>
+                {
+                    self.stats.budget_fallback_bundles += 1;
+                    self.split_into_minimal_bundles(bundle, hint_reg, SplitReason::Budget);
+                    return Ok(());
+                }
+                Requirement::StackOrRegSubset(_) => {
+                    self.stats.budget_fallback_bundles += 1;
+                    self.spillsets[self.bundles[bundle.index()].spillset.index()].required = true;
+                    self.observe(AllocationEvent::Spill { bundle });
+                    return Ok(());
+                }
+                Requirement::Any => {
+                    self.spilled_bundles.push(bundle);
+                    self.observe(AllocationEvent::Spill { bundle });
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
         // Try to allocate!
         let mut attempts = 0;
         loop {
             attempts += 1;
+            self.budget = self.budget.saturating_sub(1);
             trace!("attempt {}, req {:?}", attempts, req);
             debug_assert!(attempts < 100 * self.func.num_insts());
 
-            let fixed_preg = match req {
-                Requirement::FixedReg(preg) | Requirement::FixedStack(preg) => Some(preg),
-                Requirement::Register => None,
+            let (fixed_preg, subset) = match req {
+                Requirement::FixedReg(preg) | Requirement::FixedStack(preg) => (Some(preg), None),
+                Requirement::RegSubset(subset) | Requirement::StackOrRegSubset(subset) => {
+                    (None, Some(self.reg_subsets[subset]))
+                }
+                Requirement::Register => (None, None),
                 Requirement::Stack => {
                     // If we must be on the stack, mark our spillset
                     // as required immediately.
                     self.spillsets[self.bundles[bundle.index()].spillset.index()].required = true;
+                    self.observe(AllocationEvent::Spill { bundle });
                     return Ok(());
                 }
 
                 Requirement::Any => {
                     self.spilled_bundles.push(bundle);
+                    self.observe(AllocationEvent::Spill { bundle });
                     return Ok(());
                 }
             };
@@ -1090,6 +1201,7 @@ impl<'a, F: Function> Env<'a, F> {
                 PReg::invalid(),
                 scan_offset,
                 fixed_preg,
+                subset.as_ref(),
             ) {
                 self.stats.process_bundle_reg_probes_any += 1;
                 let preg_idx = PRegIndex::new(preg.index());
@@ -1102,7 +1214,13 @@ impl<'a, F: Function> Env<'a, F> {
                     (Some(a), Some(b)) => Some(std::cmp::max(a, b)),
                     _ => None,
                 };
-                match self.try_to_allocate_bundle_to_reg(bundle, preg_idx, scan_limit_cost) {
+                let result = self.try_to_allocate_bundle_to_reg(bundle, preg_idx, scan_limit_cost);
+                self.observe(AllocationEvent::Probe {
+                    bundle,
+                    preg,
+                    result: result.probe_result(),
+                });
+                match result {
                     AllocRegResult::Allocated(alloc) => {
                         self.stats.process_bundle_reg_success_any += 1;
                         trace!(" -> allocated to any {:?}", preg_idx);
@@ -1172,6 +1290,15 @@ impl<'a, F: Function> Env<'a, F> {
                 }
             }
 
+            // A reference-typed value that could not stay in a
+            // register across a safepoint goes to the stack; it is
+            // not worth evicting or splitting for.
+            if let Requirement::StackOrRegSubset(_) = req {
+                self.spillsets[self.bundles[bundle.index()].spillset.index()].required = true;
+                self.observe(AllocationEvent::Spill { bundle });
+                return Ok(());
+            }
+
             // Otherwise, we *require* a register, but didn't fit into
             // any with current bundle assignments. Hence, we will need
             // to either split or attempt to evict some bundles.
@@ -1207,7 +1334,7 @@ impl<'a, F: Function> Env<'a, F> {
                     || lowest_cost_evict_conflict_cost.is_none()
                     || lowest_cost_evict_conflict_cost.unwrap() >= our_spill_weight)
             {
-                if let Requirement::Register = req {
+                if let Requirement::Register | Requirement::RegSubset(_) = req {
                     // Check if this is a too-many-live-registers situation.
                     let range = self.bundles[bundle.index()].ranges[0].range;
                     trace!("checking for too many live regs");
@@ -1217,20 +1344,32 @@ impl<'a, F: Function> Env<'a, F> {
                     for preg in self.env.preferred_regs_by_class[class as u8 as usize]
                         .iter()
                         .chain(self.env.non_preferred_regs_by_class[class as u8 as usize].iter())
+                        .filter(|&&preg| match subset {
+                            Some(subset) => subset.contains(preg),
+                            None => true,
+                        })
                     {
                         trace!(" -> PR {:?}", preg);
                         let start = LiveRangeKey::from_range(&CodeRange {
                             from: range.from.prev(),
                             to: range.from.prev(),
                         });
-                        for (key, lr) in self.pregs[preg.index()].allocations.btree.range(start..) {
+                        // Values committed to overlapping registers
+                        // block this one too.
+                        let committed = std::iter::once(*preg)
+                            .chain(self.aliases(*preg).iter().copied())
+                            .flat_map(|reg| {
+                                self.pregs[reg.index()]
+                                    .allocations
+                                    .btree
+                                    .range(start..)
+                                    .take_while(|(key, _)| key.to_range().from < range.to)
+                            });
+                        for (key, lr) in committed {
                             let preg_range = key.to_range();
                             if preg_range.to <= range.from {
                                 continue;
                             }
-                            if preg_range.from >= range.to {
-                                break;
-                            }
                             if lr.is_valid() {
                                 if self.minimal_bundle(self.ranges[lr.index()].bundle) {
                                     trace!("  -> min bundle {:?}", lr);
@@ -1286,6 +1425,18 @@ impl<'a, F: Function> Env<'a, F> {
                     std::cmp::max(lowest_cost_split_conflict_point, bundle_start);
                 let requeue_with_reg = lowest_cost_split_conflict_reg;
 
+                // A value that is cheap to recompute need not stay in
+                // a register between its uses: split around every use
+                // at once, leaving the rest to the spill bundle.
+                if self.bundle_is_rematerializable(bundle) {
+                    self.split_into_minimal_bundles(
+                        bundle,
+                        requeue_with_reg,
+                        SplitReason::Rematerializable,
+                    );
+                    return Ok(());
+                }
+
                 // Adjust `split_at_point` if it is within a deeper loop
                 // than the bundle start -- hoist it to just before the
                 // first loop header it encounters.
@@ -1309,14 +1460,22 @@ impl<'a, F: Function> Env<'a, F> {
                     split_at_point,
                     requeue_with_reg,
                     /* should_trim = */ true,
+                    SplitReason::RegisterConflict,
                 );
                 return Ok(());
             } else {
                 // Evict all bundles in `conflicting bundles` and try again.
                 self.stats.evict_bundle_event += 1;
-                for &bundle in &lowest_cost_evict_conflict_set.unwrap() {
-                    trace!(" -> evicting {:?}", bundle);
-                    self.evict_bundle(bundle);
+                for &evicted in &lowest_cost_evict_conflict_set.unwrap() {
+                    trace!(" -> evicting {:?}", evicted);
+                    if let Some(preg) = self.bundles[evicted.index()].allocation.as_reg() {
+                        self.observe(AllocationEvent::Evict {
+                            bundle: evicted,
+                            preg,
+                            by: bundle,
+                        });
+                    }
+                    self.evict_bundle(evicted);
                     self.stats.evict_bundle_count += 1;
                 }
             }
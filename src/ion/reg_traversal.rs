@@ -1,4 +1,4 @@
-use crate::{MachineEnv, PReg, RegClass};
+use crate::{MachineEnv, PReg, PRegSet, RegClass};
 
 /// This iterator represents a traversal through all allocatable
 /// registers of a given class, in a certain order designed to
@@ -13,6 +13,8 @@ use crate::{MachineEnv, PReg, RegClass};
 ///   prferred registers; then, non-preferred registers. (In normal
 ///   usage, these consist of caller-save and callee-save registers
 ///   respectively, to minimize clobber-saves; but they need not.)
+/// - If the traversal is limited to a register subset, registers
+///   outside of it (including hints) are skipped.
 
 pub struct RegTraversalIter<'a> {
     env: &'a MachineEnv,
@@ -25,6 +27,7 @@ pub struct RegTraversalIter<'a> {
     offset_non_pref: usize,
     is_fixed: bool,
     fixed: Option<PReg>,
+    subset: Option<&'a PRegSet>,
 }
 
 impl<'a> RegTraversalIter<'a> {
@@ -35,6 +38,7 @@ impl<'a> RegTraversalIter<'a> {
         hint2_reg: PReg,
         offset: usize,
         fixed: Option<PReg>,
+        subset: Option<&'a PRegSet>,
     ) -> Self {
         let mut hint_reg = if hint_reg != PReg::invalid() {
             Some(hint_reg)
@@ -47,6 +51,10 @@ impl<'a> RegTraversalIter<'a> {
             None
         };
 
+        if let Some(subset) = subset {
+            hint_reg = hint_reg.filter(|&reg| subset.contains(reg));
+            hint2_reg = hint2_reg.filter(|&reg| subset.contains(reg));
+        }
         if hint_reg.is_none() {
             hint_reg = hint2_reg;
             hint2_reg = None;
@@ -74,6 +82,14 @@ impl<'a> RegTraversalIter<'a> {
             offset_non_pref,
             is_fixed: fixed.is_some(),
             fixed,
+            subset,
+        }
+    }
+
+    fn in_subset(&self, reg: PReg) -> bool {
+        match self.subset {
+            Some(subset) => subset.contains(reg),
+            None => true,
         }
     }
 }
@@ -107,6 +123,9 @@ impl<'a> std::iter::Iterator for RegTraversalIter<'a> {
             if Some(r) == self.hints[0] || Some(r) == self.hints[1] {
                 continue;
             }
+            if !self.in_subset(r) {
+                continue;
+            }
             return Some(r);
         }
         while self.non_pref_idx < self.env.non_preferred_regs_by_class[self.class].len() {
@@ -116,6 +135,9 @@ impl<'a> std::iter::Iterator for RegTraversalIter<'a> {
             if Some(r) == self.hints[0] || Some(r) == self.hints[1] {
                 continue;
             }
+            if !self.in_subset(r) {
+                continue;
+            }
             return Some(r);
         }
         None
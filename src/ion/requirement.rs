@@ -12,8 +12,8 @@
 
 //! Requirements computation.
 
-use super::{Env, LiveBundleIndex};
-use crate::{Function, Inst, Operand, OperandConstraint, PReg, ProgPoint};
+use super::{Env, LiveBundleIndex, Use, SLOT_NONE};
+use crate::{Function, Inst, OperandConstraint, PReg, PRegSet, ProgPoint};
 
 pub struct RequirementConflict;
 
@@ -60,13 +60,23 @@ impl RequirementConflictAt {
 pub enum Requirement {
     FixedReg(PReg),
     FixedStack(PReg),
+    /// An index into `Env::reg_subsets`.
+    RegSubset(usize),
+    /// Either a stack slot or a register in the given subset: the
+    /// requirement of a reference-typed value at a safepoint when it
+    /// may stay in a register.
+    StackOrRegSubset(usize),
     Register,
     Stack,
     Any,
 }
 impl Requirement {
     #[inline(always)]
-    pub fn merge(self, other: Requirement) -> Result<Requirement, RequirementConflict> {
+    pub fn merge(
+        self,
+        other: Requirement,
+        reg_subsets: &[PRegSet],
+    ) -> Result<Requirement, RequirementConflict> {
         match (self, other) {
             (other, Requirement::Any) | (Requirement::Any, other) => Ok(other),
             (Requirement::Register, Requirement::Register) => Ok(self),
@@ -79,6 +89,43 @@ impl Requirement {
             | (Requirement::FixedStack(preg), Requirement::Stack) => {
                 Ok(Requirement::FixedStack(preg))
             }
+            (Requirement::Register, Requirement::RegSubset(subset))
+            | (Requirement::RegSubset(subset), Requirement::Register) => {
+                Ok(Requirement::RegSubset(subset))
+            }
+            (Requirement::FixedReg(preg), Requirement::RegSubset(subset))
+            | (Requirement::RegSubset(subset), Requirement::FixedReg(preg))
+                if reg_subsets[subset].contains(preg) =>
+            {
+                Ok(Requirement::FixedReg(preg))
+            }
+            // Keep the smaller subset if one contains the other; we do
+            // not create intersections of arbitrary subsets.
+            (Requirement::RegSubset(a), Requirement::RegSubset(b))
+                if reg_subsets[a].is_subset_of(&reg_subsets[b]) =>
+            {
+                Ok(self)
+            }
+            (Requirement::RegSubset(a), Requirement::RegSubset(b))
+                if reg_subsets[b].is_subset_of(&reg_subsets[a]) =>
+            {
+                Ok(other)
+            }
+            (Requirement::StackOrRegSubset(a), Requirement::StackOrRegSubset(b)) if a == b => {
+                Ok(self)
+            }
+            (Requirement::StackOrRegSubset(_), Requirement::Stack)
+            | (Requirement::Stack, Requirement::StackOrRegSubset(_)) => Ok(Requirement::Stack),
+            (Requirement::StackOrRegSubset(_), Requirement::FixedStack(preg))
+            | (Requirement::FixedStack(preg), Requirement::StackOrRegSubset(_)) => {
+                Ok(Requirement::FixedStack(preg))
+            }
+            (Requirement::StackOrRegSubset(subset), other)
+            | (other, Requirement::StackOrRegSubset(subset))
+                if other.is_reg() =>
+            {
+                Requirement::RegSubset(subset).merge(other, reg_subsets)
+            }
             (Requirement::FixedReg(a), Requirement::FixedReg(b)) if a == b => Ok(self),
             (Requirement::FixedStack(a), Requirement::FixedStack(b)) if a == b => Ok(self),
             _ => Err(RequirementConflict),
@@ -89,25 +136,25 @@ impl Requirement {
     pub fn is_stack(self) -> bool {
         match self {
             Requirement::Stack | Requirement::FixedStack(..) => true,
-            Requirement::Register | Requirement::FixedReg(..) => false,
-            Requirement::Any => false,
+            Requirement::Register | Requirement::FixedReg(..) | Requirement::RegSubset(..) => false,
+            Requirement::Any | Requirement::StackOrRegSubset(..) => false,
         }
     }
 
     #[inline(always)]
     pub fn is_reg(self) -> bool {
         match self {
-            Requirement::Register | Requirement::FixedReg(..) => true,
+            Requirement::Register | Requirement::FixedReg(..) | Requirement::RegSubset(..) => true,
             Requirement::Stack | Requirement::FixedStack(..) => false,
-            Requirement::Any => false,
+            Requirement::Any | Requirement::StackOrRegSubset(..) => false,
         }
     }
 }
 
 impl<'a, F: Function> Env<'a, F> {
     #[inline(always)]
-    pub fn requirement_from_operand(&self, op: Operand) -> Requirement {
-        match op.constraint() {
+    pub fn requirement_from_use(&self, u: &Use) -> Requirement {
+        match u.operand.constraint() {
             OperandConstraint::FixedReg(preg) => {
                 if self.pregs[preg.index()].is_stack {
                     Requirement::FixedStack(preg)
@@ -115,9 +162,27 @@ impl<'a, F: Function> Env<'a, F> {
                     Requirement::FixedReg(preg)
                 }
             }
-            OperandConstraint::Reg | OperandConstraint::Reuse(_) => Requirement::Register,
+            OperandConstraint::Reg => Requirement::Register,
+            // A reused input's register subset applies to the def too,
+            // since both are given the same register.
+            OperandConstraint::Reuse(input) => {
+                match self.func.inst_operands(u.pos.inst())[input].constraint() {
+                    OperandConstraint::RegSubset(subset) => Requirement::RegSubset(subset),
+                    _ => Requirement::Register,
+                }
+            }
+            OperandConstraint::RegSubset(subset) => Requirement::RegSubset(subset),
+            // A safepoint's virtual use (the only use without an
+            // operand slot that is constrained to the stack) may
+            // also be satisfied by a stackmap register.
+            OperandConstraint::Stack if u.slot == SLOT_NONE => match self.stackmap_regs_subset {
+                Some(subset) => Requirement::StackOrRegSubset(subset),
+                None => Requirement::Stack,
+            },
             OperandConstraint::Stack => Requirement::Stack,
-            OperandConstraint::Any => Requirement::Any,
+            // Fixed stack locations are handled with fixup moves
+            // from an allocation that is otherwise unconstrained.
+            OperandConstraint::Any | OperandConstraint::FixedStackLoc(_) => Requirement::Any,
         }
     }
 
@@ -133,8 +198,8 @@ impl<'a, F: Function> Env<'a, F> {
             trace!(" -> LR {:?}", entry.index);
             for u in &self.ranges[entry.index.index()].uses {
                 trace!("  -> use {:?}", u);
-                let r = self.requirement_from_operand(u.operand);
-                req = req.merge(r).map_err(|_| {
+                let r = self.requirement_from_use(u);
+                req = req.merge(r, &self.reg_subsets).map_err(|_| {
                     trace!("     -> conflict");
                     if req.is_stack() && r.is_reg() {
                         // Suggested split point just before the reg (i.e., late split).
@@ -169,6 +234,6 @@ impl<'a, F: Function> Env<'a, F> {
         let req_b = self
             .compute_requirement(b)
             .map_err(|_| RequirementConflict)?;
-        req_a.merge(req_b)
+        req_a.merge(req_b, &self.reg_subsets)
     }
 }
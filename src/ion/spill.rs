@@ -16,8 +16,7 @@ use super::{
     AllocRegResult, Env, LiveRangeKey, LiveRangeSet, PReg, PRegIndex, RegClass, RegTraversalIter,
     SpillSetIndex, SpillSlotData, SpillSlotIndex, SpillSlotList,
 };
-use crate::{Allocation, Function, SpillSlot};
-use smallvec::smallvec;
+use crate::{Allocation, AllocationEvent, Function, SpillSlot};
 
 impl<'a, F: Function> Env<'a, F> {
     pub fn try_allocating_regs_for_spilled_bundles(&mut self) {
@@ -38,22 +37,43 @@ impl<'a, F: Function> Env<'a, F> {
                 .ranges
                 .sort_unstable_by_key(|entry| entry.range.from);
 
-            let mut success = false;
+            let mut allocated = None;
             self.stats.spill_bundle_reg_probes += 1;
-            for preg in
-                RegTraversalIter::new(self.env, class, hint, PReg::invalid(), bundle.index(), None)
-            {
+            for preg in RegTraversalIter::new(
+                self.env,
+                class,
+                hint,
+                PReg::invalid(),
+                bundle.index(),
+                None,
+                None,
+            ) {
+                // Each register tried is a step of the budget; once
+                // it is spent, the bundle stays in its spillslot.
+                if self.budget == 0 {
+                    self.stats.budget_spilled_bundles += 1;
+                    break;
+                }
+                self.budget -= 1;
                 trace!("trying bundle {:?} to preg {:?}", bundle, preg);
                 let preg_idx = PRegIndex::new(preg.index());
-                if let AllocRegResult::Allocated(_) =
-                    self.try_to_allocate_bundle_to_reg(bundle, preg_idx, None)
-                {
+                let result = self.try_to_allocate_bundle_to_reg(bundle, preg_idx, None);
+                self.observe(AllocationEvent::Probe {
+                    bundle,
+                    preg,
+                    result: result.probe_result(),
+                });
+                if let AllocRegResult::Allocated(_) = result {
                     self.stats.spill_bundle_reg_success += 1;
-                    success = true;
+                    allocated = Some(preg);
                     break;
                 }
             }
-            if !success {
+            self.observe(AllocationEvent::SecondChance {
+                bundle,
+                preg: allocated,
+            });
+            if allocated.is_none() {
                 trace!(
                     "spilling bundle {:?}: marking spillset {:?} as required",
                     bundle,
@@ -70,11 +90,14 @@ impl<'a, F: Function> Env<'a, F> {
         spillset: SpillSetIndex,
     ) -> bool {
         for &vreg in &self.spillsets[spillset.index()].vregs {
+            // The range copies in `vregs` are only brought up to date
+            // after splitting is done, so read each range itself.
             for entry in &self.vregs[vreg.index()].ranges {
+                let range = &self.ranges[entry.index.index()].range;
                 if self.spillslots[spillslot.index()]
                     .ranges
                     .btree
-                    .contains_key(&LiveRangeKey::from_range(&entry.range))
+                    .contains_key(&LiveRangeKey::from_range(range))
                 {
                     return false;
                 }
@@ -99,17 +122,18 @@ impl<'a, F: Function> Env<'a, F> {
                 vreg,
             );
             for entry in &self.vregs[vreg.index()].ranges {
+                let range = &self.ranges[entry.index.index()].range;
                 trace!(
                     "spillslot {:?} getting range {:?} from LR {:?} from vreg {:?}",
                     spillslot,
-                    entry.range,
+                    range,
                     entry.index,
                     vreg,
                 );
                 self.spillslots[spillslot.index()]
                     .ranges
                     .btree
-                    .insert(LiveRangeKey::from_range(&entry.range), entry.index);
+                    .insert(LiveRangeKey::from_range(range), entry.index);
             }
         }
     }
@@ -123,16 +147,14 @@ impl<'a, F: Function> Env<'a, F> {
             if !self.spillsets[spillset.index()].required {
                 continue;
             }
-            // Get or create the spillslot list for this size.
+            // Get or create the spillslot list for this size. Slots
+            // are shared by all classes of that size; a range's
+            // allocation names the slot with its own class (see
+            // `get_alloc_for_range`).
             let size = self.spillsets[spillset.index()].size as usize;
             if size >= self.slots_by_size.len() {
-                self.slots_by_size.resize(
-                    size + 1,
-                    SpillSlotList {
-                        slots: smallvec![],
-                        probe_start: 0,
-                    },
-                );
+                self.slots_by_size
+                    .resize(size + 1, SpillSlotList::default());
             }
             // Try a few existing spillslots.
             let mut i = self.slots_by_size[size].probe_start;
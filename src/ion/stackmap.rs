@@ -18,47 +18,42 @@ use crate::{ion::data_structures::u64_key, Function};
 impl<'a, F: Function> Env<'a, F> {
     pub fn compute_stackmaps(&mut self) {
         // For each ref-typed vreg, iterate through ranges and find
-        // safepoints in-range. Add the SpillSlot to the stackmap.
+        // safepoints in-range. Add the allocation (a SpillSlot, or a
+        // register in `MachineEnv::stackmap_regs`) to the stackmap.
 
         if self.func.reftype_vregs().is_empty() {
             return;
         }
 
-        // Given `safepoints_per_vreg` from the liveness computation,
-        // all we have to do is, for each vreg in this map, step
-        // through the LiveRanges along with a sorted list of
-        // safepoints; and for each safepoint in the current range,
-        // emit the allocation into the `safepoint_slots` list.
-
-        trace!("safepoints_per_vreg = {:?}", self.safepoints_per_vreg);
+        // Step through the LiveRanges of each vreg along with the
+        // sorted list of all safepoints; and for each safepoint in the
+        // current range, emit the range's allocation into the
+        // `safepoint_slots` list. This follows the allocation that was
+        // actually chosen, including for a vreg that is not live at
+        // the safepoint but whose range still covers it (e.g. a dead
+        // blockparam): its virtual stack use there placed it in a
+        // stack slot or a stackmap register all the same.
 
         for vreg in self.func.reftype_vregs() {
             trace!("generating safepoint info for vreg {}", vreg);
             let vreg = VRegIndex::new(vreg.vreg());
-            let mut safepoints: Vec<ProgPoint> = self
-                .safepoints_per_vreg
-                .get(&vreg.index())
-                .unwrap()
-                .iter()
-                .map(|&inst| ProgPoint::before(inst))
-                .collect();
-            safepoints.sort_unstable();
-            trace!(" -> live over safepoints: {:?}", safepoints);
 
             let mut safepoint_idx = 0;
             for entry in &self.vregs[vreg.index()].ranges {
                 let range = entry.range;
                 let alloc = self.get_alloc_for_range(entry.index);
                 trace!(" -> range {:?}: alloc {}", range, alloc);
-                while safepoint_idx < safepoints.len() && safepoints[safepoint_idx] < range.to {
-                    if safepoints[safepoint_idx] < range.from {
+                while safepoint_idx < self.safepoints.len()
+                    && ProgPoint::before(self.safepoints[safepoint_idx]) < range.to
+                {
+                    let safepoint = ProgPoint::before(self.safepoints[safepoint_idx]);
+                    if safepoint < range.from {
                         safepoint_idx += 1;
                         continue;
                     }
-                    trace!("    -> covers safepoint {:?}", safepoints[safepoint_idx]);
+                    trace!("    -> covers safepoint {:?}", safepoint);
 
-                    self.safepoint_slots
-                        .push((safepoints[safepoint_idx], alloc));
+                    self.safepoint_slots.push((safepoint, alloc));
                     safepoint_idx += 1;
                 }
             }
@@ -24,6 +24,7 @@ macro_rules! trace {
 
 pub(crate) mod cfg;
 pub(crate) mod domtree;
+pub(crate) mod fastalloc;
 pub mod indexset;
 pub(crate) mod ion;
 pub(crate) mod moves;
@@ -34,7 +35,10 @@ pub(crate) mod ssa;
 mod index;
 pub use index::{Block, Inst, InstRange, InstRangeIter};
 
+use cfg::CFGInfo;
+
 pub mod checker;
+pub mod text;
 
 #[cfg(feature = "fuzzing")]
 pub mod fuzzing;
@@ -48,18 +52,52 @@ use serde::{Deserialize, Serialize};
 /// register-allocator level. Every register must belong to only one
 /// class; i.e., they are disjoint.
 ///
-/// For tight bit-packing throughout our data structures, we support
-/// only two classes, "int" and "float". This will usually be enough
-/// on modern machines, as they have one class of general-purpose
-/// integer registers of machine width (e.g. 64 bits), and another
-/// class of float/vector registers used both for FP and for vector
-/// operations. If needed, we could adjust bitpacking to allow for
-/// more classes in the future.
+/// For tight bit-packing throughout our data structures, the class is
+/// encoded in two bits, so we support up to four classes. Many
+/// machines need only two of them, "int" and "float": one class of
+/// general-purpose integer registers of machine width (e.g. 64 bits),
+/// and another class of float/vector registers used both for FP and
+/// for vector operations. Machines with separate register files for
+/// vectors or predicates (masks) can use the `Vector` and `Predicate`
+/// classes as well. A class that the `MachineEnv` provides no
+/// registers for simply goes unused.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub enum RegClass {
     Int = 0,
     Float = 1,
+    Vector = 2,
+    Predicate = 3,
+}
+
+impl RegClass {
+    /// The number of register classes. Arrays indexed by
+    /// `class as usize` (e.g., in `MachineEnv`) have this length.
+    pub const COUNT: usize = 4;
+
+    /// All register classes, in index order.
+    pub const ALL: [RegClass; RegClass::COUNT] = [
+        RegClass::Int,
+        RegClass::Float,
+        RegClass::Vector,
+        RegClass::Predicate,
+    ];
+
+    /// Decode a register class from its two-bit encoding.
+    #[inline(always)]
+    const fn from_bits(bits: u32) -> Self {
+        Self::ALL[(bits & 3) as usize]
+    }
+
+    /// A short suffix used when printing registers of this class.
+    fn suffix(self) -> &'static str {
+        match self {
+            RegClass::Int => "i",
+            RegClass::Float => "f",
+            RegClass::Vector => "v",
+            RegClass::Predicate => "p",
+        }
+    }
 }
 
 /// A physical register. Contains a physical register number and a class.
@@ -69,25 +107,26 @@ pub enum RegClass {
 /// register 0 is different than Float register 0.
 ///
 /// Because of bit-packed encodings throughout the implementation,
-/// `hw_enc` must fit in 6 bits, i.e., at most 64 registers per class.
+/// `hw_enc` must fit in 8 bits, i.e., at most 256 registers per class.
 ///
 /// The value returned by `index()`, in contrast, is in a single index
 /// space shared by all classes, in order to enable uniform reasoning
-/// about physical registers. This is done by putting the class bit at
-/// the MSB, or equivalently, declaring that indices 0..=63 are the 64
-/// integer registers and indices 64..=127 are the 64 float registers.
+/// about physical registers. This is done by putting the two class
+/// bits at the MSBs, or equivalently, declaring that indices 0..=255
+/// are the 256 integer registers, indices 256..=511 are the 256 float
+/// registers, and so on for the remaining classes.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct PReg {
-    bits: u8,
+    bits: u16,
 }
 
 impl PReg {
-    pub const MAX_BITS: usize = 6;
+    pub const MAX_BITS: usize = 8;
     pub const MAX: usize = (1 << Self::MAX_BITS) - 1;
-    pub const NUM_INDEX: usize = 1 << (Self::MAX_BITS + 1); // including RegClass bit
+    pub const NUM_INDEX: usize = 1 << (Self::MAX_BITS + 2); // including RegClass bits
 
-    /// Create a new PReg. The `hw_enc` range is 6 bits.
+    /// Create a new PReg. The `hw_enc` range is 8 bits.
     #[inline(always)]
     pub const fn new(hw_enc: usize, class: RegClass) -> Self {
         // We don't have const panics yet (rust-lang/rust#85194) so we
@@ -99,7 +138,7 @@ impl PReg {
         let _ = HW_ENC_MUST_BE_IN_BOUNDS[hw_enc];
 
         PReg {
-            bits: ((class as u8) << Self::MAX_BITS) | (hw_enc as u8),
+            bits: ((class as u16) << Self::MAX_BITS) | (hw_enc as u16),
         }
     }
 
@@ -112,11 +151,7 @@ impl PReg {
     /// The register class.
     #[inline(always)]
     pub fn class(self) -> RegClass {
-        if self.bits & (1 << Self::MAX_BITS) == 0 {
-            RegClass::Int
-        } else {
-            RegClass::Float
-        }
+        RegClass::from_bits((self.bits >> Self::MAX_BITS) as u32)
     }
 
     /// Get an index into the (not necessarily contiguous) index space of
@@ -131,7 +166,7 @@ impl PReg {
     #[inline(always)]
     pub const fn from_index(index: usize) -> Self {
         PReg {
-            bits: (index & (Self::NUM_INDEX - 1)) as u8,
+            bits: (index & (Self::NUM_INDEX - 1)) as u16,
         }
     }
 
@@ -157,11 +192,7 @@ impl std::fmt::Debug for PReg {
 
 impl std::fmt::Display for PReg {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let class = match self.class() {
-            RegClass::Int => "i",
-            RegClass::Float => "f",
-        };
-        write!(f, "p{}{}", self.hw_enc(), class)
+        write!(f, "p{}{}", self.hw_enc(), self.class().suffix())
     }
 }
 
@@ -169,46 +200,88 @@ impl std::fmt::Display for PReg {
 /// efficiently.
 ///
 /// The set is `Copy` and is guaranteed to have constant, and small,
-/// size, as it is based on a bitset internally.
+/// size, as it is based on a bitset internally. Bits are ordered by
+/// hardware encoding before class, so the registers of a target with
+/// at most 32 registers per class all live in the first word, and
+/// iteration skips the words that are empty.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct PRegSet {
-    bits: u128,
+    bits: [u128; PRegSet::LEN],
 }
 
 impl PRegSet {
+    /// The number of 128-bit words needed to hold one bit per
+    /// `PReg::index()`.
+    const LEN: usize = PReg::NUM_INDEX.div_ceil(128);
+
     /// Create an empty set.
     pub const fn empty() -> Self {
-        Self { bits: 0 }
+        Self {
+            bits: [0; Self::LEN],
+        }
+    }
+
+    /// Split a register into a word index and a bit mask.
+    #[inline(always)]
+    const fn split_index(reg: PReg) -> (usize, u128) {
+        let index = reg.index();
+        let bit = (index & PReg::MAX) * RegClass::COUNT + (index >> PReg::MAX_BITS);
+        (bit / 128, 1u128 << (bit % 128))
+    }
+
+    /// The register for the given word index and bit position.
+    #[inline(always)]
+    const fn reg_at(word: usize, bit: usize) -> PReg {
+        let bit = word * 128 + bit;
+        PReg::from_index(((bit % RegClass::COUNT) << PReg::MAX_BITS) | (bit / RegClass::COUNT))
     }
 
     /// Add a physical register (PReg) to the set, returning the new value.
     pub const fn with(self, reg: PReg) -> Self {
-        let bit = reg.index();
-        debug_assert!(bit < 128);
-        Self {
-            bits: self.bits | (1u128 << bit),
-        }
+        let (word, mask) = Self::split_index(reg);
+        let mut bits = self.bits;
+        bits[word] |= mask;
+        Self { bits }
     }
 
     /// Add a physical register (PReg) to the set.
     pub fn add(&mut self, reg: PReg) {
-        let bit = reg.index();
-        debug_assert!(bit < 128);
-        self.bits |= 1u128 << bit;
+        let (word, mask) = Self::split_index(reg);
+        self.bits[word] |= mask;
     }
 
     /// Remove a physical register (PReg) from the set.
     pub fn remove(&mut self, reg: PReg) {
-        let bit = reg.index();
-        debug_assert!(bit < 128);
-        self.bits &= !(1u128 << bit);
+        let (word, mask) = Self::split_index(reg);
+        self.bits[word] &= !mask;
+    }
+
+    /// Is the set empty?
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&bits| bits == 0)
+    }
+
+    /// Is the given physical register (PReg) in the set?
+    pub fn contains(&self, reg: PReg) -> bool {
+        let (word, mask) = Self::split_index(reg);
+        self.bits[word] & mask != 0
+    }
+
+    /// Is every register in this set also in the other one?
+    pub fn is_subset_of(&self, other: &PRegSet) -> bool {
+        self.bits
+            .iter()
+            .zip(other.bits.iter())
+            .all(|(bits, other_bits)| bits & !other_bits == 0)
     }
 
     /// Add all of the registers in one set to this one, mutating in
     /// place.
     pub fn union_from(&mut self, other: PRegSet) {
-        self.bits |= other.bits;
+        for (bits, other_bits) in self.bits.iter_mut().zip(other.bits.iter()) {
+            *bits |= *other_bits;
+        }
     }
 }
 
@@ -216,24 +289,39 @@ impl IntoIterator for PRegSet {
     type Item = PReg;
     type IntoIter = PRegSetIter;
     fn into_iter(self) -> PRegSetIter {
-        PRegSetIter { bits: self.bits }
+        let mut words = 0;
+        for (i, &bits) in self.bits.iter().enumerate() {
+            if bits != 0 {
+                words |= 1 << i;
+            }
+        }
+        PRegSetIter {
+            bits: self.bits,
+            words,
+        }
     }
 }
 
 pub struct PRegSetIter {
-    bits: u128,
+    bits: [u128; PRegSet::LEN],
+    /// One bit per word of `bits` that is not yet exhausted.
+    words: u8,
 }
 
 impl Iterator for PRegSetIter {
     type Item = PReg;
     fn next(&mut self) -> Option<PReg> {
-        if self.bits == 0 {
-            None
-        } else {
-            let index = self.bits.trailing_zeros();
-            self.bits &= !(1u128 << index);
-            Some(PReg::from_index(index as usize))
+        while self.words != 0 {
+            let word = self.words.trailing_zeros() as usize;
+            let bits = &mut self.bits[word];
+            if *bits != 0 {
+                let bit = bits.trailing_zeros();
+                *bits &= !(1u128 << bit);
+                return Some(PRegSet::reg_at(word, bit as usize));
+            }
+            self.words &= !(1 << word);
         }
+        None
     }
 }
 
@@ -254,34 +342,30 @@ pub struct VReg {
 }
 
 impl VReg {
-    pub const MAX_BITS: usize = 21;
+    pub const MAX_BITS: usize = 30;
     pub const MAX: usize = (1 << Self::MAX_BITS) - 1;
 
     #[inline(always)]
     pub const fn new(virt_reg: usize, class: RegClass) -> Self {
         // See comment in `PReg::new()`: we are emulating a const
-        // assert here until const panics are stable.
-        const VIRT_REG_MUST_BE_IN_BOUNDS: &[bool; VReg::MAX + 1] = &[true; VReg::MAX + 1];
-        let _ = VIRT_REG_MUST_BE_IN_BOUNDS[virt_reg];
+        // assert here until const panics are stable. The vreg space
+        // is too large for a lookup table, so index a one-element
+        // array with the out-of-bounds condition instead.
+        let _ = [true][(virt_reg > VReg::MAX) as usize];
 
         VReg {
-            bits: ((virt_reg as u32) << 1) | (class as u8 as u32),
+            bits: ((virt_reg as u32) << 2) | (class as u8 as u32),
         }
     }
 
     #[inline(always)]
     pub const fn vreg(self) -> usize {
-        let vreg = (self.bits >> 1) as usize;
-        vreg
+        (self.bits >> 2) as usize
     }
 
     #[inline(always)]
     pub fn class(self) -> RegClass {
-        match self.bits & 1 {
-            0 => RegClass::Int,
-            1 => RegClass::Float,
-            _ => unreachable!(),
-        }
+        RegClass::from_bits(self.bits)
     }
 
     #[inline(always)]
@@ -341,11 +425,7 @@ impl SpillSlot {
     /// Get the class for this spillslot.
     #[inline(always)]
     pub fn class(self) -> RegClass {
-        match (self.bits >> 24) as u8 {
-            0 => RegClass::Int,
-            1 => RegClass::Float,
-            _ => unreachable!(),
-        }
+        RegClass::from_bits(self.bits >> 24)
     }
 
     /// Get the spillslot `offset` slots away.
@@ -379,6 +459,52 @@ impl std::fmt::Display for SpillSlot {
     }
 }
 
+/// A stack location is a space in the stackframe that is defined and
+/// laid out by the client, rather than by the allocator: for example,
+/// an outgoing-argument area or a slot whose address is taken.
+///
+/// Stack locations are distinct from spillslots and are never used by
+/// the allocator to hold values except where an operand asks for one
+/// with `OperandConstraint::FixedStackLoc`. They do not consume any
+/// `PReg` indices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct StackLoc {
+    bits: u32,
+}
+
+impl StackLoc {
+    /// The maximum stack location index.
+    pub const MAX: usize = (1 << 24) - 1;
+
+    /// Create a new StackLoc of a given class.
+    #[inline(always)]
+    pub fn new(index: usize, class: RegClass) -> Self {
+        debug_assert!(index <= Self::MAX);
+        StackLoc {
+            bits: (index as u32) | (class as u8 as u32) << 24,
+        }
+    }
+
+    /// Get the index of this stack location.
+    #[inline(always)]
+    pub fn index(self) -> usize {
+        (self.bits & 0x00ffffff) as usize
+    }
+
+    /// Get the class for this stack location.
+    #[inline(always)]
+    pub fn class(self) -> RegClass {
+        RegClass::from_bits(self.bits >> 24)
+    }
+}
+
+impl std::fmt::Display for StackLoc {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "loc{}", self.index())
+    }
+}
+
 /// An `OperandConstraint` specifies where a vreg's value must be
 /// placed at a particular reference to that vreg via an
 /// `Operand`. The constraint may be loose -- "any register of a given
@@ -400,8 +526,19 @@ pub enum OperandConstraint {
     Stack,
     /// Operand must be in a fixed register.
     FixedReg(PReg),
+    /// Operand must be in a fixed, client-defined stack location. Only
+    /// valid on uses and defs (not mods) of instructions that are not
+    /// moves, and may not be the input of a reused-input def. Values
+    /// in stack locations are not included in stackmaps.
+    FixedStackLoc(StackLoc),
     /// On defs only: reuse a use's register.
     Reuse(usize),
+    /// Operand must be in one of the registers in a client-defined
+    /// subset, identified by its index in `MachineEnv::reg_subsets`.
+    /// If a vreg is used more than once by an instruction at the same
+    /// program point, its constraints there must be compatible: one
+    /// subset must contain the other, or contain the fixed register.
+    RegSubset(usize),
 }
 
 impl std::fmt::Display for OperandConstraint {
@@ -411,7 +548,9 @@ impl std::fmt::Display for OperandConstraint {
             Self::Reg => write!(f, "reg"),
             Self::Stack => write!(f, "stack"),
             Self::FixedReg(preg) => write!(f, "fixed({})", preg),
+            Self::FixedStackLoc(loc) => write!(f, "fixed({})", loc),
             Self::Reuse(idx) => write!(f, "reuse({})", idx),
+            Self::RegSubset(idx) => write!(f, "subset({})", idx),
         }
     }
 }
@@ -476,25 +615,41 @@ pub enum OperandPos {
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct Operand {
-    /// Bit-pack into 32 bits.
+    /// Bit-pack into 64 bits.
     ///
-    /// constraint:7 kind:2 pos:1 class:1 vreg:21
+    /// unused:2 vreg:30 | constraint:27 kind:2 pos:1 class:2
     ///
     /// where `constraint` is an `OperandConstraint`, `kind` is an
     /// `OperandKind`, `pos` is an `OperandPos`, `class` is a
     /// `RegClass`, and `vreg` is a vreg index.
     ///
-    /// The constraints are encoded as follows:
-    /// - 1xxxxxx => FixedReg(preg)
-    /// - 01xxxxx => Reuse(index)
-    /// - 0000000 => Any
-    /// - 0000001 => Reg
-    /// - 0000010 => Stack
+    /// The constraints are encoded as follows, with the payload in
+    /// the low bits of the field:
+    /// - 1 + 26 bits => FixedReg(preg), payload is `hw_enc`
+    /// - 01 + 25 bits => Reuse(index)
+    /// - 001 + 24 bits => FixedStackLoc(loc), payload is the index
+    /// - 0001 + 23 bits => RegSubset(index)
+    /// - 0...000 => Any
+    /// - 0...001 => Reg
+    /// - 0...010 => Stack
     /// - _ => Unused for now
-    bits: u32,
+    ///
+    /// The vreg field is wide enough for `VReg::MAX`. Everything else
+    /// fits in the low word, so that the allocator can store a use
+    /// in 32 bits plus the vreg of its live range, as it did before
+    /// operands were widened.
+    ///
+    /// There is no 32-bit form for small functions: the constraint
+    /// payloads above do not fit next to even a 21-bit vreg.
+    bits: u64,
 }
 
 impl Operand {
+    const FIXED_REG_TAG: u32 = 1 << 26;
+    const REUSE_TAG: u32 = 1 << 25;
+    const FIXED_STACK_LOC_TAG: u32 = 1 << 24;
+    const REG_SUBSET_TAG: u32 = 1 << 23;
+
     /// Construct a new operand.
     #[inline(always)]
     pub fn new(
@@ -509,22 +664,28 @@ impl Operand {
             OperandConstraint::Stack => 2,
             OperandConstraint::FixedReg(preg) => {
                 debug_assert_eq!(preg.class(), vreg.class());
-                0b1000000 | preg.hw_enc() as u32
+                Self::FIXED_REG_TAG | preg.hw_enc() as u32
+            }
+            OperandConstraint::FixedStackLoc(loc) => {
+                debug_assert_eq!(loc.class(), vreg.class());
+                debug_assert!(kind != OperandKind::Mod);
+                Self::FIXED_STACK_LOC_TAG | loc.index() as u32
             }
             OperandConstraint::Reuse(which) => {
                 debug_assert!(which <= 31);
-                0b0100000 | which as u32
+                Self::REUSE_TAG | which as u32
+            }
+            OperandConstraint::RegSubset(which) => {
+                debug_assert!(which < Self::REG_SUBSET_TAG as usize);
+                Self::REG_SUBSET_TAG | which as u32
             }
         };
         let class_field = vreg.class() as u8 as u32;
         let pos_field = pos as u8 as u32;
         let kind_field = kind as u8 as u32;
+        let low = class_field | (pos_field << 2) | (kind_field << 3) | (constraint_field << 5);
         Operand {
-            bits: vreg.vreg() as u32
-                | (class_field << 21)
-                | (pos_field << 22)
-                | (kind_field << 23)
-                | (constraint_field << 25),
+            bits: ((vreg.vreg() as u64) << 32) | low as u64,
         }
     }
 
@@ -618,7 +779,8 @@ impl Operand {
     /// reuse the register assigned to an input to the
     /// instruction. The input is identified by `idx` (is the `idx`th
     /// `Operand` for the instruction) and must be constraint to a
-    /// register, i.e., be the result of `Operand::reg_use(vreg)`.
+    /// register, i.e., be the result of `Operand::reg_use(vreg)`, or
+    /// to a register subset, which then applies to the def as well.
     #[inline(always)]
     pub fn reg_reuse_def(vreg: VReg, idx: usize) -> Self {
         Operand::new(
@@ -689,26 +851,21 @@ impl Operand {
     /// are used to track dataflow.
     #[inline(always)]
     pub fn vreg(self) -> VReg {
-        let vreg_idx = ((self.bits as usize) & VReg::MAX) as usize;
+        let vreg_idx = ((self.bits >> 32) as usize) & VReg::MAX;
         VReg::new(vreg_idx, self.class())
     }
 
     /// Get the register class used by this operand.
     #[inline(always)]
     pub fn class(self) -> RegClass {
-        let class_field = (self.bits >> 21) & 1;
-        match class_field {
-            0 => RegClass::Int,
-            1 => RegClass::Float,
-            _ => unreachable!(),
-        }
+        RegClass::from_bits(self.bits as u32)
     }
 
     /// Get the "kind" of this operand: a definition (write), a use
     /// (read), or a "mod" / modify (a read followed by a write).
     #[inline(always)]
     pub fn kind(self) -> OperandKind {
-        let kind_field = (self.bits >> 23) & 3;
+        let kind_field = (self.bits >> 3) & 3;
         match kind_field {
             0 => OperandKind::Def,
             1 => OperandKind::Mod,
@@ -723,7 +880,7 @@ impl Operand {
     /// at "after", though there are cases where this is not true.
     #[inline(always)]
     pub fn pos(self) -> OperandPos {
-        let pos_field = (self.bits >> 22) & 1;
+        let pos_field = (self.bits >> 2) & 1;
         match pos_field {
             0 => OperandPos::Early,
             1 => OperandPos::Late,
@@ -735,11 +892,17 @@ impl Operand {
     /// its allocation must fulfill.
     #[inline(always)]
     pub fn constraint(self) -> OperandConstraint {
-        let constraint_field = ((self.bits >> 25) as usize) & 127;
-        if constraint_field & 0b1000000 != 0 {
-            OperandConstraint::FixedReg(PReg::new(constraint_field & 0b0111111, self.class()))
-        } else if constraint_field & 0b0100000 != 0 {
-            OperandConstraint::Reuse(constraint_field & 0b0011111)
+        let constraint_field = (self.bits as u32) >> 5;
+        if constraint_field & Self::FIXED_REG_TAG != 0 {
+            let hw_enc = constraint_field & (Self::FIXED_REG_TAG - 1);
+            OperandConstraint::FixedReg(PReg::new(hw_enc as usize, self.class()))
+        } else if constraint_field & Self::REUSE_TAG != 0 {
+            OperandConstraint::Reuse((constraint_field & (Self::REUSE_TAG - 1)) as usize)
+        } else if constraint_field & Self::FIXED_STACK_LOC_TAG != 0 {
+            let index = constraint_field & (Self::FIXED_STACK_LOC_TAG - 1);
+            OperandConstraint::FixedStackLoc(StackLoc::new(index as usize, self.class()))
+        } else if constraint_field & Self::REG_SUBSET_TAG != 0 {
+            OperandConstraint::RegSubset((constraint_field & (Self::REG_SUBSET_TAG - 1)) as usize)
         } else {
             match constraint_field {
                 0 => OperandConstraint::Any,
@@ -750,17 +913,25 @@ impl Operand {
         }
     }
 
-    /// Get the raw 32-bit encoding of this operand's fields.
+    /// Get the raw 64-bit encoding of this operand's fields.
+    ///
+    /// This was a `u32` before vreg indices grew past 21 bits; code
+    /// that stores these bits must store a `u64`.
     #[inline(always)]
-    pub fn bits(self) -> u32 {
+    pub fn bits(self) -> u64 {
         self.bits
     }
 
-    /// Construct an `Operand` from the raw 32-bit encoding returned
+    /// Construct an `Operand` from the raw 64-bit encoding returned
     /// from `bits()`.
     #[inline(always)]
-    pub fn from_bits(bits: u32) -> Self {
-        debug_assert!(bits >> 29 <= 4);
+    pub fn from_bits(bits: u64) -> Self {
+        // The vreg fits in `VReg::MAX_BITS`, the kind is one of
+        // three, and a constraint without a tag is Any, Reg or Stack.
+        let constraint_field = (bits as u32) >> 5;
+        debug_assert!(bits >> (32 + VReg::MAX_BITS) == 0);
+        debug_assert!((bits >> 3) & 3 <= 2);
+        debug_assert!(constraint_field >= Self::REG_SUBSET_TAG || constraint_field <= 2);
         Operand { bits }
     }
 }
@@ -786,10 +957,7 @@ impl std::fmt::Display for Operand {
             f,
             ": {}{} {}",
             self.vreg(),
-            match self.class() {
-                RegClass::Int => "i",
-                RegClass::Float => "f",
-            },
+            self.class().suffix(),
             self.constraint()
         )
     }
@@ -818,6 +986,7 @@ impl std::fmt::Display for Allocation {
             AllocationKind::None => write!(f, "none"),
             AllocationKind::Reg => write!(f, "{}", self.as_reg().unwrap()),
             AllocationKind::Stack => write!(f, "{}", self.as_stack().unwrap()),
+            AllocationKind::StackLoc => write!(f, "{}", self.as_stack_loc().unwrap()),
         }
     }
 }
@@ -851,13 +1020,21 @@ impl Allocation {
         Allocation::new(AllocationKind::Stack, slot.bits as usize)
     }
 
-    /// Get the allocation's "kind": none, register, or stack (spillslot).
+    /// Create an allocation into a client-defined stack location.
+    #[inline(always)]
+    pub fn stack_loc(loc: StackLoc) -> Allocation {
+        Allocation::new(AllocationKind::StackLoc, loc.bits as usize)
+    }
+
+    /// Get the allocation's "kind": none, register, stack (spillslot),
+    /// or client-defined stack location.
     #[inline(always)]
     pub fn kind(self) -> AllocationKind {
         match (self.bits >> 29) & 7 {
             0 => AllocationKind::None,
             1 => AllocationKind::Reg,
             2 => AllocationKind::Stack,
+            3 => AllocationKind::StackLoc,
             _ => unreachable!(),
         }
     }
@@ -886,6 +1063,12 @@ impl Allocation {
         self.kind() == AllocationKind::Stack
     }
 
+    /// Is the allocation a client-defined stack location?
+    #[inline(always)]
+    pub fn is_stack_loc(self) -> bool {
+        self.kind() == AllocationKind::StackLoc
+    }
+
     /// Get the index of the spillslot or register. If register, this
     /// is an index that can be used by `PReg::from_index()`.
     #[inline(always)]
@@ -915,6 +1098,18 @@ impl Allocation {
         }
     }
 
+    /// Get the allocation as a client-defined stack location, if any.
+    #[inline(always)]
+    pub fn as_stack_loc(self) -> Option<StackLoc> {
+        if self.kind() == AllocationKind::StackLoc {
+            Some(StackLoc {
+                bits: self.index() as u32,
+            })
+        } else {
+            None
+        }
+    }
+
     /// Get the raw bits for the packed encoding of this allocation.
     #[inline(always)]
     pub fn bits(self) -> u32 {
@@ -924,13 +1119,13 @@ impl Allocation {
     /// Construct an allocation from its packed encoding.
     #[inline(always)]
     pub fn from_bits(bits: u32) -> Self {
-        debug_assert!(bits >> 29 >= 5);
+        debug_assert!(bits >> 29 <= 3);
         Self { bits }
     }
 }
 
-/// An allocation is one of two "kinds" (or "none"): register or
-/// spillslot/stack.
+/// An allocation is one of three "kinds" (or "none"): register,
+/// spillslot/stack, or client-defined stack location.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u8)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
@@ -938,6 +1133,7 @@ pub enum AllocationKind {
     None = 0,
     Reg = 1,
     Stack = 2,
+    StackLoc = 3,
 }
 
 impl Allocation {
@@ -948,6 +1144,7 @@ impl Allocation {
             AllocationKind::None => panic!("Allocation::None has no class"),
             AllocationKind::Reg => self.as_reg().unwrap().class(),
             AllocationKind::Stack => self.as_stack().unwrap().class(),
+            AllocationKind::StackLoc => self.as_stack_loc().unwrap().class(),
         }
     }
 }
@@ -962,7 +1159,8 @@ pub trait Function {
     // CFG traversal
     // -------------
 
-    /// How many instructions are there?
+    /// How many instructions are there? At most 2^31, as a
+    /// `ProgPoint` packs the instruction index into 31 bits.
     fn num_insts(&self) -> usize;
 
     /// How many blocks are there?
@@ -1000,12 +1198,10 @@ pub trait Function {
     /// values to be placed onto the stack. For these instructions,
     /// stackmaps will be provided.
     ///
-    /// This is usually associated with the concept of a "safepoint",
-    /// though strictly speaking, a safepoint could also support
-    /// reference-typed values in registers if there were a way to
-    /// denote their locations and if this were acceptable to the
-    /// client. Usually garbage-collector implementations want to see
-    /// roots on the stack, so we do that for now.
+    /// This is usually associated with the concept of a "safepoint".
+    /// Reference-typed values may also stay in registers across the
+    /// instruction if they are listed in `MachineEnv::stackmap_regs`,
+    /// for clients whose stackmaps can denote register locations.
     fn requires_refs_on_stack(&self, _: Inst) -> bool {
         false
     }
@@ -1133,6 +1329,23 @@ pub trait Function {
         false
     }
 
+    /// Can the value of the given vreg be recomputed, rather than
+    /// reloaded from its spillslot, at any point where it is live?
+    /// This is typically the case for constants and for address
+    /// computations from values that are always available (e.g., the
+    /// frame pointer). If so, return the cost of doing so relative to
+    /// a reload, which has cost `REMAT_RELOAD_COST`; only values that
+    /// are cheaper to recompute than to reload are rematerialized.
+    ///
+    /// The allocator then emits `Edit::Remat` rather than a move
+    /// from the stack wherever it would reload the vreg into a
+    /// register, and weighs the cost of spilling it accordingly. The
+    /// value is still stored to its spillslot when spilled, as
+    /// instructions and block-parameter moves may read it there.
+    fn remat_cost(&self, _: VReg) -> Option<u32> {
+        None
+    }
+
     // -----------
     // Misc config
     // -----------
@@ -1192,8 +1405,11 @@ impl std::fmt::Debug for ProgPoint {
 
 impl ProgPoint {
     /// Create a new ProgPoint before or after the given instruction.
+    /// The instruction index must be below 2^31 (or the invalid
+    /// index).
     #[inline(always)]
     pub fn new(inst: Inst, pos: InstPosition) -> Self {
+        debug_assert!(inst.0 < 1 << 31 || inst == Inst::invalid());
         let bits = ((inst.0 as u32) << 1) | (pos as u8 as u32);
         Self { bits }
     }
@@ -1273,8 +1489,18 @@ pub enum Edit {
     /// are the same if the vreg changes; this allows proper metadata
     /// tracking even when moves are elided.
     Move { from: Allocation, to: Allocation },
+
+    /// Recompute the value of `vreg` into `to`, which is always a
+    /// register, rather than reloading it from the stack. Only
+    /// generated for vregs for which `Function::remat_cost` indicates
+    /// that this is cheaper than a reload.
+    Remat { vreg: VReg, to: Allocation },
 }
 
+/// The cost of a reload from a spillslot, in the units of
+/// `Function::remat_cost`.
+pub const REMAT_RELOAD_COST: u32 = 100;
+
 /// Wrapper around either an original instruction or an inserted edit.
 #[derive(Clone, Debug)]
 pub enum InstOrEdit<'a> {
@@ -1322,26 +1548,115 @@ impl<'a> Iterator for OutputIter<'a> {
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct MachineEnv {
-    /// Preferred physical registers for each class. These are the
-    /// registers that will be allocated first, if free.
-    pub preferred_regs_by_class: [Vec<PReg>; 2],
+    /// Preferred physical registers for each class, indexed by
+    /// `RegClass as usize`. These are the registers that will be
+    /// allocated first, if free.
+    pub preferred_regs_by_class: [Vec<PReg>; RegClass::COUNT],
 
     /// Non-preferred physical registers for each class. These are the
     /// registers that will be allocated if a preferred register is
     /// not available; using one of these is considered suboptimal,
     /// but still better than spilling.
-    pub non_preferred_regs_by_class: [Vec<PReg>; 2],
+    pub non_preferred_regs_by_class: [Vec<PReg>; RegClass::COUNT],
 
     /// Some `PReg`s can be designated as locations on the stack rather than
     /// actual registers. These can be used to tell the register allocator about
     /// pre-defined stack slots used for function arguments and return values.
     ///
     /// `PReg`s in this list cannot be used as an allocatable register.
+    /// `OperandConstraint::FixedStackLoc` provides the same capability
+    /// without consuming `PReg` indices.
     pub fixed_stack_slots: Vec<PReg>,
+
+    /// Pairs of physical registers that overlap, i.e., share some
+    /// storage so that writing one changes the value of the other:
+    /// for example, ARM32's `s0` and `s1` each overlap `d0`, and
+    /// x86's `al` overlaps `ax`. Both registers of a pair must be in
+    /// the same class. The relation is symmetric but not transitive,
+    /// so every overlapping pair must be listed (in either order).
+    ///
+    /// The allocator never assigns overlapping registers to values
+    /// that are live at the same time, and treats a clobber of a
+    /// register (see `Function::inst_clobbers`) as also clobbering
+    /// its aliases. At least one register in each class should have
+    /// no aliases, so that it can serve as the last-resort scratch
+    /// register during move resolution.
+    pub aliases: Vec<(PReg, PReg)>,
+
+    /// Subsets of the allocatable registers that operands may be
+    /// limited to with `OperandConstraint::RegSubset`, for encodings
+    /// that can name only some registers of a class: for example,
+    /// x86 byte operations without a REX prefix, or RISC-V compressed
+    /// instructions. Each subset must contain only registers of one
+    /// class, including at least one allocatable register.
+    pub reg_subsets: Vec<PRegSet>,
+
+    /// Registers in which reference-typed values may stay across
+    /// safepoints (see `Function::requires_refs_on_stack`), for
+    /// clients whose stackmaps can describe registers, typically
+    /// callee-saved ones. Such registers are then reported in
+    /// `Output::safepoint_slots` alongside stack slots. If empty (the
+    /// default), reference-typed values are always spilled to the
+    /// stack at safepoints.
+    pub stackmap_regs: PRegSet,
+}
+
+/// Measures of the quality of an allocation, to track regressions
+/// without post-processing `Output::edits`.
+///
+/// The weighted counts scale each edit by an estimate of how often
+/// its block runs: 8 for each level of loop nesting around the
+/// block, up to 4 levels (see `CFGInfo::approx_loop_depth`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct QualityMetrics {
+    /// Inserted moves from a register to a register.
+    pub reg_moves: usize,
+    /// Inserted moves into a spillslot or another stack location.
+    pub spills: usize,
+    /// Inserted moves from a spillslot or another stack location into
+    /// a register.
+    pub reloads: usize,
+    /// Inserted rematerializations (`Edit::Remat`).
+    pub remats: usize,
+    /// How many of the edits above are on control-flow edges, i.e.
+    /// move block parameters or values whose location differs between
+    /// a block and its successor.
+    pub edge_moves: usize,
+
+    /// `reg_moves`, weighted by loop depth.
+    pub weighted_reg_moves: u64,
+    /// `spills`, weighted by loop depth.
+    pub weighted_spills: u64,
+    /// `reloads`, weighted by loop depth.
+    pub weighted_reloads: u64,
+    /// `remats`, weighted by loop depth.
+    pub weighted_remats: u64,
+    /// `edge_moves`, weighted by loop depth.
+    pub weighted_edge_moves: u64,
+
+    /// Spillslots in the frame, as in `Output::num_spillslots`.
+    pub spillslots: usize,
+    /// Program moves (see `Function::is_move`) that needed no inserted
+    /// move, because the source and destination share a location or
+    /// the destination is never read.
+    pub coalesced_moves: usize,
+}
+
+impl QualityMetrics {
+    /// Count a move inserted at `pos` on a control-flow edge.
+    pub(crate) fn add_edge_move(&mut self, cfginfo: &CFGInfo, pos: ProgPoint) {
+        let block = cfginfo.insn_block[pos.inst().index()];
+        self.edge_moves += 1;
+        self.weighted_edge_moves += cfginfo.block_weight(block);
+    }
 }
 
 /// The output of the register allocator.
-#[derive(Clone, Debug)]
+///
+/// An `Output` may be passed to `run_with_ctx` again to reuse its
+/// buffers for the next function.
+#[derive(Clone, Debug, Default)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct Output {
     /// How many spillslots are needed in the frame?
@@ -1359,21 +1674,36 @@ pub struct Output {
     pub inst_alloc_offsets: Vec<u32>,
 
     /// Safepoint records: at a given program point, a reference-typed value
-    /// lives in the given Allocation. Currently these are guaranteed to be
-    /// stack slots, but in the future an option may be added to allow
-    /// reftype value to be kept in registers at safepoints.
+    /// lives in the given Allocation. These are stack slots, or registers
+    /// in `MachineEnv::stackmap_regs`.
     pub safepoint_slots: Vec<(ProgPoint, Allocation)>,
 
     /// Debug info: a labeled value (as applied to vregs by
     /// `Function::debug_value_labels()` on the input side) is located
     /// in the given allocation from the first program point
     /// (inclusive) to the second (exclusive). Guaranteed to be sorted
-    /// by label and program point, and the ranges are guaranteed to
-    /// be disjoint.
+    /// by label and program point, and the ranges for each label are
+    /// guaranteed to be disjoint unless the label was requested for
+    /// several vregs over overlapping ranges.
     pub debug_locations: Vec<(u32, ProgPoint, ProgPoint, Allocation)>,
 
+    /// Registers written by `Def` and `Mod` operands, e.g. to decide
+    /// which callee-saved registers must be saved. Clobbers (see
+    /// `Function::inst_clobbers`) and registers in
+    /// `MachineEnv::fixed_stack_slots` are not included.
+    pub operand_def_regs: PRegSet,
+
+    /// Registers written by edits, i.e. by moves and
+    /// rematerializations that the allocator inserted, including any
+    /// use of a scratch register during move resolution. Registers in
+    /// `MachineEnv::fixed_stack_slots` are not included.
+    pub edit_def_regs: PRegSet,
+
     /// Internal stats from the allocator.
     pub stats: ion::Stats,
+
+    /// How many moves the allocator inserted, and where.
+    pub quality: QualityMetrics,
 }
 
 impl Output {
@@ -1411,6 +1741,110 @@ impl Output {
         let edits = &self.edits[edit_idx..];
         OutputIter { inst_range, edits }
     }
+
+    /// Fill in `operand_def_regs` and `edit_def_regs` from the
+    /// allocations and edits.
+    pub(crate) fn compute_def_regs<F: Function>(&mut self, func: &F, env: &MachineEnv) {
+        let fixed_stack_slots = env
+            .fixed_stack_slots
+            .iter()
+            .fold(PRegSet::empty(), |set, &preg| set.with(preg));
+        let mut operand_def_regs = PRegSet::empty();
+        for inst in 0..func.num_insts() {
+            let inst = Inst::new(inst);
+            let operands = func.inst_operands(inst);
+            for (op, alloc) in operands.iter().zip(self.inst_allocs(inst)) {
+                if op.kind() == OperandKind::Use {
+                    continue;
+                }
+                if let Some(preg) = alloc.as_reg() {
+                    if !fixed_stack_slots.contains(preg) {
+                        operand_def_regs.add(preg);
+                    }
+                }
+            }
+        }
+        let mut edit_def_regs = PRegSet::empty();
+        for (_, edit) in &self.edits {
+            let to = match edit {
+                Edit::Move { to, .. } | Edit::Remat { to, .. } => to,
+            };
+            if let Some(preg) = to.as_reg() {
+                if !fixed_stack_slots.contains(preg) {
+                    edit_def_regs.add(preg);
+                }
+            }
+        }
+        self.operand_def_regs = operand_def_regs;
+        self.edit_def_regs = edit_def_regs;
+    }
+
+    /// Fill in the counts of `quality` that follow from the edits and
+    /// `num_spillslots`. The allocator counts edge moves and coalesced
+    /// program moves itself, as only it knows which those are.
+    pub(crate) fn compute_quality(&mut self, env: &MachineEnv, cfginfo: &CFGInfo) {
+        let fixed_stack_slots = env
+            .fixed_stack_slots
+            .iter()
+            .fold(PRegSet::empty(), |set, &preg| set.with(preg));
+        let in_memory = |alloc: Allocation| match alloc.as_reg() {
+            Some(preg) => fixed_stack_slots.contains(preg),
+            None => alloc.is_some(),
+        };
+        let mut quality = QualityMetrics {
+            edge_moves: self.quality.edge_moves,
+            weighted_edge_moves: self.quality.weighted_edge_moves,
+            spillslots: self.num_spillslots,
+            coalesced_moves: self.quality.coalesced_moves,
+            ..QualityMetrics::default()
+        };
+        for &(pos, ref edit) in &self.edits {
+            let weight = cfginfo.block_weight(cfginfo.insn_block[pos.inst().index()]);
+            let (count, weighted) = match *edit {
+                Edit::Move { to, .. } if in_memory(to) => {
+                    (&mut quality.spills, &mut quality.weighted_spills)
+                }
+                Edit::Move { from, .. } if in_memory(from) => {
+                    (&mut quality.reloads, &mut quality.weighted_reloads)
+                }
+                Edit::Move { .. } => (&mut quality.reg_moves, &mut quality.weighted_reg_moves),
+                Edit::Remat { .. } => (&mut quality.remats, &mut quality.weighted_remats),
+            };
+            *count += 1;
+            *weighted += weight;
+        }
+        self.quality = quality;
+    }
+
+    /// Returns the registers written in a block, by operand defs or by
+    /// edits: the per-block breakdown of `operand_def_regs` and
+    /// `edit_def_regs`, e.g. to place callee-saved register spills
+    /// only around the blocks that need them.
+    pub fn block_def_regs(&self, func: &impl Function, block: Block) -> PRegSet {
+        let mut regs = PRegSet::empty();
+        let mut add = |alloc: Allocation| {
+            if let Some(preg) = alloc.as_reg() {
+                if self.operand_def_regs.contains(preg) || self.edit_def_regs.contains(preg) {
+                    regs.add(preg);
+                }
+            }
+        };
+        for inst_or_edit in self.block_insts_and_edits(func, block) {
+            match inst_or_edit {
+                InstOrEdit::Inst(inst) => {
+                    let operands = func.inst_operands(inst);
+                    for (op, &alloc) in operands.iter().zip(self.inst_allocs(inst)) {
+                        if op.kind() != OperandKind::Use {
+                            add(alloc);
+                        }
+                    }
+                }
+                InstOrEdit::Edit(Edit::Move { to, .. })
+                | InstOrEdit::Edit(Edit::Remat { to, .. }) => add(*to),
+            }
+        }
+        regs
+    }
 }
 
 /// An error that prevents allocation.
@@ -1440,6 +1874,9 @@ pub enum RegAllocError {
     /// Too many pinned VRegs + Reg-constrained Operands are live at
     /// once, making allocation impossible.
     TooManyLiveRegs,
+    /// The requested operation does not support the given algorithm,
+    /// e.g. `visualize` with an algorithm other than `Algorithm::Ion`.
+    UnsupportedAlgorithm(Algorithm),
 }
 
 impl std::fmt::Display for RegAllocError {
@@ -1454,14 +1891,347 @@ impl std::error::Error for RegAllocError {}
 pub fn run<F: Function>(
     func: &F,
     env: &MachineEnv,
-    options: &RegallocOptions,
+    options: &RegallocOptions<'_>,
 ) -> Result<Output, RegAllocError> {
-    ion::run(func, env, options.verbose_log)
+    match options.algorithm {
+        Algorithm::Ion => {
+            let mut output = Output::default();
+            let mut ctx = ion::Ctx::default();
+            ion::run_with_ctx(
+                func,
+                env,
+                options.verbose_log,
+                options.budget,
+                options.observer,
+                &mut ctx,
+                &mut output,
+            )?;
+            Ok(output)
+        }
+        Algorithm::Fastalloc | Algorithm::SpillEverything => {
+            let spill_everything = options.algorithm == Algorithm::SpillEverything;
+            let mut output = Output::default();
+            let mut ctx = fastalloc::Ctx::default();
+            fastalloc::run(func, env, spill_everything, &mut ctx, &mut output)?;
+            Ok(output)
+        }
+    }
+}
+
+/// Run the allocator, like `run`, then verify the result with
+/// `checker::Checker`: that each operand finds its vreg's value in an
+/// allocation that meets its constraint, and that the stackmaps,
+/// debug locations and frame are consistent. Checking is much slower
+/// than allocation, so this suits debug builds and testing.
+pub fn run_checked<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions<'_>,
+) -> Result<Output, RunCheckedError> {
+    let output = run(func, env, options)?;
+    let mut checker = checker::Checker::new(func, env);
+    checker.prepare(&output);
+    checker.run()?;
+    Ok(output)
+}
+
+/// An error from `run_checked`.
+#[derive(Clone, Debug)]
+pub enum RunCheckedError {
+    /// Allocation failed.
+    Alloc(RegAllocError),
+    /// Allocation succeeded, but the checker found the result wrong.
+    Checker(checker::CheckerErrors),
+}
+
+impl From<RegAllocError> for RunCheckedError {
+    fn from(e: RegAllocError) -> Self {
+        RunCheckedError::Alloc(e)
+    }
+}
+
+impl From<checker::CheckerErrors> for RunCheckedError {
+    fn from(e: checker::CheckerErrors) -> Self {
+        RunCheckedError::Checker(e)
+    }
+}
+
+impl std::fmt::Display for RunCheckedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RunCheckedError::Alloc(e) => write!(f, "allocation failed: {}", e),
+            RunCheckedError::Checker(e) => write!(f, "checker failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RunCheckedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RunCheckedError::Alloc(e) => Some(e),
+            RunCheckedError::Checker(e) => Some(e),
+        }
+    }
+}
+
+/// Buffers that the allocator keeps between runs.
+///
+/// Allocating many functions with `run_with_ctx` and the same context
+/// (and `Output`) lets the allocator reuse the memory from earlier
+/// functions instead of allocating it again, so that once the buffers
+/// have grown to fit the largest function, later runs allocate little
+/// more. A context holds no results, only storage.
+///
+/// What the `Ion` allocator still allocates on every run are the
+/// B-tree nodes of its per-register and per-spillslot interval maps,
+/// and the range and use lists that it creates when it splits
+/// bundles.
+#[derive(Debug, Default)]
+pub struct RegallocContext {
+    ion: ion::Ctx,
+    fastalloc: fastalloc::Ctx,
+}
+
+impl RegallocContext {
+    /// Create an empty context.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Run the allocator, reusing the buffers in `ctx` and `output`.
+///
+/// On success, the results are in `output`, replacing whatever it
+/// held before. On failure, the contents of `output` are unspecified,
+/// but both it and `ctx` may still be used for later runs.
+pub fn run_with_ctx<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions<'_>,
+    ctx: &mut RegallocContext,
+    output: &mut Output,
+) -> Result<(), RegAllocError> {
+    match options.algorithm {
+        Algorithm::Ion => ion::run_with_ctx(
+            func,
+            env,
+            options.verbose_log,
+            options.budget,
+            options.observer,
+            &mut ctx.ion,
+            output,
+        ),
+        Algorithm::Fastalloc => fastalloc::run(func, env, false, &mut ctx.fastalloc, output),
+        Algorithm::SpillEverything => fastalloc::run(func, env, true, &mut ctx.fastalloc, output),
+    }
+}
+
+/// Run the `Ion` allocator, like `run`, and also render its live
+/// ranges, bundles, spillsets, register commitment maps and inserted
+/// moves as a self-contained HTML page, with a timeline of which vreg
+/// occupies each register and spillslot at each program point. This
+/// helps to understand, e.g., why a vreg was spilled.
+///
+/// The page always shows the state at the end of allocation; if
+/// `intermediate` is set, it also shows the state after bundle
+/// merging and after the main phases of allocation. The other
+/// algorithms have no such state, so for any `algorithm` in `options`
+/// other than `Algorithm::Ion`, this returns
+/// `RegAllocError::UnsupportedAlgorithm`.
+pub fn visualize<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions<'_>,
+    intermediate: bool,
+) -> Result<(Output, String), RegAllocError> {
+    if options.algorithm != Algorithm::Ion {
+        return Err(RegAllocError::UnsupportedAlgorithm(options.algorithm));
+    }
+    ion::visualize(
+        func,
+        env,
+        options.verbose_log,
+        options.budget,
+        options.observer,
+        intermediate,
+    )
+}
+
+/// The allocation algorithm to use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum Algorithm {
+    /// The backtracking allocator, which splits live ranges and
+    /// produces good code. See doc/DESIGN.md. The default.
+    #[default]
+    Ion,
+    /// A single-pass allocator that is much faster than `Ion` but
+    /// produces considerably worse code, for baseline JIT tiers. It
+    /// keeps values in registers only within a block, and in
+    /// spillslots between blocks.
+    Fastalloc,
+    /// A trivial allocator for debugging, which keeps every value in
+    /// a spillslot of its own and loads it into a register only for
+    /// the instructions that need it there. Its simplicity makes it
+    /// unlikely to be wrong, so comparing a miscompile under it with
+    /// one under `Ion` tells whether the allocator is to blame.
+    SpillEverything,
 }
 
 /// Options for allocation.
 #[derive(Clone, Copy, Debug, Default)]
-pub struct RegallocOptions {
+pub struct RegallocOptions<'a> {
     /// Add extra verbosity to debug logs.
     pub verbose_log: bool,
+
+    /// The allocation algorithm to use.
+    pub algorithm: Algorithm,
+
+    /// A bound on the work of the `Ion` algorithm, in steps. A step
+    /// of the main loop tries to find a register for one bundle,
+    /// evicting others if need be; a step of the second-chance pass
+    /// tries one register for one spill bundle. Once the bound is
+    /// reached, each remaining bundle that needs a register gets one
+    /// only around each of its uses and is spilled elsewhere, values
+    /// that may stay in `MachineEnv::stackmap_regs` at safepoints go
+    /// to the stack, and the remaining spill bundles stay in their
+    /// spillslots. This is
+    /// quick but produces worse code; `budget_fallback_bundles` and
+    /// `budget_spilled_bundles` in `Output::stats` count those
+    /// bundles. The budget does not bound liveness analysis, spillslot
+    /// assignment or move insertion and resolution, whose work grows
+    /// with the size of the function and of its output, not with the
+    /// search for registers. Unbounded if `None`.
+    pub budget: Option<usize>,
+
+    /// Receives the decisions of the `Ion` algorithm as they are
+    /// made; see `AllocationEvent`. The other algorithms report
+    /// nothing.
+    pub observer: Option<&'a dyn AllocationObserver>,
+}
+
+define_index!(Bundle);
+
+/// Receives the decisions of the `Ion` allocator, e.g. to collect
+/// statistics on its heuristics or to trace how a vreg ended up where
+/// it did, without parsing its logs. Set `RegallocOptions::observer`
+/// to use it.
+///
+/// Events refer to `Bundle`s, the groups of live ranges that Ion
+/// allocates as a unit. Each starts out holding one vreg
+/// (`AllocationEvent::Create`) and then changes through merges and
+/// splits.
+///
+/// Observers are shared, like the rest of `RegallocOptions`; one that
+/// keeps state, e.g. to record events, holds it in a `Cell` or
+/// `RefCell`.
+pub trait AllocationObserver {
+    /// Called for each event, in the order the allocator makes its
+    /// decisions.
+    fn event(&self, event: &AllocationEvent<'_>);
+}
+
+impl std::fmt::Debug for dyn AllocationObserver + '_ {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AllocationObserver")
+    }
+}
+
+/// A decision of the `Ion` allocator; see `AllocationObserver`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocationEvent<'a> {
+    /// `bundle` was created to hold the live ranges of `vreg`.
+    Create { bundle: Bundle, vreg: VReg },
+    /// The live ranges of bundle `from` were moved into bundle
+    /// `into`, leaving `from` empty.
+    Merge { from: Bundle, into: Bundle },
+    /// The allocator tried to put `bundle` in `preg`.
+    Probe {
+        bundle: Bundle,
+        preg: PReg,
+        result: ProbeResult<'a>,
+    },
+    /// `bundle`, which was in `preg`, was evicted to make room for
+    /// bundle `by`, and will be allocated again.
+    Evict {
+        bundle: Bundle,
+        preg: PReg,
+        by: Bundle,
+    },
+    /// `bundle` was split into itself and the bundles `into`, at
+    /// `at` or, if that is `None`, around each of its uses.
+    Split {
+        bundle: Bundle,
+        at: Option<ProgPoint>,
+        reason: SplitReason,
+        into: &'a [Bundle],
+    },
+    /// `bundle` gets no register in the main allocation loop: it
+    /// either needs no register or must be on the stack. Unless it
+    /// must be on the stack, it gets a second chance later.
+    Spill { bundle: Bundle },
+    /// After the main allocation loop, the spilled `bundle` was given
+    /// `preg` if one was free throughout it, or else left in its
+    /// spillslot.
+    SecondChance { bundle: Bundle, preg: Option<PReg> },
+}
+
+/// The result of `AllocationEvent::Probe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeResult<'a> {
+    /// The register was free, and the bundle now has it.
+    Allocated,
+    /// The register is taken by `bundles`, first at `point`; they
+    /// might be evicted.
+    Conflict {
+        bundles: &'a [Bundle],
+        point: ProgPoint,
+    },
+    /// The register is reserved at `point` by a fixed-register
+    /// constraint or a pinned vreg.
+    ConflictWithFixed { point: ProgPoint },
+    /// The register is taken by bundles that would cost more to evict
+    /// than those in an earlier conflict, so the probe was cut short.
+    ConflictHighCost,
+}
+
+/// Why a bundle was split; see `AllocationEvent::Split`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitReason {
+    /// Its uses have constraints that no single location meets, e.g.
+    /// two different fixed registers.
+    ConflictingRequirements,
+    /// No register was free throughout it, and it costs less to split
+    /// than to evict the bundles in the way.
+    RegisterConflict,
+    /// It can be rematerialized, so it only needs a register at its
+    /// uses.
+    Rematerializable,
+    /// It was split too many times already.
+    TooManySplits,
+    /// The `RegallocOptions::budget` is spent.
+    Budget,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allocation_bits_round_trip() {
+        let allocs = [
+            Allocation::none(),
+            Allocation::reg(PReg::new(PReg::MAX, RegClass::Predicate)),
+            Allocation::stack(SpillSlot::new(SpillSlot::MAX, RegClass::Vector)),
+            Allocation::stack_loc(StackLoc::new(StackLoc::MAX, RegClass::Float)),
+            Allocation::stack_loc(StackLoc::new(0, RegClass::Int)),
+        ];
+        for &alloc in &allocs {
+            assert_eq!(Allocation::from_bits(alloc.bits()), alloc);
+        }
+        let loc = Allocation::stack_loc(StackLoc::new(7, RegClass::Vector));
+        let loc = Allocation::from_bits(loc.bits());
+        assert_eq!(loc.kind(), AllocationKind::StackLoc);
+        assert_eq!(loc.as_stack_loc(), Some(StackLoc::new(7, RegClass::Vector)));
+    }
 }
@@ -284,12 +284,13 @@ impl<T> MoveVecWithScratch<T> {
         }
     }
 
-    /// Do any moves go from stack to stack?
-    pub fn stack_to_stack(&self) -> bool {
+    /// Do any moves go from stack to stack, given which allocations
+    /// are on the stack?
+    pub fn stack_to_stack(&self, is_stack_alloc: impl Fn(Allocation) -> bool) -> bool {
         match self {
             MoveVecWithScratch::NoScratch(moves) | MoveVecWithScratch::Scratch(moves) => moves
                 .iter()
-                .any(|(src, dst, _)| src.is_stack() && dst.is_stack()),
+                .any(|&(src, dst, _)| is_stack_alloc(src) && is_stack_alloc(dst)),
         }
     }
 }
@@ -320,10 +321,11 @@ impl<T> MoveVecWithScratch<T> {
 /// Sometimes move elision will be able to clean this up a bit. But,
 /// for simplicity reasons, let's keep the concerns separated! So we
 /// always do the full expansion above.
-pub struct MoveAndScratchResolver<GetReg, GetStackSlot>
+pub struct MoveAndScratchResolver<GetReg, GetStackSlot, IsStackAlloc>
 where
     GetReg: FnMut() -> Option<Allocation>,
     GetStackSlot: FnMut() -> Allocation,
+    IsStackAlloc: Fn(Allocation) -> bool,
 {
     /// Scratch register for stack-to-stack move expansion.
     stack_stack_scratch_reg: Option<Allocation>,
@@ -335,6 +337,11 @@ where
     find_free_reg: GetReg,
     /// Closure that gets us a stackslot, if needed.
     get_stackslot: GetStackSlot,
+    /// Closure that determines whether an allocation is in memory,
+    /// such that a move between two such allocations needs a
+    /// register: a spillslot, a client-defined stack location, or a
+    /// register that stands for a fixed stack slot.
+    is_stack_alloc: IsStackAlloc,
     /// The victim PReg to evict to another stackslot at every
     /// stack-to-stack move if a free PReg is not otherwise
     /// available. Provided by caller and statically chosen. This is a
@@ -342,17 +349,24 @@ where
     victim: PReg,
 }
 
-impl<GetReg, GetStackSlot> MoveAndScratchResolver<GetReg, GetStackSlot>
+impl<GetReg, GetStackSlot, IsStackAlloc> MoveAndScratchResolver<GetReg, GetStackSlot, IsStackAlloc>
 where
     GetReg: FnMut() -> Option<Allocation>,
     GetStackSlot: FnMut() -> Allocation,
+    IsStackAlloc: Fn(Allocation) -> bool,
 {
-    pub fn new(find_free_reg: GetReg, get_stackslot: GetStackSlot, victim: PReg) -> Self {
+    pub fn new(
+        find_free_reg: GetReg,
+        get_stackslot: GetStackSlot,
+        is_stack_alloc: IsStackAlloc,
+        victim: PReg,
+    ) -> Self {
         Self {
             stack_stack_scratch_reg: None,
             stack_stack_scratch_reg_save: None,
             find_free_reg,
             get_stackslot,
+            is_stack_alloc,
             victim,
         }
     }
@@ -360,7 +374,7 @@ where
     pub fn compute<T: Debug + Copy>(mut self, moves: MoveVecWithScratch<T>) -> MoveVec<T> {
         // First, do we have a vec with no stack-to-stack moves or use
         // of a scratch register? Fast return if so.
-        if !moves.needs_scratch() && !moves.stack_to_stack() {
+        if !moves.needs_scratch() && !moves.stack_to_stack(&self.is_stack_alloc) {
             return moves.without_scratch().unwrap();
         }
 
@@ -373,7 +387,7 @@ where
         let moves = moves.with_scratch(scratch);
         for &(src, dst, data) in &moves {
             // Do we have a stack-to-stack move? If so, resolve.
-            if src.is_stack() && dst.is_stack() {
+            if (self.is_stack_alloc)(src) && (self.is_stack_alloc)(dst) {
                 trace!("scratch resolver: stack to stack: {:?} -> {:?}", src, dst);
                 // Lazily allocate a stack-to-stack scratch.
                 if self.stack_stack_scratch_reg.is_none() {
@@ -426,3 +440,34 @@ where
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{RegClass, SpillSlot};
+
+    /// A register in `MachineEnv::fixed_stack_slots` is memory, so a
+    /// move between it and a spillslot must go through a register.
+    #[test]
+    fn test_fixed_stack_slot_to_spillslot() {
+        let fixed = Allocation::reg(PReg::new(63, RegClass::Int));
+        let slot = Allocation::stack(SpillSlot::new(0, RegClass::Int));
+        let scratch = Allocation::reg(PReg::new(0, RegClass::Int));
+        let victim = PReg::new(1, RegClass::Int);
+        let is_stack_alloc = |alloc: Allocation| alloc.is_stack() || alloc == fixed;
+
+        let mut moves = ParallelMoves::new();
+        moves.add(slot, fixed, ());
+        let resolved = MoveAndScratchResolver::new(
+            || Some(scratch),
+            || panic!("no stackslot needed"),
+            is_stack_alloc,
+            victim,
+        )
+        .compute(moves.resolve());
+        assert_eq!(
+            resolved.into_vec(),
+            vec![(slot, scratch, ()), (scratch, fixed, ())]
+        );
+    }
+}
@@ -0,0 +1,1214 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! A human-readable text format for allocator inputs.
+//!
+//! A function and the machine environment to allocate it for can be
+//! written down, e.g. to reproduce a bug outside of the program that
+//! hit it, and read back in as a `TextFunction`, which implements
+//! `Function`. Any `Function` implementor can be printed in this
+//! format with `write_function`, and a `MachineEnv` with
+//! `write_machine_env`.
+//!
+//! The format looks like this:
+//!
+//! ```text
+//! machine_env {
+//!     preferred(p0i, p1i, p2i, p0f, p1f)
+//!     non_preferred(p3i, p2f)
+//!     fixed_stack_slots(p63i)
+//!     alias(p2f, p0f)
+//!     reg_subset(p0i, p1i)
+//!     stackmap_regs()
+//! }
+//!
+//! function {
+//!     entry block0
+//!     vregs 4
+//!     spillslot_sizes(i: 1, f: 2, v: 4, p: 1)
+//!     reftype v3i
+//!     pinned v2i p2i
+//!     remat v1i 10
+//!     debug_label v0i inst0 inst2 7
+//!     block0():
+//!         inst0: op(Def: v0i reg)
+//!         inst1: op(Def: v1i any, Use: v0i reg) clobbers(p1i)
+//!         inst2: branch() -> block1(v1i)
+//!     block1(v3i) preds(block0):
+//!         inst3: op(Def: v2i reg, Use: v3i any) move(Use: v3i any, Def: v2i reg)
+//!         inst4: op() safepoint
+//!         inst5: ret(Use@Late: v2i fixed(p0i))
+//! }
+//! ```
+//!
+//! Registers are written as in their `Display` output, and so are
+//! operands: the kind (with the position if it is not the usual one
+//! for that kind), the vreg with the letter of its class, and the
+//! constraint. Every instruction is an `op`, a `branch` (followed by
+//! the successors and their arguments) or a `ret`; `move` gives the
+//! operands of `Function::is_move`, and `safepoint` marks an
+//! instruction for which `Function::requires_refs_on_stack` holds.
+//! Blocks must be written in index order, and the instructions of
+//! each block must be numbered consecutively, but the instruction
+//! ranges of the blocks need not follow block order: a later block
+//! may take lower instruction indices, as long as every index is
+//! used exactly once. Everything after a `#` on a line is a
+//! comment. Either section may be omitted when only the other is
+//! needed.
+
+use crate::{
+    Block, Function, Inst, InstRange, MachineEnv, Operand, OperandConstraint, OperandKind,
+    OperandPos, PReg, PRegSet, RegClass, StackLoc, VReg,
+};
+use fxhash::FxHashMap;
+use std::fmt::Write;
+
+/// An error found while parsing the text format.
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    /// The line, starting at 1, on which the error was found.
+    pub line: usize,
+    /// What is wrong there.
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InstKind {
+    Op,
+    Branch,
+    Ret,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct InstData {
+    pub(crate) kind: InstKind,
+    pub(crate) operands: Vec<Operand>,
+    pub(crate) is_move: Option<(Operand, Operand)>,
+    pub(crate) clobbers: PRegSet,
+    pub(crate) is_safepoint: bool,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct BlockData {
+    pub(crate) insts: InstRange,
+    pub(crate) params: Vec<VReg>,
+    pub(crate) succs: Vec<Block>,
+    pub(crate) preds: Vec<Block>,
+    pub(crate) branch_args: Vec<Vec<VReg>>,
+}
+
+/// A function read from the text format, or copied from another
+/// `Function` with `TextFunction::from_function`.
+#[derive(Clone, Debug)]
+pub struct TextFunction {
+    pub(crate) entry: Block,
+    pub(crate) num_vregs: usize,
+    pub(crate) blocks: Vec<BlockData>,
+    pub(crate) insts: Vec<InstData>,
+    pub(crate) reftype_vregs: Vec<VReg>,
+    pub(crate) pinned_vregs: FxHashMap<VReg, PReg>,
+    pub(crate) remat_costs: FxHashMap<VReg, u32>,
+    pub(crate) debug_value_labels: Vec<(VReg, Inst, Inst, u32)>,
+    pub(crate) spillslot_sizes: [usize; RegClass::COUNT],
+    pub(crate) multi_spillslot_named_by_last_slot: bool,
+    pub(crate) allow_multiple_vreg_defs: bool,
+}
+
+impl TextFunction {
+    /// Copy `func`, e.g. to print or edit it.
+    pub fn from_function<F: Function>(func: &F) -> TextFunction {
+        let vregs = mentioned_vregs(func);
+        let blocks = (0..func.num_blocks())
+            .map(|block| {
+                let block = Block::new(block);
+                let insts = func.block_insns(block);
+                BlockData {
+                    insts,
+                    params: func.block_params(block).to_vec(),
+                    succs: func.block_succs(block).to_vec(),
+                    preds: func.block_preds(block).to_vec(),
+                    branch_args: (0..func.block_succs(block).len())
+                        .map(|succ_idx| {
+                            func.branch_blockparams(block, insts.last(), succ_idx)
+                                .to_vec()
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        let insts = (0..func.num_insts())
+            .map(|inst| {
+                let inst = Inst::new(inst);
+                InstData {
+                    kind: if func.is_branch(inst) {
+                        InstKind::Branch
+                    } else if func.is_ret(inst) {
+                        InstKind::Ret
+                    } else {
+                        InstKind::Op
+                    },
+                    operands: func.inst_operands(inst).to_vec(),
+                    is_move: func.is_move(inst),
+                    clobbers: func.inst_clobbers(inst),
+                    is_safepoint: func.requires_refs_on_stack(inst),
+                }
+            })
+            .collect();
+        let mut spillslot_sizes = [0; RegClass::COUNT];
+        for &class in &RegClass::ALL {
+            spillslot_sizes[class as usize] = func.spillslot_size(class);
+        }
+        TextFunction {
+            entry: func.entry_block(),
+            num_vregs: func.num_vregs(),
+            blocks,
+            insts,
+            reftype_vregs: func.reftype_vregs().to_vec(),
+            pinned_vregs: vregs
+                .iter()
+                .filter_map(|&vreg| Some((vreg, func.is_pinned_vreg(vreg)?)))
+                .collect(),
+            remat_costs: vregs
+                .iter()
+                .filter_map(|&vreg| Some((vreg, func.remat_cost(vreg)?)))
+                .collect(),
+            debug_value_labels: func.debug_value_labels().to_vec(),
+            spillslot_sizes,
+            multi_spillslot_named_by_last_slot: func.multi_spillslot_named_by_last_slot(),
+            allow_multiple_vreg_defs: func.allow_multiple_vreg_defs(),
+        }
+    }
+}
+
+impl Function for TextFunction {
+    fn num_insts(&self) -> usize {
+        self.insts.len()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn entry_block(&self) -> Block {
+        self.entry
+    }
+
+    fn block_insns(&self, block: Block) -> InstRange {
+        self.blocks[block.index()].insts
+    }
+
+    fn block_succs(&self, block: Block) -> &[Block] {
+        &self.blocks[block.index()].succs[..]
+    }
+
+    fn block_preds(&self, block: Block) -> &[Block] {
+        &self.blocks[block.index()].preds[..]
+    }
+
+    fn block_params(&self, block: Block) -> &[VReg] {
+        &self.blocks[block.index()].params[..]
+    }
+
+    fn is_ret(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].kind == InstKind::Ret
+    }
+
+    fn is_branch(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].kind == InstKind::Branch
+    }
+
+    fn branch_blockparams(&self, block: Block, _: Inst, succ_idx: usize) -> &[VReg] {
+        &self.blocks[block.index()].branch_args[succ_idx][..]
+    }
+
+    fn requires_refs_on_stack(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].is_safepoint
+    }
+
+    fn is_move(&self, insn: Inst) -> Option<(Operand, Operand)> {
+        self.insts[insn.index()].is_move
+    }
+
+    fn inst_operands(&self, insn: Inst) -> &[Operand] {
+        &self.insts[insn.index()].operands[..]
+    }
+
+    fn inst_clobbers(&self, insn: Inst) -> PRegSet {
+        self.insts[insn.index()].clobbers
+    }
+
+    fn num_vregs(&self) -> usize {
+        self.num_vregs
+    }
+
+    fn reftype_vregs(&self) -> &[VReg] {
+        &self.reftype_vregs[..]
+    }
+
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        &self.debug_value_labels[..]
+    }
+
+    fn is_pinned_vreg(&self, vreg: VReg) -> Option<PReg> {
+        self.pinned_vregs.get(&vreg).cloned()
+    }
+
+    fn spillslot_size(&self, regclass: RegClass) -> usize {
+        self.spillslot_sizes[regclass as usize]
+    }
+
+    fn multi_spillslot_named_by_last_slot(&self) -> bool {
+        self.multi_spillslot_named_by_last_slot
+    }
+
+    fn remat_cost(&self, vreg: VReg) -> Option<u32> {
+        self.remat_costs.get(&vreg).cloned()
+    }
+
+    fn allow_multiple_vreg_defs(&self) -> bool {
+        self.allow_multiple_vreg_defs
+    }
+}
+
+impl std::fmt::Display for TextFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write_function(f, self)
+    }
+}
+
+/// The sections of an input in the text format.
+#[derive(Clone, Debug)]
+pub struct Sections {
+    /// The `function` section, if any.
+    pub func: Option<TextFunction>,
+    /// The `machine_env` section, if any.
+    pub env: Option<MachineEnv>,
+}
+
+/// Parse all sections of `input`.
+pub fn parse(input: &str) -> Result<Sections, ParseError> {
+    Parser::new(input)?.sections()
+}
+
+/// Parse the `function` section of `input`.
+pub fn parse_function(input: &str) -> Result<TextFunction, ParseError> {
+    let sections = parse(input)?;
+    match sections.func {
+        Some(func) => Ok(func),
+        None => Err(ParseError {
+            line: 1,
+            message: "no function section".to_string(),
+        }),
+    }
+}
+
+/// Parse the `machine_env` section of `input`.
+pub fn parse_machine_env(input: &str) -> Result<MachineEnv, ParseError> {
+    let sections = parse(input)?;
+    match sections.env {
+        Some(env) => Ok(env),
+        None => Err(ParseError {
+            line: 1,
+            message: "no machine_env section".to_string(),
+        }),
+    }
+}
+
+/// The vregs that `func` mentions, in order. Pinned and
+/// rematerializable vregs can only be found by asking about each of
+/// these.
+fn mentioned_vregs<F: Function>(func: &F) -> Vec<VReg> {
+    let mut vregs = vec![];
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        vregs.extend_from_slice(func.block_params(block));
+        for inst in func.block_insns(block).iter() {
+            vregs.extend(func.inst_operands(inst).iter().map(|op| op.vreg()));
+            if let Some((src, dst)) = func.is_move(inst) {
+                vregs.push(src.vreg());
+                vregs.push(dst.vreg());
+            }
+            if func.is_branch(inst) {
+                for succ_idx in 0..func.block_succs(block).len() {
+                    vregs.extend_from_slice(func.branch_blockparams(block, inst, succ_idx));
+                }
+            }
+        }
+    }
+    vregs.sort_unstable();
+    vregs.dedup();
+    vregs
+}
+
+/// Print `func` as a `function` section.
+pub fn write_function<F: Function, W: Write>(w: &mut W, func: &F) -> std::fmt::Result {
+    let vregs = mentioned_vregs(func);
+
+    writeln!(w, "function {{")?;
+    writeln!(w, "    entry block{}", func.entry_block().index())?;
+    writeln!(w, "    vregs {}", func.num_vregs())?;
+    let sizes = RegClass::ALL
+        .iter()
+        .map(|&class| format!("{}: {}", class.suffix(), func.spillslot_size(class)))
+        .collect::<Vec<_>>();
+    writeln!(w, "    spillslot_sizes({})", sizes.join(", "))?;
+    if func.multi_spillslot_named_by_last_slot() {
+        writeln!(w, "    multi_spillslot_named_by_last_slot")?;
+    }
+    if func.allow_multiple_vreg_defs() {
+        writeln!(w, "    allow_multiple_vreg_defs")?;
+    }
+    for &vreg in func.reftype_vregs() {
+        writeln!(w, "    reftype {}", vreg_name(vreg))?;
+    }
+    for &vreg in &vregs {
+        if let Some(preg) = func.is_pinned_vreg(vreg) {
+            writeln!(w, "    pinned {} {}", vreg_name(vreg), preg)?;
+        }
+    }
+    for &vreg in &vregs {
+        if let Some(cost) = func.remat_cost(vreg) {
+            writeln!(w, "    remat {} {}", vreg_name(vreg), cost)?;
+        }
+    }
+    for &(vreg, from, to, label) in func.debug_value_labels() {
+        writeln!(
+            w,
+            "    debug_label {} inst{} inst{} {}",
+            vreg_name(vreg),
+            from.index(),
+            to.index(),
+            label
+        )?;
+    }
+
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        write!(
+            w,
+            "    block{}({})",
+            block.index(),
+            join(func.block_params(block).iter().map(|&v| vreg_name(v)))
+        )?;
+        if !func.block_preds(block).is_empty() {
+            write!(
+                w,
+                " preds({})",
+                join(
+                    func.block_preds(block)
+                        .iter()
+                        .map(|b| format!("block{}", b.index()))
+                )
+            )?;
+        }
+        writeln!(w, ":")?;
+
+        for inst in func.block_insns(block).iter() {
+            let kind = if func.is_branch(inst) {
+                "branch"
+            } else if func.is_ret(inst) {
+                "ret"
+            } else {
+                "op"
+            };
+            write!(
+                w,
+                "        inst{}: {}({})",
+                inst.index(),
+                kind,
+                join(func.inst_operands(inst).iter())
+            )?;
+            if let Some((src, dst)) = func.is_move(inst) {
+                write!(w, " move({}, {})", src, dst)?;
+            }
+            let clobbers = func.inst_clobbers(inst);
+            if !clobbers.is_empty() {
+                write!(w, " clobbers({})", join(clobbers.into_iter()))?;
+            }
+            if func.requires_refs_on_stack(inst) {
+                write!(w, " safepoint")?;
+            }
+            if func.is_branch(inst) && !func.block_succs(block).is_empty() {
+                let succs = func
+                    .block_succs(block)
+                    .iter()
+                    .enumerate()
+                    .map(|(succ_idx, succ)| {
+                        let args = func.branch_blockparams(block, inst, succ_idx);
+                        format!(
+                            "block{}({})",
+                            succ.index(),
+                            join(args.iter().map(|&v| vreg_name(v)))
+                        )
+                    });
+                write!(w, " -> {}", join(succs))?;
+            }
+            writeln!(w)?;
+        }
+    }
+    writeln!(w, "}}")
+}
+
+/// Print `env` as a `machine_env` section.
+pub fn write_machine_env<W: Write>(w: &mut W, env: &MachineEnv) -> std::fmt::Result {
+    writeln!(w, "machine_env {{")?;
+    writeln!(
+        w,
+        "    preferred({})",
+        join(env.preferred_regs_by_class.iter().flatten())
+    )?;
+    writeln!(
+        w,
+        "    non_preferred({})",
+        join(env.non_preferred_regs_by_class.iter().flatten())
+    )?;
+    writeln!(
+        w,
+        "    fixed_stack_slots({})",
+        join(env.fixed_stack_slots.iter())
+    )?;
+    for &(a, b) in &env.aliases {
+        writeln!(w, "    alias({}, {})", a, b)?;
+    }
+    for &subset in &env.reg_subsets {
+        writeln!(w, "    reg_subset({})", join(subset.into_iter()))?;
+    }
+    writeln!(
+        w,
+        "    stackmap_regs({})",
+        join(env.stackmap_regs.into_iter())
+    )?;
+    writeln!(w, "}}")
+}
+
+/// The name of a vreg in the text format, which includes its class,
+/// unlike its `Display` output.
+fn vreg_name(vreg: VReg) -> String {
+    format!("{}{}", vreg, vreg.class().suffix())
+}
+
+fn join<T: std::fmt::Display>(items: impl Iterator<Item = T>) -> String {
+    items
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Word(&'a str),
+    Punct(&'static str),
+}
+
+/// A block as parsed, before its instructions are laid out.
+struct ParsedBlock {
+    line: usize,
+    params: Vec<VReg>,
+    preds: Option<Vec<Block>>,
+    insts: Vec<(usize, InstData)>,
+    succs: Vec<(Block, Vec<VReg>)>,
+}
+
+struct Parser<'a> {
+    tokens: Vec<(usize, Token<'a>)>,
+    pos: usize,
+    last_line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Result<Self, ParseError> {
+        const PUNCTS: [&str; 7] = ["->", "(", ")", "{", "}", ":", ","];
+        let mut tokens = vec![];
+        let mut last_line = 1;
+        for (i, line) in input.lines().enumerate() {
+            let line_num = i + 1;
+            last_line = line_num;
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            let mut rest = line.trim_start();
+            while !rest.is_empty() {
+                if let Some(&punct) = PUNCTS.iter().find(|p| rest.starts_with(*p)) {
+                    tokens.push((line_num, Token::Punct(punct)));
+                    rest = &rest[punct.len()..];
+                } else {
+                    let len = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '@'))
+                        .unwrap_or(rest.len());
+                    if len == 0 {
+                        return Err(ParseError {
+                            line: line_num,
+                            message: format!(
+                                "unexpected character '{}'",
+                                rest.chars().next().unwrap()
+                            ),
+                        });
+                    }
+                    tokens.push((line_num, Token::Word(&rest[..len])));
+                    rest = &rest[len..];
+                }
+                rest = rest.trim_start();
+            }
+        }
+        Ok(Parser {
+            tokens,
+            pos: 0,
+            last_line,
+        })
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.last_line, |&(line, _)| line)
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line(),
+            message,
+        })
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).map(|&(_, token)| token)
+    }
+
+    fn eat_punct(&mut self, punct: &'static str) -> bool {
+        if self.peek() == Some(Token::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_punct(&mut self, punct: &'static str) -> Result<(), ParseError> {
+        if self.eat_punct(punct) {
+            Ok(())
+        } else {
+            self.error(format!("expected '{}'", punct))
+        }
+    }
+
+    fn word(&mut self) -> Result<&'a str, ParseError> {
+        match self.peek() {
+            Some(Token::Word(word)) => {
+                self.pos += 1;
+                Ok(word)
+            }
+            _ => self.error("expected a word".to_string()),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T, ParseError> {
+        let word = self.word()?;
+        match word.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => {
+                self.pos -= 1;
+                self.error(format!("expected a number, found '{}'", word))
+            }
+        }
+    }
+
+    /// Parse a parenthesized, comma-separated list.
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        self.expect_punct("(")?;
+        let mut items = vec![];
+        if self.eat_punct(")") {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.eat_punct(")") {
+                return Ok(items);
+            }
+            self.expect_punct(",")?;
+        }
+    }
+
+    /// Parse a word made of `prefix`, a number, and a class suffix if
+    /// `with_class` is set.
+    fn indexed(
+        &mut self,
+        prefix: &str,
+        max: usize,
+        with_class: bool,
+    ) -> Result<(usize, RegClass), ParseError> {
+        let word = self.word()?;
+        let parsed = word.strip_prefix(prefix).and_then(|rest| {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let index = rest[..digits].parse::<usize>().ok()?;
+            let class = if with_class {
+                *RegClass::ALL
+                    .iter()
+                    .find(|class| class.suffix() == &rest[digits..])?
+            } else if digits == rest.len() {
+                RegClass::Int
+            } else {
+                return None;
+            };
+            Some((index, class))
+        });
+        self.pos -= 1;
+        match parsed {
+            Some((index, _)) if index > max => {
+                self.error(format!("index in '{}' is out of range", word))
+            }
+            Some(parsed) => {
+                self.pos += 1;
+                Ok(parsed)
+            }
+            None => self.error(format!("expected a '{}' name, found '{}'", prefix, word)),
+        }
+    }
+
+    fn block(&mut self) -> Result<Block, ParseError> {
+        let (index, _) = self.indexed("block", u32::MAX as usize - 1, false)?;
+        Ok(Block::new(index))
+    }
+
+    fn inst(&mut self) -> Result<Inst, ParseError> {
+        let (index, _) = self.indexed("inst", u32::MAX as usize - 1, false)?;
+        Ok(Inst::new(index))
+    }
+
+    fn vreg(&mut self) -> Result<VReg, ParseError> {
+        let (index, class) = self.indexed("v", VReg::MAX - 1, true)?;
+        Ok(VReg::new(index, class))
+    }
+
+    fn preg(&mut self) -> Result<PReg, ParseError> {
+        let (index, class) = self.indexed("p", PReg::MAX, true)?;
+        Ok(PReg::new(index, class))
+    }
+
+    fn preg_set(&mut self) -> Result<PRegSet, ParseError> {
+        let pregs = self.list(|p| p.preg())?;
+        Ok(pregs.into_iter().fold(PRegSet::empty(), PRegSet::with))
+    }
+
+    fn operand(&mut self) -> Result<Operand, ParseError> {
+        let word = self.word()?;
+        let (kind, pos) = match word.find('@') {
+            Some(at) => (&word[..at], Some(&word[at + 1..])),
+            None => (word, None),
+        };
+        let kind = match kind {
+            "Def" => OperandKind::Def,
+            "Mod" => OperandKind::Mod,
+            "Use" => OperandKind::Use,
+            _ => return self.error(format!("unknown operand kind '{}'", kind)),
+        };
+        let pos = match pos {
+            Some("Early") => OperandPos::Early,
+            Some("Late") => OperandPos::Late,
+            Some(pos) => return self.error(format!("unknown operand position '{}'", pos)),
+            None if kind == OperandKind::Def => OperandPos::Late,
+            None => OperandPos::Early,
+        };
+        self.expect_punct(":")?;
+        let vreg = self.vreg()?;
+
+        let constraint = match self.word()? {
+            "any" => OperandConstraint::Any,
+            "reg" => OperandConstraint::Reg,
+            "stack" => OperandConstraint::Stack,
+            "fixed" => {
+                self.expect_punct("(")?;
+                let constraint = if let Some(Token::Word(word)) = self.peek() {
+                    if word.starts_with("loc") {
+                        let (index, _) = self.indexed("loc", StackLoc::MAX, false)?;
+                        if kind == OperandKind::Mod {
+                            return self.error("a mod cannot be in a stack location".to_string());
+                        }
+                        OperandConstraint::FixedStackLoc(StackLoc::new(index, vreg.class()))
+                    } else {
+                        let preg = self.preg()?;
+                        if preg.class() != vreg.class() {
+                            return self.error(format!("{} is not of the class of its vreg", preg));
+                        }
+                        OperandConstraint::FixedReg(preg)
+                    }
+                } else {
+                    return self.error("expected a register or stack location".to_string());
+                };
+                self.expect_punct(")")?;
+                constraint
+            }
+            "reuse" => {
+                self.expect_punct("(")?;
+                let index = self.number()?;
+                if index > 31 {
+                    return self.error("reused operand index is out of range".to_string());
+                }
+                self.expect_punct(")")?;
+                OperandConstraint::Reuse(index)
+            }
+            "subset" => {
+                self.expect_punct("(")?;
+                let index = self.number()?;
+                if index >= Operand::REG_SUBSET_TAG as usize {
+                    return self.error("register subset index is out of range".to_string());
+                }
+                self.expect_punct(")")?;
+                OperandConstraint::RegSubset(index)
+            }
+            constraint => {
+                self.pos -= 1;
+                return self.error(format!("unknown constraint '{}'", constraint));
+            }
+        };
+        Ok(Operand::new(vreg, constraint, kind, pos))
+    }
+
+    fn sections(mut self) -> Result<Sections, ParseError> {
+        let mut sections = Sections {
+            func: None,
+            env: None,
+        };
+        while self.peek().is_some() {
+            match self.word()? {
+                "function" if sections.func.is_none() => {
+                    sections.func = Some(self.function()?);
+                }
+                "machine_env" if sections.env.is_none() => {
+                    sections.env = Some(self.machine_env()?);
+                }
+                word => {
+                    self.pos -= 1;
+                    return self.error(format!("unexpected '{}'", word));
+                }
+            }
+        }
+        Ok(sections)
+    }
+
+    fn machine_env(&mut self) -> Result<MachineEnv, ParseError> {
+        let mut env = MachineEnv {
+            preferred_regs_by_class: Default::default(),
+            non_preferred_regs_by_class: Default::default(),
+            fixed_stack_slots: vec![],
+            aliases: vec![],
+            reg_subsets: vec![],
+            stackmap_regs: PRegSet::empty(),
+        };
+        self.expect_punct("{")?;
+        while !self.eat_punct("}") {
+            match self.word()? {
+                "preferred" => {
+                    for preg in self.list(|p| p.preg())? {
+                        env.preferred_regs_by_class[preg.class() as usize].push(preg);
+                    }
+                }
+                "non_preferred" => {
+                    for preg in self.list(|p| p.preg())? {
+                        env.non_preferred_regs_by_class[preg.class() as usize].push(preg);
+                    }
+                }
+                "fixed_stack_slots" => {
+                    env.fixed_stack_slots.extend(self.list(|p| p.preg())?);
+                }
+                "alias" => {
+                    let pair = self.list(|p| p.preg())?;
+                    if pair.len() != 2 {
+                        return self.error("an alias must name two registers".to_string());
+                    }
+                    env.aliases.push((pair[0], pair[1]));
+                }
+                "reg_subset" => {
+                    let subset = self.preg_set()?;
+                    env.reg_subsets.push(subset);
+                }
+                "stackmap_regs" => {
+                    env.stackmap_regs = self.preg_set()?;
+                }
+                word => {
+                    self.pos -= 1;
+                    return self.error(format!("unexpected '{}'", word));
+                }
+            }
+        }
+        Ok(env)
+    }
+
+    fn function(&mut self) -> Result<TextFunction, ParseError> {
+        let mut func = TextFunction {
+            entry: Block::new(0),
+            num_vregs: 0,
+            blocks: vec![],
+            insts: vec![],
+            reftype_vregs: vec![],
+            pinned_vregs: FxHashMap::default(),
+            remat_costs: FxHashMap::default(),
+            debug_value_labels: vec![],
+            spillslot_sizes: [1; RegClass::COUNT],
+            multi_spillslot_named_by_last_slot: false,
+            allow_multiple_vreg_defs: false,
+        };
+        let mut declared_vregs = None;
+        let mut blocks: Vec<ParsedBlock> = vec![];
+        let start_line = self.line();
+
+        self.expect_punct("{")?;
+        while !self.eat_punct("}") {
+            let line = self.line();
+            match self.word()? {
+                "entry" => func.entry = self.block()?,
+                "vregs" => declared_vregs = Some((line, self.number()?)),
+                "spillslot_sizes" => {
+                    for (class, size) in self.list(|p| {
+                        let class = p.word()?;
+                        p.expect_punct(":")?;
+                        let size = p.number()?;
+                        match RegClass::ALL.iter().find(|c| c.suffix() == class) {
+                            Some(&class) => Ok((class, size)),
+                            None => p.error(format!("unknown class '{}'", class)),
+                        }
+                    })? {
+                        func.spillslot_sizes[class as usize] = size;
+                    }
+                }
+                "multi_spillslot_named_by_last_slot" => {
+                    func.multi_spillslot_named_by_last_slot = true;
+                }
+                "allow_multiple_vreg_defs" => func.allow_multiple_vreg_defs = true,
+                "reftype" => {
+                    let vreg = self.vreg()?;
+                    func.reftype_vregs.push(vreg);
+                }
+                "pinned" => {
+                    let vreg = self.vreg()?;
+                    let preg = self.preg()?;
+                    if preg.class() != vreg.class() {
+                        return self.error(format!("{} is not of the class of its vreg", preg));
+                    }
+                    func.pinned_vregs.insert(vreg, preg);
+                }
+                "remat" => {
+                    let vreg = self.vreg()?;
+                    let cost = self.number()?;
+                    func.remat_costs.insert(vreg, cost);
+                }
+                "debug_label" => {
+                    let vreg = self.vreg()?;
+                    let from = self.inst()?;
+                    let to = self.inst()?;
+                    let label = self.number()?;
+                    func.debug_value_labels.push((vreg, from, to, label));
+                }
+                word if word.starts_with("block") => {
+                    self.pos -= 1;
+                    let block = self.block()?;
+                    if block.index() != blocks.len() {
+                        return Err(ParseError {
+                            line,
+                            message: format!("expected block{}", blocks.len()),
+                        });
+                    }
+                    blocks.push(self.block_body(line)?);
+                }
+                word => {
+                    self.pos -= 1;
+                    return self.error(format!("unexpected '{}'", word));
+                }
+            }
+        }
+
+        Self::lay_out(&mut func, blocks)?;
+
+        let max_vreg = func
+            .blocks
+            .iter()
+            .flat_map(|block| {
+                block
+                    .params
+                    .iter()
+                    .chain(block.branch_args.iter().flatten())
+                    .cloned()
+            })
+            .chain(func.insts.iter().flat_map(|inst| {
+                inst.operands
+                    .iter()
+                    .chain(inst.is_move.iter().flat_map(|(src, dst)| vec![src, dst]))
+                    .map(|op| op.vreg())
+            }))
+            .chain(func.reftype_vregs.iter().cloned())
+            .chain(func.pinned_vregs.keys().cloned())
+            .chain(func.remat_costs.keys().cloned())
+            .chain(func.debug_value_labels.iter().map(|&(vreg, ..)| vreg))
+            .map(|vreg| vreg.vreg() + 1)
+            .max()
+            .unwrap_or(0);
+        func.num_vregs = match declared_vregs {
+            Some((line, num_vregs)) if num_vregs < max_vreg => {
+                return Err(ParseError {
+                    line,
+                    message: format!(
+                        "function uses v{}, but has {} vregs",
+                        max_vreg - 1,
+                        num_vregs
+                    ),
+                });
+            }
+            Some((_, num_vregs)) => num_vregs,
+            None => max_vreg,
+        };
+        if func.entry.index() >= func.blocks.len() {
+            return Err(ParseError {
+                line: start_line,
+                message: format!("entry block{} does not exist", func.entry.index()),
+            });
+        }
+        // `Function::debug_value_labels` requires them sorted by vreg.
+        func.debug_value_labels.sort_unstable();
+        Ok(func)
+    }
+
+    /// Parse the rest of a block, after its name.
+    fn block_body(&mut self, line: usize) -> Result<ParsedBlock, ParseError> {
+        let mut block = ParsedBlock {
+            line,
+            params: self.list(|p| p.vreg())?,
+            preds: None,
+            insts: vec![],
+            succs: vec![],
+        };
+        if let Some(Token::Word("preds")) = self.peek() {
+            self.pos += 1;
+            block.preds = Some(self.list(|p| p.block())?);
+        }
+        self.expect_punct(":")?;
+
+        while let Some(Token::Word(word)) = self.peek() {
+            if !word.starts_with("inst") {
+                break;
+            }
+            if matches!(block.insts.last(), Some((_, inst)) if inst.kind != InstKind::Op) {
+                return self.error("instruction after the end of the block".to_string());
+            }
+            let inst = self.inst()?;
+            self.expect_punct(":")?;
+            let kind = match self.word()? {
+                "op" => InstKind::Op,
+                "branch" => InstKind::Branch,
+                "ret" => InstKind::Ret,
+                word => {
+                    self.pos -= 1;
+                    return self.error(format!("unknown instruction '{}'", word));
+                }
+            };
+            let mut data = InstData {
+                kind,
+                operands: self.list(|p| p.operand())?,
+                is_move: None,
+                clobbers: PRegSet::empty(),
+                is_safepoint: false,
+            };
+            loop {
+                match self.peek() {
+                    Some(Token::Word("move")) => {
+                        self.pos += 1;
+                        let ops = self.list(|p| p.operand())?;
+                        if ops.len() != 2 {
+                            return self.error("a move must have two operands".to_string());
+                        }
+                        data.is_move = Some((ops[0], ops[1]));
+                    }
+                    Some(Token::Word("clobbers")) => {
+                        self.pos += 1;
+                        data.clobbers = self.preg_set()?;
+                    }
+                    Some(Token::Word("safepoint")) => {
+                        self.pos += 1;
+                        data.is_safepoint = true;
+                    }
+                    Some(Token::Punct("->")) if kind == InstKind::Branch => {
+                        self.pos += 1;
+                        loop {
+                            let succ = self.block()?;
+                            let args = self.list(|p| p.vreg())?;
+                            block.succs.push((succ, args));
+                            if !self.eat_punct(",") {
+                                break;
+                            }
+                        }
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+            block.insts.push((inst.index(), data));
+        }
+        Ok(block)
+    }
+
+    /// Place the instructions of `blocks` at their indices and fill
+    /// in the CFG of `func`.
+    fn lay_out(func: &mut TextFunction, blocks: Vec<ParsedBlock>) -> Result<(), ParseError> {
+        let num_blocks = blocks.len();
+        let num_insts = blocks.iter().map(|block| block.insts.len()).sum();
+        let mut insts: Vec<Option<InstData>> = vec![None; num_insts];
+        let mut preds = vec![vec![]; num_blocks];
+        for (index, block) in blocks.iter().enumerate() {
+            for &(succ, _) in &block.succs {
+                if succ.index() >= num_blocks {
+                    return Err(ParseError {
+                        line: block.line,
+                        message: format!("successor block{} does not exist", succ.index()),
+                    });
+                }
+                preds[succ.index()].push(Block::new(index));
+            }
+        }
+
+        for (index, block) in blocks.into_iter().enumerate() {
+            let line = block.line;
+            let error = |message: String| Err(ParseError { line, message });
+            let first = match block.insts.first() {
+                Some(&(first, _)) => first,
+                None => return error(format!("block{} has no instructions", index)),
+            };
+            let len = block.insts.len();
+            let mut block_preds = preds[index].clone();
+            if let Some(listed) = &block.preds {
+                let mut sorted = listed.clone();
+                sorted.sort_unstable();
+                block_preds.sort_unstable();
+                if sorted != block_preds {
+                    return error(format!(
+                        "preds of block{} do not match the branches to it",
+                        index
+                    ));
+                }
+                block_preds = listed.clone();
+            }
+            for (i, (inst, data)) in block.insts.into_iter().enumerate() {
+                if inst != first + i {
+                    return error(format!(
+                        "instructions of block{} are not numbered consecutively",
+                        index
+                    ));
+                }
+                match insts.get_mut(inst) {
+                    Some(slot @ None) => *slot = Some(data),
+                    _ => return error(format!("inst{} is out of range or repeated", inst)),
+                }
+            }
+            let (succs, branch_args) = block.succs.into_iter().unzip();
+            func.blocks.push(BlockData {
+                insts: InstRange::forward(Inst::new(first), Inst::new(first + len)),
+                params: block.params,
+                succs,
+                preds: block_preds,
+                branch_args,
+            });
+        }
+
+        // Every slot was filled exactly once, as each block's
+        // instructions are consecutive and they add up to the total.
+        func.insts = insts.into_iter().map(|inst| inst.unwrap()).collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "
+machine_env {
+    preferred(p0i, p1i, p2i, p0f, p1f)
+    non_preferred(p3i, p2f)
+    fixed_stack_slots(p63i)
+    alias(p2f, p0f)
+    reg_subset(p0i, p1i)
+    stackmap_regs()
+}
+
+function {
+    entry block0
+    vregs 6
+    spillslot_sizes(i: 1, f: 2, v: 4, p: 1)
+    reftype v3i
+    pinned v5i p2i
+    remat v1i 10
+    debug_label v0i inst0 inst2 7
+    block0():
+        inst0: op(Def: v0i reg)  # a comment
+        inst1: op(Def: v1i any, Use: v0i reg) clobbers(p1i)
+        inst2: branch() -> block1(v1i)
+    block1(v3i) preds(block0):
+        inst3: op(Def: v2i reg, Use: v3i any) move(Use: v3i any, Def: v2i reg)
+        inst4: op(Def@Early: v4f fixed(p1f), Use@Late: v2i subset(0), Def: v5i fixed(loc3)) safepoint
+        inst5: ret(Use: v2i fixed(p0i))
+}
+";
+
+    #[test]
+    fn round_trip() {
+        let func = parse_function(INPUT).unwrap();
+        let env = parse_machine_env(INPUT).unwrap();
+        assert_eq!(func.num_blocks(), 2);
+        assert_eq!(func.num_insts(), 6);
+        assert_eq!(func.num_vregs(), 6);
+        assert_eq!(func.block_preds(Block::new(1)), &[Block::new(0)]);
+        assert_eq!(
+            func.branch_blockparams(Block::new(0), Inst::new(2), 0),
+            &[VReg::new(1, RegClass::Int)]
+        );
+        assert!(func.is_move(Inst::new(3)).is_some());
+        assert!(func.requires_refs_on_stack(Inst::new(4)));
+        assert_eq!(
+            func.is_pinned_vreg(VReg::new(5, RegClass::Int)),
+            Some(PReg::new(2, RegClass::Int))
+        );
+        assert_eq!(
+            env.preferred_regs_by_class[RegClass::Float as usize].len(),
+            2
+        );
+
+        let mut printed = String::new();
+        write_machine_env(&mut printed, &env).unwrap();
+        write_function(&mut printed, &func).unwrap();
+        let reparsed = parse_function(&printed).unwrap();
+        let mut reprinted = String::new();
+        write_machine_env(&mut reprinted, &parse_machine_env(&printed).unwrap()).unwrap();
+        write_function(&mut reprinted, &reparsed).unwrap();
+        assert_eq!(printed, reprinted);
+
+        let copy = TextFunction::from_function(&func);
+        assert_eq!(copy.to_string(), func.to_string());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |input: &str| parse_function(input).unwrap_err();
+        assert_eq!(error("function { vregs x }").line, 1);
+        let e = error("function {\n block0():\n  inst0: op(Def: v0i fixed(p0f))\n}");
+        assert_eq!(e.line, 3);
+        let e = error("function {\n block0():\n  inst0: branch() -> block1()\n}");
+        assert_eq!(e.line, 2);
+        let e = error("function {\n block0():\n  inst1: ret()\n}");
+        assert_eq!(e.line, 2);
+        let e = error("function {\n block0():\n  inst0: ret()\n  inst1: ret()\n}");
+        assert_eq!(e.line, 4);
+    }
+}
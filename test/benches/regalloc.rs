@@ -6,12 +6,10 @@ use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Through
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 use regalloc2::fuzzing::func::{machine_env, Func};
-use regalloc2::ion;
-use regalloc2::Function;
+use regalloc2::{Function, RegallocOptions};
 
 fn create_random_func(seed: u64, size: usize) -> Func {
-    let mut bytes: Vec<u8> = vec![];
-    bytes.resize(size, 0);
+    let mut bytes: Vec<u8> = vec![0; size];
     let mut rng = ChaCha8Rng::seed_from_u64(seed);
     rng.fill(&mut bytes[..]);
     loop {
@@ -37,15 +35,16 @@ fn run_regalloc(c: &mut Criterion) {
     let mut group = c.benchmark_group("benches");
     for iter in 0..3 {
         let func = create_random_func(iter, SIZE);
-        eprintln!("==== {} instructions", func.insts());
-        group.throughput(Throughput::Elements(func.insts() as u64));
+        eprintln!("==== {} instructions", func.num_insts());
+        group.throughput(Throughput::Elements(func.num_insts() as u64));
         group.bench_with_input(BenchmarkId::from_parameter(iter), &iter, |b, _| {
             b.iter(|| {
                 // For fair comparison with regalloc.rs, which needs
                 // to clone its Func on every alloc, we clone
                 // too. Seems to make a few percent difference.
                 let func = func.clone();
-                ion::run(&func, &env).expect("regalloc did not succeed");
+                regalloc2::run(&func, &env, &RegallocOptions::default())
+                    .expect("regalloc did not succeed");
             });
         });
     }
@@ -3,16 +3,104 @@
  * exception. See `LICENSE` for details.
  */
 
+//! Command-line driver that runs the allocator on a function saved in
+//! the text format of `regalloc2::text`, e.g. to replay a bug report,
+//! or on a random function.
+
 use arbitrary::{Arbitrary, Unstructured};
+use clap::{Parser, ValueEnum};
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
+use regalloc2::checker::Checker;
 use regalloc2::fuzzing::func::{machine_env, Func};
-use regalloc2::ion;
-use regalloc2::Function;
+use regalloc2::{
+    Algorithm, AllocationEvent, AllocationObserver, Block, Edit, Function, InstOrEdit, MachineEnv,
+    Output, RegallocContext, RegallocOptions, RunCheckedError,
+};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum AlgorithmArg {
+    Ion,
+    Fastalloc,
+    SpillEverything,
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Runs regalloc2 on a saved or random function")]
+struct Args {
+    /// The function to allocate, in the text format. If the file has
+    /// no `machine_env` section, the environment used for fuzzing is
+    /// used.
+    #[arg(required_unless_present = "random")]
+    input: Option<PathBuf>,
+
+    /// Allocate a random function generated from this seed instead.
+    #[arg(long, conflicts_with = "input")]
+    random: Option<u64>,
+
+    /// The size in bytes of the random input from which the random
+    /// function is generated.
+    #[arg(long, default_value_t = 1000 * 1000)]
+    random_size: usize,
+
+    /// The allocation algorithm.
+    #[arg(long, value_enum, default_value_t = AlgorithmArg::Ion)]
+    algorithm: AlgorithmArg,
+
+    /// Bound the work of the Ion allocator (`RegallocOptions::budget`).
+    #[arg(long)]
+    budget: Option<usize>,
+
+    /// Add extra verbosity to the allocator's debug logs.
+    #[arg(long)]
+    verbose_log: bool,
+
+    /// Log at trace level, regardless of `RUST_LOG`.
+    #[arg(long)]
+    trace: bool,
+
+    /// Verify the result with the checker.
+    #[arg(long)]
+    check: bool,
+
+    /// Run the allocator this many times, for timing.
+    #[arg(long, default_value_t = 1)]
+    iterations: u32,
+
+    /// Print the function and environment before allocating.
+    #[arg(long)]
+    print_input: bool,
+
+    /// Print only stats and timing, not the allocations and edits.
+    #[arg(long)]
+    summary: bool,
+
+    /// Print each decision of the Ion allocator as it is made.
+    #[arg(long)]
+    events: bool,
+
+    /// Instead of printing the allocation, shrink a function on which
+    /// allocation or the checker fails, or the allocator panics, to a
+    /// smaller one that fails the same way, and write that to this
+    /// file.
+    #[arg(long)]
+    reduce: Option<PathBuf>,
+
+    /// Write the Ion allocator's final state to this HTML file. Only
+    /// supported with `--algorithm ion`.
+    #[arg(long)]
+    html: Option<PathBuf>,
+
+    /// Include intermediate states in the HTML file.
+    #[arg(long, requires = "html")]
+    html_intermediate: bool,
+}
 
 fn create_random_func(seed: u64, size: usize) -> Func {
-    let mut bytes: Vec<u8> = vec![];
-    bytes.resize(size, 0);
+    let mut bytes: Vec<u8> = vec![0; size];
     let mut rng = ChaCha8Rng::seed_from_u64(seed);
     rng.fill(&mut bytes[..]);
     loop {
@@ -31,20 +119,195 @@ fn create_random_func(seed: u64, size: usize) -> Func {
     }
 }
 
+/// Prints allocation events, for `--events`.
+struct PrintEvents;
+
+impl AllocationObserver for PrintEvents {
+    fn event(&self, event: &AllocationEvent<'_>) {
+        println!("event: {:?}", event);
+    }
+}
+
 fn main() {
-    const SIZE: usize = 1000 * 1000;
-    env_logger::init();
-    let env = machine_env();
-    for iter in 0..3 {
-        let func = create_random_func(iter, SIZE);
-        eprintln!("==== {} instructions", func.insts());
-        let mut stats: ion::Stats = ion::Stats::default();
-        for i in 0..1000 {
-            let out = ion::run(&func, &env).expect("regalloc did not succeed");
-            if i == 0 {
-                stats = out.stats;
+    let args = Args::parse();
+    if args.trace {
+        env_logger::Builder::new()
+            .filter_level(log::LevelFilter::Trace)
+            .init();
+    } else {
+        env_logger::init();
+    }
+
+    let result = match (&args.input, args.random) {
+        (Some(path), _) => std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| regalloc2::text::parse(&text).map_err(|e| e.to_string()))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+            .and_then(|sections| match sections.func {
+                Some(func) => run(&func, &sections.env.unwrap_or_else(machine_env), &args),
+                None => Err(format!("{}: no function section", path.display())),
+            }),
+        (None, Some(seed)) => run(
+            &create_random_func(seed, args.random_size),
+            &machine_env(),
+            &args,
+        ),
+        (None, None) => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run<F: Function>(func: &F, env: &MachineEnv, args: &Args) -> Result<(), String> {
+    if args.print_input {
+        let mut text = String::new();
+        regalloc2::text::write_machine_env(&mut text, env).unwrap();
+        regalloc2::text::write_function(&mut text, func).unwrap();
+        println!("{}", text);
+    }
+
+    let options = RegallocOptions {
+        verbose_log: args.verbose_log,
+        algorithm: match args.algorithm {
+            AlgorithmArg::Ion => Algorithm::Ion,
+            AlgorithmArg::Fastalloc => Algorithm::Fastalloc,
+            AlgorithmArg::SpillEverything => Algorithm::SpillEverything,
+        },
+        budget: args.budget,
+        observer: if args.events {
+            Some(&PrintEvents)
+        } else {
+            None
+        },
+    };
+    if let Some(path) = &args.reduce {
+        return reduce(func, env, &options, path);
+    }
+
+    let mut ctx = RegallocContext::new();
+    let mut output = Output::default();
+    let mut total = Duration::ZERO;
+    for _ in 0..args.iterations.max(1) {
+        let start = Instant::now();
+        regalloc2::run_with_ctx(func, env, &options, &mut ctx, &mut output)
+            .map_err(|e| format!("allocation failed: {}", e))?;
+        total += start.elapsed();
+    }
+
+    if let Some(path) = &args.html {
+        let (_, html) = regalloc2::visualize(func, env, &options, args.html_intermediate)
+            .map_err(|e| format!("allocation failed: {}", e))?;
+        std::fs::write(path, html).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+
+    if !args.summary {
+        print_output(func, &output);
+    }
+    println!("spillslots: {}", output.num_spillslots);
+    println!("stats: {:?}", output.stats);
+    println!("quality: {:?}", output.quality);
+    let per_iteration = total / args.iterations.max(1);
+    println!(
+        "time: {:?} for {} instructions ({:.0} instructions/s)",
+        per_iteration,
+        func.num_insts(),
+        func.num_insts() as f64 / per_iteration.as_secs_f64()
+    );
+
+    if args.check {
+        let mut checker = Checker::new(func, env);
+        checker.prepare(&output);
+        checker
+            .run()
+            .map_err(|e| format!("checker failed: {}", e))?;
+        println!("checker: ok");
+    }
+    Ok(())
+}
+
+/// How allocating a function fails, for `--reduce`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Failure {
+    Allocation,
+    Checker,
+    Panic,
+}
+
+fn failure<F: Function>(func: &F, env: &MachineEnv, options: &RegallocOptions) -> Option<Failure> {
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        regalloc2::run_checked(func, env, options)
+    }));
+    match result {
+        Ok(Ok(_)) => None,
+        Ok(Err(RunCheckedError::Alloc(_))) => Some(Failure::Allocation),
+        Ok(Err(RunCheckedError::Checker(_))) => Some(Failure::Checker),
+        Err(_) => Some(Failure::Panic),
+    }
+}
+
+fn reduce<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+    path: &Path,
+) -> Result<(), String> {
+    // Keep the panics of the many failing runs quiet.
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let original = failure(func, env, options);
+    let reduced = original.map(|original| {
+        regalloc2::fuzzing::reduce::reduce(func, |func| {
+            failure(func, env, options) == Some(original)
+        })
+    });
+    std::panic::set_hook(hook);
+    let (original, reduced) = match (original, reduced) {
+        (Some(original), Some(reduced)) => (original, reduced),
+        _ => return Err("the input does not fail, so there is nothing to reduce".to_string()),
+    };
+
+    let mut text = String::new();
+    regalloc2::text::write_machine_env(&mut text, env).unwrap();
+    regalloc2::text::write_function(&mut text, &reduced).unwrap();
+    std::fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    println!(
+        "reduced {:?} failure from {} blocks and {} instructions to {} blocks and {} instructions",
+        original,
+        func.num_blocks(),
+        func.num_insts(),
+        reduced.num_blocks(),
+        reduced.num_insts()
+    );
+    Ok(())
+}
+
+fn print_output<F: Function>(func: &F, output: &Output) {
+    for block in 0..func.num_blocks() {
+        let block = Block::new(block);
+        println!("block{}:", block.index());
+        for inst_or_edit in output.block_insts_and_edits(func, block) {
+            match inst_or_edit {
+                InstOrEdit::Inst(inst) => {
+                    let allocs = func
+                        .inst_operands(inst)
+                        .iter()
+                        .zip(output.inst_allocs(inst))
+                        .map(|(op, alloc)| format!(" {} => {}", op, alloc))
+                        .collect::<Vec<_>>();
+                    println!("    inst{}:{}", inst.index(), allocs.join(","));
+                }
+                InstOrEdit::Edit(Edit::Move { from, to }) => {
+                    println!("    move {} -> {}", from, to);
+                }
+                InstOrEdit::Edit(Edit::Remat { vreg, to }) => {
+                    println!("    remat {} -> {}", vreg, to);
+                }
             }
         }
-        eprintln!("Stats: {:?}", stats);
+    }
+    for (point, alloc) in &output.safepoint_slots {
+        println!("safepoint slot: {:?} {}", point, alloc);
     }
 }